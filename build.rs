@@ -0,0 +1,20 @@
+// Generates the C header of the ffi module into OUT_DIR, only with the "ffi" feature; the copy
+// in include/ is refreshed with cbindgen by hand and the ffi_header test keeps the two equal
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir))
+            .expect("unable to read cbindgen.toml");
+        // Only the ffi module, the rest of the crate isn't part of the C API
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(format!("{}/src/ffi.rs", crate_dir))
+            .generate()
+            .expect("unable to generate the C header")
+            .write_to_file(format!("{}/rust_image_codec.h", out_dir));
+    }
+}
@@ -0,0 +1,3 @@
+// ric_encode, ric_decode, ric_free and ric_last_error of rust_image_codec::ffi, exported by the
+// shared library
+pub use rust_image_codec::ffi::*;
@@ -0,0 +1,84 @@
+#ifndef RUST_IMAGE_CODEC_H
+#define RUST_IMAGE_CODEC_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define RIC_OK 0
+
+/*
+ Null pointer, wrong buffer length, palette size or dimensions out of range
+ */
+#define RIC_ERR_INVALID_INPUT 1
+
+#define RIC_ERR_KEY 2
+
+/*
+ Unsupported version, header or pixels data doesn't match, wrong compression
+ */
+#define RIC_ERR_DATA 3
+
+#define RIC_ERR_COMPRESSION 4
+
+#define RIC_ERR_PANIC 5
+
+/*
+ Bytes allocated by the library, release them with ric_free
+ */
+typedef struct RicBuffer {
+  uint8_t *data;
+  size_t len;
+} RicBuffer;
+
+/*
+ Encodes width * height * 3 bytes of RGB rows without padding into out.
+ key is 16, 24 or 32 bytes or null for no encryption, compress is 0 or 1.
+ Returns RIC_OK or an error code, see ric_last_error.
+
+ # Safety
+ rgb must point to rgb_len readable bytes, key to key_len bytes when not null,
+ out to a writable RicBuffer.
+ */
+int ric_encode(const uint8_t *rgb,
+               size_t rgb_len,
+               uint32_t width,
+               uint32_t height,
+               uint32_t palette_size,
+               const uint8_t *key,
+               size_t key_len,
+               int compress,
+               struct RicBuffer *out);
+
+/*
+ Decodes a file into RGB rows without padding, compression is detected.
+ key is the one the file was encoded with or null.
+ Returns RIC_OK or an error code, see ric_last_error.
+
+ # Safety
+ data must point to data_len readable bytes, key to key_len bytes when not null,
+ out, width and height to writable values.
+ */
+int ric_decode(const uint8_t *data,
+               size_t data_len,
+               const uint8_t *key,
+               size_t key_len,
+               struct RicBuffer *out,
+               uint32_t *width,
+               uint32_t *height);
+
+/*
+ Releases a buffer filled by ric_encode or ric_decode, a null buffer is ignored.
+
+ # Safety
+ buffer must come from this library and not be freed before.
+ */
+void ric_free(struct RicBuffer buffer);
+
+/*
+ Message of the last error on the calling thread, valid until the next call on it.
+ */
+const char *ric_last_error(void);
+
+#endif  /* RUST_IMAGE_CODEC_H */
@@ -0,0 +1,41 @@
+use age::{Decryptor, Encryptor, IdentityFile, Recipient, x25519};
+use std::{
+    io::{Read, Write},
+    str::FromStr,
+};
+
+pub fn parse_recipient(code: &str) -> Option<x25519::Recipient> {
+    x25519::Recipient::from_str(code).ok()
+}
+
+// Layout: age file length (2 bytes, big endian), then the age file holding the data key
+pub fn wrap_key(data_key: &[u8], recipients: &[x25519::Recipient]) -> Option<Vec<u8>> {
+    let encryptor =
+        Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn Recipient)).ok()?;
+    let mut wrapped = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut wrapped).ok()?;
+    writer.write_all(data_key).ok()?;
+    writer.finish().ok()?;
+    let len = u16::try_from(wrapped.len()).ok()?;
+    let mut block = Vec::with_capacity(2 + wrapped.len());
+    block.extend_from_slice(&len.to_be_bytes());
+    block.extend_from_slice(&wrapped);
+    Some(block)
+}
+
+// Tries every identity of the identity file, returns data key and the whole block length
+pub fn unwrap_key(bytes: &[u8], identity_file_path: &str) -> Option<(Vec<u8>, usize)> {
+    let len = u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]) as usize;
+    let wrapped = bytes.get(2..2 + len)?;
+    let identities = IdentityFile::from_file(identity_file_path.to_string())
+        .ok()?
+        .into_identities()
+        .ok()?;
+    let decryptor = Decryptor::new_buffered(wrapped).ok()?;
+    let mut reader = decryptor
+        .decrypt(identities.iter().map(|identity| identity.as_ref()))
+        .ok()?;
+    let mut data_key = Vec::new();
+    reader.read_to_end(&mut data_key).ok()?;
+    Some((data_key, 2 + len))
+}
@@ -0,0 +1,116 @@
+// Frames of animated GIFs and APNGs with their delays and loop count, which a container keeps
+// per entry and in its head (see container)
+use std::io::{Cursor, Write};
+
+use image::{
+    AnimationDecoder, Delay, Frame, ImageError, ImageFormat, ImageResult, RgbaImage,
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::PngDecoder,
+    },
+    metadata::LoopCount,
+};
+
+// Delay of a frame that has none, e.g. an entry added without --delay
+pub const DEFAULT_DELAY: u32 = 100;
+
+pub struct Animation {
+    // Whole frames, composed over the ones before as the source shows them, and how many
+    // milliseconds each shows for
+    pub frames: Vec<(RgbaImage, u32)>,
+    // Loop count of the source, 0 plays it forever
+    pub loops: u32,
+}
+
+fn delay_ms(delay: Delay) -> u32 {
+    let (numer, denom) = delay.numer_denom_ms();
+    numer.checked_div(denom).unwrap_or(0)
+}
+
+// Frames of a GIF or APNG, None for any other image (a still PNG included)
+pub fn read(bytes: &[u8]) -> ImageResult<Option<Animation>> {
+    let (loops, frames) = match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => {
+            let decoder = GifDecoder::new(Cursor::new(bytes))?;
+            (decoder.loop_count(), decoder.into_frames())
+        }
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(Cursor::new(bytes))?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            let decoder = decoder.apng()?;
+            (decoder.loop_count(), decoder.into_frames())
+        }
+        _ => return Ok(None),
+    };
+    let frames = frames
+        .map(|frame| {
+            let frame = frame?;
+            let delay = delay_ms(frame.delay());
+            Ok((frame.into_buffer(), delay))
+        })
+        .collect::<ImageResult<Vec<_>>>()?;
+    let loops = match loops {
+        LoopCount::Infinite => 0,
+        LoopCount::Finite(loops) => loops.get(),
+    };
+    Ok(Some(Animation { frames, loops }))
+}
+
+fn size_error(what: &str) -> ImageError {
+    ImageError::IoError(std::io::Error::other(what.to_string()))
+}
+
+// Frames of one animation are all of one size
+fn check_frames(animation: &Animation) -> ImageResult<(u32, u32)> {
+    let Some((first, _)) = animation.frames.first() else {
+        return Err(size_error("an animation needs a frame at least"));
+    };
+    if animation
+        .frames
+        .iter()
+        .any(|(img, _)| img.dimensions() != first.dimensions())
+    {
+        return Err(size_error(
+            "frames of different sizes can't be one animation",
+        ));
+    }
+    Ok(first.dimensions())
+}
+
+// Animated GIF, each frame quantized to a palette of its own by the gif encoder
+pub fn write_gif(animation: &Animation, writer: impl Write) -> ImageResult<()> {
+    check_frames(animation)?;
+    let mut encoder = GifEncoder::new(writer);
+    encoder.set_repeat(match animation.loops {
+        0 => Repeat::Infinite,
+        loops => Repeat::Finite(loops.min(u16::MAX as u32) as u16),
+    })?;
+    encoder.encode_frames(animation.frames.iter().map(|(img, delay)| {
+        Frame::from_parts(img.clone(), 0, 0, Delay::from_numer_denom_ms(*delay, 1))
+    }))
+}
+
+// APNG of whole RGBA frames; the frame delay is a u16 fraction of a second, milliseconds up to
+// 65535 are kept exactly, longer ones to the second
+pub fn write_apng(animation: &Animation, writer: impl Write) -> ImageResult<()> {
+    let png_error = |err: png::EncodingError| ImageError::IoError(std::io::Error::other(err));
+    let (width, height) = check_frames(animation)?;
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(animation.frames.len() as u32, animation.loops)
+        .map_err(png_error)?;
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    for (img, delay) in &animation.frames {
+        let (numer, denom) = match u16::try_from(*delay) {
+            Ok(delay) => (delay, 1000),
+            Err(_) => ((delay / 1000).min(u16::MAX as u32) as u16, 1),
+        };
+        writer.set_frame_delay(numer, denom).map_err(png_error)?;
+        writer.write_image_data(img.as_raw()).map_err(png_error)?;
+    }
+    writer.finish().map_err(png_error)
+}
@@ -0,0 +1,110 @@
+// The short syntax as the subcommand it stands for: "ecz in.png out.ric 16 KEY" is
+// "encode --compress --palette-size=16 --key=KEY in.png out.ric", "g" is keygen and "i" info.
+// Every other argument goes to the subcommand as it is, so the short syntax takes its flags
+use clap::{Command, CommandFactory};
+use rust_image_codec::{error::EXIT_USAGE, log};
+use std::process::exit;
+
+use crate::cli::Cli;
+
+// Subcommands the short syntax stands for, whose flags it takes
+const SUBCOMMANDS: [&str; 3] = ["encode", "decode", "keygen"];
+
+// Whether arg names a flag of the commands that takes the next argument as its value; None when
+// it names none of them, e.g. "-" (stdin) or a key starting with "-"
+fn takes_next(commands: &[Command], arg: &str) -> Option<bool> {
+    // A value after "=" is the flag's own
+    if arg.starts_with("--") && arg.contains('=') {
+        return Some(false);
+    }
+    let short = arg.strip_prefix('-').and_then(|short| {
+        let mut chars = short.chars();
+        chars.next().filter(|_| chars.next().is_none())
+    });
+    let long = arg.strip_prefix("--");
+    let flag = commands
+        .iter()
+        .flat_map(Command::get_arguments)
+        .find(|flag| {
+            long.is_some_and(|long| flag.get_long() == Some(long))
+                || short.is_some_and(|short| flag.get_short() == Some(short))
+        })?;
+    Some(flag.get_action().takes_values() && !flag.is_require_equals_set())
+}
+
+fn usage_error(message: &str) -> ! {
+    log::error(format_args!(
+        "{}, run without arguments for the usage",
+        message
+    ));
+    exit(EXIT_USAGE);
+}
+
+// Subcommand argv of the short syntax args, the program name first
+pub fn short_syntax(args: &[String]) -> Vec<String> {
+    let mut cli = Cli::command();
+    // Global flags (--json, --config) are a subcommand's too once built
+    cli.build();
+    let commands = SUBCOMMANDS.map(|name| cli.find_subcommand(name).unwrap().clone());
+    let (mut flags, mut positional) = (Vec::new(), Vec::new());
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match takes_next(&commands, arg) {
+            Some(takes_next) => {
+                flags.push(arg.clone());
+                if takes_next {
+                    flags.extend(rest.next().cloned());
+                }
+            }
+            None => positional.push(arg.clone()),
+        }
+    }
+    if positional.is_empty() {
+        usage_error("missing arguments");
+    }
+    let options = positional.remove(0);
+    let encode = options.contains('e');
+    let name = match options.as_str() {
+        "g" => "keygen",
+        "i" => "info",
+        _ if options.chars().all(|option| "edcz".contains(option))
+            && encode != options.contains('d') =>
+        {
+            if encode {
+                "encode"
+            } else {
+                "decode"
+            }
+        }
+        _ => usage_error(&format!("unknown options {}", options)),
+    };
+    let mut argv = vec![args[0].clone(), name.to_string()];
+    argv.append(&mut flags);
+    if name == "keygen" || name == "info" {
+        argv.append(&mut positional);
+        return argv;
+    }
+    // Palette size and key after the paths, of which a batch has none
+    let keyed = options.contains('c');
+    let batch = argv
+        .iter()
+        .any(|flag| flag == "--batch" || flag.starts_with("--batch="));
+    let trailing = encode as usize + keyed as usize;
+    if positional.len() < trailing + !batch as usize {
+        usage_error("missing arguments");
+    }
+    let mut trailing = positional
+        .split_off(positional.len() - trailing)
+        .into_iter();
+    if options.contains('z') {
+        argv.push("--compress".to_string());
+    }
+    if encode {
+        argv.push(format!("--palette-size={}", trailing.next().unwrap()));
+    }
+    if keyed {
+        argv.push(format!("--key={}", trailing.next().unwrap()));
+    }
+    argv.append(&mut positional);
+    argv
+}
@@ -0,0 +1,63 @@
+use image::{DynamicImage, ImageFormat};
+use std::io::Cursor;
+
+use crate::{Decoder, Encoder, error::CodecError};
+
+async fn read(path: &str) -> Result<Vec<u8>, CodecError> {
+    tokio::fs::read(path)
+        .await
+        .map_err(|source| CodecError::Io {
+            path: path.to_string(),
+            source,
+        })
+}
+
+async fn write(path: &str, bytes: Vec<u8>) -> Result<(), CodecError> {
+    tokio::fs::write(path, bytes)
+        .await
+        .map_err(|source| CodecError::Io {
+            path: path.to_string(),
+            source,
+        })
+}
+
+// Runs CPU-bound work on the blocking pool, a panic there is resumed in the caller
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, CodecError> + Send + 'static,
+) -> Result<T, CodecError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+// Encodes the image file at input (format detected by content) into output
+pub async fn encode_async(encoder: &Encoder, input: &str, output: &str) -> Result<(), CodecError> {
+    let bytes = read(input).await?;
+    let encoder = encoder.clone();
+    let path = input.to_string();
+    let encoded = blocking(move || {
+        let img = image::load_from_memory(&bytes)
+            .map_err(|source| CodecError::Image { path, source })?
+            .into_rgb8();
+        encoder.encode(&img)
+    })
+    .await?;
+    write(output, encoded).await
+}
+
+// Decodes the file at input into a PNG at output
+pub async fn decode_async(decoder: &Decoder, input: &str, output: &str) -> Result<(), CodecError> {
+    let bytes = read(input).await?;
+    let decoder = decoder.clone();
+    let path = output.to_string();
+    let png = blocking(move || {
+        let img = decoder.decode(&bytes)?;
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut png, ImageFormat::Png)
+            .map_err(|source| CodecError::Image { path, source })?;
+        Ok(png.into_inner())
+    })
+    .await?;
+    write(output, png).await
+}
@@ -0,0 +1,418 @@
+// Decoder core on core and alloc only: prefix, header and palette expansion of plain,
+// uncompressed files, for targets without std. Compression and encryption need the "std" feature
+use alloc::vec::Vec;
+use core::{fmt, ops::Range};
+
+use crate::format::{
+    FLAG_ENCRYPTED, FLAG_METADATA, FLAG_TRANSPARENT, LAYOUT_FILTERED, LAYOUT_INTERLACED,
+    LAYOUT_RLE, LAYOUT_SEEK, LAYOUT_TILED, LAYOUT_TRUECOLOR, PREFIX_LEN, V2_PREFIX_LEN, V3, V4,
+    VERSION, channels, content_len, data_len, flags, index_bits, layout_flags, palette_entries,
+    palette_len, row_len, stored_len, tile_size, transparent_len, unpack_indices, v5_prefix_len,
+    version,
+};
+use crate::{
+    filter, interlace, rle,
+    scan::{self, Scan},
+    seek, tiles,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    UnsupportedVersion(u8),
+    TooShort,
+    // Stored CRC32 and the one of the bytes before it
+    ChecksumMismatch { stored: u32, actual: u32 },
+    Encrypted,
+    NotEnoughData,
+    // Bytes after the pixels data, a key check and key block of an encrypted file
+    TrailingBytes(usize),
+    // Row filter type beyond Paeth
+    UnknownFilter(u8),
+    // Tile whose bytes or offsets don't fit the pixels data
+    BadTile(usize),
+    // Row of a seekable file whose bytes or offset don't fit the pixels data
+    BadRow(usize),
+    // Layout flags, index bits or tile size no encoder writes
+    BadLayout(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported format version {}, this build reads 1 to {}",
+                version, VERSION
+            ),
+            Error::TooShort => write!(f, "file is too short for a header"),
+            Error::ChecksumMismatch { stored, actual } => write!(
+                f,
+                "checksum mismatch (stored {:08x}, computed {:08x}), the file is corrupt",
+                stored, actual
+            ),
+            Error::Encrypted => write!(f, "file is encrypted, decrypting needs the std feature"),
+            Error::NotEnoughData => write!(f, "not enough data, image is corrupted"),
+            Error::TrailingBytes(len) => write!(
+                f,
+                "{} bytes after the pixels data, the file is likely encrypted",
+                len
+            ),
+            Error::UnknownFilter(filter) => {
+                write!(f, "unknown row filter {}, the file is corrupt", filter)
+            }
+            Error::BadTile(tile) => write!(f, "tile {} is malformed, the file is corrupt", tile),
+            Error::BadRow(row) => write!(f, "row {} is malformed, the file is corrupt", row),
+            Error::BadLayout(reason) => write!(f, "{}, the file is corrupt", reason),
+        }
+    }
+}
+
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    // RGBA, opaque for files without the alpha flag but for the transparent index
+    pub palette: Vec<[u8; 4]>,
+    // Bytes per stored palette entry, 3, 4 with the alpha flag or 1 with the gray one
+    pub channels: usize,
+    // Palette index stored with the transparent flag, it may be beyond the palette
+    pub transparent: Option<u16>,
+    // Bits per index of the pixels data
+    pub index_bits: u32,
+    // Layout flags of the pixels data, see format::LAYOUT_FILTERED
+    pub layout: u8,
+    // Side of the tiles of tiled files, see tiles.rs
+    pub tile_size: Option<u32>,
+    // Entries of the metadata section, empty without one
+    pub metadata: Range<usize>,
+    // Offset of the pixels data (or the key block and key check) from the start of the file,
+    // prefix included
+    pub data_offset: usize,
+}
+
+pub fn unpack_dimensions(bytes: &[u8]) -> (u32, u32) {
+    let combined: u32 = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | (bytes[2] as u32);
+
+    let width = (combined >> 12) as u16 & 0xFFF;
+    let height = (combined & 0xFFF) as u16;
+
+    (width as u32, height as u32)
+}
+
+// Length of the bytes before the v1 layout (dimensions, palette size, palette, pixels data),
+// which every version so far keeps after its prefix, v4 with wider dimensions and v5 with a
+// wider palette size too
+pub fn prefix_len(bytes: &[u8]) -> Result<usize, Error> {
+    let prefix_len = match version(bytes) {
+        1 => return Ok(0),
+        2 => return Ok(V2_PREFIX_LEN),
+        V3 | V4 => PREFIX_LEN,
+        VERSION => v5_prefix_len(layout_flags(bytes)),
+        version => return Err(Error::UnsupportedVersion(version)),
+    };
+    if bytes.len() < prefix_len {
+        return Err(Error::TooShort);
+    }
+    Ok(prefix_len)
+}
+
+// Width, height and the offset of the palette size byte following them. Before v4 they're
+// 12 bits each stored minus 2, v4 stores them as little endian u32
+pub fn dimensions(bytes: &[u8]) -> Result<(u32, u32, usize), Error> {
+    let prefix_len = prefix_len(bytes)?;
+    if version(bytes) < V4 {
+        let packed = bytes
+            .get(prefix_len..prefix_len + 3)
+            .ok_or(Error::TooShort)?;
+        let (width, height) = unpack_dimensions(packed);
+        return Ok((width + 2, height + 2, prefix_len + 3));
+    }
+    let wide = bytes
+        .get(prefix_len..prefix_len + 8)
+        .ok_or(Error::TooShort)?;
+    let width = u32::from_le_bytes([wide[0], wide[1], wide[2], wide[3]]);
+    let height = u32::from_le_bytes([wide[4], wide[5], wide[6], wide[7]]);
+    Ok((width, height, prefix_len + 8))
+}
+
+// Palette size and the offset of the palette following it, the size is stored minus 2 in a
+// byte before v5 and a little endian u16 since
+pub fn palette_size(bytes: &[u8]) -> Result<(usize, usize), Error> {
+    let (_, _, palette_size_at) = dimensions(bytes)?;
+    if version(bytes) < VERSION {
+        let size = *bytes.get(palette_size_at).ok_or(Error::TooShort)?;
+        return Ok((size as usize + 2, palette_size_at + 1));
+    }
+    let size = bytes
+        .get(palette_size_at..palette_size_at + 2)
+        .ok_or(Error::TooShort)?;
+    Ok((
+        u16::from_le_bytes([size[0], size[1]]) as usize + 2,
+        palette_size_at + 2,
+    ))
+}
+
+// Bytes before the checksum footer, which they're checked against, all of them without one
+pub fn without_checksum(bytes: &[u8]) -> Result<&[u8], Error> {
+    let len = content_len(bytes);
+    if len == bytes.len() {
+        return Ok(bytes);
+    }
+    let (content, footer) = bytes.split_at(len);
+    let stored = u32::from_le_bytes(footer.try_into().map_err(|_| Error::TooShort)?);
+    let actual = crc32fast::hash(content);
+    if stored != actual {
+        return Err(Error::ChecksumMismatch { stored, actual });
+    }
+    Ok(content)
+}
+
+// Entries of the metadata section after the palette, from the start of the file. The section is
+// a little endian u32 length and the entries, so the range may end beyond the bytes given
+pub fn metadata_range(bytes: &[u8]) -> Result<Range<usize>, Error> {
+    let (palette_size, palette_start) = palette_size(bytes)?;
+    let palette_end = palette_start + palette_len(bytes, palette_size);
+    if flags(bytes).is_none_or(|flags| flags & FLAG_METADATA == 0) {
+        return Ok(palette_end..palette_end);
+    }
+    let len = bytes
+        .get(palette_end..palette_end + 4)
+        .ok_or(Error::TooShort)?;
+    let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    Ok(palette_end + 4..palette_end + 4 + len)
+}
+
+// Fails on v5 headers the encoder never writes, whose pixels data would be unpacked with a
+// shift, divisor or palette that isn't there: index bits other than 1, 2, 4, 8 or 16, truecolor
+// pixels other than filtered rows of their channel bytes, tiles of another size or with other
+// layout flags, and interlacing or seek tables in another scan order
+pub fn check_layout(bytes: &[u8]) -> Result<(), Error> {
+    if version(bytes) < VERSION {
+        return Ok(());
+    }
+    let layout = layout_flags(bytes);
+    let index_bits = index_bits(bytes);
+    let raster = Scan::from_layout(layout) == Scan::Raster;
+    if layout & LAYOUT_TRUECOLOR != 0 {
+        if layout & !(LAYOUT_TRUECOLOR | LAYOUT_FILTERED) != 0
+            || flags(bytes).is_some_and(|flags| flags & FLAG_TRANSPARENT != 0)
+        {
+            return Err(Error::BadLayout(
+                "truecolor pixels data with layout flags other than filtering",
+            ));
+        }
+        if index_bits != channels(bytes) as u32 * 8 {
+            return Err(Error::BadLayout(
+                "truecolor pixels data of another size than the channels",
+            ));
+        }
+        return Ok(());
+    }
+    if ![1, 2, 4, 8, 16].contains(&index_bits) {
+        return Err(Error::BadLayout("index bits other than 1, 2, 4, 8 or 16"));
+    }
+    if let Some(size) = tile_size(bytes) {
+        if !(tiles::MIN_SIZE..=tiles::MAX_SIZE).contains(&size) {
+            return Err(Error::BadLayout("tile size out of range"));
+        }
+        if layout & !LAYOUT_TILED != 0 {
+            return Err(Error::BadLayout("tiles with other layout flags"));
+        }
+    }
+    if layout & (LAYOUT_INTERLACED | LAYOUT_SEEK) != 0 && !raster {
+        return Err(Error::BadLayout(
+            "interlacing or a seek table in another scan order",
+        ));
+    }
+    if layout & LAYOUT_INTERLACED != 0 && layout & LAYOUT_SEEK != 0 {
+        return Err(Error::BadLayout("interlacing with a seek table"));
+    }
+    Ok(())
+}
+
+pub fn parse_header(bytes: &[u8]) -> Result<Header, Error> {
+    check_layout(bytes)?;
+    let metadata = metadata_range(bytes)?;
+    if metadata.end > bytes.len() {
+        return Err(Error::TooShort);
+    }
+    let (width, height, _) = dimensions(bytes)?;
+    let (palette_size, palette_start) = palette_size(bytes)?;
+    let channels = channels(bytes);
+    let palette_end = palette_start + palette_entries(bytes, palette_size) * channels;
+    let transparent = match transparent_len(bytes) {
+        0 => None,
+        1 => Some(bytes[palette_end] as u16),
+        _ => Some(u16::from_le_bytes([
+            bytes[palette_end],
+            bytes[palette_end + 1],
+        ])),
+    };
+    let mut palette: Vec<[u8; 4]> = bytes[palette_start..palette_end]
+        .chunks_exact(channels)
+        .map(entry_rgba)
+        .collect();
+    if let Some(entry) = transparent.and_then(|index| palette.get_mut(index as usize)) {
+        entry[3] = 0;
+    }
+    Ok(Header {
+        width,
+        height,
+        palette,
+        channels,
+        transparent,
+        index_bits: index_bits(bytes),
+        layout: layout_flags(bytes),
+        tile_size: tile_size(bytes),
+        data_offset: metadata.end,
+        metadata,
+    })
+}
+
+// RGBA of a stored palette entry, of 1 (luma), 3 (RGB) or 4 bytes
+pub fn entry_rgba(entry: &[u8]) -> [u8; 4] {
+    match *entry {
+        [luma] => [luma, luma, luma, u8::MAX],
+        [red, green, blue] => [red, green, blue, u8::MAX],
+        _ => [entry[0], entry[1], entry[2], entry[3]],
+    }
+}
+
+// Indices beyond the palette map to its first color, as in the std decoder
+pub fn color(palette: &[[u8; 4]], index: u16) -> [u8; 4] {
+    palette.get(index as usize).copied().unwrap_or(palette[0])
+}
+
+// Appends the RGB (channels 3), RGBA (channels 4) or luma (channels 1, of gray palettes)
+// bytes of palette indices
+pub fn expand(palette: &[[u8; 4]], indices: &[u16], channels: usize, out: &mut Vec<u8>) {
+    out.reserve(indices.len() * channels);
+    for &index in indices {
+        out.extend_from_slice(&color(palette, index)[..channels]);
+    }
+}
+
+// Length of the stored pixels data, the header holds it for run-length encoded, tiled and
+// seekable files
+pub fn stored_data_len(bytes: &[u8], header: &Header) -> usize {
+    stored_len(bytes).unwrap_or_else(|| {
+        data_len(
+            header.width,
+            header.height,
+            header.index_bits,
+            header.layout,
+        )
+    })
+}
+
+// Fails unless len bytes of stored pixels data can hold an image of these dimensions, so the
+// header of a crafted file can't have memory reserved beyond what its data makes up: run-length
+// encoded rows decode to rle::max_decoded_len() bytes at most, other rows are stored as they are
+pub fn check_data_len(
+    width: u32,
+    height: u32,
+    index_bits: u32,
+    layout: u8,
+    len: usize,
+) -> Result<(), Error> {
+    let unpacked = data_len(width, height, index_bits, layout);
+    let holds = if layout & LAYOUT_RLE != 0 {
+        let table_len = if layout & LAYOUT_SEEK != 0 {
+            seek::table_len(height)
+        } else {
+            0
+        };
+        len >= table_len
+            && unpacked - table_len
+                <= rle::max_decoded_len(len - table_len, filter::bpp(index_bits))
+    } else {
+        len >= unpacked
+    };
+    if holds {
+        Ok(())
+    } else {
+        Err(Error::NotEnoughData)
+    }
+}
+
+// Packed indices of stored pixels data, run-length encoded data is decoded to whole rows of the
+// image (or passes of interlaced ones) and filtered rows are unfiltered; rows of seekable files
+// one by one
+pub fn packed_data<'a>(
+    data: &'a [u8],
+    width: u32,
+    height: u32,
+    index_bits: u32,
+    layout: u8,
+) -> Result<alloc::borrow::Cow<'a, [u8]>, Error> {
+    check_data_len(width, height, index_bits, layout, data.len())?;
+    if layout & LAYOUT_SEEK != 0 {
+        return Ok(seek::packed_data(data, width, height, index_bits, layout)?.into());
+    }
+    let bpp = filter::bpp(index_bits);
+    let mut data = alloc::borrow::Cow::from(data);
+    if layout & LAYOUT_RLE != 0 {
+        data = rle::decode(&data, data_len(width, height, index_bits, layout), bpp)?.into();
+    }
+    if layout & LAYOUT_FILTERED != 0 && layout & LAYOUT_INTERLACED != 0 {
+        data = interlace::unfilter(&data, width, height, index_bits)?.into();
+    } else if layout & LAYOUT_FILTERED != 0 {
+        let row_len = row_len(width, index_bits);
+        let mut prev = alloc::vec![0u8; row_len];
+        data = filter::unfilter(&data, row_len, bpp, &mut prev)?.into();
+    }
+    Ok(data)
+}
+
+// Raw RGB bytes with width and height of a plain, uncompressed file; alpha is dropped, expand
+// with header.channels keeps it
+pub fn decode(bytes: &[u8]) -> Result<(Vec<u8>, u32, u32), Error> {
+    let bytes = without_checksum(bytes)?;
+    let header = parse_header(bytes)?;
+    if flags(bytes).is_some_and(|flags| flags & FLAG_ENCRYPTED != 0) {
+        return Err(Error::Encrypted);
+    }
+    let pixels = (header.width as usize).saturating_mul(header.height as usize);
+    let data_len = stored_data_len(bytes, &header);
+    let data = &bytes[header.data_offset..];
+    if data.len() < data_len {
+        return Err(Error::NotEnoughData);
+    }
+    if data.len() > data_len {
+        return Err(Error::TrailingBytes(data.len() - data_len));
+    }
+    if let Some(size) = header.tile_size {
+        let tiled = tiles::decode(data, header.width, header.height, size, header.channels)?;
+        let mut rgb = Vec::with_capacity(pixels * 3);
+        for (pixel, &index) in tiled.indices.iter().enumerate() {
+            let palette = &tiled.palette[tiled.palettes.range(pixel, header.width)];
+            rgb.extend_from_slice(&color(palette, index)[..3]);
+        }
+        return Ok((rgb, header.width, header.height));
+    }
+    let data = packed_data(
+        data,
+        header.width,
+        header.height,
+        header.index_bits,
+        header.layout,
+    )?;
+    if header.layout & LAYOUT_TRUECOLOR != 0 {
+        let rgb = data
+            .chunks_exact(header.channels)
+            .flat_map(|pixel| entry_rgba(pixel).into_iter().take(3))
+            .collect();
+        return Ok((rgb, header.width, header.height));
+    }
+    let indices = if header.layout & LAYOUT_INTERLACED != 0 {
+        interlace::deinterlace(&data, header.width, header.height, header.index_bits).0
+    } else {
+        let indices = unpack_indices(&data, header.width, header.index_bits);
+        match Scan::from_layout(header.layout) {
+            Scan::Raster => indices,
+            order => scan::deserialize(&indices, &order.order(header.width, header.height)),
+        }
+    };
+    let mut rgb = Vec::with_capacity(pixels * 3);
+    expand(&header.palette, &indices, 3, &mut rgb);
+    Ok((rgb, header.width, header.height))
+}
@@ -0,0 +1,200 @@
+use crate::files::default_output;
+use image::ImageFormat;
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+use walkdir::WalkDir;
+
+// Jobs file: one "input output" pair per line (tab separated if paths contain spaces),
+// empty lines and lines starting with "#" are skipped
+pub fn read_jobs(path: &str) -> Result<Vec<(String, String)>, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let mut jobs = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let pair = if line.contains('\t') {
+            line.split_once('\t')
+                .map(|(input, output)| (input.trim(), output.trim()))
+        } else {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(input), Some(output), None) => Some((input, output)),
+                _ => None,
+            }
+        };
+        match pair {
+            Some((input, output)) => jobs.push((input.to_string(), output.to_string())),
+            None => return Err(format!("{}:{}: expected \"input output\"", path, idx + 1)),
+        }
+    }
+    Ok(jobs)
+}
+
+pub fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+// Output named like the inferred one, in out_dir or next to the input
+pub fn output_path(input: &str, out_dir: Option<&str>, encode: bool) -> Result<String, String> {
+    let output = default_output(input, encode)
+        .ok_or_else(|| format!("can't infer [output_file_path] from {}", input))?;
+    Ok(match (out_dir, Path::new(&output).file_name()) {
+        (Some(dir), Some(name)) => Path::new(dir).join(name).to_string_lossy().into_owned(),
+        _ => output,
+    })
+}
+
+// One job per file matching the pattern, in path order
+pub fn glob_jobs(
+    pattern: &str,
+    out_dir: Option<&str>,
+    encode: bool,
+) -> Result<Vec<(String, String)>, String> {
+    let paths = glob::glob(pattern).map_err(|err| format!("{}: {}", pattern, err))?;
+    let mut jobs = Vec::new();
+    let mut outputs = HashSet::new();
+    for path in paths {
+        let path = path.map_err(|err| err.to_string())?;
+        if !path.is_file() {
+            continue;
+        }
+        let input = path.to_string_lossy().into_owned();
+        let output = output_path(&input, out_dir, encode)?;
+        if !outputs.insert(output.clone()) {
+            return Err(format!("{} and another match both write {}", input, output));
+        }
+        jobs.push((input, output));
+    }
+    if jobs.is_empty() {
+        return Err(format!("no files match {}", pattern));
+    }
+    Ok(jobs)
+}
+
+// Images the image crate reads by extension on encode, .ric files on decode
+fn is_supported(path: &Path, encode: bool) -> bool {
+    let ric = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("ric"));
+    if encode {
+        !ric && ImageFormat::from_path(path).is_ok()
+    } else {
+        ric
+    }
+}
+
+// Output exists and is not older than its input
+fn is_up_to_date(input: &Path, output: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|meta| meta.modified());
+    match (modified(input), modified(output)) {
+        (Ok(input), Ok(output)) => output >= input,
+        _ => false,
+    }
+}
+
+// One job per supported file under dir, in path order, outputs at the same relative path
+// under out_dir (next to the inputs without it); returns the jobs and the up-to-date count
+pub fn tree_jobs(
+    dir: &str,
+    out_dir: Option<&str>,
+    encode: bool,
+) -> Result<(Vec<(String, String)>, usize), String> {
+    if !Path::new(dir).is_dir() {
+        return Err(format!("{}: --recursive needs a directory", dir));
+    }
+    let mut jobs = Vec::new();
+    let mut up_to_date = 0;
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry.map_err(|err| err.to_string())?;
+        if !entry.file_type().is_file() || !is_supported(entry.path(), encode) {
+            continue;
+        }
+        let input = entry.path().to_string_lossy().into_owned();
+        let relative = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        let output = match out_dir {
+            Some(out_dir) => {
+                let output = Path::new(out_dir).join(relative);
+                output_path(&output.to_string_lossy(), None, encode)?
+            }
+            None => output_path(&input, None, encode)?,
+        };
+        if is_up_to_date(entry.path(), Path::new(&output)) {
+            up_to_date += 1;
+            continue;
+        }
+        jobs.push((input, output));
+    }
+    Ok((jobs, up_to_date))
+}
+
+// Append-only log of "start"/"done" records per output, so an interrupted batch
+// can be resumed: finished outputs are skipped, started but unfinished ones are redone
+pub struct Journal {
+    file: File,
+    done: HashSet<String>,
+    // Outputs the resumed run started but didn't finish, whatever it left of them is replaced
+    unfinished: HashSet<String>,
+}
+
+impl Journal {
+    pub fn open(path: &str, resume: bool) -> io::Result<Self> {
+        let mut done = HashSet::new();
+        let mut unfinished = HashSet::new();
+        if resume {
+            match std::fs::read_to_string(path) {
+                Ok(text) => {
+                    for line in text.lines() {
+                        if let Some(output) = line.strip_prefix("start\t") {
+                            unfinished.insert(output.to_string());
+                        } else if let Some(output) = line.strip_prefix("done\t") {
+                            unfinished.remove(output);
+                            done.insert(output.to_string());
+                        }
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(resume)
+            .write(true)
+            .truncate(!resume)
+            .open(path)?;
+        Ok(Self {
+            file,
+            done,
+            unfinished,
+        })
+    }
+
+    pub fn is_done(&self, output: &str) -> bool {
+        self.done.contains(output)
+    }
+
+    pub fn is_unfinished(&self, output: &str) -> bool {
+        self.unfinished.contains(output)
+    }
+
+    fn append(&mut self, record: &str, output: &str) -> io::Result<()> {
+        writeln!(self.file, "{}\t{}", record, output)?;
+        self.file.sync_data()
+    }
+
+    pub fn start(&mut self, output: &str) -> io::Result<()> {
+        self.append("start", output)
+    }
+
+    pub fn finish(&mut self, output: &str) -> io::Result<()> {
+        self.append("done", output)?;
+        self.done.insert(output.to_string());
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+// Checksums of the file in blocks (encode --chunk-checksums), kept as the "chunks" metadata
+// entry: the little endian u32 block length in bytes, the CRC32 of the header before the
+// metadata section (its compressed flag cleared), then the CRC32 of every block of what
+// follows the section (key block, key check and pixels data as stored, after encryption and
+// before compression), the last block maybe shorter. A file failing its checksum is then
+// checked block by block, which tells the rows the damage is in, and decode can fill those with
+// a placeholder color instead of failing
+use image::Rgba;
+use std::ops::Range;
+
+use crate::{
+    bare, codec::covering_segments, error::CodecError, filter, format, metadata, scan::Scan, seek,
+};
+
+pub const KEY: &str = "chunks";
+const LEN_LEN: usize = 4;
+const CRC_LEN: usize = 4;
+
+// CRC32 of the header before the metadata section, the compressed flag left out as it's
+// cleared when compression doesn't pay off
+fn header_crc(header: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[..format::PREFIX_LEN - 1]);
+    hasher.update(&[header[format::PREFIX_LEN - 1] & !format::FLAG_COMPRESSED]);
+    hasher.update(&header[format::PREFIX_LEN..]);
+    hasher.finalize()
+}
+
+// Entry value of the header before the metadata section and the parts of what follows it
+pub fn entry(block_len: usize, header: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut value = (block_len as u32).to_le_bytes().to_vec();
+    value.extend_from_slice(&header_crc(header).to_le_bytes());
+    let mut hasher = crc32fast::Hasher::new();
+    let mut filled = 0;
+    for mut part in parts.iter().copied() {
+        while !part.is_empty() {
+            let (block, rest) = part.split_at((block_len - filled).min(part.len()));
+            hasher.update(block);
+            filled += block.len();
+            part = rest;
+            if filled == block_len {
+                value.extend_from_slice(&hasher.clone().finalize().to_le_bytes());
+                hasher.reset();
+                filled = 0;
+            }
+        }
+    }
+    if filled > 0 {
+        value.extend_from_slice(&hasher.finalize().to_le_bytes());
+    }
+    value
+}
+
+// Damaged ranges of what follows the metadata section of a file's content (the checksum footer
+// left out), whole blocks merged when adjacent; None for files without chunk checksums
+pub fn damaged(content: &[u8]) -> Result<Option<Vec<Range<usize>>>, CodecError> {
+    let header = bare::parse_header(content)?;
+    let Some((_, value)) = metadata::parse(&content[header.metadata.clone()])?
+        .into_iter()
+        .find(|(key, _)| key == KEY)
+    else {
+        return Ok(None);
+    };
+    let corrupt = || CodecError::BadHeader("corrupt in its chunk checksums".to_string());
+    if value.len() < LEN_LEN + CRC_LEN || !(value.len() - LEN_LEN).is_multiple_of(CRC_LEN) {
+        return Err(corrupt());
+    }
+    let block_len = u32::from_le_bytes(value[..LEN_LEN].try_into().unwrap()) as usize;
+    let mut crcs = value[LEN_LEN..]
+        .chunks_exact(CRC_LEN)
+        .map(|crc| u32::from_le_bytes(crc.try_into().unwrap()));
+    // Bytes before the metadata section, its length included
+    let header_end = header.metadata.start - LEN_LEN;
+    if crcs.next() != Some(header_crc(&content[..header_end])) {
+        return Err(CodecError::BadData(
+            "the header is damaged, its palette or dimensions can't be trusted".to_string(),
+        ));
+    }
+    let tail = &content[header.data_offset..];
+    if block_len == 0 || tail.len().div_ceil(block_len) != crcs.len() {
+        return Err(corrupt());
+    }
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (i, (block, crc)) in tail.chunks(block_len).zip(crcs).enumerate() {
+        if crc32fast::hash(block) == crc {
+            continue;
+        }
+        let range = i * block_len..i * block_len + block.len();
+        match ranges.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => ranges.push(range),
+        }
+    }
+    Ok(Some(ranges))
+}
+
+// Damaged rows of stored pixels data whose damaged byte ranges are known, filtered rows below
+// one predicted from it (Up, Average, Paeth) included. Encrypted data is damaged by the whole
+// 64KiB segments a range is in, and its filter types are read after decryption. None for
+// layouts whose bytes aren't a row's: run-length encoded without a seek table, interlaced,
+// tiled or in another scan order, and seekable data of a damaged seek table
+pub fn damaged_rows(
+    damaged: &[Range<usize>],
+    data: &[u8],
+    width: u32,
+    height: u32,
+    index_bits: u32,
+    layout: u8,
+    encrypted: bool,
+) -> Option<Vec<bool>> {
+    let ranges = damaged
+        .iter()
+        .map(|range| {
+            let covering = covering_segments(range, data.len());
+            match (encrypted, covering.first(), covering.last()) {
+                (true, Some((_, first)), Some((_, last))) => first.start..last.end,
+                _ => range.clone(),
+            }
+        })
+        .collect::<Vec<_>>();
+    let hit = |row: Range<usize>| {
+        ranges
+            .iter()
+            .any(|range| range.start < row.end && row.start < range.end)
+    };
+    if layout & (format::LAYOUT_INTERLACED | format::LAYOUT_TILED) != 0
+        || Scan::from_layout(layout) != Scan::Raster
+    {
+        return None;
+    }
+    if layout & format::LAYOUT_SEEK != 0 {
+        let table_len = seek::table_len(height);
+        if hit(0..table_len) {
+            return None;
+        }
+        let offsets = seek::ranges(&data[..table_len], 0..height as usize, height, data.len());
+        return Some(offsets.ok()?.into_iter().map(hit).collect());
+    }
+    if layout & format::LAYOUT_RLE != 0 {
+        return None;
+    }
+    let stride = format::stride(width, index_bits, layout);
+    let mut rows = (0..height as usize)
+        .map(|row| hit(row * stride..(row + 1) * stride))
+        .collect::<Vec<_>>();
+    if layout & format::LAYOUT_FILTERED != 0 {
+        for row in 1..rows.len() {
+            let from_above = [filter::UP, filter::AVERAGE, filter::PAETH];
+            if rows[row - 1] && from_above.contains(&data[row * stride]) {
+                rows[row] = true;
+            }
+        }
+    }
+    Some(rows)
+}
+
+// Packed indices of the whole stored pixels data, damaged rows left zero
+pub fn packed_data(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    index_bits: u32,
+    layout: u8,
+    rows: &[bool],
+) -> Result<Vec<u8>, bare::Error> {
+    bare::check_data_len(width, height, index_bits, layout, data.len())?;
+    let row_len = format::row_len(width, index_bits);
+    if layout & format::LAYOUT_SEEK != 0 {
+        let table = &data[..seek::table_len(height)];
+        let ranges = seek::ranges(table, 0..height as usize, height, data.len())?;
+        let mut packed = Vec::with_capacity(row_len * height as usize);
+        for (row, range) in ranges.into_iter().enumerate() {
+            if rows[row] {
+                packed.resize(packed.len() + row_len, 0);
+            } else {
+                packed.extend(seek::rows(
+                    data,
+                    0,
+                    &[range],
+                    row,
+                    width,
+                    index_bits,
+                    layout,
+                )?);
+            }
+        }
+        return Ok(packed);
+    }
+    // A zero row, filter type None when filtered
+    let stride = format::stride(width, index_bits, layout);
+    let mut data = data.to_vec();
+    for (row, _) in rows.iter().enumerate().filter(|&(_, &damaged)| damaged) {
+        data[row * stride..(row + 1) * stride].fill(0);
+    }
+    Ok(bare::packed_data(&data, width, height, index_bits, layout)?.into_owned())
+}
+
+// Rows of the image shown as a placeholder color
+pub(crate) struct Damaged {
+    pub(crate) rows: Vec<bool>,
+    pub(crate) color: Rgba<u8>,
+}
+
+// "3-7, 40" of the damaged rows
+pub fn describe_rows(rows: &[bool]) -> String {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (row, _) in rows.iter().enumerate().filter(|&(_, &damaged)| damaged) {
+        match ranges.last_mut() {
+            Some(last) if last.end == row => last.end = row + 1,
+            _ => ranges.push(row..row + 1),
+        }
+    }
+    ranges
+        .iter()
+        .map(|range| match range.len() {
+            1 => range.start.to_string(),
+            _ => format!("{}-{}", range.start, range.end - 1),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
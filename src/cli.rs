@@ -0,0 +1,604 @@
+// Subcommands with named flags; the short option strings ("ecz", "g", "i") are rewritten into
+// them, see args.rs
+use clap::{Args, Parser, Subcommand, ValueEnum, builder::PossibleValuesParser};
+use clap_complete::Shell;
+use rust_image_codec::{compression, dither::Dither, format, scan::Scan};
+
+#[derive(Parser)]
+#[command(
+    name = "rust_image-codec",
+    version,
+    about = "Palette image codec with optional compression and encryption",
+    after_help = "The short syntax (e.g. `rust_image-codec ecz input.png output.ric 16 KEY`) still works, run without arguments for its usage"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+    /// Defaults for encode and decode, ~/.config/ric/config.toml when it exists
+    #[arg(long, global = true, value_name = "CONFIG_FILE")]
+    pub config: Option<String>,
+    /// Machine-readable output: JSON results on stdout, JSON error lines on stderr, no status lines
+    #[arg(long, global = true)]
+    pub json: bool,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Encode an image
+    Encode(EncodeArgs),
+    /// Encode INPUT to OUTPUT, then again whenever INPUT is saved, until interrupted
+    Watch(EncodeArgs),
+    /// Decode a file into an image
+    Decode(DecodeArgs),
+    /// Print dimensions, palette size, format version and the options to decode with
+    Info(InfoArgs),
+    /// Generate a key
+    Keygen(KeygenArgs),
+    /// Check that a key opens a file (its key check and authentication tag) without decoding it
+    VerifyKey(VerifyKeyArgs),
+    /// Check the layout and checksum footer of a file without decoding it or its key
+    Verify(VerifyArgs),
+    /// Decode a file without writing it and report the first pixel index beyond the palette
+    Validate(ValidateArgs),
+    /// Annotated hex view of the header, palette and pixels data of a file
+    Inspect(InspectArgs),
+    /// Montage of an image encoded at several palette sizes, labeled with encoded size and PSNR
+    CompareSizes(CompareSizesArgs),
+    /// Montage of an image with every dithering algorithm, labeled with encoded size and SSIM
+    CompareDither(CompareDitherArgs),
+    /// Generate only the palette of an image, printed as hex lines or written as a palette file
+    Quantize(QuantizeArgs),
+    /// Rewrap a file of format version 1 or 2 into the latest version with a metadata section, pixels data kept bit-for-bit
+    Upgrade(UpgradeArgs),
+    /// Check keys without a file
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Re-encrypt the pixels data of encrypted files with a new key in place, without decoding them
+    Rekey(RekeyArgs),
+    /// List the key slots of a file encoded with --key-slot, or add and remove slots in place
+    Slots(SlotsArgs),
+    /// Rebuild the damaged shards of a file encoded with --fec, parity included
+    Repair(RepairArgs),
+    /// Append .ric files to a container of many images, created if missing
+    Add(AddArgs),
+    /// Every entry of a container with its number, size, dimensions, palette size and state
+    List(ListArgs),
+    /// Write an entry of a container as the .ric file it was added as
+    Extract(ExtractArgs),
+    /// One container of the entries of containers and .ric files in order
+    Cat(CatArgs),
+    /// Round trip synthetic images with every options combination and report pass/fail
+    Selftest,
+    /// Print the completion script of a shell, e.g. `rust_image-codec completions bash > /etc/bash_completion.d/rust_image-codec`
+    Completions { shell: Shell },
+}
+
+#[derive(Subcommand)]
+pub enum KeyCommand {
+    /// Check that a key is base64url of 16, 24 or 32 bytes before a long encode needs it
+    Check(KeyCheckArgs),
+}
+
+fn dither_names() -> PossibleValuesParser {
+    PossibleValuesParser::new(Dither::ALL.map(Dither::name).into_iter().chain(["auto"]))
+}
+
+fn scan_names() -> PossibleValuesParser {
+    PossibleValuesParser::new(Scan::ALL.map(Scan::name))
+}
+
+#[derive(Args)]
+pub struct KeyOptions {
+    /// Base64url key (16, 24 or 32 bytes) to encrypt or decrypt with
+    #[arg(long, allow_hyphen_values = true)]
+    pub key: Option<String>,
+    /// Read the key from an environment variable instead of --key, which shows up in ps and shell history
+    #[arg(long, value_name = "VAR", conflicts_with = "key")]
+    pub key_env: Option<String>,
+    /// Read the key (base64url or 16, 24, 32 raw bytes) from a file, e.g. written by keygen --out
+    #[arg(long, value_name = "KEY_FILE", conflicts_with_all = ["key", "key_env"])]
+    pub key_file: Option<String>,
+    /// Read the key stored with keygen --key-name from the OS keyring (Keychain, Secret Service, Windows Credential Manager)
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["key", "key_env", "key_file"])]
+    pub key_name: Option<String>,
+    /// Key restored from shares instead of --key
+    #[arg(long, value_name = "SHARE1,SHARE2,...", conflicts_with_all = ["key", "key_env", "key_file", "key_name"], allow_hyphen_values = true)]
+    pub shares: Option<String>,
+    /// Prompt for a passphrase (twice on encode) and stretch it with Argon2id and a salt of each file's own, which the file stores with the costs; decode with --derive opens files of --ask-key
+    #[arg(long, conflicts_with_all = ["key", "key_env", "key_file", "key_name", "shares"])]
+    pub passphrase: bool,
+    /// Former name of --passphrase --derive
+    #[arg(long, hide = true, conflicts_with_all = ["key", "key_env", "key_file", "key_name", "shares", "passphrase"])]
+    pub ask_key: bool,
+    /// Treat the key as a master key and use a per-file HKDF-derived key
+    #[arg(long)]
+    pub derive: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum StatsFormat {
+    Text,
+    Json,
+}
+
+#[derive(Args)]
+pub struct RunOptions {
+    /// Print wall time per phase and per-thread utilization to stderr
+    #[arg(long)]
+    pub timings: bool,
+    /// Write phase and thread spans in Chrome trace format
+    #[arg(long, value_name = "TRACE_FILE")]
+    pub trace_out: Option<String>,
+    /// Print per-encode statistics (size, ratio, palette use, quantization error, stage times) and peak memory to stderr, --stats=json as JSON lines
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, require_equals = true, default_missing_value = "text")]
+    pub stats: Option<StatsFormat>,
+    /// Process every "input output" line of the file instead of INPUT OUTPUT
+    #[arg(long, value_name = "JOBS_FILE")]
+    pub batch: Option<String>,
+    /// Skip jobs finished by an interrupted --batch run
+    #[arg(long, requires = "batch")]
+    pub resume: bool,
+    /// No progress or status lines, only errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Also print the time each stage (palette, dither, map, encrypt, compress) takes
+    #[arg(short, long)]
+    pub verbose: bool,
+    /// Replace existing outputs
+    #[arg(long)]
+    pub force: bool,
+    /// Worker threads for palette, mapping and encryption, one per CPU by default
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    pub jobs: Option<u16>,
+}
+
+#[derive(Args)]
+pub struct EncodeArgs {
+    /// Image to encode, "-" reads stdin, a glob pattern encodes every match
+    #[arg(required_unless_present = "batch")]
+    pub input: Option<String>,
+    /// Encoded file, INPUT with the .ric extension by default
+    #[arg(conflicts_with = "batch")]
+    pub output: Option<String>,
+    /// Directory for the outputs, created if missing; INPUT may then be a glob pattern like 'photos/*.png'
+    #[arg(long, conflicts_with_all = ["output", "batch"])]
+    pub out_dir: Option<String>,
+    /// Encode every image under the INPUT directory, recreating the tree under --out-dir
+    #[arg(short, long, requires = "input", conflicts_with_all = ["output", "batch"])]
+    pub recursive: bool,
+    /// Colors in the palette, 256 by default
+    #[arg(short, long, value_parser = clap::value_parser!(u64).range(2..=format::MAX_PALETTE_SIZE as u64))]
+    pub palette_size: Option<u64>,
+    /// Zstd over the whole file, kept only when it's smaller
+    #[arg(short = 'z', long)]
+    pub compress: bool,
+    /// Zstd level, 1 (fastest) to 22 (smallest), 3 by default
+    #[arg(long, value_name = "LEVEL", value_parser = clap::value_parser!(i32).range(compression::MIN_LEVEL as i64..=compression::MAX_LEVEL as i64))]
+    pub compression_level: Option<i32>,
+    /// Palette size, dither and compression of pixel-art, photo, icon, max-compression or a preset of the config file; flags win over it
+    #[arg(long, value_name = "NAME")]
+    pub preset: Option<String>,
+    /// No compression even if the config file or preset turns it on
+    #[arg(long, conflicts_with = "compress")]
+    pub no_compress: bool,
+    /// Dithering algorithm, floyd-steinberg by default; auto scores each on a downscaled copy by SSIM
+    #[arg(long, value_parser = dither_names())]
+    pub dither: Option<String>,
+    #[arg(long, default_value = "image", value_parser = ["image", "pnm", "raw"])]
+    pub input_format: String,
+    /// Dimensions of raw input
+    #[arg(long, value_name = "WxH")]
+    pub size: Option<String>,
+    /// Encode raw or pnm input without holding the whole image; not encrypted, the tag comes before the pixels data
+    #[arg(long)]
+    pub stream: bool,
+    /// Quantize and compress but write nothing, print the would-be size, its ratio to INPUT and palette usage
+    #[arg(long, conflicts_with_all = ["stream", "debug_error_map"])]
+    pub dry_run: bool,
+    /// Write a PNG of per-pixel quantization error magnitude
+    #[arg(long, value_name = "ERROR_MAP_FILE")]
+    pub debug_error_map: Option<String>,
+    /// Store a metadata field, e.g. title, author, created, source or comment; repeatable
+    #[arg(long, value_name = "KEY=VALUE")]
+    pub meta: Vec<String>,
+    /// Keep the alpha channel of INPUT with an RGBA palette, decode then writes an RGBA PNG
+    #[arg(long, conflicts_with = "stream")]
+    pub alpha: bool,
+    /// Quantize on luma with one byte palette entries, decode then writes a grayscale PNG; grayscale INPUT gets it without this flag
+    #[arg(long, conflicts_with_all = ["stream", "alpha", "transparent"])]
+    pub gray: bool,
+    /// Filter every row of palette indices (ordered by luma) as PNG does before compressing, smooth gradients compress better, noisy images may not; written as format version 5
+    #[arg(long)]
+    pub filter: bool,
+    /// Run-length encode the palette indices, so flat artwork takes little space even without compression and decodes faster; written as format version 5
+    #[arg(long, conflicts_with = "stream")]
+    pub rle: bool,
+    /// Order the palette indices are stored in, raster (rows, default), hilbert (a Hilbert curve), zigzag (serpentine rows) or column; hilbert keeps close pixels close in the data, so photos compress better; written as format version 5
+    #[arg(long, value_parser = scan_names())]
+    pub scan: Option<String>,
+    /// Cut the image into SIZE x SIZE tiles, each with a palette of up to --palette-size colors of its own, so large varied images keep more colors; written as format version 5
+    #[arg(long, value_name = "SIZE", conflicts_with_all = ["stream", "filter", "rle", "scan", "transparent"])]
+    pub tiles: Option<u32>,
+    /// Store the palette indices in 7 Adam7 passes, as interlaced PNGs do, so the start of the file already holds a coarse image (decode --preview); written as format version 5
+    #[arg(long, conflicts_with_all = ["stream", "scan", "tiles"])]
+    pub interlace: bool,
+    /// Store the offset of every row before the rows, each filtered and run-length encoded on its own, so decode --crop reads only the rows of a region; not with compression; written as format version 5
+    #[arg(long, conflicts_with_all = ["stream", "scan", "tiles", "interlace", "compress"])]
+    pub seek: bool,
+    /// Keep every pixel: an image of at most --palette-size colors gets them as its palette, undithered, one of more is stored as its pixels' colors (truecolor), filtered and compressed; written as format version 5
+    #[arg(long, conflicts_with_all = ["stream", "rle", "scan", "tiles", "interlace", "seek", "transparent"])]
+    pub lossless: bool,
+    /// Encrypt the palette entries too, not only the pixels data; dimensions, palette size, flags and metadata stay in the clear either way
+    #[arg(long, conflicts_with = "tiles")]
+    pub encrypt_palette: bool,
+    /// Format-preserving encryption of the pixels data (and palette entries with --encrypt-palette), FF1 by default or ff3-1, the codec's own block mode over the FF3-1 rounds; decode reads it from the file
+    #[arg(long, value_name = "MODE", value_parser = ["ff1", "ff3-1"])]
+    pub fpe: Option<String>,
+    /// Store a CRC32 of the header and of every KIB KiB block after it, so a damaged file tells the rows the damage is in and decode --placeholder gets the others
+    #[arg(long, value_name = "KIB", conflicts_with = "stream")]
+    pub chunk_checksums: Option<u32>,
+    /// Append Reed-Solomon parity of N% of the file (1 to 100), so decode repairs a burst of damage of up to that share of it (scattered damage costs a shard each) and repair rebuilds the file
+    #[arg(long, value_name = "N%", conflicts_with = "stream")]
+    pub fec: Option<String>,
+    /// Sign the file with an HMAC-SHA256 keyed with KEY (base64url, 16 bytes at least, e.g. from keygen), encrypted or not; verify --sign KEY checks it
+    #[arg(
+        long,
+        value_name = "KEY",
+        conflicts_with = "stream",
+        allow_hyphen_values = true
+    )]
+    pub sign: Option<String>,
+    /// Store pixels of this #rrggbb color with a transparent palette index, decode then writes an RGBA PNG; fully transparent pixels of INPUT get it without this flag
+    #[arg(long, value_name = "#RRGGBB", conflicts_with_all = ["stream", "alpha"])]
+    pub transparent: Option<String>,
+    /// Encode every frame of an animated GIF or APNG INPUT into a container (see add) with its delay and the loop count; decode writes it as an animation again
+    #[arg(long, conflicts_with_all = ["stream", "dry_run", "size", "icc", "exif", "debug_error_map"])]
+    pub frames: bool,
+    /// Keep the ICC color profile of INPUT, decode attaches it to the PNG again
+    #[arg(long, conflicts_with = "stream")]
+    pub icc: bool,
+    /// Keep the EXIF orientation, timestamps and camera info of INPUT, without location; decode attaches them to the PNG again
+    #[arg(long, conflicts_with = "stream")]
+    pub exif: bool,
+    /// Encrypt to an X25519 recipient instead of --key, repeatable
+    #[arg(long, value_name = "PUBLIC_KEY", allow_hyphen_values = true)]
+    pub recipient: Vec<String>,
+    /// Encrypt to an age X25519 recipient instead of --key, repeatable
+    #[arg(long, value_name = "AGE1...")]
+    pub age_recipient: Vec<String>,
+    /// Give another key (base64url, 16, 24 or 32 bytes) a slot of the key-slot table, repeatable; each key, --key included, opens the file
+    #[arg(long, value_name = "KEY", conflicts_with_all = ["recipient", "age_recipient"], allow_hyphen_values = true)]
+    pub key_slot: Vec<String>,
+    #[command(flatten)]
+    pub keys: KeyOptions,
+    #[command(flatten)]
+    pub run: RunOptions,
+}
+
+#[derive(Args)]
+pub struct DecodeArgs {
+    /// File to decode, "-" reads stdin, a glob pattern decodes every match
+    #[arg(required_unless_present = "batch")]
+    pub input: Option<String>,
+    /// Decoded image, INPUT with the .png extension by default
+    #[arg(conflicts_with = "batch")]
+    pub output: Option<String>,
+    /// Directory for the outputs, created if missing; INPUT may then be a glob pattern like 'encoded/*.ric'
+    #[arg(long, conflicts_with_all = ["output", "batch"])]
+    pub out_dir: Option<String>,
+    /// Decode every .ric file under the INPUT directory, recreating the tree under --out-dir
+    #[arg(short, long, requires = "input", conflicts_with_all = ["output", "batch"])]
+    pub recursive: bool,
+    /// Compressed files are detected, kept for scripts passing it
+    #[arg(short = 'z', long)]
+    pub compress: bool,
+    /// Fail on the first pixel index beyond the palette
+    #[arg(long)]
+    pub strict: bool,
+    /// Decode what an interlaced INPUT holds so far, even cut short, as a coarse image refined by each of its 7 passes
+    #[arg(long, conflicts_with_all = ["stream", "strict"])]
+    pub preview: bool,
+    /// Decode only the WIDTH x HEIGHT pixels at X,Y, reading just the rows or tiles they're in from a file with a seek table (encode --seek) or tiles
+    #[arg(long, value_name = "X,Y,WIDTH,HEIGHT", conflicts_with_all = ["stream", "strict", "preview"])]
+    pub crop: Option<String>,
+    /// Decode a damaged plain file with chunk checksums (encode --chunk-checksums) with its damaged rows in this #rrggbb color instead of failing; damaged encrypted files fail their authentication tag
+    #[arg(long, value_name = "#RRGGBB", conflicts_with = "stream")]
+    pub placeholder: Option<String>,
+    /// Also read format version 1 files, which have no RIC1 magic
+    #[arg(long)]
+    pub legacy: bool,
+    /// Decode to binary PPM without holding the whole image
+    #[arg(long)]
+    pub stream: bool,
+    /// Decrypt with an X25519 identity instead of --key
+    #[arg(long, value_name = "SECRET_KEY", allow_hyphen_values = true)]
+    pub identity: Option<String>,
+    /// Decrypt with the identities of an age identity file instead of --key
+    #[arg(long, value_name = "IDENTITY_FILE")]
+    pub age_identity: Option<String>,
+    #[command(flatten)]
+    pub keys: KeyOptions,
+    #[command(flatten)]
+    pub run: RunOptions,
+}
+
+#[derive(Args)]
+pub struct InfoArgs {
+    /// Files to describe, stdin by default
+    pub paths: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct KeygenArgs {
+    /// Key size for AES-128/192/256
+    #[arg(long, value_parser = ["16", "24", "32"])]
+    pub bytes: Option<String>,
+    /// Key size in bits instead of --bytes
+    #[arg(long, value_parser = ["128", "192", "256"], conflicts_with = "bytes")]
+    pub bits: Option<String>,
+    /// Write the key with 0600 permissions, an existing file is never overwritten
+    #[arg(long, value_name = "KEY_FILE")]
+    pub out: Option<String>,
+    /// X25519 identity (secret) and recipient (public) key pair
+    #[arg(long, conflicts_with_all = ["bytes", "bits", "split"])]
+    pub asymmetric: bool,
+    /// Print n key shares (or write KEY_FILE.1..n), any k of them restore the key
+    #[arg(long, value_name = "k/n")]
+    pub split: Option<String>,
+    /// Store the key in the OS keyring under this name instead of printing it, read with --key-name
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["out", "asymmetric", "split"])]
+    pub key_name: Option<String>,
+}
+
+#[derive(Args)]
+pub struct VerifyKeyArgs {
+    /// File to check the key against, "-" reads stdin
+    pub input: String,
+    /// Also read format version 1 files, which have no RIC1 magic
+    #[arg(long)]
+    pub legacy: bool,
+    /// Check an X25519 identity instead of --key
+    #[arg(long, value_name = "SECRET_KEY", allow_hyphen_values = true)]
+    pub identity: Option<String>,
+    /// Check the identities of an age identity file instead of --key
+    #[arg(long, value_name = "IDENTITY_FILE")]
+    pub age_identity: Option<String>,
+    #[command(flatten)]
+    pub keys: KeyOptions,
+}
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    /// File to verify, "-" reads stdin
+    pub input: String,
+    /// Also check the signature of a file encoded with --sign, keyed with KEY
+    #[arg(long, value_name = "KEY", allow_hyphen_values = true)]
+    pub sign: Option<String>,
+}
+
+#[derive(Args)]
+pub struct ValidateArgs {
+    /// File to validate, "-" reads stdin
+    pub input: String,
+    /// Accept pixel indices beyond the palette, as decode does without --strict
+    #[arg(long)]
+    pub permissive: bool,
+    /// Also read format version 1 files, which have no RIC1 magic
+    #[arg(long)]
+    pub legacy: bool,
+    /// Decrypt with an X25519 identity instead of --key
+    #[arg(long, value_name = "SECRET_KEY", allow_hyphen_values = true)]
+    pub identity: Option<String>,
+    /// Decrypt with the identities of an age identity file instead of --key
+    #[arg(long, value_name = "IDENTITY_FILE")]
+    pub age_identity: Option<String>,
+    #[command(flatten)]
+    pub keys: KeyOptions,
+}
+
+#[derive(Args)]
+pub struct InspectArgs {
+    /// File to inspect, stdin by default
+    #[arg(default_value = "-")]
+    pub input: String,
+    /// Dump the whole pixels data instead of its first lines
+    #[arg(long)]
+    pub full: bool,
+}
+
+#[derive(Args)]
+pub struct CompareSizesArgs {
+    /// Image to compare
+    pub input: String,
+    /// Palette sizes of the variants
+    #[arg(long, value_name = "SIZE,...", default_value = "8,16,32,64,128,256")]
+    pub sizes: String,
+    /// Montage to write
+    #[arg(long, value_name = "MONTAGE_FILE", default_value = "compare-sizes.png")]
+    pub out: String,
+    /// Replace an existing output
+    #[arg(long)]
+    pub force: bool,
+    /// Measure zstd compressed sizes
+    #[arg(long)]
+    pub compress: bool,
+}
+
+#[derive(Args)]
+pub struct CompareDitherArgs {
+    /// Image to compare
+    pub input: String,
+    /// Colors in the palette of every variant
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u64).range(2..=format::MAX_PALETTE_SIZE as u64))]
+    pub palette_size: u64,
+    /// Montage to write
+    #[arg(
+        long,
+        value_name = "MONTAGE_FILE",
+        default_value = "compare-dither.png"
+    )]
+    pub out: String,
+    /// Replace an existing output
+    #[arg(long)]
+    pub force: bool,
+    /// Measure zstd compressed sizes
+    #[arg(long)]
+    pub compress: bool,
+}
+
+#[derive(Args)]
+pub struct QuantizeArgs {
+    /// Image to generate the palette of
+    pub input: String,
+    /// Colors in the palette
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u64).range(2..=format::MAX_PALETTE_SIZE as u64))]
+    pub palette_size: u64,
+    /// Write the palette as .gpl (GIMP), .pal (JASC), .hex or .act (Adobe) by extension instead of printing it
+    #[arg(long, value_name = "PALETTE_FILE")]
+    pub out: Option<String>,
+    /// Replace an existing output
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct UpgradeArgs {
+    /// File to upgrade
+    pub input: String,
+    /// Upgraded file
+    pub output: String,
+    /// Replace an existing output
+    #[arg(long)]
+    pub force: bool,
+    /// Key of an encrypted file, which its authentication tag is made with; files of the first builds are decrypted with it and stored with a key check
+    #[arg(long, allow_hyphen_values = true)]
+    pub key: Option<String>,
+    /// CPUs of the machine which encoded such a file, one block was encrypted per CPU
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    pub jobs: Option<u16>,
+}
+
+#[derive(Args)]
+pub struct AddArgs {
+    /// Container to append to
+    pub container: String,
+    /// Files to append, copied as they are
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+    /// Milliseconds each of them shows for as a frame of an animation, see decode
+    #[arg(long, value_name = "MS")]
+    pub delay: Option<u32>,
+    /// Times the animation of the entries plays, 0 forever
+    #[arg(long)]
+    pub loops: Option<u32>,
+}
+
+#[derive(Args)]
+pub struct ListArgs {
+    /// Container to list, "-" reads stdin
+    pub container: String,
+}
+
+#[derive(Args)]
+pub struct ExtractArgs {
+    /// Container to extract from
+    pub container: String,
+    /// Number of the entry, from 0
+    pub entry: usize,
+    /// File to write, the name the entry was added under by default
+    pub output: Option<String>,
+    /// Replace an existing output
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct CatArgs {
+    /// Containers and .ric files whose entries go into the container in order
+    #[arg(required = true)]
+    pub inputs: Vec<String>,
+    /// Container to write
+    #[arg(short, long, value_name = "CONTAINER_FILE")]
+    pub out: String,
+    /// Replace an existing output
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct RepairArgs {
+    /// Damaged file, "-" reads stdin
+    pub input: String,
+    /// Repaired file
+    pub output: String,
+    /// Replace an existing output
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Args)]
+pub struct RekeyArgs {
+    /// Files to re-encrypt in place
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+    /// Base64url key the files are encrypted with
+    #[arg(long, value_name = "KEY", allow_hyphen_values = true)]
+    pub old_key: String,
+    /// Base64url key to encrypt them with
+    #[arg(long, value_name = "KEY", allow_hyphen_values = true)]
+    pub new_key: String,
+}
+
+// Where a key comes from besides the key itself, for the subcommands which only read keys
+#[derive(Args)]
+pub struct KeySource {
+    /// Read the key from an environment variable
+    #[arg(long, value_name = "VAR", conflicts_with = "key")]
+    pub key_env: Option<String>,
+    /// Read the key (base64url or 16, 24, 32 raw bytes) from a file
+    #[arg(long, value_name = "KEY_FILE", conflicts_with_all = ["key", "key_env"])]
+    pub key_file: Option<String>,
+    /// Read the key stored with keygen --key-name from the OS keyring
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["key", "key_env", "key_file"])]
+    pub key_name: Option<String>,
+    /// Key restored from shares
+    #[arg(long, value_name = "SHARE1,SHARE2,...", conflicts_with_all = ["key", "key_env", "key_file", "key_name"], allow_hyphen_values = true)]
+    pub shares: Option<String>,
+}
+
+#[derive(Args)]
+pub struct SlotsArgs {
+    /// File with a key-slot table
+    pub input: String,
+    /// Base64url key of a slot, marked in the list; adding and removing slots requires it
+    #[arg(long, allow_hyphen_values = true)]
+    pub key: Option<String>,
+    #[command(flatten)]
+    pub source: KeySource,
+    /// Give another key (base64url) a slot, repeatable
+    #[arg(long, value_name = "KEY", allow_hyphen_values = true)]
+    pub add: Vec<String>,
+    /// Take the slot of this number out, repeatable
+    #[arg(long, value_name = "SLOT")]
+    pub remove: Vec<usize>,
+}
+
+#[derive(Args)]
+pub struct KeyCheckArgs {
+    /// Base64url key to check
+    #[arg(allow_hyphen_values = true)]
+    pub key: Option<String>,
+    /// Base64url key to check, as --key of the other commands
+    #[arg(
+        id = "key_option",
+        long = "key",
+        value_name = "KEY",
+        conflicts_with = "key",
+        allow_hyphen_values = true
+    )]
+    pub key_option: Option<String>,
+    #[command(flatten)]
+    pub source: KeySource,
+}
@@ -0,0 +1,2374 @@
+#[cfg(feature = "crypto")]
+use aes::{Aes128, Aes192, Aes256};
+#[cfg(feature = "crypto")]
+use cosmian_fpe::ff1::{BinaryNumeralString, FF1};
+use hmac::Mac;
+use image::{
+    DynamicImage, ImageBuffer, Pixel, Rgb, RgbImage, Rgba, RgbaImage,
+    imageops::{self, ColorMap},
+};
+use std::{borrow::Cow, ops::Range, sync::Arc, thread};
+
+#[cfg(feature = "crypto")]
+use crate::ff3;
+use crate::{
+    ZSTD_MAGIC, bare, chunks, compare, compression, detect,
+    dither::Dither,
+    error::CodecError,
+    fec, filter, format, interlace,
+    keys::*,
+    log, metadata,
+    progress::{Progress, Silent, Tracker},
+    rle,
+    scan::{self, Scan},
+    seek, tiles,
+    timings::{Instant, Timings},
+    utils::*,
+};
+
+// Key length selects AES-128/192/256, tweaks are little endian u64 numbers
+#[cfg(feature = "crypto")]
+pub(crate) fn encrypt(bytes: &mut [u8], key: &str, tweak: &[u8], fpe: Fpe) -> Option<()> {
+    let byte_key = base64url_to_bytes(key)?;
+    if fpe == Fpe::Ff31 {
+        return ff3::encrypt(bytes, &byte_key, ff3_number(tweak));
+    }
+    let bn = BinaryNumeralString::from_bytes_le(bytes);
+    let encrypted = match byte_key.len() {
+        16 => FF1::<Aes128>::new(&byte_key, 2)
+            .ok()?
+            .encrypt(tweak, &bn)
+            .ok()?,
+        24 => FF1::<Aes192>::new(&byte_key, 2)
+            .ok()?
+            .encrypt(tweak, &bn)
+            .ok()?,
+        32 => FF1::<Aes256>::new(&byte_key, 2)
+            .ok()?
+            .encrypt(tweak, &bn)
+            .ok()?,
+        _ => return None,
+    };
+    let encrypted_bytes = encrypted.to_bytes_le();
+    bytes.copy_from_slice(&encrypted_bytes);
+    Some(())
+}
+
+#[cfg(feature = "crypto")]
+pub(crate) fn decrypt(cipher: &mut [u8], key: &str, tweak: &[u8], fpe: Fpe) -> Option<()> {
+    let byte_key = base64url_to_bytes(key)?;
+    if fpe == Fpe::Ff31 {
+        return ff3::decrypt(cipher, &byte_key, ff3_number(tweak));
+    }
+    let bn = BinaryNumeralString::from_bytes_le(cipher);
+    let decrypted = match byte_key.len() {
+        16 => FF1::<Aes128>::new(&byte_key, 2)
+            .ok()?
+            .decrypt(tweak, &bn)
+            .ok()?,
+        24 => FF1::<Aes192>::new(&byte_key, 2)
+            .ok()?
+            .decrypt(tweak, &bn)
+            .ok()?,
+        32 => FF1::<Aes256>::new(&byte_key, 2)
+            .ok()?
+            .decrypt(tweak, &bn)
+            .ok()?,
+        _ => return None,
+    };
+    let decrypted_bytes = decrypted.to_bytes_le();
+    cipher.copy_from_slice(decrypted_bytes.as_slice());
+    Some(())
+}
+
+// FF3-1's 56 bit tweaks hold less than FF1's, it numbers blocks by the tweak's little endian u64
+// halves xored, the header tweak folded into the segment number; ff3 keeps the low 40 bits of it
+// above its 16 bit block index
+#[cfg(feature = "crypto")]
+fn ff3_number(tweak: &[u8]) -> u64 {
+    tweak
+        .chunks(8)
+        .map(|half| {
+            let mut bytes = [0u8; 8];
+            bytes[..half.len()].copy_from_slice(half);
+            u64::from_le_bytes(bytes)
+        })
+        .fold(0, |number, half| number ^ half)
+}
+
+// Keys are rejected by Encoder::check and read_header before these are reached
+#[cfg(not(feature = "crypto"))]
+pub(crate) fn encrypt(_bytes: &mut [u8], _key: &str, _tweak: &[u8], _fpe: Fpe) -> Option<()> {
+    None
+}
+
+#[cfg(not(feature = "crypto"))]
+pub(crate) fn decrypt(_cipher: &mut [u8], _key: &str, _tweak: &[u8], _fpe: Fpe) -> Option<()> {
+    None
+}
+
+pub(crate) fn require_crypto() -> Result<(), CodecError> {
+    if cfg!(feature = "crypto") {
+        Ok(())
+    } else {
+        Err(CodecError::FeatureDisabled("crypto"))
+    }
+}
+
+// Pixels data is encrypted in segments of a fixed size with the segment number in the FF1 tweak,
+// so the result doesn't depend on the threads amount
+pub(crate) const SEGMENT_LEN: usize = 1 << 16;
+// FF1 tweak of encrypted palette entries, beyond any segment number
+pub(crate) const PALETTE_TWEAK: [u8; 8] = u64::MAX.to_le_bytes();
+// FF1 and FF3-1 take 3 bytes at least, a shorter last segment is part of the one before it
+const MIN_SEGMENT_LEN: usize = 3;
+
+// Byte ranges of the segments of len bytes of pixels data in segment number order: SEGMENT_LEN
+// bytes each, the last one shorter or up to 2 bytes longer
+pub(crate) fn segments(len: usize) -> impl ExactSizeIterator<Item = Range<usize>> {
+    let tail = len % SEGMENT_LEN;
+    let count = if len > SEGMENT_LEN && tail < MIN_SEGMENT_LEN {
+        len / SEGMENT_LEN
+    } else {
+        len.div_ceil(SEGMENT_LEN)
+    };
+    (0..count).map(move |i| {
+        i * SEGMENT_LEN..if i + 1 == count {
+            len
+        } else {
+            (i + 1) * SEGMENT_LEN
+        }
+    })
+}
+
+// The segments of data with their numbers
+pub(crate) fn split_segments(data: &mut [u8]) -> Vec<(u64, &mut [u8])> {
+    let mut rest = data;
+    segments(rest.len())
+        .enumerate()
+        .map(|(number, range)| {
+            let (segment, tail) = std::mem::take(&mut rest).split_at_mut(range.len());
+            rest = tail;
+            (number as u64, segment)
+        })
+        .collect()
+}
+
+// Numbers and byte ranges of the segments of len bytes of pixels data a range overlaps
+pub(crate) fn covering_segments(range: &Range<usize>, len: usize) -> Vec<(u64, Range<usize>)> {
+    segments(len)
+        .enumerate()
+        .filter(|(_, segment)| segment.start < range.end && range.start < segment.end)
+        .map(|(number, segment)| (number as u64, segment))
+        .collect()
+}
+
+// Key and mode the segments of pixels data are encrypted with, and the header tweak (see
+// keys::header_tweak) following the segment number in their tweaks, None for files of builds
+// before it
+#[derive(Clone, Copy)]
+pub(crate) struct SegmentKey<'a> {
+    pub(crate) key: &'a str,
+    pub(crate) fpe: Fpe,
+    pub(crate) header_tweak: Option<[u8; 8]>,
+}
+
+impl SegmentKey<'_> {
+    fn tweak(&self, number: u64) -> Vec<u8> {
+        let mut tweak = number.to_le_bytes().to_vec();
+        if let Some(header_tweak) = self.header_tweak {
+            tweak.extend_from_slice(&header_tweak);
+        }
+        tweak
+    }
+
+    pub(crate) fn encrypt(&self, segment: &mut [u8], number: u64) -> Result<(), CodecError> {
+        encrypt(segment, self.key, &self.tweak(number), self.fpe)
+            .ok_or(CodecError::BadKey("invalid key".to_string()))
+    }
+
+    pub(crate) fn decrypt(&self, segment: &mut [u8], number: u64) -> Result<(), CodecError> {
+        decrypt(segment, self.key, &self.tweak(number), self.fpe)
+            .ok_or(CodecError::BadKey("invalid key".to_string()))
+    }
+}
+
+// Runs the jobs on named threads, or in place with a single thread since targets without
+// threads (wasm32) can't spawn any; results come back in job order
+pub(crate) fn run_jobs<T: Send>(
+    phase: &'static str,
+    name: &str,
+    jobs: Vec<impl FnOnce() -> T + Send>,
+    threads: usize,
+    timings: &mut Timings,
+) -> Vec<T> {
+    if threads <= 1 {
+        return jobs.into_iter().map(|job| job()).collect();
+    }
+    thread::scope(|scope| {
+        let handles = jobs
+            .into_iter()
+            .enumerate()
+            .map(|(i, job)| {
+                thread::Builder::new()
+                    .name(format!("{name}-{i}/{threads}"))
+                    .spawn_scoped(scope, move || {
+                        let start = Instant::now();
+                        let result = job();
+                        (result, start, start.elapsed())
+                    })
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| {
+                let name = handle.thread().name().unwrap_or_default().to_string();
+                let (result, since, busy) = handle.join().unwrap();
+                timings.record_thread(phase, name, since, busy);
+                result
+            })
+            .collect()
+    })
+}
+
+// Where an index at offset in the pixels data is, for strict errors; run-length encoded data
+// only tells its byte once decoded
+pub(crate) fn index_location(
+    data_offset: usize,
+    offset: usize,
+    layout: u8,
+    compressed: bool,
+) -> String {
+    if layout & format::LAYOUT_RLE != 0 {
+        return format!("byte {} of the run-length decoded pixels data", offset);
+    }
+    format!(
+        "byte {} of the {}file",
+        data_offset + offset,
+        if compressed { "decompressed " } else { "" }
+    )
+}
+
+fn process_segments(
+    data: &mut [u8],
+    key: SegmentKey,
+    decrypting: bool,
+    tracker: &Tracker,
+    threads: usize,
+    timings: &mut Timings,
+) -> Result<(), CodecError> {
+    let mut segments = split_segments(data).into_iter().peekable();
+    let segments_per_thread = segments.len().div_ceil(threads.max(1)).max(1);
+    let phase = if decrypting { "decrypt" } else { "encrypt" };
+    let start = Instant::now();
+    let mut groups = Vec::new();
+    while segments.peek().is_some() {
+        groups.push(
+            segments
+                .by_ref()
+                .take(segments_per_thread)
+                .collect::<Vec<_>>(),
+        );
+    }
+    let jobs = groups
+        .into_iter()
+        .map(|group| {
+            move || {
+                for (number, segment) in group {
+                    if decrypting {
+                        key.decrypt(segment, number)?;
+                    } else {
+                        key.encrypt(segment, number)?;
+                    }
+                    tracker.step();
+                }
+                Ok::<_, CodecError>(())
+            }
+        })
+        .collect();
+    run_jobs(phase, "crypting", jobs, threads, timings)
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    timings.record(phase, start);
+    Ok(())
+}
+
+// Pixels are dithered to palette colors, so the nearest one is the first equal one
+fn process_encode<P: Color>(chunk: &[P], palette: &Palette<P>, tracker: &Tracker) -> Vec<u16> {
+    let mut encode: Vec<u16> = Vec::with_capacity(chunk.len());
+    for pixel in chunk {
+        encode.push(palette.index_of(pixel) as u16);
+        tracker.step();
+    }
+    encode
+}
+
+// Indices beyond the palette are decoded as the first palette color, out takes RGB (channels
+// 3) or RGBA (channels 4) bytes of the pixels
+fn process_decode(
+    indexed: &Indexed,
+    pixels: Range<usize>,
+    channels: usize,
+    tracker: &Tracker,
+    out: &mut [u8],
+) {
+    for (pixel, out) in pixels.zip(out.chunks_exact_mut(channels)) {
+        out.copy_from_slice(&indexed.color(pixel).0[..channels]);
+        tracker.step();
+    }
+}
+
+pub(crate) fn color(palette: &[Rgba<u8>], index: u16) -> Rgba<u8> {
+    palette.get(index as usize).copied().unwrap_or(palette[0])
+}
+
+// What an encode produced, reported by --dry-run and --stats
+#[derive(Clone, Debug, Default)]
+pub struct EncodeStats {
+    pub width: u32,
+    pub height: u32,
+    // Median cut may stop below the requested palette size
+    pub palette_colors: usize,
+    // Palette colors at least one pixel is mapped to
+    pub used_colors: usize,
+    // Mean RGB distance between source and dithered pixels, None when the source pixels
+    // weren't kept (RowEncoder)
+    pub mean_error: Option<f64>,
+    // File size before zstd, equal to len when not compressed
+    pub uncompressed_len: usize,
+    // Pixels stored as their colors without a palette, see Encoder::lossless
+    pub truecolor: bool,
+    pub len: usize,
+}
+
+// Receiver of the quantization error image, see Encoder::error_map
+pub type ErrorMapWriter = dyn Fn(RgbImage) -> Result<(), CodecError> + Send + Sync;
+
+// Encode settings, each setter has a default so new settings don't break callers
+#[derive(Clone)]
+pub struct Encoder {
+    pub(crate) palette_size: usize,
+    pub(crate) dither: Dither,
+    pub(crate) compress: bool,
+    pub(crate) compression_level: i32,
+    // Base64url data key
+    pub(crate) key: Option<String>,
+    // Recipients, age or salt block stored before the key check
+    pub(crate) key_slots: Option<Vec<u8>>,
+    // Data key wrapped with every key that opens the file, see slot_table.rs
+    pub(crate) slot_table: Option<Vec<u8>>,
+    pub(crate) metadata: metadata::Entries,
+    // Pixels of this color are stored with a transparent palette index
+    pub(crate) transparent: Option<Rgb<u8>>,
+    // Takes the per-pixel quantization error image, e.g. to save it
+    pub(crate) error_map: Option<Arc<ErrorMapWriter>>,
+    // PNG-style row filters before compression
+    pub(crate) filter: bool,
+    // Run-length encoding of the (filtered) pixels data
+    pub(crate) rle: bool,
+    // Order the indices are serialized in
+    pub(crate) scan: Scan,
+    // Side of the square tiles with a palette each, None for one palette
+    pub(crate) tile_size: Option<u32>,
+    // Adam7 passes instead of rows
+    pub(crate) interlace: bool,
+    // Row offsets before rows filtered and run-length encoded one by one
+    pub(crate) seek: bool,
+    // The image's own colors as the palette, or its pixels as they are when they don't fit
+    pub(crate) lossless: bool,
+    // Palette entries encrypted too, not only the pixels data
+    pub(crate) encrypt_palette: bool,
+    // Format-preserving encryption mode of the key
+    pub(crate) fpe: Fpe,
+    // Bytes of the blocks checksummed one by one, see chunks.rs
+    pub(crate) chunk_len: Option<usize>,
+    // Reed-Solomon parity shards per 100 data shards, see fec.rs
+    pub(crate) fec: Option<u8>,
+    // Key of the signature entry, see keys::signer
+    pub(crate) signing_key: Option<Vec<u8>>,
+    pub(crate) progress: Arc<dyn Progress>,
+    pub(crate) threads: usize,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self {
+            palette_size: 256,
+            dither: Dither::FloydSteinberg,
+            compress: false,
+            compression_level: compression::DEFAULT_LEVEL,
+            key: None,
+            key_slots: None,
+            slot_table: None,
+            metadata: Vec::new(),
+            transparent: None,
+            error_map: None,
+            filter: false,
+            rle: false,
+            scan: Scan::Raster,
+            tile_size: None,
+            interlace: false,
+            seek: false,
+            lossless: false,
+            encrypt_palette: false,
+            fpe: Fpe::Ff1,
+            chunk_len: None,
+            fec: None,
+            signing_key: None,
+            progress: Arc::new(Silent),
+            threads: num_cpus::get(),
+        }
+    }
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn palette_size(mut self, palette_size: usize) -> Self {
+        self.palette_size = palette_size;
+        self
+    }
+
+    pub fn dither(mut self, dither: Dither) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    // Zstd over the whole file, kept only when it's smaller
+    pub fn compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    // Zstd level, 1 (fastest) to 22 (smallest), 3 by default
+    pub fn compression_level(mut self, level: i32) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    // 16, 24 or 32 bytes select AES-128/192/256
+    pub fn key(mut self, key: &[u8]) -> Self {
+        self.key = Some(bytes_to_base64url(key));
+        self
+    }
+
+    pub fn key_slots(mut self, key_slots: Vec<u8>) -> Self {
+        self.key_slots = Some(key_slots);
+        self
+    }
+
+    // Key-slot table of the data key given to key() (see slot_table::wrap_key), stored as the
+    // key-slots entry so each of its keys opens the file
+    pub fn slot_table(mut self, slot_table: Vec<u8>) -> Self {
+        self.slot_table = Some(slot_table);
+        self
+    }
+
+    // Stored in the clear even when encrypting, a key may be given once
+    pub fn metadata(mut self, key: &str, value: &[u8]) -> Self {
+        self.metadata.push((key.to_string(), value.to_vec()));
+        self
+    }
+
+    // GIF-style transparency of RGB images: pixels of color get palette index 0, which is
+    // stored as transparent, the other colors share the rest of the palette
+    pub fn transparent(mut self, color: Rgb<u8>) -> Self {
+        self.transparent = Some(color);
+        self
+    }
+
+    // Hands the per-pixel quantization error magnitude of each encode, as an image, to write;
+    // the caller decides where it goes and whether an existing file may be replaced
+    pub fn error_map(
+        mut self,
+        write: impl Fn(RgbImage) -> Result<(), CodecError> + Send + Sync + 'static,
+    ) -> Self {
+        self.error_map = Some(Arc::new(write));
+        self
+    }
+
+    // Filters every row of indices as PNG does (Sub, Up, Average or Paeth, whichever suits the
+    // row) with the palette ordered by luma, smooth gradients then compress better; files are
+    // written as v5
+    pub fn filter(mut self, filter: bool) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // Runs of one index take a few bytes, so flat artwork shrinks without zstd and decodes
+    // faster; the whole image is needed, it can't be streamed. Files are written as v5
+    pub fn rle(mut self, rle: bool) -> Self {
+        self.rle = rle;
+        self
+    }
+
+    // Serializes the indices along another path than rows (a Hilbert curve, serpentine rows or
+    // columns), pixels close in the image then stay close in the data, which suits zstd and
+    // run-length encoding; the whole image is needed, it can't be streamed. Files are written
+    // as v5
+    pub fn scan(mut self, scan: Scan) -> Self {
+        self.scan = scan;
+        self
+    }
+
+    // Cuts the image into tiles of size x size pixels, each with a palette of up to palette_size
+    // colors of its own, so large varied images keep the colors of each part; tiles are
+    // quantized in parallel and decoded on their own. Files are written as v5
+    pub fn tiles(mut self, size: u32) -> Self {
+        self.tile_size = Some(size);
+        self
+    }
+
+    // Stores the indices in 7 Adam7 passes, as interlaced PNGs do, so the start of the file
+    // already holds a coarse image (see Decoder::preview) that later passes refine; files grow a
+    // little and can't be streamed. Files are written as v5
+    pub fn interlace(mut self, interlace: bool) -> Self {
+        self.interlace = interlace;
+        self
+    }
+
+    // Stores the offset of every row before the rows, which are filtered and run-length encoded
+    // one by one, so a region is decoded from its rows alone (see Decoder::decode_region); row
+    // filters lose Up, Average and Paeth's row above and the file can't be compressed with zstd.
+    // Files are written as v5
+    pub fn seek(mut self, seek: bool) -> Self {
+        self.seek = seek;
+        self
+    }
+
+    // Keeps every pixel as it is: an image of at most palette_size colors gets them as its
+    // palette, undithered, and one of more is stored as its pixels' channel bytes (truecolor),
+    // filtered and compressed whenever the build has zstd, so photos round-trip too. Truecolor
+    // files are written as v5
+    pub fn lossless(mut self, lossless: bool) -> Self {
+        self.lossless = lossless;
+        self
+    }
+
+    // Encrypts the palette entries along with the pixels data, which a key encrypts alone; the
+    // rest of the header stays in the clear either way (see read_header). Tiled and truecolor
+    // files have no palette entries in the header, and a palette of 2 gray colors is too short
+    // for FF1
+    pub fn encrypt_palette(mut self, encrypt_palette: bool) -> Self {
+        self.encrypt_palette = encrypt_palette;
+        self
+    }
+
+    // Encrypts with FF3-1 instead of FF1, recorded in the file so decoding needs only the key
+    pub fn fpe(mut self, fpe: Fpe) -> Self {
+        self.fpe = fpe;
+        self
+    }
+
+    // Stores a CRC32 of the header and of every block_len bytes of the data after it in the
+    // metadata, so a damaged file tells which rows are damaged and decodes with the others (see
+    // Decoder::placeholder); not for encode_stream, whose data follows its header
+    pub fn chunk_checksums(mut self, block_len: usize) -> Self {
+        self.chunk_len = Some(block_len);
+        self
+    }
+
+    // Wraps the file as written with Reed-Solomon parity of percent (1 to 100) of it, so
+    // decoding repairs a burst of damage of up to that share of it (see fec.rs); not for
+    // encode_stream
+    pub fn fec(mut self, percent: u8) -> Self {
+        self.fec = Some(percent);
+        self
+    }
+
+    // Signs the file with an HMAC-SHA256 keyed with key (see keys::signer), which
+    // verify::check_signature checks, encrypted or not; not for encode_stream
+    pub fn sign(mut self, key: &[u8]) -> Self {
+        self.signing_key = Some(key.to_vec());
+        self
+    }
+
+    // Silent by default, the CLI passes utils::ProgressBar
+    pub fn progress(mut self, progress: impl Progress + 'static) -> Self {
+        self.progress = Arc::new(progress);
+        self
+    }
+
+    // One thread per CPU by default, 1 runs everything on the calling thread
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    // Rejects settings and dimensions the format can't hold
+    pub(crate) fn check(&self, width: u32, height: u32) -> Result<(), CodecError> {
+        let key_len = self
+            .key
+            .as_deref()
+            .map(|key| base64url_to_bytes(key).unwrap_or_default().len());
+        if key_len.is_some() {
+            require_crypto()?;
+        }
+        if self.compress && !cfg!(feature = "zstd") {
+            return Err(CodecError::FeatureDisabled("zstd"));
+        }
+        if !(compression::MIN_LEVEL..=compression::MAX_LEVEL).contains(&self.compression_level) {
+            return Err(CodecError::InvalidInput(format!(
+                "compression level should be between {} and {}",
+                compression::MIN_LEVEL,
+                compression::MAX_LEVEL
+            )));
+        }
+        if key_len.is_some_and(|len| ![16, 24, 32].contains(&len)) {
+            return Err(CodecError::BadKey(
+                "key size should be 16, 24 or 32 bytes".to_string(),
+            ));
+        }
+        metadata::check(&self.metadata)?;
+        if self
+            .fec
+            .is_some_and(|percent| !(1..=100).contains(&percent))
+        {
+            return Err(CodecError::InvalidInput(
+                "forward error correction should be 1% to 100%".to_string(),
+            ));
+        }
+        if self.fpe != Fpe::Ff1 && self.key.is_none() {
+            return Err(CodecError::InvalidInput(format!(
+                "{} encrypts, it needs a key",
+                self.fpe.name().to_uppercase()
+            )));
+        }
+        if self.encrypt_palette {
+            if self.key.is_none() {
+                return Err(CodecError::InvalidInput(
+                    "encrypting the palette needs a key".to_string(),
+                ));
+            }
+            if self.tile_size.is_some() {
+                return Err(CodecError::InvalidInput(
+                    "tiles keep their palettes in the pixels data, which is encrypted already"
+                        .to_string(),
+                ));
+            }
+            if self
+                .metadata
+                .iter()
+                .any(|(key, _)| key == metadata::ENCRYPTED_PALETTE_KEY)
+            {
+                return Err(CodecError::InvalidInput(format!(
+                    "metadata key \"{}\" marks an encrypted palette",
+                    metadata::ENCRYPTED_PALETTE_KEY
+                )));
+            }
+        }
+        if let Some(block_len) = self.chunk_len {
+            if !(1..=u32::MAX as usize).contains(&block_len) {
+                return Err(CodecError::InvalidInput(
+                    "chunk checksum blocks should be 1 byte to 4GiB".to_string(),
+                ));
+            }
+            if self.metadata.iter().any(|(key, _)| key == chunks::KEY) {
+                return Err(CodecError::InvalidInput(format!(
+                    "metadata key \"{}\" holds the chunk checksums",
+                    chunks::KEY
+                )));
+            }
+        }
+        if self.key.is_some() && self.metadata.iter().any(|(key, _)| key == AUTH_KEY) {
+            return Err(CodecError::InvalidInput(format!(
+                "metadata key \"{}\" holds the authentication tag of encrypted files",
+                AUTH_KEY
+            )));
+        }
+        if self.key.is_some() && self.metadata.iter().any(|(key, _)| key == HEADER_TWEAK_KEY) {
+            return Err(CodecError::InvalidInput(format!(
+                "metadata key \"{}\" marks the pixels data bound to the header of encrypted files",
+                HEADER_TWEAK_KEY
+            )));
+        }
+        if self.metadata.iter().any(|(key, _)| key == SIGNATURE_KEY) {
+            return Err(CodecError::InvalidInput(format!(
+                "metadata key \"{}\" holds the signature of signed files",
+                SIGNATURE_KEY
+            )));
+        }
+        if self.key.is_some() && self.metadata.iter().any(|(key, _)| key == KEY_SLOTS_KEY) {
+            return Err(CodecError::InvalidInput(format!(
+                "metadata key \"{}\" holds the key-slot table of encrypted files",
+                KEY_SLOTS_KEY
+            )));
+        }
+        if self.key.is_some() && self.metadata.iter().any(|(key, _)| key == CIPHER_KEY) {
+            return Err(CodecError::InvalidInput(format!(
+                "metadata key \"{}\" names the cipher of encrypted files",
+                CIPHER_KEY
+            )));
+        }
+        if !(2..=format::MAX_PALETTE_SIZE).contains(&self.palette_size) {
+            return Err(CodecError::InvalidInput(format!(
+                "palette size should be between 2 and {}",
+                format::MAX_PALETTE_SIZE
+            )));
+        }
+        if width == 0 || height == 0 {
+            return Err(CodecError::InvalidInput(
+                "width and height should be at least 1".to_string(),
+            ));
+        }
+        // FF1 takes 20 bits at least, so 3 bytes of pixels data
+        if self.key.is_some() && (width as u64) * (height as u64) < 3 {
+            return Err(CodecError::InvalidInput(
+                "encrypting needs an image of 3 pixels at least".to_string(),
+            ));
+        }
+        if let Some(size) = self.tile_size {
+            if !(tiles::MIN_SIZE..=tiles::MAX_SIZE).contains(&size) {
+                return Err(CodecError::InvalidInput(format!(
+                    "tile size should be between {} and {}",
+                    tiles::MIN_SIZE,
+                    tiles::MAX_SIZE
+                )));
+            }
+            if self.filter
+                || self.rle
+                || self.scan != Scan::Raster
+                || self.interlace
+                || self.seek
+                || self.transparent.is_some()
+            {
+                return Err(CodecError::InvalidInput(
+                    "tiles can't be combined with row filters, run-length encoding, another scan order, interlacing, a seek table or a transparent color"
+                        .to_string(),
+                ));
+            }
+        }
+        if self.interlace && self.scan != Scan::Raster {
+            return Err(CodecError::InvalidInput(format!(
+                "interlacing can't be combined with the {} scan, its passes are an order of their own",
+                self.scan.name()
+            )));
+        }
+        if self.seek && (self.interlace || self.scan != Scan::Raster) {
+            return Err(CodecError::InvalidInput(
+                "a seek table can't be combined with interlacing or another scan order, it points at rows"
+                    .to_string(),
+            ));
+        }
+        if self.lossless
+            && (self.tile_size.is_some()
+                || self.rle
+                || self.scan != Scan::Raster
+                || self.interlace
+                || self.seek
+                || self.transparent.is_some())
+        {
+            return Err(CodecError::InvalidInput(
+                "lossless can't be combined with tiles, run-length encoding, another scan order, interlacing, a seek table or a transparent color, a truecolor file is filtered rows only"
+                    .to_string(),
+            ));
+        }
+        if self.seek && self.compress {
+            return Err(CodecError::InvalidInput(
+                "a seek table can't be combined with compression, zstd compresses the whole file as one frame; run-length encoding keeps it seekable"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn flags(&self) -> u8 {
+        let mut flags = format::FLAG_CHECKSUM;
+        if self.compress {
+            flags |= format::FLAG_COMPRESSED;
+        }
+        if self.key.is_some() {
+            // The metadata names the cipher
+            flags |= format::FLAG_ENCRYPTED | format::FLAG_METADATA;
+        }
+        if self.key_slots.is_some() {
+            flags |= format::FLAG_KEY_BLOCK;
+        }
+        if !self.metadata.is_empty() || self.chunk_len.is_some() || self.signing_key.is_some() {
+            flags |= format::FLAG_METADATA;
+        }
+        flags
+    }
+
+    // Bits per index of the pixels data. FF1 takes 3 bytes at least, so tiny encrypted images
+    // keep byte indices
+    pub(crate) fn index_bits(&self, width: u32, height: u32, palette_size: usize) -> u32 {
+        let index_bits = format::index_bits_for(palette_size);
+        if self.key.is_some() && format::data_len(width, height, index_bits, 0) < 3 {
+            return index_bits.max(8);
+        }
+        index_bits
+    }
+
+    // Layout flags byte of v5 files
+    pub(crate) fn layout_flags(&self) -> u8 {
+        let mut layout = 0;
+        if self.filter {
+            layout |= format::LAYOUT_FILTERED;
+        }
+        if self.rle {
+            layout |= format::LAYOUT_RLE;
+        }
+        if self.tile_size.is_some() {
+            layout |= format::LAYOUT_TILED;
+        }
+        if self.interlace {
+            layout |= format::LAYOUT_INTERLACED;
+        }
+        if self.seek {
+            layout |= format::LAYOUT_SEEK;
+        }
+        layout | self.scan.layout()
+    }
+
+    // Palette colors left for the pixels which aren't of the transparent color
+    pub(crate) fn opaque_palette_size(&self) -> usize {
+        self.palette_size - self.transparent.is_some() as usize
+    }
+
+    // Key the pixels data is encrypted with, bound to the dimensions and palette header() stores
+    // for them (only the palette's size with tiles and for truecolor files)
+    pub(crate) fn segment_key<P: Color>(
+        &self,
+        width: u32,
+        height: u32,
+        palette: &[P],
+    ) -> Option<SegmentKey<'_>> {
+        let key = self.key.as_deref()?;
+        let (palette_size, entries) = if self.tile_size.is_some() || palette.is_empty() {
+            (palette.len().max(2), Vec::new())
+        } else {
+            let entries = palette.iter().flat_map(|entry| entry.channels().to_vec());
+            (palette.len(), entries.collect())
+        };
+        Some(SegmentKey {
+            key,
+            fpe: self.fpe,
+            header_tweak: Some(header_tweak(width, height, palette_size, &entries)),
+        })
+    }
+
+    // Everything before the pixels data, palette entries are RGB or RGBA as given; data_len is
+    // the length of the stored pixels data, which run-length encoded, tiled and seekable files
+    // hold, and data the data itself for the chunk checksums, None when it follows later. With
+    // tiles palette is the largest tile palette, only its size is stored; an empty palette is
+    // that of a truecolor file, see encode_truecolor
+    pub(crate) fn header<P: Color>(
+        &self,
+        width: u32,
+        height: u32,
+        palette: &[P],
+        transparent: Option<u16>,
+        data_len: usize,
+        data: Option<&[u8]>,
+    ) -> Result<Vec<u8>, CodecError> {
+        let channels = P::CHANNEL_COUNT as usize;
+        let mut header = Vec::with_capacity(format::V5_PREFIX_LEN + 10 + palette.len() * channels);
+        let mut flags = self.flags() | P::FLAGS;
+        if transparent.is_some() {
+            flags |= format::FLAG_TRANSPARENT;
+        }
+        let truecolor = palette.is_empty();
+        let encrypt_palette =
+            self.encrypt_palette && self.key.is_some() && self.tile_size.is_none() && !truecolor;
+        let index_bits = match self.tile_size {
+            _ if truecolor => P::CHANNEL_COUNT as u32 * 8,
+            Some(_) => format::index_bits_for(palette.len()),
+            None => self.index_bits(width, height, palette.len()),
+        };
+        let mut layout = self.layout_flags();
+        if truecolor {
+            layout |= format::LAYOUT_TRUECOLOR;
+        }
+        let version = format::version_for(width, height, index_bits, layout);
+        header.extend_from_slice(&format::prefix(version, flags));
+        if version >= format::VERSION {
+            header.extend_from_slice(&[layout, index_bits as u8]);
+        }
+        if layout & format::LAYOUT_STORED_LEN != 0 {
+            header.extend_from_slice(&(data_len as u64).to_le_bytes());
+        }
+        if let Some(size) = self.tile_size {
+            header.extend_from_slice(&(size as u16).to_le_bytes());
+        }
+        header.extend_from_slice(&format::dimensions_bytes(version, width, height));
+        // Median cut may stop early, so the header holds the actual palette size
+        if self.tile_size.is_some() || truecolor {
+            header.extend_from_slice(&format::palette_size_bytes(version, palette.len().max(2)));
+        } else {
+            header.extend_from_slice(&format::palette_size_bytes(version, palette.len()));
+            let start = header.len();
+            header.extend(palette.iter().flat_map(|entry| entry.channels().to_vec()));
+            if encrypt_palette {
+                let entries = &mut header[start..];
+                // FF1 takes 20 bits at least
+                if entries.len() < 3 {
+                    return Err(CodecError::InvalidInput(
+                        "a palette of 2 gray colors is too short to encrypt".to_string(),
+                    ));
+                }
+                encrypt(
+                    entries,
+                    self.key.as_ref().unwrap(),
+                    &PALETTE_TWEAK,
+                    self.fpe,
+                )
+                .ok_or(CodecError::BadKey("invalid key".to_string()))?;
+            }
+        }
+        match transparent {
+            Some(index) if index_bits > 8 => header.extend_from_slice(&index.to_le_bytes()),
+            Some(index) => header.push(index as u8),
+            None => {}
+        }
+        let key_slots = self.key_slots.as_deref().unwrap_or_default();
+        let key_check = self.key.as_ref().map_or(Vec::new(), |key| {
+            key_check(&base64url_to_bytes(key).unwrap()).to_vec()
+        });
+        let mut metadata = Cow::Borrowed(&self.metadata);
+        if let Some(key) = &self.key {
+            let cipher = cipher_name(self.fpe, base64url_to_bytes(key).unwrap().len()).unwrap();
+            metadata
+                .to_mut()
+                .push((CIPHER_KEY.to_string(), cipher.as_bytes().to_vec()));
+            metadata
+                .to_mut()
+                .push((HEADER_TWEAK_KEY.to_string(), Vec::new()));
+            if let Some(slot_table) = &self.slot_table {
+                metadata
+                    .to_mut()
+                    .push((KEY_SLOTS_KEY.to_string(), slot_table.clone()));
+            }
+        }
+        if encrypt_palette {
+            metadata
+                .to_mut()
+                .push((metadata::ENCRYPTED_PALETTE_KEY.to_string(), Vec::new()));
+        }
+        if let (Some(block_len), Some(data)) = (self.chunk_len, data) {
+            let entry = chunks::entry(block_len, &header, &[key_slots, &key_check, data]);
+            metadata.to_mut().push((chunks::KEY.to_string(), entry));
+        }
+        if let (Some(key), Some(data)) = (&self.signing_key, data) {
+            let mut mac = signer(key, &header, &metadata);
+            mac.update(key_slots);
+            mac.update(&key_check);
+            mac.update(data);
+            let signature = mac.finalize().into_bytes().to_vec();
+            metadata
+                .to_mut()
+                .push((SIGNATURE_KEY.to_string(), signature));
+        }
+        if let (Some(key), Some(data)) = (&self.key, data) {
+            let mut mac = authenticator(&base64url_to_bytes(key).unwrap(), &header, &metadata);
+            mac.update(key_slots);
+            mac.update(&key_check);
+            mac.update(data);
+            let tag = auth_tag(mac).to_vec();
+            metadata.to_mut().push((AUTH_KEY.to_string(), tag));
+        }
+        if !metadata.is_empty() {
+            header.extend_from_slice(&metadata::write(&metadata));
+        }
+        header.extend_from_slice(key_slots);
+        header.extend_from_slice(&key_check);
+        Ok(header)
+    }
+
+    // RGB images, or RGBA ones which are stored with 4 byte palette entries
+    pub fn encode<P: Color>(&self, img: &ImageBuffer<P, Vec<u8>>) -> Result<Vec<u8>, CodecError> {
+        self.encode_timed(img.clone(), &mut Timings::new())
+    }
+
+    // Encodes a framebuffer of RGB bytes, skipping image decoding
+    pub fn encode_raw(
+        &self,
+        pixels: &[u8],
+        width: u32,
+        height: u32,
+    ) -> Result<Vec<u8>, CodecError> {
+        let img = raw_img(pixels.to_vec(), width, height)?;
+        self.encode_timed(img, &mut Timings::new())
+    }
+
+    pub fn encode_timed<P: Color>(
+        &self,
+        img: ImageBuffer<P, Vec<u8>>,
+        timings: &mut Timings,
+    ) -> Result<Vec<u8>, CodecError> {
+        Ok(self.encode_with(img, None, timings)?.0)
+    }
+
+    // Same as encode_timed() but also returns sizes and palette usage
+    pub fn encode_stats<P: Color>(
+        &self,
+        img: ImageBuffer<P, Vec<u8>>,
+        timings: &mut Timings,
+    ) -> Result<(Vec<u8>, EncodeStats), CodecError> {
+        self.encode_with(img, None, timings)
+    }
+
+    // The palette is built from the whole image unless given, lossless takes the image's own
+    // colors either way
+    pub(crate) fn encode_with<P: Color>(
+        &self,
+        mut img: ImageBuffer<P, Vec<u8>>,
+        palette: Option<Vec<P>>,
+        timings: &mut Timings,
+    ) -> Result<(Vec<u8>, EncodeStats), CodecError> {
+        let (width, height) = img.dimensions();
+        self.check(width, height)?;
+        if let Some(size) = self.tile_size {
+            if palette.is_some() {
+                return Err(CodecError::InvalidInput(
+                    "tiles get a palette each, a given palette can't be tiled".to_string(),
+                ));
+            }
+            return self.encode_tiled(img, size, timings);
+        }
+        if self.transparent.is_some() && P::FLAGS != 0 {
+            return Err(CodecError::InvalidInput(
+                "a transparent color is for RGB images, RGBA ones keep their own alpha and gray ones have none"
+                    .to_string(),
+            ));
+        }
+        // The transparent color, unless no pixel has it
+        let key = self
+            .transparent
+            .map(|color| *P::from_slice(&color.0))
+            .filter(|key| img.pixels().any(|pixel| pixel == key));
+        let pixels: Vec<P> = if palette.is_none() || self.error_map.is_some() || self.lossless {
+            img.pixels().cloned().collect()
+        } else {
+            Vec::new()
+        };
+        let mut palette = match palette {
+            _ if self.lossless => match exact_palette(&pixels, self.palette_size) {
+                Some(palette) => palette,
+                None => return self.encode_truecolor(img, &pixels, timings),
+            },
+            Some(palette) => palette,
+            None => {
+                let start = Instant::now();
+                let palette = match key {
+                    Some(key) => {
+                        let opaque = pixels
+                            .iter()
+                            .filter(|&&pixel| pixel != key)
+                            .copied()
+                            .collect::<Vec<_>>();
+                        if opaque.is_empty() {
+                            Vec::new()
+                        } else {
+                            gen_palette(&opaque, self.opaque_palette_size(), self.threads)
+                        }
+                    }
+                    None => gen_palette(pixels.as_slice(), self.palette_size, self.threads),
+                };
+                timings.record("palette", start);
+                palette
+            }
+        };
+        if self.filter {
+            sort_by_luma(&mut palette);
+        }
+        let keyed = key.map(|key| key_out(&mut img, &mut palette, key));
+        let start = Instant::now();
+        // The palette holds every color, dithering would only move pixels off theirs
+        let dither = if self.lossless {
+            Dither::None
+        } else {
+            self.dither
+        };
+        dither.apply(
+            &mut img,
+            &Palette::new(palette[keyed.is_some() as usize..].to_vec()),
+        );
+        if let (Some(key), Some(keyed)) = (key, &keyed) {
+            for (pixel, _) in img.pixels_mut().zip(keyed).filter(|&(_, &keyed)| keyed) {
+                *pixel = key;
+            }
+        }
+        timings.record("dither", start);
+        let mean_error = self.quantization_error(&pixels, &img)?;
+
+        let data = img.pixels().cloned().collect::<Vec<P>>();
+        let pixels_per_thread = data.len().div_ceil(self.threads.max(1));
+        let index_bits = self.index_bits(width, height, palette.len());
+        let segments_amount = if self.key.is_some() {
+            segments(format::data_len(
+                width,
+                height,
+                index_bits,
+                self.layout_flags(),
+            ))
+            .len()
+        } else {
+            0
+        };
+        let tracker = Tracker::new(&self.progress, data.len() + segments_amount);
+        let start = Instant::now();
+        let full_palette = Palette::new(palette.clone());
+        let jobs = data
+            .chunks(pixels_per_thread.max(1))
+            .map(|chunk| || process_encode(chunk, &full_palette, &tracker))
+            .collect();
+        let mut indices = run_jobs("map", "processing", jobs, self.threads, timings).concat();
+        // Row filters predict from the luma order instead
+        if !self.filter {
+            order_by_frequency(&mut palette, &mut indices, key.is_some() as usize);
+        }
+        timings.record("map", start);
+        let mut used = vec![false; palette.len()];
+        for &index in &indices {
+            used[index as usize] = true;
+        }
+        if self.scan != Scan::Raster {
+            let start = Instant::now();
+            indices = scan::serialize(&indices, &self.scan.order(width, height));
+            timings.record("scan", start);
+        }
+        let mut result = if self.interlace {
+            let start = Instant::now();
+            let serialized = scan::serialize(&indices, &interlace::order(width, height));
+            let result = interlace::pack(&serialized, width, height, index_bits, self.filter);
+            timings.record("interlace", start);
+            result
+        } else {
+            format::pack_indices(&indices, width, index_bits)
+        };
+        drop(indices);
+        if self.seek {
+            let start = Instant::now();
+            result = seek::write(&result, width, index_bits, self.filter, self.rle);
+            timings.record("seek", start);
+        }
+        if self.filter && !self.interlace && !self.seek {
+            let start = Instant::now();
+            let row_len = format::row_len(width, index_bits);
+            let mut prev = vec![0u8; row_len];
+            result = filter::filter(&result, row_len, filter::bpp(index_bits), &mut prev);
+            timings.record("filter", start);
+        }
+        if self.rle {
+            let start = Instant::now();
+            if !self.seek {
+                result = rle::encode(&result, filter::bpp(index_bits));
+            }
+            // FF1 takes 3 bytes at least, the last segment is padded to them
+            if self.key.is_some() {
+                let short = result.len() % SEGMENT_LEN;
+                if (1..=rle::MAX_PADDING).contains(&short) {
+                    result.resize(result.len() + 3 - short, 0);
+                }
+            }
+            timings.record("rle", start);
+        }
+        if let Some(key) = self.segment_key(width, height, &palette) {
+            process_segments(&mut result, key, false, &tracker, self.threads, timings)?;
+        }
+        let output_bytes = self.header(
+            width,
+            height,
+            &palette,
+            key.map(|_| 0),
+            result.len(),
+            Some(&result),
+        )?;
+        let stats = EncodeStats {
+            width,
+            height,
+            palette_colors: palette.len(),
+            used_colors: used.iter().filter(|&&used| used).count(),
+            mean_error,
+            ..EncodeStats::default()
+        };
+        self.finish(output_bytes, result, stats, timings)
+    }
+
+    // Pixels as their channel bytes in filtered rows, for lossless images of more colors than
+    // the palette size; compressed whenever the build has zstd
+    fn encode_truecolor<P: Color>(
+        &self,
+        img: ImageBuffer<P, Vec<u8>>,
+        pixels: &[P],
+        timings: &mut Timings,
+    ) -> Result<(Vec<u8>, EncodeStats), CodecError> {
+        let encoder = Self {
+            compress: cfg!(feature = "zstd"),
+            filter: true,
+            ..self.clone()
+        };
+        let (width, height) = img.dimensions();
+        let mean_error = self.quantization_error(pixels, &img)?;
+        let index_bits = P::CHANNEL_COUNT as u32 * 8;
+        let start = Instant::now();
+        let row_len = format::row_len(width, index_bits);
+        let mut prev = vec![0u8; row_len];
+        let mut result = filter::filter(img.as_raw(), row_len, filter::bpp(index_bits), &mut prev);
+        drop(img);
+        timings.record("filter", start);
+        if let Some(key) = self.segment_key::<P>(width, height, &[]) {
+            let tracker = Tracker::new(&self.progress, segments(result.len()).len());
+            process_segments(&mut result, key, false, &tracker, self.threads, timings)?;
+        }
+        let output_bytes =
+            encoder.header::<P>(width, height, &[], None, result.len(), Some(&result))?;
+        let stats = EncodeStats {
+            width,
+            height,
+            mean_error,
+            truecolor: true,
+            ..EncodeStats::default()
+        };
+        encoder.finish(output_bytes, result, stats, timings)
+    }
+
+    // Each tile gets a palette of its own pixels and is dithered on its own, tiles are spread
+    // over the threads
+    fn encode_tiled<P: Color>(
+        &self,
+        img: ImageBuffer<P, Vec<u8>>,
+        size: u32,
+        timings: &mut Timings,
+    ) -> Result<(Vec<u8>, EncodeStats), CodecError> {
+        let (width, height) = img.dimensions();
+        let (across, down) = tiles::grid(width, height, size);
+        let count = across * down;
+        let tracker = Tracker::new(&self.progress, count);
+        let start = Instant::now();
+        let tiles_per_thread = count.div_ceil(self.threads.max(1)).max(1);
+        let (img_ref, tracker_ref) = (&img, &tracker);
+        let jobs = (0..count)
+            .step_by(tiles_per_thread)
+            .map(|first| {
+                move || {
+                    (first..(first + tiles_per_thread).min(count))
+                        .map(|i| {
+                            let tile = self.encode_tile(img_ref, i, size);
+                            tracker_ref.step();
+                            tile
+                        })
+                        .collect::<Vec<_>>()
+                }
+            })
+            .collect();
+        let encoded = run_jobs("tiles", "tiling", jobs, self.threads, timings).concat();
+        timings.record("tiles", start);
+        let mut quantized = img.clone();
+        let mut records = Vec::with_capacity(count);
+        for (i, (palette, indices)) in encoded.iter().enumerate() {
+            let (x, y, tile_width, _) = tiles::rect(i, width, height, size);
+            for (j, &index) in indices.iter().enumerate() {
+                let (dx, dy) = (j as u32 % tile_width, j as u32 / tile_width);
+                quantized.put_pixel(x + dx, y + dy, palette[index as usize]);
+            }
+            let entries = palette
+                .iter()
+                .flat_map(|entry| entry.channels().to_vec())
+                .collect::<Vec<_>>();
+            records.push(tiles::write(&entries, palette.len(), indices, tile_width));
+        }
+        let pixels = img.pixels().cloned().collect::<Vec<P>>();
+        let mean_error = self.quantization_error(&pixels, &quantized)?;
+        let mut result = tiles::join(&records);
+        drop(records);
+        let largest = encoded
+            .iter()
+            .map(|(palette, _)| palette)
+            .max_by_key(|palette| palette.len())
+            .unwrap();
+        if let Some(key) = self.segment_key(width, height, largest) {
+            let tracker = Tracker::new(&self.progress, segments(result.len()).len());
+            process_segments(&mut result, key, false, &tracker, self.threads, timings)?;
+        }
+        let output_bytes =
+            self.header(width, height, largest, None, result.len(), Some(&result))?;
+        let stats = EncodeStats {
+            width,
+            height,
+            palette_colors: encoded.iter().map(|(palette, _)| palette.len()).sum(),
+            used_colors: encoded.iter().map(|(palette, _)| palette.len()).sum(),
+            mean_error,
+            ..EncodeStats::default()
+        };
+        self.finish(output_bytes, result, stats, timings)
+    }
+
+    // Palette and indices of tile i, dithered to a palette of its own pixels ordered by use and
+    // without the colors no pixel got
+    fn encode_tile<P: Color>(
+        &self,
+        img: &ImageBuffer<P, Vec<u8>>,
+        i: usize,
+        size: u32,
+    ) -> (Vec<P>, Vec<u16>) {
+        let (width, height) = img.dimensions();
+        let (x, y, tile_width, tile_height) = tiles::rect(i, width, height, size);
+        let mut tile = imageops::crop_imm(img, x, y, tile_width, tile_height).to_image();
+        let pixels = tile.pixels().cloned().collect::<Vec<P>>();
+        let palette = Palette::new(gen_palette(&pixels, self.palette_size, 1));
+        self.dither.apply(&mut tile, &palette);
+        let mut indices = tile
+            .pixels()
+            .map(|pixel| palette.index_of(pixel) as u16)
+            .collect::<Vec<_>>();
+        let mut colors = palette.colors;
+        order_by_frequency(&mut colors, &mut indices, 0);
+        // Unused colors come last, every tile would store them
+        let used = indices
+            .iter()
+            .map(|&index| index as usize + 1)
+            .max()
+            .unwrap_or(1);
+        colors.truncate(used);
+        (colors, indices)
+    }
+
+    // Mean error of the quantized image against the source pixels, also written as an error
+    // map when asked for; None when the source pixels weren't kept
+    fn quantization_error<P: Color>(
+        &self,
+        pixels: &[P],
+        quantized: &ImageBuffer<P, Vec<u8>>,
+    ) -> Result<Option<f64>, CodecError> {
+        if let Some(write) = &self.error_map {
+            let (error_map, mean, max) = compare::error_map(pixels, quantized);
+            log::info(format_args!(
+                "Quantization error: mean {:.2}, max {:.2}",
+                mean, max
+            ));
+            write(error_map)?;
+            return Ok(Some(mean));
+        }
+        Ok((!pixels.is_empty()).then(|| compare::mean_error(pixels, quantized)))
+    }
+
+    // The file of a header and the stored pixels data, checksummed and compressed when it pays
+    // off
+    fn finish(
+        &self,
+        mut output_bytes: Vec<u8>,
+        data: Vec<u8>,
+        mut stats: EncodeStats,
+        timings: &mut Timings,
+    ) -> Result<(Vec<u8>, EncodeStats), CodecError> {
+        output_bytes.extend_from_slice(&data);
+        drop(data);
+        format::append_checksum(&mut output_bytes);
+        stats.uncompressed_len = output_bytes.len();
+        if self.compress {
+            let start = Instant::now();
+            let compressed = compression::compress(&output_bytes, self.compression_level)?;
+            timings.record("compress", start);
+            if compressed.len() < output_bytes.len() {
+                output_bytes = compressed;
+            } else {
+                output_bytes[format::PREFIX_LEN - 1] &= !format::FLAG_COMPRESSED;
+                output_bytes.truncate(output_bytes.len() - format::CHECKSUM_LEN);
+                format::append_checksum(&mut output_bytes);
+            }
+        }
+        if let Some(percent) = self.fec {
+            let start = Instant::now();
+            output_bytes = fec::wrap(&output_bytes, percent)?;
+            timings.record("fec", start);
+        }
+        stats.len = output_bytes.len();
+        Ok((output_bytes, stats))
+    }
+}
+
+// Puts key first in the palette, changing opaque colors equal to it by one, and gives its
+// pixels the first opaque color, which they're dithered to without spreading any error.
+// Returns which pixels had key
+fn key_out<P: Color>(img: &mut ImageBuffer<P, Vec<u8>>, palette: &mut Vec<P>, key: P) -> Vec<bool> {
+    if palette.is_empty() {
+        palette.push(key);
+    }
+    for entry in palette.iter_mut().filter(|entry| **entry == key) {
+        entry[2] ^= 1;
+    }
+    palette.insert(0, key);
+    let keyed = img.pixels().map(|&pixel| pixel == key).collect::<Vec<_>>();
+    for (pixel, _) in img.pixels_mut().zip(&keyed).filter(|&(_, &keyed)| keyed) {
+        *pixel = palette[1];
+    }
+    keyed
+}
+
+// Returns palette (opaque RGBA without the alpha flag but for the transparent index) and pixels data offset of the
+// (decompressed) file, prefix included, replaces key with the unwrapped data key and checks it
+// against the stored key check. Encryption leaves the header in the clear: prefix, flags,
+// layout, dimensions, palette size, metadata, key block and key check, so info, verify and
+// inspect read an encrypted file without its key. The pixels data is encrypted, and the palette
+// entries too when the metadata holds the encrypted-palette entry (see Encoder::encrypt_palette)
+pub fn read_header(
+    bytes: &[u8],
+    key_opt: &mut Option<String>,
+    key_block: Option<KeyBlock>,
+) -> Result<(Vec<Rgba<u8>>, usize), CodecError> {
+    if key_opt.is_some() || key_block.is_some() {
+        require_crypto()?;
+    }
+    let header = bare::parse_header(bytes)?;
+    let mut palette = header.palette.into_iter().map(Rgba).collect::<Vec<_>>();
+    let mut data_offset = header.data_offset;
+    if let Some(key_block) = key_block {
+        let (data_key, block_len) = open_key_block(&bytes[data_offset..], &key_block)
+            .ok_or_else(|| {
+                CodecError::BadKey(match key_block {
+                    KeyBlock::Passphrase(_) => {
+                        "file is cut short in its passphrase key block, or it asks for Argon2id costs beyond the limits".to_string()
+                    }
+                    _ => "file is not encrypted to this identity".to_string(),
+                })
+            })?;
+        *key_opt = Some(bytes_to_base64url(&data_key));
+        data_offset += block_len;
+    }
+    if let Some(key) = key_opt {
+        let mut byte_key =
+            base64url_to_bytes(key).ok_or(CodecError::BadKey("invalid key".to_string()))?;
+        // Nothing tells a wrong key of these but the decoded image
+        if unchecked_v1(bytes, data_offset) {
+            return Ok((palette, data_offset));
+        }
+        let entries = metadata::parse(&bytes[header.metadata.clone()])?;
+        // Any key with a slot opens the data key, which the rest is checked and decrypted with
+        if let Some((_, table)) = entries.iter().find(|(entry, _)| entry == KEY_SLOTS_KEY) {
+            byte_key = open_slot_table(table, &byte_key).ok_or(CodecError::BadKey(
+                "wrong key, it has none of the file's key slots".to_string(),
+            ))?;
+            *key = bytes_to_base64url(&byte_key);
+        }
+        // A key of another size fails the key check too, this tells why
+        if let Some((_, cipher)) = entries.iter().find(|(entry, _)| entry == CIPHER_KEY) {
+            let Some((_, key_len)) = parse_cipher(cipher) else {
+                return Err(CodecError::BadHeader(format!(
+                    "encrypted with the unknown cipher {}, it needs a newer build",
+                    String::from_utf8_lossy(cipher)
+                )));
+            };
+            if key_len != byte_key.len() {
+                return Err(CodecError::BadKey(format!(
+                    "file is encrypted with {}, the key is {} bytes",
+                    String::from_utf8_lossy(cipher),
+                    byte_key.len()
+                )));
+            }
+        }
+        if bytes.get(data_offset..data_offset + KEY_CHECK_LEN) != Some(&key_check(&byte_key)[..]) {
+            return Err(CodecError::BadKey(
+                "wrong key or not encrypted file".to_string(),
+            ));
+        }
+        data_offset += KEY_CHECK_LEN;
+        if entries
+            .iter()
+            .any(|(key, _)| key == metadata::ENCRYPTED_PALETTE_KEY)
+        {
+            palette = decrypt_palette(&bytes[..header.metadata.end], key, fpe(bytes)?)?;
+        }
+    }
+    Ok((palette, data_offset))
+}
+
+// Format version 1 files of the first builds have no key check, their pixels data follows the
+// palette right away, see decrypt_v1
+pub fn unchecked_v1(bytes: &[u8], data_offset: usize) -> bool {
+    format::version(bytes) == 1
+        && bare::dimensions(bytes).is_ok_and(|(width, height, _)| {
+            bytes.len().saturating_sub(data_offset) == format::data_len(width, height, 8, 0)
+        })
+}
+
+// The first builds encrypted pixels data in one FF1 block per thread, as many as the machine
+// had CPUs, with an empty tweak; such files decode with the threads they were encoded with
+fn decrypt_v1(data: &mut [u8], key: &str, threads: usize) -> Result<(), CodecError> {
+    let chunk_len = data.len().div_ceil(threads.max(1)).max(1);
+    for chunk in data.chunks_mut(chunk_len) {
+        decrypt(chunk, key, &[], Fpe::Ff1).ok_or(CodecError::BadKey("invalid key".to_string()))?;
+    }
+    Ok(())
+}
+
+// An encrypted v1 file of the first builds as later v1 files are: the key check after the
+// palette and pixels data encrypted in segments. Other files come back as they are
+pub(crate) fn checked_v1<'a>(
+    bytes: &'a [u8],
+    key: &str,
+    threads: usize,
+) -> Result<Cow<'a, [u8]>, CodecError> {
+    let data_offset = bare::parse_header(bytes)?.data_offset;
+    if !unchecked_v1(bytes, data_offset) {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    let byte_key = base64url_to_bytes(key).ok_or(CodecError::BadKey("invalid key".to_string()))?;
+    let mut data = bytes[data_offset..].to_vec();
+    decrypt_v1(&mut data, key, threads)?;
+    let segment_key = SegmentKey {
+        key,
+        fpe: Fpe::Ff1,
+        header_tweak: None,
+    };
+    for (number, segment) in split_segments(&mut data) {
+        segment_key.encrypt(segment, number)?;
+    }
+    let mut checked = bytes[..data_offset].to_vec();
+    checked.extend_from_slice(&key_check(&byte_key));
+    checked.extend_from_slice(&data);
+    Ok(Cow::Owned(checked))
+}
+
+// Checks the authentication tag of an encrypted file's content (the checksum footer left out)
+// with its data key, see keys::authenticator. Only version 1 and 2 files, which have no flags
+// or metadata, pass without one
+pub fn check_auth(content: &[u8], key: &str) -> Result<(), CodecError> {
+    let Some(mac) = auth_mac(content, key)? else {
+        return Ok(());
+    };
+    let (mut mac, tag) = mac;
+    mac.update(&content[bare::metadata_range(content)?.end..]);
+    mac.verify_truncated_left(&tag)
+        .map_err(|_| CodecError::BadKey("wrong key or tampered data".to_string()))
+}
+
+// Authenticator of a file's header (see keys::authenticator) and the tag it holds. None for
+// files flagged plain without cipher or header-tweak entry, which have nothing to authenticate;
+// encrypted files without a tag had it stripped and are rejected
+pub(crate) fn auth_mac(
+    header: &[u8],
+    key: &str,
+) -> Result<Option<(Authenticator, Vec<u8>)>, CodecError> {
+    let range = bare::metadata_range(header)?;
+    let entries = if range.is_empty() {
+        metadata::Entries::new()
+    } else {
+        metadata::parse(&header[range.clone()])?
+    };
+    let has = |name: &str| entries.iter().any(|(entry, _)| entry == name);
+    let encrypted = format::flags(header).is_some_and(|flags| flags & format::FLAG_ENCRYPTED != 0);
+    let Some((_, tag)) = entries.iter().find(|(entry, _)| entry == AUTH_KEY) else {
+        if encrypted || has(CIPHER_KEY) || has(HEADER_TWEAK_KEY) {
+            return Err(CodecError::BadKey(
+                "wrong key or tampered data, the authentication tag is missing".to_string(),
+            ));
+        }
+        return Ok(None);
+    };
+    if tag.len() != AUTH_TAG_LEN {
+        return Err(CodecError::BadHeader(
+            "corrupt in its authentication tag".to_string(),
+        ));
+    }
+    let byte_key = base64url_to_bytes(key).ok_or(CodecError::BadKey("invalid key".to_string()))?;
+    let mac = authenticator(&byte_key, &header[..range.start - 4], &entries);
+    Ok(Some((mac, tag.clone())))
+}
+
+// Encryption mode of an encrypted file's header by its cipher entry, FF1 for files of builds
+// before it
+pub(crate) fn fpe(header: &[u8]) -> Result<Fpe, CodecError> {
+    let range = bare::metadata_range(header)?;
+    let cipher = metadata::parse(&header[range])?
+        .into_iter()
+        .find(|(entry, _)| entry == CIPHER_KEY);
+    Ok(cipher
+        .and_then(|(_, cipher)| parse_cipher(&cipher))
+        .map_or(Fpe::Ff1, |(fpe, _)| fpe))
+}
+
+// Header whose palette entries are encrypted with them decrypted, see Encoder::encrypt_palette
+pub(crate) fn plain_header(header: &[u8], key: &str, fpe: Fpe) -> Result<Vec<u8>, CodecError> {
+    let (palette_size, start) = bare::palette_size(header)?;
+    let len = format::palette_entries(header, palette_size) * format::channels(header);
+    let mut plain = header.to_vec();
+    decrypt(&mut plain[start..start + len], key, &PALETTE_TWEAK, fpe)
+        .ok_or(CodecError::BadKey("invalid key".to_string()))?;
+    Ok(plain)
+}
+
+fn decrypt_palette(header: &[u8], key: &str, fpe: Fpe) -> Result<Vec<Rgba<u8>>, CodecError> {
+    Ok(bare::parse_header(&plain_header(header, key, fpe)?)?
+        .palette
+        .into_iter()
+        .map(Rgba)
+        .collect())
+}
+
+// Key the pixels data of an encrypted file's header is decrypted with, by its cipher entry, and
+// bound to its dimensions and palette with the header-tweak entry as Encoder::segment_key does
+pub(crate) fn segment_key<'a>(header: &[u8], key: &'a str) -> Result<SegmentKey<'a>, CodecError> {
+    let fpe = fpe(header)?;
+    let entries = metadata::parse(&header[bare::metadata_range(header)?])?;
+    let has = |name: &str| entries.iter().any(|(entry, _)| entry == name);
+    if !has(HEADER_TWEAK_KEY) {
+        return Ok(SegmentKey {
+            key,
+            fpe,
+            header_tweak: None,
+        });
+    }
+    let plain = if has(metadata::ENCRYPTED_PALETTE_KEY) {
+        Cow::Owned(plain_header(header, key, fpe)?)
+    } else {
+        Cow::Borrowed(header)
+    };
+    let (width, height, _) = bare::dimensions(&plain)?;
+    let (palette_size, start) = bare::palette_size(&plain)?;
+    let len = format::palette_entries(&plain, palette_size) * format::channels(&plain);
+    let palette = plain.get(start..start + len).ok_or(bare::Error::TooShort)?;
+    Ok(SegmentKey {
+        key,
+        fpe,
+        header_tweak: Some(header_tweak(width, height, palette_size, palette)),
+    })
+}
+
+// Dimensions, palette and plain palette indices of a file
+pub(crate) struct Indexed {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) palette: Vec<Rgba<u8>>,
+    // Channels of the decoded image: 4 with alpha or a transparent index, 1 when gray, else 3
+    pub(crate) channels: usize,
+    pub(crate) indices: Vec<u16>,
+    // Packed indices of raster rows and their index bits, left packed for Decoder::rows and
+    // Decoder::pixels instead of indices
+    pub(crate) packed: Option<(Vec<u8>, u32)>,
+    // Where each tile's palette is in palette, which holds them one after another, of tiled
+    // files
+    pub(crate) tiles: Option<tiles::Palettes>,
+    // Channel bytes of every pixel of truecolor files, which have no palette or indices
+    pub(crate) truecolor: Option<Vec<u8>>,
+    // Rows failing their chunk checksums, shown as the placeholder color
+    pub(crate) damaged: Option<chunks::Damaged>,
+}
+
+// Channels of the decoded image of a file, see Indexed
+pub(crate) fn decoded_channels(bytes: &[u8]) -> usize {
+    match format::flags(bytes).unwrap_or(0) {
+        flags if flags & (format::FLAG_ALPHA | format::FLAG_TRANSPARENT) != 0 => 4,
+        flags if flags & format::FLAG_GRAY != 0 => 1,
+        _ => 3,
+    }
+}
+
+impl Indexed {
+    fn len(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    // Color of a pixel by its index, in its tile's palette for tiled files
+    fn color(&self, pixel: usize) -> Rgba<u8> {
+        if let Some(damaged) = &self.damaged
+            && damaged.rows[pixel / self.width as usize]
+        {
+            return damaged.color;
+        }
+        if let Some(pixels) = &self.truecolor {
+            let channels = self.channels;
+            return Rgba(bare::entry_rgba(
+                &pixels[pixel * channels..(pixel + 1) * channels],
+            ));
+        }
+        let index = match &self.packed {
+            Some((data, index_bits)) => format::packed_index(data, pixel, self.width, *index_bits),
+            None => self.indices[pixel],
+        };
+        match &self.tiles {
+            Some(tiles) => color(&self.palette[tiles.range(pixel, self.width)], index),
+            None => color(&self.palette, index),
+        }
+    }
+}
+
+// Decode settings, compression is detected by the zstd magic unless set
+#[derive(Clone)]
+pub struct Decoder {
+    // Base64url data key
+    pub(crate) key: Option<String>,
+    pub(crate) key_block: Option<KeyBlock>,
+    pub(crate) compress: Option<bool>,
+    pub(crate) strict: bool,
+    pub(crate) legacy: bool,
+    pub(crate) placeholder: Option<Rgba<u8>>,
+    pub(crate) progress: Arc<dyn Progress>,
+    pub(crate) threads: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self {
+            key: None,
+            key_block: None,
+            compress: None,
+            strict: false,
+            legacy: false,
+            placeholder: None,
+            progress: Arc::new(Silent),
+            threads: num_cpus::get(),
+        }
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key(mut self, key: &[u8]) -> Self {
+        self.key = Some(bytes_to_base64url(key));
+        self
+    }
+
+    // Identity or master key the data key is recovered with from the file's key block
+    pub fn key_block(mut self, key_block: KeyBlock) -> Self {
+        self.key_block = Some(key_block);
+        self
+    }
+
+    // Expected compression, a mismatching file is rejected with a hint
+    pub fn compression(mut self, compress: bool) -> Self {
+        self.compress = Some(compress);
+        self
+    }
+
+    // Fail on the first index beyond the palette instead of using the first palette color
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    // Also read format version 1 files, which start without the magic
+    pub fn legacy(mut self, legacy: bool) -> Self {
+        self.legacy = legacy;
+        self
+    }
+
+    // Color of the rows a file with chunk checksums (see Encoder::chunk_checksums) has damaged,
+    // which then decodes with the others instead of failing; not for encrypted files, which fail
+    // their authentication tag when damaged
+    pub fn placeholder(mut self, color: Rgba<u8>) -> Self {
+        self.placeholder = Some(color);
+        self
+    }
+
+    // Silent by default, the CLI passes utils::ProgressBar
+    pub fn progress(mut self, progress: impl Progress + 'static) -> Self {
+        self.progress = Arc::new(progress);
+        self
+    }
+
+    // One thread per CPU by default, 1 runs everything on the calling thread
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    // Alpha of RGBA files is dropped and the transparent index gets its stored color, see
+    // decode_rgba
+    pub fn decode(&self, bytes: &[u8]) -> Result<RgbImage, CodecError> {
+        self.decode_timed(bytes.to_vec(), &mut Timings::new())
+    }
+
+    // RGBA of any file, RGB ones come out opaque
+    pub fn decode_rgba(&self, bytes: &[u8]) -> Result<RgbaImage, CodecError> {
+        let indexed = self.indexed(bytes.to_vec(), &mut Timings::new())?;
+        self.image(&indexed, &mut Timings::new())
+    }
+
+    // Raw RGB bytes (rows without padding) with width and height, for texture uploads and
+    // further processing without an image container
+    pub fn decode_to_vec(&self, bytes: &[u8]) -> Result<(Vec<u8>, u32, u32), CodecError> {
+        let img = self.decode(bytes)?;
+        let (width, height) = img.dimensions();
+        Ok((img.into_raw(), width, height))
+    }
+
+    // Writes RGB rows without padding into the start of out, which holds at least
+    // width * height * 3 bytes, and returns width and height
+    pub fn decode_into(&self, bytes: &[u8], out: &mut [u8]) -> Result<(u32, u32), CodecError> {
+        let indexed = self.indexed(bytes.to_vec(), &mut Timings::new())?;
+        let len = indexed.len() * 3;
+        if out.len() < len {
+            return Err(CodecError::InvalidInput(format!(
+                "output buffer is {} bytes, {}x{} RGB needs {}",
+                out.len(),
+                indexed.width,
+                indexed.height,
+                len
+            )));
+        }
+        self.map(&indexed, 3, &mut out[..len], &mut Timings::new());
+        Ok((indexed.width, indexed.height))
+    }
+
+    pub fn decode_timed(
+        &self,
+        bytes: Vec<u8>,
+        timings: &mut Timings,
+    ) -> Result<RgbImage, CodecError> {
+        let indexed = self.indexed(bytes, timings)?;
+        self.image(&indexed, timings)
+    }
+
+    // RGBA images for files with alpha or a transparent index, grayscale ones for gray files,
+    // RGB ones otherwise
+    pub fn decode_dynamic_timed(
+        &self,
+        bytes: Vec<u8>,
+        timings: &mut Timings,
+    ) -> Result<DynamicImage, CodecError> {
+        let indexed = self.indexed(bytes, timings)?;
+        self.dynamic(&indexed, timings)
+    }
+
+    // Coarse image of the start of an interlaced file, e.g. while it arrives: the passes read so
+    // far, each pixel standing for the ones of the passes still to come, and how many of the 7
+    // passes it holds in full. The file may end anywhere after its key check, encrypted ones
+    // show whole 64KiB segments only, and the checksum isn't checked; files that aren't
+    // interlaced are decoded as usual, so they have to be whole
+    pub fn preview(&self, bytes: &[u8]) -> Result<(DynamicImage, usize), CodecError> {
+        let mut timings = Timings::new();
+        let bytes = &*fec::unwrap(bytes)?;
+        let mut content = if bytes.starts_with(&ZSTD_MAGIC) {
+            compression::decompress_partial(bytes)?
+        } else {
+            bytes.to_vec()
+        };
+        // Before the first whole zstd block
+        if content.len() < format::PREFIX_LEN && bytes.starts_with(&ZSTD_MAGIC) {
+            return Err(bare::Error::TooShort.into());
+        }
+        format::checked_prefix_len(&content, self.legacy)?;
+        let layout = format::layout_flags(&content);
+        if layout & format::LAYOUT_INTERLACED == 0 {
+            let indexed = self.indexed(bytes.to_vec(), &mut timings)?;
+            return Ok((self.dynamic(&indexed, &mut timings)?, interlace::PASSES));
+        }
+        let encrypted =
+            format::flags(&content).is_some_and(|flags| flags & format::FLAG_ENCRYPTED != 0);
+        if encrypted && self.key.is_none() && self.key_block.is_none() {
+            return Err(CodecError::BadKey(
+                "file is encrypted, a preview needs its key".to_string(),
+            ));
+        }
+        let mut key_opt = self.key.clone();
+        let (palette, data_offset) = read_header(&content, &mut key_opt, self.key_block.clone())?;
+        let (width, height, _) = bare::dimensions(&content)?;
+        let index_bits = format::index_bits(&content);
+        let unpacked_len = format::data_len(width, height, index_bits, layout);
+        let data_len = format::stored_len(&content).unwrap_or(unpacked_len);
+        let mut data = content.split_off(data_offset.min(content.len()));
+        data.truncate(data_len);
+        if let Some(key) = &key_opt {
+            // A segment is only decrypted whole
+            if data.len() < data_len {
+                let whole = segments(data_len)
+                    .take_while(|segment| segment.end <= data.len())
+                    .last()
+                    .map_or(0, |segment| segment.end);
+                data.truncate(whole);
+            }
+            let tracker = Tracker::new(&self.progress, segments(data.len()).len());
+            process_segments(
+                &mut data,
+                segment_key(&content, key)?,
+                true,
+                &tracker,
+                self.threads,
+                &mut timings,
+            )?;
+        }
+        if layout & format::LAYOUT_RLE != 0 {
+            bare::check_data_len(width, height, index_bits, layout, data_len)?;
+            let bpp = filter::bpp(index_bits);
+            let mut decoder = rle::Decoder::new(unpacked_len, bpp);
+            let mut unpacked =
+                Vec::with_capacity(unpacked_len.min(rle::max_decoded_len(data.len(), bpp)));
+            decoder.feed(&data, &mut unpacked);
+            data = unpacked;
+        }
+        if layout & format::LAYOUT_FILTERED != 0 {
+            data = interlace::unfilter(&data, width, height, index_bits)?;
+        }
+        // The image is only allocated once its first pass is read, which is a 64th of it
+        if data.len() < interlace::pass_len(0, width, height, index_bits, 0) {
+            return Err(CodecError::BadData(
+                "not enough data for a preview, the first pass isn't read yet".to_string(),
+            ));
+        }
+        let (indices, passes) = interlace::deinterlace(&data, width, height, index_bits);
+        let indexed = Indexed {
+            width,
+            height,
+            palette,
+            channels: decoded_channels(&content),
+            indices,
+            packed: None,
+            tiles: None,
+            truecolor: None,
+            damaged: None,
+        };
+        Ok((self.dynamic(&indexed, &mut timings)?, passes))
+    }
+
+    // RGBA, grayscale or RGB image, as the file's channels call for
+    pub(crate) fn dynamic(
+        &self,
+        indexed: &Indexed,
+        timings: &mut Timings,
+    ) -> Result<DynamicImage, CodecError> {
+        Ok(match indexed.channels {
+            4 => DynamicImage::ImageRgba8(self.image(indexed, timings)?),
+            1 => DynamicImage::ImageLuma8(self.image(indexed, timings)?),
+            _ => DynamicImage::ImageRgb8(self.image(indexed, timings)?),
+        })
+    }
+
+    // Pixels of P's channels, RGB drops alpha, RGBA of RGB files is opaque and luma is the red
+    // channel, which is the luma of gray palettes
+    fn image<P: Color>(
+        &self,
+        indexed: &Indexed,
+        timings: &mut Timings,
+    ) -> Result<ImageBuffer<P, Vec<u8>>, CodecError> {
+        let mut result = vec![0u8; indexed.len() * P::CHANNEL_COUNT as usize];
+        self.map(indexed, P::CHANNEL_COUNT as usize, &mut result, timings);
+        ImageBuffer::from_raw(indexed.width, indexed.height, result).ok_or(CodecError::BadData(
+            "not enough data, image is corrupted".to_string(),
+        ))
+    }
+
+    // Each thread writes its own part of out, which is 3 (RGB), 4 (RGBA) or 1 (luma) bytes per
+    // index
+    fn map(&self, indexed: &Indexed, channels: usize, out: &mut [u8], timings: &mut Timings) {
+        let pixels = indexed.len();
+        let tracker = Tracker::new(&self.progress, pixels);
+        let start = Instant::now();
+        let pixels_per_thread = pixels.div_ceil(self.threads.max(1)).max(1);
+        let tracker = &tracker;
+        let jobs = (0..pixels)
+            .step_by(pixels_per_thread)
+            .zip(out.chunks_mut(pixels_per_thread * channels))
+            .map(|(first, out)| {
+                let chunk = first..(first + pixels_per_thread).min(pixels);
+                move || process_decode(indexed, chunk, channels, tracker, out)
+            })
+            .collect();
+        run_jobs("map", "processing", jobs, self.threads, timings);
+        timings.record("map", start);
+    }
+
+    // Decoded rows, each mapped when reached; the iterator owns its data, so it outlives the
+    // decoder and the bytes. The file is decompressed, decrypted and unfiltered upfront (zstd,
+    // checksums and the auth tag take all of it), after which raster files stay packed at their
+    // index bits and each index is read when reached; interlaced, tiled and other scan order
+    // files, and any with strict set, are unpacked to a u16 per pixel first
+    pub fn rows(
+        &self,
+        bytes: &[u8],
+    ) -> Result<impl ExactSizeIterator<Item = Vec<Rgb<u8>>> + use<>, CodecError> {
+        let indexed = self.decode_indexed(bytes.to_vec(), true, &mut Timings::new())?;
+        let width = indexed.width as usize;
+        Ok((0..indexed.height as usize).map(move |y| {
+            (y * width..(y + 1) * width)
+                .map(|pixel| indexed.color(pixel).to_rgb())
+                .collect()
+        }))
+    }
+
+    // Decoded pixels in row-major order, mapped from palette indices one by one, held in memory
+    // as for rows
+    pub fn pixels(
+        &self,
+        bytes: &[u8],
+    ) -> Result<impl ExactSizeIterator<Item = Rgb<u8>> + use<>, CodecError> {
+        let indexed = self.decode_indexed(bytes.to_vec(), true, &mut Timings::new())?;
+        Ok((0..indexed.len()).map(move |pixel| indexed.color(pixel).to_rgb()))
+    }
+
+    // Decompresses, checks the file against the settings and decrypts pixels data
+    fn indexed(&self, bytes: Vec<u8>, timings: &mut Timings) -> Result<Indexed, CodecError> {
+        self.decode_indexed(bytes, false, timings)
+    }
+
+    // As indexed, packed leaves the indices of raster files packed, see Indexed::packed
+    fn decode_indexed(
+        &self,
+        mut bytes: Vec<u8>,
+        packed: bool,
+        timings: &mut Timings,
+    ) -> Result<Indexed, CodecError> {
+        // Files protected with --fec come out repaired
+        if fec::is_wrapped(&bytes) {
+            let start = Instant::now();
+            bytes = fec::unwrap(&bytes)?.into_owned();
+            timings.record("fec", start);
+        }
+        let compressed = bytes.starts_with(&ZSTD_MAGIC);
+        if compressed && !cfg!(feature = "zstd") {
+            return Err(CodecError::FeatureDisabled("zstd"));
+        }
+        let compress = self.compress.unwrap_or(compressed);
+        if !compress && compressed {
+            let detection = detect::detect(&bytes);
+            return Err(CodecError::WrongOptions {
+                looks: detection.describe(),
+                hint: detection.suggestion(),
+            });
+        }
+        // Encoder keeps raw bytes when compression doesn't pay off
+        if compressed {
+            let start = Instant::now();
+            bytes = compression::decompress(&bytes)?;
+            timings.record("decompress", start);
+        }
+        // Rejects files without the magic unless legacy is set
+        format::checked_prefix_len(&bytes, self.legacy)?;
+        // Check the file is what the settings say, by its flags or, before v3, its bytes
+        let detection = detect::Detection {
+            compressed: compress,
+            state: detect::detect_state(&bytes),
+        };
+        let keyed = self.key.is_some() || self.key_block.is_some();
+        let flagged = format::flags(&bytes).is_some();
+        let mismatch = match detection.state {
+            detect::State::Plain => keyed && flagged,
+            detect::State::Encrypted { .. } | detect::State::EncryptedLegacy => !keyed,
+            detect::State::Invalid(_) => true,
+        };
+        if mismatch {
+            return Err(match detection.state {
+                detect::State::Invalid(_) => CodecError::BadHeader(detection.describe()),
+                _ => CodecError::WrongOptions {
+                    looks: detection.describe(),
+                    hint: detection.suggestion(),
+                },
+            });
+        }
+        // The length checks above come first, so a truncated file is reported as such
+        let mismatch = match bare::without_checksum(&bytes) {
+            Ok(_) => None,
+            Err(err @ bare::Error::ChecksumMismatch { .. }) => Some(err),
+            Err(err) => return Err(err.into()),
+        };
+        bytes.truncate(format::content_len(&bytes));
+        // Files with chunk checksums tell where they are damaged
+        let damaged = match mismatch {
+            Some(err) => Some(chunks::damaged(&bytes)?.ok_or(err)?),
+            None => None,
+        };
+        let mut key_opt = self.key.clone();
+        let (palette, data_offset) = read_header(&bytes, &mut key_opt, self.key_block.clone())?;
+        // Checksums aren't keyed, so the tag is checked whatever they say: a damaged encrypted
+        // file fails it before its rows are looked at
+        if let Some(key) = &key_opt {
+            check_auth(&bytes, key)?;
+        }
+        let (width, height, _) = bare::dimensions(&bytes)?;
+        let index_bits = format::index_bits(&bytes);
+        let layout = format::layout_flags(&bytes);
+        let data_len = format::stored_len(&bytes)
+            .unwrap_or_else(|| format::data_len(width, height, index_bits, layout));
+        let unchecked = unchecked_v1(&bytes, data_offset);
+        let mut data = bytes.split_off(data_offset);
+        if let Some(key) = &key_opt
+            && unchecked
+        {
+            decrypt_v1(&mut data, key, self.threads)?;
+        } else if let Some(key) = &key_opt {
+            let tracker = Tracker::new(&self.progress, segments(data.len()).len());
+            let key = segment_key(&bytes, key)?;
+            process_segments(&mut data, key, true, &tracker, self.threads, timings)?;
+        }
+        if data.len() < data_len {
+            return Err(CodecError::BadData(
+                "not enough data, image is corrupted".to_string(),
+            ));
+        }
+        data.truncate(data_len);
+        let damaged = match damaged {
+            Some(ranges) => {
+                Some(self.damaged_rows(&ranges, &data, &bytes, data_offset, key_opt.is_some())?)
+            }
+            None => None,
+        };
+        let channels = decoded_channels(&bytes);
+        if let Some(size) = format::tile_size(&bytes) {
+            let start = Instant::now();
+            let tiled = self.read_tiles(
+                &data,
+                width,
+                height,
+                size,
+                format::channels(&bytes),
+                timings,
+            )?;
+            timings.record("tiles", start);
+            if self.strict
+                && let Some(pixel) = (0..tiled.indices.len()).find(|&pixel| {
+                    tiled.indices[pixel] as usize >= tiled.palettes.range(pixel, width).len()
+                })
+            {
+                return Err(CodecError::BadData(format!(
+                    "index {} of pixel {} (x {}, y {}) is beyond the palette of tile {} of {} colors",
+                    tiled.indices[pixel],
+                    pixel,
+                    pixel % width as usize,
+                    pixel / width as usize,
+                    tiled.palettes.tile(pixel, width),
+                    tiled.palettes.range(pixel, width).len()
+                )));
+            }
+            return Ok(Indexed {
+                width,
+                height,
+                palette: tiled.palette.into_iter().map(Rgba).collect(),
+                channels,
+                indices: tiled.indices,
+                packed: None,
+                tiles: Some(tiled.palettes),
+                truecolor: None,
+                damaged: None,
+            });
+        }
+        let data = match &damaged {
+            Some(damaged) => Cow::Owned(chunks::packed_data(
+                &data,
+                width,
+                height,
+                index_bits,
+                layout,
+                &damaged.rows,
+            )?),
+            None => bare::packed_data(&data, width, height, index_bits, layout)?,
+        };
+        if layout & format::LAYOUT_TRUECOLOR != 0 {
+            return Ok(Indexed {
+                width,
+                height,
+                palette,
+                channels,
+                indices: Vec::new(),
+                packed: None,
+                tiles: None,
+                truecolor: Some(data.into_owned()),
+                damaged,
+            });
+        }
+        let interlaced = layout & format::LAYOUT_INTERLACED != 0;
+        let scan = Scan::from_layout(layout);
+        if packed && !interlaced && scan == Scan::Raster && !self.strict {
+            return Ok(Indexed {
+                width,
+                height,
+                palette,
+                channels,
+                indices: Vec::new(),
+                packed: Some((data.into_owned(), index_bits)),
+                tiles: None,
+                truecolor: None,
+                damaged,
+            });
+        }
+        let mut indices = if interlaced {
+            interlace::deinterlace(&data, width, height, index_bits).0
+        } else {
+            format::unpack_indices(&data, width, index_bits)
+        };
+        drop(data);
+        // Raster offsets of the serialized indices, of files in another scan order
+        let order = (scan != Scan::Raster).then(|| scan.order(width, height));
+        if self.strict
+            && let Some(position) = indices
+                .iter()
+                .position(|&index| index as usize >= palette.len())
+        {
+            let pixel = order.as_ref().map_or(position, |order| order[position]);
+            let offset = if interlaced {
+                interlace::index_offset(pixel, width, height, index_bits, layout)
+            } else if layout & format::LAYOUT_SEEK != 0 {
+                seek::index_offset(pixel, width, height, index_bits, layout)
+            } else {
+                format::index_offset(position, width, index_bits, layout)
+            };
+            return Err(CodecError::BadData(format!(
+                "index {} of pixel {} (x {}, y {}) is beyond the palette of {} colors, at {}",
+                indices[position],
+                pixel,
+                pixel % width as usize,
+                pixel / width as usize,
+                palette.len(),
+                index_location(data_offset, offset, layout, compress)
+            )));
+        }
+        if let Some(order) = &order {
+            indices = scan::deserialize(&indices, order);
+        }
+        Ok(Indexed {
+            width,
+            height,
+            palette,
+            channels,
+            indices,
+            packed: None,
+            tiles: None,
+            truecolor: None,
+            damaged,
+        })
+    }
+
+    // Damaged rows of a file from the damaged ranges of what follows its metadata section, see
+    // chunks::damaged, and its decrypted pixels data; an error naming them without a placeholder
+    // color, or when the file's layout doesn't tell them
+    fn damaged_rows(
+        &self,
+        ranges: &[Range<usize>],
+        data: &[u8],
+        bytes: &[u8],
+        data_offset: usize,
+        encrypted: bool,
+    ) -> Result<chunks::Damaged, CodecError> {
+        // Damage to the key block or key check fails decryption before this
+        let shift = data_offset - bare::metadata_range(bytes)?.end;
+        let ranges = ranges
+            .iter()
+            .map(|range| range.start.saturating_sub(shift)..range.end.saturating_sub(shift))
+            .filter(|range| !range.is_empty())
+            .collect::<Vec<_>>();
+        let (width, height, _) = bare::dimensions(bytes)?;
+        let rows = chunks::damaged_rows(
+            &ranges,
+            data,
+            width,
+            height,
+            format::index_bits(bytes),
+            format::layout_flags(bytes),
+            encrypted,
+        )
+        .ok_or_else(|| {
+            CodecError::BadData(format!(
+                "damaged at bytes {} of its pixels data, its layout doesn't tell the rows they are in",
+                ranges
+                    .iter()
+                    .map(|range| format!("{}-{}", range.start, range.end - 1))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        })?;
+        match self.placeholder {
+            Some(color) => Ok(chunks::Damaged { rows, color }),
+            None if !rows.contains(&true) => Err(CodecError::BadData(
+                "damaged outside its pixels data, which checks out; decode it with a placeholder color"
+                    .to_string(),
+            )),
+            None => Err(CodecError::BadData(format!(
+                "rows {} are damaged, decode it with a placeholder color for them to get the others",
+                chunks::describe_rows(&rows)
+            ))),
+        }
+    }
+
+    // tiles::decode with the tiles spread over the threads
+    fn read_tiles(
+        &self,
+        data: &[u8],
+        width: u32,
+        height: u32,
+        size: u32,
+        channels: usize,
+        timings: &mut Timings,
+    ) -> Result<tiles::Tiled, CodecError> {
+        let (across, down) = tiles::grid(width, height, size);
+        let count = across * down;
+        let tiles_per_thread = count.div_ceil(self.threads.max(1)).max(1);
+        let jobs = (0..count)
+            .step_by(tiles_per_thread)
+            .map(|first| {
+                move || {
+                    (first..(first + tiles_per_thread).min(count))
+                        .map(|i| {
+                            let (_, _, tile_width, tile_height) =
+                                tiles::rect(i, width, height, size);
+                            let bytes = tiles::bytes(data, i, count)?;
+                            tiles::read(bytes, i, tile_width, tile_height, channels)
+                        })
+                        .collect::<Result<Vec<_>, bare::Error>>()
+                }
+            })
+            .collect();
+        let tiles = run_jobs("tiles", "tiling", jobs, self.threads, timings)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(tiles::assemble(tiles, width, height, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interlaced() -> Vec<u8> {
+        let image = RgbImage::from_fn(16, 12, |x, y| {
+            Rgb([(x * 16) as u8, (y * 20) as u8, ((x + y) * 8) as u8])
+        });
+        Encoder::new()
+            .palette_size(16)
+            .interlace(true)
+            .encode(&image)
+            .unwrap()
+    }
+
+    // With its checksum made anew, so only the header says the file is corrupt
+    fn patched(at: usize, byte: u8) -> Vec<u8> {
+        let mut bytes = interlaced();
+        bytes[at] = byte;
+        bytes.truncate(bytes.len() - format::CHECKSUM_LEN);
+        format::append_checksum(&mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn preview_whole_file() {
+        let (image, passes) = Decoder::new().preview(&interlaced()).unwrap();
+        assert_eq!(
+            (image.width(), image.height(), passes),
+            (16, 12, interlace::PASSES)
+        );
+    }
+
+    // Index bits the unpacking would shift, subtract or divide by, and an interlaced truecolor
+    // file, which has no palette, are a corrupt header to preview and decode alike
+    #[test]
+    fn preview_bad_layout() {
+        let layout = format::layout_flags(&interlaced());
+        for (at, byte) in [
+            (format::PREFIX_LEN + 1, 128),
+            (format::PREFIX_LEN + 1, 5),
+            (format::PREFIX_LEN + 1, 0),
+            (format::PREFIX_LEN, layout | format::LAYOUT_TRUECOLOR),
+        ] {
+            let bytes = patched(at, byte);
+            assert!(
+                matches!(
+                    Decoder::new().preview(&bytes),
+                    Err(CodecError::BadHeader(_))
+                ),
+                "{at} {byte}"
+            );
+            assert!(
+                matches!(Decoder::new().decode(&bytes), Err(CodecError::BadHeader(_))),
+                "{at} {byte}"
+            );
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use image::{
+    ImageBuffer, Rgb,
+    imageops::{self, FilterType},
+};
+
+use crate::{Decoder, Encoder, dither::Dither, error::CodecError, montage::montage, utils::Color};
+
+type Image = ImageBuffer<Rgb<u8>, Vec<u8>>;
+
+pub fn psnr(a: &Image, b: &Image) -> f64 {
+    let squared: u64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&x, &y)| (x.abs_diff(y) as u64).pow(2))
+        .sum();
+    let mse = squared as f64 / a.as_raw().len() as f64;
+    if mse == 0.0 {
+        return f64::INFINITY;
+    }
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+// Structural similarity of luma, averaged over 8x8 windows
+pub fn ssim(a: &Image, b: &Image) -> f64 {
+    const WINDOW: u32 = 8;
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let luma = |p: &Rgb<u8>| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64;
+    let (width, height) = a.dimensions();
+    let mut total = 0.0;
+    let mut windows = 0;
+    for wy in (0..height).step_by(WINDOW as usize) {
+        for wx in (0..width).step_by(WINDOW as usize) {
+            let mut pairs = Vec::with_capacity((WINDOW * WINDOW) as usize);
+            for y in wy..(wy + WINDOW).min(height) {
+                for x in wx..(wx + WINDOW).min(width) {
+                    pairs.push((luma(a.get_pixel(x, y)), luma(b.get_pixel(x, y))));
+                }
+            }
+            let n = pairs.len() as f64;
+            let mean_a = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+            let mean_b = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut covar) = (0.0, 0.0, 0.0);
+            for (x, y) in &pairs {
+                var_a += (x - mean_a).powi(2) / n;
+                var_b += (y - mean_b).powi(2) / n;
+                covar += (x - mean_a) * (y - mean_b) / n;
+            }
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covar + C2))
+                / ((mean_a.powi(2) + mean_b.powi(2) + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+fn format_size(bytes: usize) -> String {
+    if bytes < 1024 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}KB", bytes as f64 / 1024.0)
+    }
+}
+
+// Measurements of one variant of a comparison, its tile in the montage shows the label
+pub struct Row {
+    pub label: String,
+    pub palette_size: usize,
+    pub dither: Dither,
+    pub encoded_len: usize,
+    // Infinite for a variant that is identical to the image
+    pub psnr: f64,
+    pub ssim: f64,
+}
+
+// Encodes and decodes one variant, returns the decoded image and the encoded size
+fn variant(
+    img: &Image,
+    palette_size: usize,
+    dither: Dither,
+    compress: bool,
+) -> Result<(Image, usize), CodecError> {
+    let encoded = Encoder::new()
+        .palette_size(palette_size)
+        .dither(dither)
+        .compression(compress)
+        .encode(img)?;
+    let decoded = Decoder::new().compression(compress).decode(&encoded)?;
+    Ok((decoded, encoded.len()))
+}
+
+// Encodes the image once per palette size, returns the montage of the decoded variants labeled
+// with palette size, encoded size and PSNR, and their rows
+pub fn compare_sizes(
+    img: &Image,
+    sizes: &[usize],
+    compress: bool,
+) -> Result<(Image, Vec<Row>), CodecError> {
+    let (tiles, rows) = sizes
+        .iter()
+        .map(|&palette_size| {
+            let dither = Dither::FloydSteinberg;
+            let (decoded, encoded_len) = variant(img, palette_size, dither, compress)?;
+            let psnr = psnr(img, &decoded);
+            let label = format!(
+                "{}: {} {:.1}dB",
+                palette_size,
+                format_size(encoded_len),
+                psnr
+            );
+            let row = Row {
+                label: label.clone(),
+                palette_size,
+                dither,
+                encoded_len,
+                psnr,
+                ssim: ssim(img, &decoded),
+            };
+            Ok(((decoded, label), row))
+        })
+        .collect::<Result<(Vec<_>, Vec<_>), CodecError>>()?;
+    Ok((montage(&tiles), rows))
+}
+
+// Encodes the image once per dithering algorithm at the same palette size, labeled with
+// encoded size and SSIM; returns the montage and the rows
+pub fn compare_dither(
+    img: &Image,
+    palette_size: usize,
+    compress: bool,
+) -> Result<(Image, Vec<Row>), CodecError> {
+    let (tiles, rows) = Dither::ALL
+        .iter()
+        .map(|&dither| {
+            let (decoded, encoded_len) = variant(img, palette_size, dither, compress)?;
+            let ssim = ssim(img, &decoded);
+            let label = format!(
+                "{}: {} SSIM {:.4}",
+                dither.name(),
+                format_size(encoded_len),
+                ssim
+            );
+            let row = Row {
+                label: label.clone(),
+                palette_size,
+                dither,
+                encoded_len,
+                psnr: psnr(img, &decoded),
+                ssim,
+            };
+            Ok(((decoded, label), row))
+        })
+        .collect::<Result<(Vec<_>, Vec<_>), CodecError>>()?;
+    Ok((montage(&tiles), rows))
+}
+
+// Longest side of the proxy image --dither auto scores the algorithms on
+const PROXY_SIZE: u32 = 256;
+
+// Picks the dithering algorithm with the best SSIM on a downscaled proxy of the image
+pub fn auto_dither(img: &Image, palette_size: usize) -> Result<Dither, CodecError> {
+    let scale = (PROXY_SIZE as f64 / img.width().max(img.height()) as f64).min(1.0);
+    let proxy = imageops::resize(
+        img,
+        ((img.width() as f64 * scale) as u32).max(2),
+        ((img.height() as f64 * scale) as u32).max(2),
+        FilterType::Triangle,
+    );
+    let mut scores = Vec::with_capacity(Dither::ALL.len());
+    for dither in Dither::ALL {
+        let (decoded, _) = variant(&proxy, palette_size, dither, false)?;
+        scores.push((dither, ssim(&proxy, &decoded)));
+    }
+    Ok(scores
+        .into_iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(dither, _)| dither)
+        .unwrap())
+}
+
+// Euclidean RGB (or RGBA) distance
+fn pixel_error<P: Color>(a: &P, b: &P) -> f64 {
+    a.channels()
+        .iter()
+        .zip(b.channels())
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
+
+// Mean of the per-pixel distances error_map shows, without building the map
+pub fn mean_error<P: Color>(original: &[P], quantized: &ImageBuffer<P, Vec<u8>>) -> f64 {
+    let total = original
+        .iter()
+        .zip(quantized.pixels())
+        .map(|(a, b)| pixel_error(a, b))
+        .sum::<f64>();
+    total / original.len().max(1) as f64
+}
+
+// Per-pixel distance between the source and quantized colors, brightest where the error is
+// largest; returns the map with mean and max error
+pub fn error_map<P: Color>(
+    original: &[P],
+    quantized: &ImageBuffer<P, Vec<u8>>,
+) -> (Image, f64, f64) {
+    let errors = original
+        .iter()
+        .zip(quantized.pixels())
+        .map(|(a, b)| pixel_error(a, b))
+        .collect::<Vec<_>>();
+    let max = errors.iter().cloned().fold(0.0, f64::max);
+    let mean = errors.iter().sum::<f64>() / errors.len() as f64;
+    let scale = if max > 0.0 { 255.0 / max } else { 0.0 };
+    let map = ImageBuffer::from_fn(quantized.width(), quantized.height(), |x, y| {
+        let value = (errors[(y * quantized.width() + x) as usize] * scale).round() as u8;
+        Rgb([value, value, value])
+    });
+    (map, mean, max)
+}
@@ -0,0 +1,48 @@
+// Zstd over whole files, minimal builds can leave it out with the "zstd" feature
+use crate::error::CodecError;
+
+// Levels go from 1 (fastest) to 22 (smallest), zstd's own default is 3
+pub const MIN_LEVEL: i32 = 1;
+pub const DEFAULT_LEVEL: i32 = 3;
+pub const MAX_LEVEL: i32 = 22;
+
+#[cfg(feature = "zstd")]
+pub fn compress(bytes: &[u8], level: i32) -> Result<Vec<u8>, CodecError> {
+    zstd::encode_all(bytes, level).map_err(CodecError::Compression)
+}
+
+#[cfg(feature = "zstd")]
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+    zstd::decode_all(bytes).map_err(CodecError::Decompression)
+}
+
+// Start of the contents of a zstd frame that may be cut short, as far as its whole blocks go
+#[cfg(feature = "zstd")]
+pub fn decompress_partial(bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+    use std::io::Read;
+
+    let mut decoder = zstd::stream::read::Decoder::new(bytes).map_err(CodecError::Decompression)?;
+    let mut out = Vec::new();
+    let mut buffer = vec![0u8; 1 << 16];
+    loop {
+        match decoder.read(&mut buffer) {
+            Ok(0) | Err(_) => return Ok(out),
+            Ok(len) => out.extend_from_slice(&buffer[..len]),
+        }
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn compress(_bytes: &[u8], _level: i32) -> Result<Vec<u8>, CodecError> {
+    Err(CodecError::FeatureDisabled("zstd"))
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn decompress(_bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+    Err(CodecError::FeatureDisabled("zstd"))
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn decompress_partial(_bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+    Err(CodecError::FeatureDisabled("zstd"))
+}
@@ -0,0 +1,186 @@
+// Defaults of the encode/decode subcommands from a TOML file, flags given on the command line
+// win over them:
+//
+//     palette-size = 64
+//     compress = true
+//     dither = "atkinson"
+//     compression-level = 9
+//     jobs = 4
+//     scan = "hilbert"
+//
+//     [presets.photo]
+//     compression-level = 9
+use rust_image_codec::{compression, dither::Dither, format, scan::Scan};
+use serde::Deserialize;
+use std::{collections::BTreeMap, env, path::PathBuf};
+
+pub const PRESETS: [&str; 4] = ["pixel-art", "photo", "icon", "max-compression"];
+
+// Encode settings bundled by --preset, unset fields fall back to the defaults
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Preset {
+    pub palette_size: Option<u64>,
+    pub dither: Option<String>,
+    pub compress: Option<bool>,
+    pub compression_level: Option<i32>,
+    // Encode only, see --scan
+    pub scan: Option<String>,
+}
+
+impl Preset {
+    // Built-in presets, see PRESETS
+    fn builtin(name: &str) -> Option<Preset> {
+        let (palette_size, dither, compression_level) = match name {
+            // Few flat colors and hard edges, dithering only adds noise
+            "pixel-art" => (16, "none", 19),
+            "photo" => (256, "floyd-steinberg", 3),
+            "icon" => (64, "atkinson", 19),
+            // Long runs of the same index are what zstd compresses best
+            "max-compression" => (8, "none", compression::MAX_LEVEL),
+            _ => return None,
+        };
+        Some(Preset {
+            palette_size: Some(palette_size),
+            dither: Some(dither.to_string()),
+            compress: Some(true),
+            compression_level: Some(compression_level),
+            scan: None,
+        })
+    }
+
+    // Fields of self win over fallback's
+    pub fn or(self, fallback: Preset) -> Preset {
+        Preset {
+            palette_size: self.palette_size.or(fallback.palette_size),
+            dither: self.dither.or(fallback.dither),
+            compress: self.compress.or(fallback.compress),
+            compression_level: self.compression_level.or(fallback.compression_level),
+            scan: self.scan.or(fallback.scan),
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if let Some(size) = self.palette_size
+            && !(2..=format::MAX_PALETTE_SIZE as u64).contains(&size)
+        {
+            return Err(format!(
+                "palette-size should be between 2 and {}",
+                format::MAX_PALETTE_SIZE
+            ));
+        }
+        if let Some(name) = &self.dither
+            && name != "auto"
+            && Dither::parse(name).is_none()
+        {
+            return Err(format!("unknown dither {}", name));
+        }
+        if let Some(name) = &self.scan
+            && Scan::parse(name).is_none()
+        {
+            return Err(format!("unknown scan {}", name));
+        }
+        if let Some(level) = self.compression_level
+            && !(compression::MIN_LEVEL..=compression::MAX_LEVEL).contains(&level)
+        {
+            return Err(format!(
+                "compression-level should be between {} and {}",
+                compression::MIN_LEVEL,
+                compression::MAX_LEVEL
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub palette_size: Option<u64>,
+    // Encode only
+    pub compress: Option<bool>,
+    pub dither: Option<String>,
+    pub compression_level: Option<i32>,
+    // Encode only
+    pub scan: Option<String>,
+    pub jobs: Option<u16>,
+    // Overrides of the built-in presets and new ones, by name
+    pub presets: BTreeMap<String, Preset>,
+}
+
+impl Config {
+    // Top-level encode settings
+    pub fn defaults(&self) -> Preset {
+        Preset {
+            palette_size: self.palette_size,
+            dither: self.dither.clone(),
+            compress: self.compress,
+            compression_level: self.compression_level,
+            scan: self.scan.clone(),
+        }
+    }
+
+    // Config fields of a preset win over the built-in ones
+    pub fn preset(&self, name: &str) -> Result<Preset, String> {
+        match (self.presets.get(name), Preset::builtin(name)) {
+            (Some(preset), builtin) => Ok(preset.clone().or(builtin.unwrap_or_default())),
+            (None, Some(builtin)) => Ok(builtin),
+            (None, None) => Err(format!(
+                "unknown preset {}, expected one of: {}",
+                name,
+                PRESETS
+                    .iter()
+                    .copied()
+                    .chain(
+                        self.presets
+                            .keys()
+                            .map(String::as_str)
+                            .filter(|name| !PRESETS.contains(name))
+                    )
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/ric/config.toml, ~/.config/ric/config.toml or %APPDATA%\ric\config.toml
+fn default_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("ric").join("config.toml"))
+}
+
+// The given file must exist, a missing default one is an empty config
+pub fn load(path: Option<&str>) -> Result<Config, String> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => match default_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Config::default()),
+        },
+    };
+    let text =
+        std::fs::read_to_string(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let config: Config =
+        toml::from_str(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+    config
+        .defaults()
+        .check()
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    for (name, preset) in &config.presets {
+        preset
+            .check()
+            .map_err(|err| format!("{}: presets.{}: {}", path.display(), name, err))?;
+    }
+    if config.jobs == Some(0) {
+        return Err(format!(
+            "{}: jobs should be a positive number",
+            path.display()
+        ));
+    }
+    Ok(config)
+}
@@ -0,0 +1,460 @@
+// Container of many files (sprite sheets, page scans) behind a table of contents: the magic, a
+// version byte and the little endian u32 entry count, then per entry its little endian u64
+// offset from the start of the container and u64 length, a flags byte and its name as a little
+// endian u16 length and UTF-8 bytes, then a CRC32 of everything before it. The entries follow,
+// each a whole file as encode writes it, copied in and out byte for byte so adding or taking
+// out one never re-encodes the others. An entry whose palette is that of an earlier one is
+// stored without it and gets it back when read, frames of a sprite sheet often share one.
+// Entries may be the frames of an animation with a delay each, version 2 adds the loop count
+// after the entry count as a little endian u32; containers without one are still written as
+// version 1
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::{ZSTD_MAGIC, bare, error::CodecError, fec, format};
+
+pub const MAGIC: [u8; 4] = *b"RICC";
+pub const VERSION: u8 = 2;
+// Version of containers without a loop count
+const VERSION_STILL: u8 = 1;
+const HEAD_LEN: usize = MAGIC.len() + 1 + 4;
+const LOOPS_LEN: usize = 4;
+// Offset, length and flags byte, the name's length follows
+const ENTRY_LEN: usize = 8 + 8 + 1;
+const NAME_LEN_LEN: usize = 2;
+// The entry is stored without its palette entries, a little endian u32 after the flags byte
+// tells the earlier entry whose palette they are
+const ENTRY_SHARED_PALETTE: u8 = 1;
+const SOURCE_LEN: usize = 4;
+// The entry is a frame shown for a little endian u32 of milliseconds, after the source entry
+const ENTRY_DELAY: u8 = 2;
+const DELAY_LEN: usize = 4;
+// Entry flags this build reads, entries with others are rejected
+const ENTRY_FLAGS_KNOWN: u8 = ENTRY_SHARED_PALETTE | ENTRY_DELAY;
+
+pub struct Entry<'a> {
+    pub name: String,
+    // The whole file
+    pub bytes: Cow<'a, [u8]>,
+    // Earlier entry whose palette was stored for this one too, set by read; write finds the
+    // entries it can share palettes between itself
+    pub shared_palette: Option<usize>,
+    // Milliseconds the entry shows for as a frame of an animation
+    pub delay: Option<u32>,
+}
+
+impl<'a> Entry<'a> {
+    pub fn new(name: String, bytes: &'a [u8]) -> Self {
+        Self {
+            name,
+            bytes: Cow::Borrowed(bytes),
+            shared_palette: None,
+            delay: None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Container<'a> {
+    pub entries: Vec<Entry<'a>>,
+    // Loop count of the animation of the entries, 0 plays it forever
+    pub loops: Option<u32>,
+}
+
+// Palette entries of a file, None for compressed files, whose palette isn't in the clear, and
+// tiled ones, whose tiles hold theirs
+fn palette_range(bytes: &[u8]) -> Option<Range<usize>> {
+    if bytes.starts_with(&ZSTD_MAGIC) || !bytes.starts_with(&format::MAGIC) {
+        return None;
+    }
+    let (palette_size, start) = bare::palette_size(bytes).ok()?;
+    let len = format::palette_entries(bytes, palette_size) * format::channels(bytes);
+    (len > 0 && start + len <= bytes.len()).then_some(start..start + len)
+}
+
+pub fn is_container(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+fn truncated() -> CodecError {
+    CodecError::BadHeader("a container cut short in its table of contents".to_string())
+}
+
+// Entries of a container, their bytes borrowed from it
+pub fn read(bytes: &[u8]) -> Result<Container<'_>, CodecError> {
+    if !is_container(bytes) {
+        return Err(CodecError::BadHeader(
+            "not a container, missing the RICC magic".to_string(),
+        ));
+    }
+    let head = bytes.get(..HEAD_LEN).ok_or_else(truncated)?;
+    let version = head[MAGIC.len()];
+    if !(VERSION_STILL..=VERSION).contains(&version) {
+        return Err(CodecError::BadHeader(format!(
+            "a container of version {}, this build reads {} to {}",
+            version, VERSION_STILL, VERSION
+        )));
+    }
+    let count = u32::from_le_bytes(head[MAGIC.len() + 1..].try_into().unwrap()) as usize;
+    let mut at = HEAD_LEN;
+    let loops = if version == VERSION_STILL {
+        None
+    } else {
+        let loops = bytes.get(at..at + LOOPS_LEN).ok_or_else(truncated)?;
+        at += LOOPS_LEN;
+        Some(u32::from_le_bytes(loops.try_into().unwrap()))
+    };
+    let mut table = Vec::with_capacity(count.min(bytes.len() / ENTRY_LEN));
+    for i in 0..count {
+        let entry = bytes.get(at..at + ENTRY_LEN).ok_or_else(truncated)?;
+        let offset = u64::from_le_bytes(entry[..8].try_into().unwrap()) as usize;
+        let len = u64::from_le_bytes(entry[8..16].try_into().unwrap()) as usize;
+        let flags = entry[16];
+        // Other flags may come with fields this build doesn't know of
+        if flags & !ENTRY_FLAGS_KNOWN != 0 {
+            return Err(CodecError::BadHeader(format!(
+                "a container with entry {} of unknown flags {:#04x}, it needs a newer build",
+                i, flags
+            )));
+        }
+        at += ENTRY_LEN;
+        let source = if flags & ENTRY_SHARED_PALETTE != 0 {
+            let source = bytes.get(at..at + SOURCE_LEN).ok_or_else(truncated)?;
+            at += SOURCE_LEN;
+            Some(u32::from_le_bytes(source.try_into().unwrap()) as usize)
+        } else {
+            None
+        };
+        let delay = if flags & ENTRY_DELAY != 0 {
+            let delay = bytes.get(at..at + DELAY_LEN).ok_or_else(truncated)?;
+            at += DELAY_LEN;
+            Some(u32::from_le_bytes(delay.try_into().unwrap()))
+        } else {
+            None
+        };
+        let name_len = bytes.get(at..at + NAME_LEN_LEN).ok_or_else(truncated)?;
+        let name_len = u16::from_le_bytes([name_len[0], name_len[1]]) as usize;
+        at += NAME_LEN_LEN;
+        let name = bytes.get(at..at + name_len).ok_or_else(truncated)?;
+        at += name_len;
+        table.push((offset, len, source, delay, name));
+    }
+    let checksum = bytes
+        .get(at..at + format::CHECKSUM_LEN)
+        .ok_or_else(truncated)?;
+    if u32::from_le_bytes(checksum.try_into().unwrap()) != crc32fast::hash(&bytes[..at]) {
+        return Err(CodecError::BadHeader(
+            "a container whose table of contents doesn't match its checksum".to_string(),
+        ));
+    }
+    let entries_start = at + format::CHECKSUM_LEN;
+    // Entries in order, as those sharing a palette take it from an earlier one
+    let mut entries: Vec<Entry> = Vec::with_capacity(table.len());
+    for (i, (offset, len, source, delay, name)) in table.into_iter().enumerate() {
+        let stored = offset
+            .checked_add(len)
+            .filter(|_| offset >= entries_start)
+            .and_then(|end| bytes.get(offset..end))
+            .ok_or_else(|| {
+                CodecError::BadHeader(format!(
+                    "a container with entry {} beyond its end, it's truncated",
+                    i
+                ))
+            })?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| {
+            CodecError::BadHeader(format!(
+                "a container with entry {} named in invalid UTF-8",
+                i
+            ))
+        })?;
+        let bytes = match source {
+            Some(source) => Cow::Owned(with_palette(&entries, i, source, stored)?),
+            None => Cow::Borrowed(stored),
+        };
+        entries.push(Entry {
+            name,
+            bytes,
+            shared_palette: source,
+            delay,
+        });
+    }
+    Ok(Container { entries, loops })
+}
+
+// Entry i's file from its bytes stored without its palette and the palette of entry source
+fn with_palette(
+    entries: &[Entry],
+    i: usize,
+    source: usize,
+    stored: &[u8],
+) -> Result<Vec<u8>, CodecError> {
+    let bad = || {
+        CodecError::BadHeader(format!(
+            "a container with entry {} sharing the palette of entry {}, which doesn't have one fitting it",
+            i, source
+        ))
+    };
+    let palette = entries
+        .get(source)
+        .and_then(|entry| Some(&entry.bytes[palette_range(&entry.bytes)?]))
+        .ok_or_else(bad)?;
+    let (palette_size, start) = bare::palette_size(stored).map_err(|_| bad())?;
+    if format::palette_entries(stored, palette_size) * format::channels(stored) != palette.len()
+        || start > stored.len()
+    {
+        return Err(bad());
+    }
+    let mut bytes = Vec::with_capacity(stored.len() + palette.len());
+    bytes.extend_from_slice(&stored[..start]);
+    bytes.extend_from_slice(palette);
+    bytes.extend_from_slice(&stored[start..]);
+    Ok(bytes)
+}
+
+// Container of the entries in order and how many of them share an earlier one's palette. A
+// palette is shared when it's byte for byte that of an earlier entry (same size, channels and
+// colors in the same order) and longer than the entry number that replaces it
+pub fn write(container: &Container) -> (Vec<u8>, usize) {
+    let entries = &container.entries;
+    let mut first_with = HashMap::new();
+    let stored = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let Some(range) = palette_range(&entry.bytes) else {
+                return (None, Cow::Borrowed(&entry.bytes[..]));
+            };
+            let palette = &entry.bytes[range.clone()];
+            match first_with.get(palette) {
+                Some(&source) if palette.len() > SOURCE_LEN => {
+                    let mut stored = entry.bytes[..range.start].to_vec();
+                    stored.extend_from_slice(&entry.bytes[range.end..]);
+                    (Some(source), Cow::Owned(stored))
+                }
+                Some(_) => (None, Cow::Borrowed(&entry.bytes[..])),
+                None => {
+                    first_with.insert(palette, i);
+                    (None, Cow::Borrowed(&entry.bytes[..]))
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let table_len = entries
+        .iter()
+        .zip(&stored)
+        .map(|(entry, (source, _))| {
+            ENTRY_LEN
+                + source.map_or(0, |_| SOURCE_LEN)
+                + entry.delay.map_or(0, |_| DELAY_LEN)
+                + NAME_LEN_LEN
+                + entry.name.len()
+        })
+        .sum::<usize>();
+    let head_len = HEAD_LEN + container.loops.map_or(0, |_| LOOPS_LEN);
+    let mut offset = head_len + table_len + format::CHECKSUM_LEN;
+    let mut bytes =
+        Vec::with_capacity(offset + stored.iter().map(|(_, stored)| stored.len()).sum::<usize>());
+    bytes.extend_from_slice(&MAGIC);
+    match container.loops {
+        Some(loops) => {
+            bytes.push(VERSION);
+            bytes.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&loops.to_le_bytes());
+        }
+        None => {
+            bytes.push(VERSION_STILL);
+            bytes.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        }
+    }
+    for (entry, (source, stored)) in entries.iter().zip(&stored) {
+        bytes.extend_from_slice(&(offset as u64).to_le_bytes());
+        bytes.extend_from_slice(&(stored.len() as u64).to_le_bytes());
+        let flags =
+            source.map_or(0, |_| ENTRY_SHARED_PALETTE) | entry.delay.map_or(0, |_| ENTRY_DELAY);
+        bytes.push(flags);
+        if let Some(source) = source {
+            bytes.extend_from_slice(&(*source as u32).to_le_bytes());
+        }
+        if let Some(delay) = entry.delay {
+            bytes.extend_from_slice(&delay.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(entry.name.as_bytes());
+        offset += stored.len();
+    }
+    format::append_checksum(&mut bytes);
+    for (_, stored) in &stored {
+        bytes.extend_from_slice(stored);
+    }
+    let shared = stored.iter().filter(|(source, _)| source.is_some()).count();
+    (bytes, shared)
+}
+
+// Checks a file can be added, a .ric file (compressed, protected with --fec or not) and not a
+// container; an entry's name is at most u16::MAX bytes
+pub fn check_entry(name: &str, bytes: &[u8]) -> Result<(), CodecError> {
+    if is_container(bytes) {
+        return Err(CodecError::InvalidInput(
+            "is a container, containers can't be nested".to_string(),
+        ));
+    }
+    if !bytes.starts_with(&format::MAGIC)
+        && !bytes.starts_with(&ZSTD_MAGIC)
+        && !fec::is_wrapped(bytes)
+    {
+        return Err(CodecError::InvalidInput(
+            "not a .ric file, encode it first (or upgrade a format version 1 one)".to_string(),
+        ));
+    }
+    if name.len() > u16::MAX as usize {
+        return Err(CodecError::InvalidInput(format!(
+            "entry name is {} bytes, at most {} are stored",
+            name.len(),
+            u16::MAX
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Encoder;
+
+    // Files of the same 4 colors in other orders, so they quantize to the same palette
+    fn files() -> Vec<Vec<u8>> {
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+        (0..3)
+            .map(|shift| {
+                let pixels: Vec<u8> = (0..64).flat_map(|i| colors[(i + shift) % 4]).collect();
+                Encoder::new()
+                    .palette_size(4)
+                    .encode_raw(&pixels, 8, 8)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn container<'a>(files: &'a [Vec<u8>], names: &[&str]) -> Container<'a> {
+        Container {
+            entries: files
+                .iter()
+                .zip(names)
+                .map(|(file, name)| Entry::new(name.to_string(), file))
+                .collect(),
+            loops: None,
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let (bytes, shared) = write(&Container::default());
+        assert_eq!((bytes.len(), shared), (HEAD_LEN + format::CHECKSUM_LEN, 0));
+        let read = read(&bytes).unwrap();
+        assert!(read.entries.is_empty());
+        assert_eq!(read.loops, None);
+    }
+
+    // Entries come back byte for byte, those sharing a palette with it put back in
+    #[test]
+    fn round_trip() {
+        let files = files();
+        let (bytes, shared) = write(&container(&files, &["a", "", "ü.ric"]));
+        assert_eq!(shared, 2);
+        let read = read(&bytes).unwrap();
+        let names: Vec<&str> = read
+            .entries
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect();
+        assert_eq!(names, ["a", "", "ü.ric"]);
+        for (entry, file) in read.entries.iter().zip(&files) {
+            assert_eq!(&entry.bytes[..], &file[..]);
+        }
+        let sources: Vec<_> = read
+            .entries
+            .iter()
+            .map(|entry| entry.shared_palette)
+            .collect();
+        assert_eq!(sources, [None, Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn bytes_other_than_files() {
+        let files = [b"RIC1".to_vec(), Vec::new(), vec![0xff; 3]];
+        let (bytes, shared) = write(&container(&files, &["x", "y", "z"]));
+        assert_eq!(shared, 0);
+        let read = read(&bytes).unwrap();
+        for (entry, file) in read.entries.iter().zip(&files) {
+            assert_eq!(&entry.bytes[..], &file[..]);
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let files = files();
+        let mut frames = container(&files, &["0", "1", "2"]);
+        frames.loops = Some(0);
+        frames.entries[1].delay = Some(250);
+        let bytes = write(&frames).0;
+        assert_eq!(bytes[MAGIC.len()], VERSION);
+        let read = read(&bytes).unwrap();
+        assert_eq!(read.loops, Some(0));
+        let delays: Vec<_> = read.entries.iter().map(|entry| entry.delay).collect();
+        assert_eq!(delays, [None, Some(250), None]);
+    }
+
+    // Cut anywhere in its table of contents or entries, a container doesn't read
+    #[test]
+    fn truncated() {
+        let files = files();
+        let bytes = write(&container(&files, &["a", "b", "c"])).0;
+        for len in 0..bytes.len() {
+            assert!(read(&bytes[..len]).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn damaged_table() {
+        let files = files();
+        let bytes = write(&container(&files, &["a", "b"])).0;
+        let mut damaged = bytes.clone();
+        damaged[HEAD_LEN + 1] ^= 1;
+        assert!(matches!(read(&damaged), Err(CodecError::BadHeader(_))));
+        let mut version = bytes.clone();
+        version[MAGIC.len()] = VERSION + 1;
+        assert!(read(&version).is_err());
+        assert!(read(b"RIC1").is_err());
+    }
+
+    // Flags and offsets are checked even under a valid checksum
+    #[test]
+    fn crafted_table() {
+        let files = [b"RIC1".to_vec()];
+        let bytes = write(&container(&files, &["a"])).0;
+        let table_end = bytes.len() - files[0].len() - format::CHECKSUM_LEN;
+        let rewrite = |at: usize, value: &[u8]| {
+            let mut crafted = bytes[..table_end].to_vec();
+            crafted[at..at + value.len()].copy_from_slice(value);
+            format::append_checksum(&mut crafted);
+            crafted.extend_from_slice(&files[0]);
+            crafted
+        };
+        assert!(read(&rewrite(HEAD_LEN + 16, &[4])).is_err());
+        assert!(read(&rewrite(HEAD_LEN, &0u64.to_le_bytes())).is_err());
+        assert!(read(&rewrite(HEAD_LEN + 8, &5u64.to_le_bytes())).is_err());
+        assert!(read(&rewrite(HEAD_LEN + 8, &u64::MAX.to_le_bytes())).is_err());
+        assert!(read(&rewrite(HEAD_LEN + 8, &4u64.to_le_bytes())).is_ok());
+    }
+
+    #[test]
+    fn entries_to_add() {
+        let files = files();
+        assert!(check_entry("a", &files[0]).is_ok());
+        let bytes = write(&container(&files, &["a"])).0;
+        assert!(check_entry("a", &bytes).is_err());
+        assert!(check_entry("a", b"\x89PNG").is_err());
+        assert!(check_entry(&"n".repeat(u16::MAX as usize + 1), &files[0]).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{ZSTD_MAGIC, bare, compression, fec, format, keys::KEY_CHECK_LEN};
+
+// Extra bits per byte over the palette's own entropy that still look like indices
+const ENTROPY_MARGIN: f64 = 0.5;
+// More than 1/10 of indices beyond the palette looks like ciphertext
+const OUT_OF_RANGE_RATIO: usize = 10;
+
+// Processing state of a file, read from the header flags since v3 and guessed from its bytes
+// before
+pub enum State {
+    Plain,
+    // Pixel data is preceded by a key check and maybe a key block of the given length
+    Encrypted { key_block_len: usize },
+    // Same length as a plain file, but the data doesn't look like palette indices
+    EncryptedLegacy,
+    Invalid(String),
+}
+
+pub struct Detection {
+    pub compressed: bool,
+    pub state: State,
+}
+
+// How to decode a file encrypted and compressed or not, in the words of both the subcommands
+// and the short syntax, for CodecError::WrongOptions
+pub fn hint(encrypted: bool, compressed: bool) -> String {
+    let key = if encrypted {
+        "with its key: decode --key (or --key-env, --key-file, --key-name) or --passphrase, \"dc\" in the short syntax"
+    } else {
+        "without a key"
+    };
+    if compressed {
+        format!("as compressed (decode --compress, \"z\") and {}", key)
+    } else {
+        key.to_string()
+    }
+}
+
+impl Detection {
+    // How the file should most likely be decoded, see hint
+    pub fn suggestion(&self) -> String {
+        hint(
+            !matches!(self.state, State::Plain | State::Invalid(_)),
+            self.compressed,
+        )
+    }
+
+    pub fn describe(&self) -> String {
+        let state = match &self.state {
+            State::Plain => "plain".to_string(),
+            State::Encrypted { key_block_len: 0 } => "encrypted".to_string(),
+            State::Encrypted { key_block_len } => format!(
+                "encrypted with a {}-byte key block (--identity, --age-identity or --derive)",
+                key_block_len
+            ),
+            State::EncryptedLegacy => "likely encrypted (no key check)".to_string(),
+            State::Invalid(reason) => format!("invalid: {}", reason),
+        };
+        if self.compressed {
+            format!("compressed, {}", state)
+        } else {
+            state
+        }
+    }
+}
+
+fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in bytes {
+        counts[byte as usize] += 1;
+    }
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / bytes.len() as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// Checks the header against the data length, then the data against the flags or, without
+// them, the palette size
+pub fn detect_state(bytes: &[u8]) -> State {
+    let flags = format::flags(bytes);
+    let file_len = bytes.len();
+    let content_len = format::content_len(bytes);
+    let prefix_len = match format::prefix_len(bytes) {
+        Ok(prefix_len) => prefix_len,
+        Err(err) => return State::Invalid(err.to_string()),
+    };
+    let file = bytes;
+    let bytes = bytes.get(prefix_len..content_len).unwrap_or_default();
+    let (width, height, _) = match bare::dimensions(file) {
+        Ok(dimensions) => dimensions,
+        _ => return State::Invalid("too short for a header".to_string()),
+    };
+    let (palette_size, palette_start) = match bare::palette_size(file) {
+        Ok(palette_size) if palette_size.1 <= content_len => palette_size,
+        _ => return State::Invalid("too short for a header".to_string()),
+    };
+    let data_len = format::stored_len(file).unwrap_or(format::data_len(
+        width,
+        height,
+        format::index_bits(file),
+        format::layout_flags(file),
+    ));
+    if content_len < palette_start + format::palette_len(file, palette_size) {
+        return State::Invalid("shorter than its palette".to_string());
+    }
+    let data_offset = match bare::metadata_range(file) {
+        Ok(metadata) if metadata.end <= content_len => metadata.end - prefix_len,
+        _ => return State::Invalid("shorter than its metadata".to_string()),
+    };
+    let rest = bytes.len() - data_offset;
+    if rest < data_len {
+        return State::Invalid(format!(
+            "truncated at byte {}, {} bytes of pixels data, {}x{} needs {}",
+            file_len, rest, width, height, data_len
+        ));
+    }
+    if let Some(flags) = flags {
+        return flagged_state(flags, rest - data_len);
+    }
+    if rest - data_len >= KEY_CHECK_LEN {
+        return State::Encrypted {
+            key_block_len: rest - data_len - KEY_CHECK_LEN,
+        };
+    }
+    if rest != data_len {
+        return State::Invalid(format!("{} unexpected trailing bytes", rest - data_len));
+    }
+    let data = &bytes[data_offset..];
+    // Indices are one byte, so only smaller palettes can be told apart from ciphertext,
+    // a few out-of-range indices are corruption rather than encryption
+    let out_of_range = data
+        .iter()
+        .filter(|&&index| index as usize >= palette_size)
+        .count();
+    if palette_size < 256
+        && (out_of_range > data.len() / OUT_OF_RANGE_RATIO
+            || entropy(data) > (palette_size as f64).log2() + ENTROPY_MARGIN)
+    {
+        return State::EncryptedLegacy;
+    }
+    State::Plain
+}
+
+// Extra is the length of the data beyond the pixels
+fn flagged_state(flags: u8, extra: usize) -> State {
+    if flags & format::FLAG_ENCRYPTED == 0 {
+        return match extra {
+            0 => State::Plain,
+            extra => State::Invalid(format!("{} unexpected trailing bytes", extra)),
+        };
+    }
+    let Some(key_block_len) = extra.checked_sub(KEY_CHECK_LEN) else {
+        return State::Invalid("encrypted, but missing its key check".to_string());
+    };
+    match (flags & format::FLAG_KEY_BLOCK != 0, key_block_len) {
+        (true, 0) => State::Invalid("encrypted, but missing its key block".to_string()),
+        (false, 1..) => State::Invalid(format!("{} unexpected trailing bytes", key_block_len)),
+        _ => State::Encrypted { key_block_len },
+    }
+}
+
+pub fn detect(bytes: &[u8]) -> Detection {
+    // What a file protected with --fec holds
+    if fec::is_wrapped(bytes) {
+        return match fec::unwrap(bytes) {
+            Ok(file) => detect(&file),
+            Err(err) => Detection {
+                compressed: false,
+                state: State::Invalid(err.to_string()),
+            },
+        };
+    }
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        return Detection {
+            compressed: true,
+            state: match compression::decompress(bytes) {
+                Ok(decompressed) => detect_state(&decompressed),
+                Err(err) => State::Invalid(format!("zstd: {}", err)),
+            },
+        };
+    }
+    Detection {
+        compressed: false,
+        state: detect_state(bytes),
+    }
+}
@@ -0,0 +1,118 @@
+use image::{
+    ImageBuffer,
+    imageops::{ColorMap, dither},
+};
+
+use crate::utils::{Color, Palette};
+
+type Image<P> = ImageBuffer<P, Vec<u8>>;
+
+// 4x4 Bayer threshold matrix
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+// Ordered dithering threshold amplitude per channel
+const BAYER_SPREAD: f32 = 32.0;
+// Neighbours (dx, dy) and the error share they get; Atkinson spreads 6/8 of it, losing the rest
+// for higher contrast
+const ATKINSON: [(i64, i64, f32); 6] = [
+    (1, 0, 1.0 / 8.0),
+    (2, 0, 1.0 / 8.0),
+    (-1, 1, 1.0 / 8.0),
+    (0, 1, 1.0 / 8.0),
+    (1, 1, 1.0 / 8.0),
+    (0, 2, 1.0 / 8.0),
+];
+const FLOYD_STEINBERG: [(i64, i64, f32); 4] = [
+    (1, 0, 7.0 / 16.0),
+    (-1, 1, 3.0 / 16.0),
+    (0, 1, 5.0 / 16.0),
+    (1, 1, 1.0 / 16.0),
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Dither {
+    None,
+    FloydSteinberg,
+    Atkinson,
+    Bayer,
+}
+
+impl Dither {
+    pub const ALL: [Dither; 4] = [
+        Dither::None,
+        Dither::FloydSteinberg,
+        Dither::Atkinson,
+        Dither::Bayer,
+    ];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|d| d.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Dither::None => "none",
+            Dither::FloydSteinberg => "floyd-steinberg",
+            Dither::Atkinson => "atkinson",
+            Dither::Bayer => "bayer",
+        }
+    }
+
+    // Replaces every pixel with a palette color; alpha is matched but not dithered
+    pub fn apply<P: Color>(self, img: &mut Image<P>, palette: &Palette<P>) {
+        match self {
+            Dither::None => {
+                for pixel in img.pixels_mut() {
+                    palette.map_color(pixel);
+                }
+            }
+            // image's version reads the next column, which 1 pixel wide images don't have
+            Dither::FloydSteinberg if img.width() < 2 => diffuse(img, palette, &FLOYD_STEINBERG),
+            Dither::FloydSteinberg => dither(img, palette),
+            Dither::Atkinson => diffuse(img, palette, &ATKINSON),
+            Dither::Bayer => {
+                for (x, y, pixel) in img.enumerate_pixels_mut() {
+                    let threshold =
+                        (BAYER[y as usize % 4][x as usize % 4] as f32 + 0.5) / 16.0 - 0.5;
+                    for channel in pixel.channels_mut().iter_mut().take(P::COLOR_CHANNELS) {
+                        *channel = (*channel as f32 + threshold * BAYER_SPREAD)
+                            .round()
+                            .clamp(0.0, 255.0) as u8;
+                    }
+                    palette.map_color(pixel);
+                }
+            }
+        }
+    }
+}
+
+// Error diffusion over the kernel's neighbours, which may lie outside the image
+fn diffuse<P: Color>(img: &mut Image<P>, palette: &Palette<P>, kernel: &[(i64, i64, f32)]) {
+    let (width, height) = (img.width() as i64, img.height() as i64);
+    let mut errors = vec![[0f32; 3]; (width * height) as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let idx = (y * width + x) as usize;
+            let pixel = img.get_pixel_mut(x as u32, y as u32);
+            let mut wanted = [0f32; 3];
+            for c in 0..P::COLOR_CHANNELS {
+                wanted[c] = (pixel[c] as f32 + errors[idx][c]).clamp(0.0, 255.0);
+                pixel[c] = wanted[c].round() as u8;
+            }
+            palette.map_color(pixel);
+            let mut error = [0f32; 3];
+            for c in 0..P::COLOR_CHANNELS {
+                error[c] = wanted[c] - pixel[c] as f32;
+            }
+            for &(dx, dy, share) in kernel {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || nx >= width || ny >= height {
+                    continue;
+                }
+                let neighbour = &mut errors[(ny * width + nx) as usize];
+                for c in 0..P::COLOR_CHANNELS {
+                    neighbour[c] += error[c] * share;
+                }
+            }
+        }
+    }
+}
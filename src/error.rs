@@ -0,0 +1,80 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::{bare, format};
+
+// Every failure of reading, encoding, decoding or writing, the CLI prints it and exits
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("{path}: {source}")]
+    Io { path: String, source: io::Error },
+    #[error("{path}: {source}")]
+    Image {
+        path: String,
+        source: image::ImageError,
+    },
+    // Settings or source image out of the supported range
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error(
+        "unsupported format version {0}, this build reads 1 to {latest}",
+        latest = format::VERSION
+    )]
+    UnsupportedVersion(u8),
+    // Header doesn't match the data length
+    #[error("file is {0}")]
+    BadHeader(String),
+    // Decode settings don't match what the file looks like
+    #[error("file looks {looks}, decode it {hint}")]
+    WrongOptions { looks: String, hint: String },
+    #[error("{0}")]
+    BadKey(String),
+    #[error("compression failed: {0}")]
+    Compression(io::Error),
+    #[error("decompression failed: {0}")]
+    Decompression(io::Error),
+    // Pixels data doesn't fit the header
+    #[error("{0}")]
+    BadData(String),
+    // Compression or encryption left out of the build
+    #[error("this build has the \"{0}\" feature disabled")]
+    FeatureDisabled(&'static str),
+}
+
+// Exit codes of the CLI per failure class
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_IO: i32 = 3;
+pub const EXIT_CORRUPT: i32 = 4;
+pub const EXIT_CRYPTO: i32 = 5;
+pub const EXIT_DECOMPRESSION: i32 = 6;
+// A selftest case of the build failed, no input of the user is at fault
+pub const EXIT_SELFTEST: i32 = 7;
+
+impl CodecError {
+    // Lets scripts tell a wrong key from a missing file or a damaged one
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CodecError::InvalidInput(_)
+            | CodecError::WrongOptions { .. }
+            | CodecError::FeatureDisabled(_) => EXIT_USAGE,
+            CodecError::Io { .. } | CodecError::Image { .. } => EXIT_IO,
+            CodecError::UnsupportedVersion(_)
+            | CodecError::BadHeader(_)
+            | CodecError::BadData(_) => EXIT_CORRUPT,
+            CodecError::BadKey(_) => EXIT_CRYPTO,
+            CodecError::Compression(_) | CodecError::Decompression(_) => EXIT_DECOMPRESSION,
+        }
+    }
+}
+
+impl From<bare::Error> for CodecError {
+    fn from(err: bare::Error) -> Self {
+        match err {
+            bare::Error::UnsupportedVersion(version) => CodecError::UnsupportedVersion(version),
+            bare::Error::TooShort => CodecError::BadHeader("too short for a header".to_string()),
+            err @ bare::Error::BadLayout(_) => CodecError::BadHeader(err.to_string()),
+            err => CodecError::BadData(err.to_string()),
+        }
+    }
+}
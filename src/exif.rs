@@ -0,0 +1,212 @@
+// EXIF kept by encode --exif: orientation, timestamps and camera info, rewritten as a new TIFF
+// structure in the byte order of the source. Location (GPS), maker notes, thumbnails and any
+// other tag are dropped
+const IFD0_TAGS: [u16; 7] = [
+    0x010f, // Make
+    0x0110, // Model
+    0x0112, // Orientation
+    0x0131, // Software
+    0x0132, // DateTime
+    0x013b, // Artist
+    0x8298, // Copyright
+];
+const EXIF_TAGS: [u16; 11] = [
+    0x829a, // ExposureTime
+    0x829d, // FNumber
+    0x8827, // ISO
+    0x9003, // DateTimeOriginal
+    0x9004, // DateTimeDigitized
+    0x9010, // OffsetTime
+    0x9011, // OffsetTimeOriginal
+    0x9012, // OffsetTimeDigitized
+    0x920a, // FocalLength
+    0xa433, // LensMake
+    0xa434, // LensModel
+];
+// IFD0 entry pointing to the Exif IFD
+const EXIF_IFD: u16 = 0x8769;
+const LONG: u16 = 4;
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: Vec<u8>,
+}
+
+struct Tiff<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl Tiff<'_> {
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes = self.bytes.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        read_u32(self.bytes.get(at..at + 4)?, self.big_endian)
+    }
+
+    // Entries of the IFD at offset with one of the tags, values of 4 bytes or less are inline
+    fn entries(&self, offset: usize, tags: &[u16]) -> Option<Vec<Entry>> {
+        let count = self.u16(offset)? as usize;
+        let mut entries = Vec::new();
+        for idx in 0..count {
+            let at = offset + 2 + idx * 12;
+            let tag = self.u16(at)?;
+            if !tags.contains(&tag) {
+                continue;
+            }
+            let kind = self.u16(at + 2)?;
+            let count = self.u32(at + 4)?;
+            // Unknown types can't be sized, so they're dropped
+            let Some(len) = type_len(kind).and_then(|len| len.checked_mul(count as usize)) else {
+                continue;
+            };
+            let start = if len <= 4 {
+                at + 8
+            } else {
+                self.u32(at + 8)? as usize
+            };
+            let value = self.bytes.get(start..start.checked_add(len)?)?.to_vec();
+            entries.push(Entry {
+                tag,
+                kind,
+                count,
+                value,
+            });
+        }
+        Some(entries)
+    }
+}
+
+// Bytes per value of a TIFF field type
+fn type_len(kind: u16) -> Option<usize> {
+    match kind {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+// The kept tags of an EXIF block (a TIFF structure, JPEG's "Exif\0\0" prefix is skipped),
+// None when it's unreadable or has none of them
+pub fn select(exif: &[u8]) -> Option<Vec<u8>> {
+    let bytes = exif.strip_prefix(b"Exif\0\0").unwrap_or(exif);
+    let big_endian = match bytes.get(..4)? {
+        [b'I', b'I', 42, 0] => false,
+        [b'M', b'M', 0, 42] => true,
+        _ => return None,
+    };
+    let tiff = Tiff { bytes, big_endian };
+    let ifd0 = tiff.u32(4)? as usize;
+    let mut entries = tiff.entries(ifd0, &IFD0_TAGS)?;
+    // A broken Exif IFD still leaves the IFD0 tags
+    let mut exif_entries = tiff
+        .entries(ifd0, &[EXIF_IFD])?
+        .first()
+        .and_then(|pointer| read_u32(&pointer.value, big_endian))
+        .and_then(|offset| tiff.entries(offset as usize, &EXIF_TAGS))
+        .unwrap_or_default();
+    if entries.is_empty() && exif_entries.is_empty() {
+        return None;
+    }
+    let mut out = if big_endian {
+        b"MM\0\x2a".to_vec()
+    } else {
+        b"II\x2a\0".to_vec()
+    };
+    put_u32(&mut out, 8, big_endian);
+    if !exif_entries.is_empty() {
+        // The Exif IFD follows IFD0, whose length counts this pointer already
+        entries.push(Entry {
+            tag: EXIF_IFD,
+            kind: LONG,
+            count: 1,
+            value: vec![0; 4],
+        });
+        let exif_offset = (8 + ifd_len(&entries)) as u32;
+        entries.last_mut().unwrap().value = u32_bytes(exif_offset, big_endian).to_vec();
+    }
+    write_ifd(&mut out, &mut entries, big_endian);
+    write_ifd(&mut out, &mut exif_entries, big_endian);
+    Some(out)
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> Option<u32> {
+    let bytes = bytes.try_into().ok()?;
+    Some(if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    })
+}
+
+fn u32_bytes(value: u32, big_endian: bool) -> [u8; 4] {
+    if big_endian {
+        value.to_be_bytes()
+    } else {
+        value.to_le_bytes()
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16, big_endian: bool) {
+    let bytes = if big_endian {
+        value.to_be_bytes()
+    } else {
+        value.to_le_bytes()
+    };
+    out.extend_from_slice(&bytes);
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32, big_endian: bool) {
+    out.extend_from_slice(&u32_bytes(value, big_endian));
+}
+
+// Values longer than 4 bytes go after the entries, padded to an even length
+fn data_len(entry: &Entry) -> usize {
+    if entry.value.len() > 4 {
+        entry.value.len().next_multiple_of(2)
+    } else {
+        0
+    }
+}
+
+fn ifd_len(entries: &[Entry]) -> usize {
+    2 + entries.len() * 12 + 4 + entries.iter().map(data_len).sum::<usize>()
+}
+
+// An IFD with its values at the end of out and no next IFD; nothing for no entries
+fn write_ifd(out: &mut Vec<u8>, entries: &mut [Entry], big_endian: bool) {
+    if entries.is_empty() {
+        return;
+    }
+    entries.sort_by_key(|entry| entry.tag);
+    let mut data_offset = out.len() + 2 + entries.len() * 12 + 4;
+    let mut data = Vec::new();
+    put_u16(out, entries.len() as u16, big_endian);
+    for entry in entries.iter() {
+        put_u16(out, entry.tag, big_endian);
+        put_u16(out, entry.kind, big_endian);
+        put_u32(out, entry.count, big_endian);
+        if entry.value.len() <= 4 {
+            out.extend_from_slice(&entry.value);
+            out.resize(out.len() + 4 - entry.value.len(), 0);
+            continue;
+        }
+        put_u32(out, data_offset as u32, big_endian);
+        data.extend_from_slice(&entry.value);
+        data.resize(data.len() + data_len(entry) - entry.value.len(), 0);
+        data_offset += data_len(entry);
+    }
+    put_u32(out, 0, big_endian);
+    out.extend_from_slice(&data);
+}
@@ -0,0 +1,327 @@
+// Forward error correction (encode --fec): the file as written, compressed or not, wrapped with
+// Reed-Solomon parity so damage to up to the stored share of it is repaired on reading. A
+// wrapped file is the magic and a version byte, its parameters twice (each the little endian u32
+// shard length, u32 data shard count, u32 parity shard count and u64 length of the file, then a
+// CRC32 of them), the CRC32 of every shard twice (each copy followed by a CRC32 of it), then the
+// file and the parity shards. The file is cut into the data shards, the last one padded with
+// zeros for the parity, and a shard failing its CRC is rebuilt from the others.
+// Damage costs whole shards, so what's guaranteed is a burst: one run of damaged bytes (a bad
+// sector, a torn download) of up to the stored share of the file, which an extra parity shard
+// covers when it straddles two shards. Bytes damaged at scattered places cost a shard each, so
+// only as many places as there are parity shards are repaired
+use std::borrow::Cow;
+use std::ops::Range;
+
+use crate::error::CodecError;
+
+pub const MAGIC: [u8; 4] = *b"RICF";
+pub const VERSION: u8 = 1;
+const HEAD_LEN: usize = MAGIC.len() + 1;
+const PARAMS_LEN: usize = 4 + 4 + 4 + 8;
+const CRC_LEN: usize = 4;
+// Magic, version and both copies of the parameters
+pub const PARAMS_END: usize = HEAD_LEN + 2 * (PARAMS_LEN + CRC_LEN);
+// Shards of smaller files, fewer take more of the file each
+const MIN_SHARD_LEN: usize = 1024;
+// Data and parity shards together, of Reed-Solomon over GF(256)
+const MAX_SHARDS: usize = 256;
+
+#[derive(Clone, Copy)]
+struct Params {
+    shard_len: usize,
+    data: usize,
+    parity: usize,
+    len: usize,
+}
+
+// Shards as read, None for those failing their CRC
+type Shards = Vec<Option<Vec<u8>>>;
+
+// Damaged shards of a wrapped file, repaired or not
+pub struct Repair {
+    pub damaged: usize,
+    pub shards: usize,
+    // Most shards the parity can rebuild
+    pub parity: usize,
+}
+
+pub fn is_wrapped(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+// Data and parity shards of a file of len bytes, parity percent of the data shards and one more
+// for a burst across a shard boundary
+fn params(len: usize, percent: u8) -> Params {
+    let parity = |data: usize| (data * percent as usize).div_ceil(100) + 1;
+    let mut data = len.div_ceil(MIN_SHARD_LEN).max(1);
+    while data + parity(data) > MAX_SHARDS {
+        data -= 1;
+    }
+    let shard_len = len.div_ceil(data).max(1);
+    let data = len.div_ceil(shard_len).max(1);
+    Params {
+        shard_len,
+        data,
+        parity: parity(data),
+        len,
+    }
+}
+
+fn write_params(params: Params, out: &mut Vec<u8>) {
+    let start = out.len();
+    out.extend_from_slice(&(params.shard_len as u32).to_le_bytes());
+    out.extend_from_slice(&(params.data as u32).to_le_bytes());
+    out.extend_from_slice(&(params.parity as u32).to_le_bytes());
+    out.extend_from_slice(&(params.len as u64).to_le_bytes());
+    let crc = crc32fast::hash(&out[start..]);
+    out.extend_from_slice(&crc.to_le_bytes());
+}
+
+// The file wrapped with parity shards of percent (1 to 100) of its data shards
+pub fn wrap(bytes: &[u8], percent: u8) -> Result<Vec<u8>, CodecError> {
+    if !(1..=100).contains(&percent) {
+        return Err(CodecError::InvalidInput(
+            "forward error correction should be 1% to 100%".to_string(),
+        ));
+    }
+    let params = params(bytes.len(), percent);
+    if params.shard_len > u32::MAX as usize {
+        return Err(CodecError::InvalidInput(
+            "too large for forward error correction, its shards would exceed 4GiB".to_string(),
+        ));
+    }
+    wrap_with(bytes, params)
+}
+
+fn wrap_with(bytes: &[u8], params: Params) -> Result<Vec<u8>, CodecError> {
+    let mut shards = bytes
+        .chunks(params.shard_len)
+        .map(|chunk| {
+            let mut shard = chunk.to_vec();
+            shard.resize(params.shard_len, 0);
+            shard
+        })
+        .collect::<Vec<_>>();
+    shards.resize(params.data + params.parity, vec![0u8; params.shard_len]);
+    encode(&mut shards, params)?;
+    Ok(write(params, &shards))
+}
+
+// A wrapped file holding another file in place of its own, with the shards it had when the
+// length is the same (rekey changes a file's bytes, not its length) and at least as much
+// parity otherwise
+pub fn rewrap(bytes: &[u8], file: &[u8]) -> Result<Vec<u8>, CodecError> {
+    let params = read_params(bytes)?;
+    if file.len() == params.len {
+        return wrap_with(file, params);
+    }
+    let percent = ((params.parity - 1) * 100)
+        .div_ceil(params.data)
+        .clamp(1, 100);
+    wrap(file, percent as u8)
+}
+
+#[cfg(feature = "fec")]
+fn encode(shards: &mut [Vec<u8>], params: Params) -> Result<(), CodecError> {
+    use reed_solomon_erasure::galois_8::ReedSolomon;
+
+    ReedSolomon::new(params.data, params.parity)
+        .and_then(|codec| codec.encode(shards))
+        .map_err(|err| CodecError::InvalidInput(format!("forward error correction: {}", err)))
+}
+
+#[cfg(not(feature = "fec"))]
+fn encode(_shards: &mut [Vec<u8>], _params: Params) -> Result<(), CodecError> {
+    Err(CodecError::FeatureDisabled("fec"))
+}
+
+#[cfg(feature = "fec")]
+fn reconstruct(shards: &mut [Option<Vec<u8>>], params: Params) -> Result<(), CodecError> {
+    use reed_solomon_erasure::galois_8::ReedSolomon;
+
+    ReedSolomon::new(params.data, params.parity)
+        .and_then(|codec| codec.reconstruct(shards))
+        .map_err(|err| CodecError::BadData(format!("forward error correction: {}", err)))
+}
+
+#[cfg(not(feature = "fec"))]
+fn reconstruct(_shards: &mut [Option<Vec<u8>>], _params: Params) -> Result<(), CodecError> {
+    Err(CodecError::FeatureDisabled("fec"))
+}
+
+// Wrapped file of whole shards, the data shards stored up to the file's length
+fn write(params: Params, shards: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(
+        PARAMS_END
+            + 2 * (shards.len() + 1) * CRC_LEN
+            + params.len
+            + params.parity * params.shard_len,
+    );
+    out.extend_from_slice(&MAGIC);
+    out.push(VERSION);
+    write_params(params, &mut out);
+    write_params(params, &mut out);
+    let mut table = shards
+        .iter()
+        .flat_map(|shard| crc32fast::hash(shard).to_le_bytes())
+        .collect::<Vec<_>>();
+    table.extend_from_slice(&crc32fast::hash(&table).to_le_bytes());
+    out.extend_from_slice(&table);
+    out.extend_from_slice(&table);
+    let mut left = params.len;
+    for shard in &shards[..params.data] {
+        out.extend_from_slice(&shard[..left.min(params.shard_len)]);
+        left = left.saturating_sub(params.shard_len);
+    }
+    for shard in &shards[params.data..] {
+        out.extend_from_slice(shard);
+    }
+    out
+}
+
+fn corrupt(reason: &str) -> CodecError {
+    CodecError::BadHeader(format!("protected with --fec, but {}", reason))
+}
+
+// The first of the copies at start, each len bytes and a CRC32 of them, that checks out
+fn checked_copy(bytes: &[u8], start: usize, len: usize) -> Option<&[u8]> {
+    (0..2).find_map(|copy| {
+        let at = start + copy * (len + CRC_LEN);
+        let copy = bytes.get(at..at + len)?;
+        let crc = bytes.get(at + len..at + len + CRC_LEN)?;
+        (crc32fast::hash(copy).to_le_bytes() == crc).then_some(copy)
+    })
+}
+
+fn read_params(bytes: &[u8]) -> Result<Params, CodecError> {
+    if bytes.get(MAGIC.len()) != Some(&VERSION) {
+        return Err(corrupt("of an unknown version"));
+    }
+    let fields = checked_copy(bytes, HEAD_LEN, PARAMS_LEN)
+        .ok_or_else(|| corrupt("both copies of its parameters are damaged"))?;
+    let u32_at = |at: usize| u32::from_le_bytes(fields[at..at + 4].try_into().unwrap()) as usize;
+    let params = Params {
+        shard_len: u32_at(0),
+        data: u32_at(4),
+        parity: u32_at(8),
+        len: u64::from_le_bytes(fields[12..].try_into().unwrap()) as usize,
+    };
+    let count = params.data + params.parity;
+    if params.shard_len == 0
+        || params.data == 0
+        || count > MAX_SHARDS
+        || params.len.div_ceil(params.shard_len) != params.data
+    {
+        return Err(corrupt("its parameters don't fit together"));
+    }
+    Ok(params)
+}
+
+// Where the file is in a wrapped one, from its first PARAMS_END bytes; decode --stream reads it
+// from there as stored, without repairing it
+pub fn file_range(head: &[u8]) -> Result<Range<usize>, CodecError> {
+    let params = read_params(head)?;
+    let start = PARAMS_END + 2 * (params.data + params.parity + 1) * CRC_LEN;
+    Ok(start..start + params.len)
+}
+
+// Parameters and shards of a wrapped file
+fn read(bytes: &[u8]) -> Result<(Params, Shards), CodecError> {
+    let params = read_params(bytes)?;
+    let count = params.data + params.parity;
+    let table = checked_copy(bytes, PARAMS_END, count * CRC_LEN)
+        .ok_or_else(|| corrupt("both copies of its shard checksums are damaged"))?;
+    let mut at = file_range(bytes)?.start;
+    let shards = table
+        .chunks_exact(CRC_LEN)
+        .enumerate()
+        .map(|(i, crc)| {
+            let len = if i < params.data {
+                params.shard_len.min(params.len - i * params.shard_len)
+            } else {
+                params.shard_len
+            };
+            let mut shard = bytes.get(at..at + len)?.to_vec();
+            at += len;
+            shard.resize(params.shard_len, 0);
+            (crc32fast::hash(&shard).to_le_bytes() == crc).then_some(shard)
+        })
+        .collect::<Vec<_>>();
+    Ok((params, shards))
+}
+
+// Shards of a wrapped file with the damaged ones rebuilt
+fn repaired(bytes: &[u8]) -> Result<(Params, Vec<Vec<u8>>, Repair), CodecError> {
+    let (params, mut shards) = read(bytes)?;
+    let repair = Repair {
+        damaged: shards.iter().filter(|shard| shard.is_none()).count(),
+        shards: shards.len(),
+        parity: params.parity,
+    };
+    if repair.damaged > repair.parity {
+        return Err(CodecError::BadData(format!(
+            "{} of its {} forward error correction shards are damaged, its parity rebuilds {} at most",
+            repair.damaged, repair.shards, repair.parity
+        )));
+    }
+    if repair.damaged > 0 {
+        reconstruct(&mut shards, params)?;
+    }
+    let shards = shards.into_iter().map(Option::unwrap).collect();
+    Ok((params, shards, repair))
+}
+
+// The file a wrapped one holds, its damaged shards repaired, and the bytes as they are when
+// they aren't wrapped
+pub fn unwrap(bytes: &[u8]) -> Result<Cow<'_, [u8]>, CodecError> {
+    if !is_wrapped(bytes) {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    let (params, shards, _) = repaired(bytes)?;
+    let mut file = shards[..params.data].concat();
+    file.truncate(params.len);
+    Ok(Cow::Owned(file))
+}
+
+// A wrapped file with its damaged shards repaired, parity shards included, and what was damaged
+pub fn repair(bytes: &[u8]) -> Result<(Vec<u8>, Repair), CodecError> {
+    if !is_wrapped(bytes) {
+        return Err(CodecError::InvalidInput(
+            "not protected with --fec, missing the RICF magic".to_string(),
+        ));
+    }
+    let (params, shards, repair) = repaired(bytes)?;
+    Ok((write(params, &shards), repair))
+}
+
+// Longest run of damaged bytes the parity always rebuilds, wherever it starts
+fn max_burst(params: Params) -> usize {
+    (params.parity - 1) * params.shard_len
+}
+
+// "forward error correction, 8 data and 2 parity shards of 926 bytes, 1 damaged, repairs a
+// burst of 926 bytes or 2 damaged shards" for inspect
+pub fn describe(bytes: &[u8]) -> String {
+    match read(bytes) {
+        Ok((params, shards)) => format!(
+            "forward error correction, {} data and {} parity shards of {} bytes, {} damaged, repairs a burst of {} bytes or {} damaged shards",
+            params.data,
+            params.parity,
+            params.shard_len,
+            shards.iter().filter(|shard| shard.is_none()).count(),
+            max_burst(params),
+            params.parity
+        ),
+        Err(err) => err.to_string(),
+    }
+}
+
+// Damaged shards of a wrapped file, which verify reports without repairing them
+pub fn check(bytes: &[u8]) -> Result<Repair, CodecError> {
+    let (params, shards) = read(bytes)?;
+    Ok(Repair {
+        damaged: shards.iter().filter(|shard| shard.is_none()).count(),
+        shards: shards.len(),
+        parity: params.parity,
+    })
+}
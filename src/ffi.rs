@@ -0,0 +1,172 @@
+// C bindings, include/rust_image_codec.h is their header as the build script generates it; the
+// ffi package builds them into a shared library
+use std::{
+    cell::RefCell,
+    ffi::{CString, c_char, c_int},
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use crate::{Decoder, Encoder, error::CodecError};
+
+pub const RIC_OK: c_int = 0;
+/// Null pointer, wrong buffer length, palette size or dimensions out of range
+pub const RIC_ERR_INVALID_INPUT: c_int = 1;
+pub const RIC_ERR_KEY: c_int = 2;
+/// Unsupported version, header or pixels data doesn't match, wrong compression
+pub const RIC_ERR_DATA: c_int = 3;
+pub const RIC_ERR_COMPRESSION: c_int = 4;
+pub const RIC_ERR_PANIC: c_int = 5;
+
+/// Bytes allocated by the library, release them with ric_free
+#[repr(C)]
+pub struct RicBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+fn error_code(err: &CodecError) -> c_int {
+    match err {
+        CodecError::InvalidInput(_)
+        | CodecError::Io { .. }
+        | CodecError::Image { .. }
+        | CodecError::FeatureDisabled(_) => RIC_ERR_INVALID_INPUT,
+        CodecError::BadKey(_) => RIC_ERR_KEY,
+        CodecError::UnsupportedVersion(_)
+        | CodecError::BadHeader(_)
+        | CodecError::WrongOptions { .. }
+        | CodecError::BadData(_) => RIC_ERR_DATA,
+        CodecError::Compression(_) | CodecError::Decompression(_) => RIC_ERR_COMPRESSION,
+    }
+}
+
+// Runs a call with the error message kept for ric_last_error, panics don't cross into C
+fn guard(call: impl FnOnce() -> Result<(), CodecError>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(call)) {
+        Ok(Ok(())) => RIC_OK,
+        Ok(Err(err)) => {
+            let code = error_code(&err);
+            set_error(err.to_string());
+            code
+        }
+        Err(_) => {
+            set_error("internal error".to_string());
+            RIC_ERR_PANIC
+        }
+    }
+}
+
+// Borrowed input bytes, null with zero length is an empty slice
+unsafe fn input<'a>(data: *const u8, len: usize, name: &str) -> Result<&'a [u8], CodecError> {
+    match (data.is_null(), len) {
+        (true, 0) => Ok(&[]),
+        (true, _) => Err(CodecError::InvalidInput(format!("{} is null", name))),
+        (false, _) => Ok(unsafe { slice::from_raw_parts(data, len) }),
+    }
+}
+
+fn output(bytes: Vec<u8>) -> RicBuffer {
+    let len = bytes.len();
+    let data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+    RicBuffer { data, len }
+}
+
+/// Encodes width * height * 3 bytes of RGB rows without padding into out.
+/// key is 16, 24 or 32 bytes or null for no encryption, compress is 0 or 1.
+/// Returns RIC_OK or an error code, see ric_last_error.
+///
+/// # Safety
+/// rgb must point to rgb_len readable bytes, key to key_len bytes when not null,
+/// out to a writable RicBuffer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ric_encode(
+    rgb: *const u8,
+    rgb_len: usize,
+    width: u32,
+    height: u32,
+    palette_size: u32,
+    key: *const u8,
+    key_len: usize,
+    compress: c_int,
+    out: *mut RicBuffer,
+) -> c_int {
+    guard(|| {
+        if out.is_null() {
+            return Err(CodecError::InvalidInput("out is null".to_string()));
+        }
+        let rgb = unsafe { input(rgb, rgb_len, "rgb") }?;
+        let mut encoder = Encoder::new()
+            .palette_size(palette_size as usize)
+            .compression(compress != 0);
+        if !key.is_null() {
+            encoder = encoder.key(unsafe { input(key, key_len, "key") }?);
+        }
+        let encoded = encoder.encode_raw(rgb, width, height)?;
+        unsafe { out.write(output(encoded)) };
+        Ok(())
+    })
+}
+
+/// Decodes a file into RGB rows without padding, compression is detected.
+/// key is the one the file was encoded with or null.
+/// Returns RIC_OK or an error code, see ric_last_error.
+///
+/// # Safety
+/// data must point to data_len readable bytes, key to key_len bytes when not null,
+/// out, width and height to writable values.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ric_decode(
+    data: *const u8,
+    data_len: usize,
+    key: *const u8,
+    key_len: usize,
+    out: *mut RicBuffer,
+    width: *mut u32,
+    height: *mut u32,
+) -> c_int {
+    guard(|| {
+        if out.is_null() || width.is_null() || height.is_null() {
+            return Err(CodecError::InvalidInput(
+                "out, width or height is null".to_string(),
+            ));
+        }
+        let data = unsafe { input(data, data_len, "data") }?;
+        let mut decoder = Decoder::new();
+        if !key.is_null() {
+            decoder = decoder.key(unsafe { input(key, key_len, "key") }?);
+        }
+        let (rgb, decoded_width, decoded_height) = decoder.decode_to_vec(data)?;
+        unsafe {
+            out.write(output(rgb));
+            width.write(decoded_width);
+            height.write(decoded_height);
+        }
+        Ok(())
+    })
+}
+
+/// Releases a buffer filled by ric_encode or ric_decode, a null buffer is ignored.
+///
+/// # Safety
+/// buffer must come from this library and not be freed before.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ric_free(buffer: RicBuffer) {
+    if !buffer.data.is_null() {
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(buffer.data, buffer.len)) });
+    }
+}
+
+/// Message of the last error on the calling thread, valid until the next call on it.
+#[unsafe(no_mangle)]
+pub extern "C" fn ric_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
@@ -0,0 +1,431 @@
+// Files the binary reads and writes: "-" paths, atomic outputs, encode inputs, key files and
+// the info of encoded files
+use image::{DynamicImage, ImageBuffer, ImageDecoder, ImageFormat, ImageReader, Rgb};
+use rust_image_codec::{
+    CodecError, bare, chunks, compression,
+    detect::detect,
+    fec, format, keys, log, metadata,
+    utils::{
+        Embedded, base64url_to_bytes, bytes_to_base64url, json_escape, raw_img, save_png,
+        write_stdout,
+    },
+};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Write, stdout};
+
+// Runs write on a temporary sibling of path (see create_temp) and renames it over path once
+// complete, so an error or crash never leaves a truncated output; "-" (stdout) is written
+// directly. An existing path is only replaced with force
+pub fn write_atomically(
+    path: &str,
+    force: bool,
+    write: impl FnOnce(&str) -> Result<(), CodecError>,
+) -> Result<(), CodecError> {
+    if path == "-" {
+        return write(path);
+    }
+    check_output(path, force)?;
+    let tmp = create_temp(path)?;
+    let result = write(&tmp).and_then(|()| {
+        std::fs::rename(&tmp, path).map_err(|source| CodecError::Io {
+            path: path.to_string(),
+            source,
+        })
+    });
+    if result.is_err() {
+        _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+// Creates an empty "<path>.<pid>-<n>.tmp" for the first n no file has the name of yet, so a file
+// of the user's is never truncated, renamed over path or removed in its place
+fn create_temp(path: &str) -> Result<String, CodecError> {
+    let mut attempt = 0u32;
+    loop {
+        let tmp = format!("{}.{}-{}.tmp", path, std::process::id(), attempt);
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)
+        {
+            Ok(_) => return Ok(tmp),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(source) => {
+                return Err(CodecError::Io {
+                    path: path.to_string(),
+                    source,
+                });
+            }
+        }
+    }
+}
+
+// Refuses an existing output without force, "-" (stdout) never exists
+pub fn check_output(path: &str, force: bool) -> Result<(), CodecError> {
+    if force || path == "-" || !std::path::Path::new(path).exists() {
+        return Ok(());
+    }
+    Err(CodecError::InvalidInput(format!(
+        "{} already exists, pass --force to overwrite it",
+        path
+    )))
+}
+
+// "-" writes to stdout, files are replaced atomically and only with force
+pub fn write_file(bytes: &[u8], output_file_path: &str, force: bool) -> Result<(), CodecError> {
+    write_atomically(output_file_path, force, |path| {
+        let written = if path == "-" {
+            write_stdout(bytes)
+        } else {
+            std::fs::File::create(path).and_then(|mut file| {
+                file.write_all(bytes)?;
+                file.sync_all()
+            })
+        };
+        written.map_err(|source| CodecError::Io {
+            path: output_file_path.to_string(),
+            source,
+        })
+    })
+}
+
+// PNG of img with the ICC profile and EXIF of the source, written as write_file does
+pub fn write_png(
+    img: DynamicImage,
+    output_file_path: &str,
+    embedded: &Embedded,
+    force: bool,
+) -> Result<(), CodecError> {
+    write_atomically(output_file_path, force, |path| {
+        save_png(img, path, embedded).map_err(|source| CodecError::Image {
+            path: output_file_path.to_string(),
+            source,
+        })
+    })
+}
+
+pub fn open_img(path: &str) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, image::ImageError> {
+    let img: ImageBuffer<Rgb<u8>, Vec<u8>> = image::ImageReader::open(path)?.decode()?.into_rgb8();
+    Ok(img)
+}
+
+// The image crate's decoder for the format, ICC profile and EXIF included
+fn decode_embedded<R: BufRead + std::io::Seek>(
+    reader: ImageReader<R>,
+) -> Result<(DynamicImage, Embedded), image::ImageError> {
+    let mut decoder = reader.into_decoder()?;
+    let icc = decoder.icc_profile()?;
+    let exif = decoder.exif_metadata()?;
+    let img = DynamicImage::from_decoder(decoder)?;
+    Ok((img, Embedded { icc, exif }))
+}
+
+// How encode input bytes are read
+#[derive(Clone, Copy)]
+pub enum InputFormat {
+    // Anything the image crate detects (PNG, JPEG, PNM, ...)
+    Image,
+    Pnm,
+    // Headerless RGB bytes with the given width and height
+    Raw(u32, u32),
+}
+
+impl InputFormat {
+    pub fn parse(format: &str, size: Option<&str>) -> Result<Self, String> {
+        match (format, size) {
+            ("image", None) => Ok(InputFormat::Image),
+            ("pnm", None) => Ok(InputFormat::Pnm),
+            ("raw", Some(size)) => size
+                .split_once('x')
+                .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+                .map(|(w, h)| InputFormat::Raw(w, h))
+                .ok_or(format!("invalid size {}, expected WxH", size)),
+            ("raw", None) => Err("raw input requires --size WxH".to_string()),
+            ("image" | "pnm", Some(_)) => Err("--size is only used with raw input".to_string()),
+            _ => Err(format!(
+                "unknown input format {}, expected image, pnm or raw",
+                format
+            )),
+        }
+    }
+}
+
+// "-" reads stdin; the image keeps its source color type, raw input is RGB and embeds nothing
+pub fn read_img(path: &str, format: InputFormat) -> Result<(DynamicImage, Embedded), CodecError> {
+    let image_error = |source| CodecError::Image {
+        path: path.to_string(),
+        source,
+    };
+    if let (InputFormat::Image, false) = (format, path == "-") {
+        let reader = ImageReader::open(path)
+            .map_err(image::ImageError::IoError)
+            .and_then(|reader| {
+                reader
+                    .with_guessed_format()
+                    .map_err(image::ImageError::IoError)
+            });
+        return reader.and_then(decode_embedded).map_err(image_error);
+    }
+    let bytes = read_input(path).map_err(|source| CodecError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    match format {
+        InputFormat::Raw(width, height) => raw_img(bytes, width, height)
+            .map(|img| (DynamicImage::ImageRgb8(img), Embedded::default()))
+            .map_err(|err| CodecError::InvalidInput(format!("{}: {}", path, err))),
+        InputFormat::Pnm => decode_embedded(ImageReader::with_format(
+            Cursor::new(bytes),
+            ImageFormat::Pnm,
+        ))
+        .map_err(image_error),
+        InputFormat::Image => ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .map_err(image::ImageError::IoError)
+            .and_then(decode_embedded)
+            .map_err(image_error),
+    }
+}
+
+pub struct Info {
+    pub path: String,
+    pub version: u8,
+    pub width: u32,
+    pub height: u32,
+    // 0 for truecolor files, which have no palette
+    pub palette_size: usize,
+    // Guessed processing state
+    pub state: String,
+    // User metadata entries (and cipher, icc, ...), binary values shown by their size
+    pub metadata: Vec<(String, String)>,
+}
+
+impl Info {
+    pub fn to_json(&self) -> String {
+        let metadata = self
+            .metadata
+            .iter()
+            .map(|(key, value)| format!("\"{}\":\"{}\"", json_escape(key), json_escape(value)))
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"path\":\"{}\",\"version\":{},\"width\":{},\"height\":{},\"palette_size\":{},\"state\":\"{}\",\"metadata\":{{{}}}}}",
+            json_escape(&self.path),
+            self.version,
+            self.width,
+            self.height,
+            self.palette_size,
+            json_escape(&self.state),
+            metadata.join(","),
+        )
+    }
+}
+
+impl std::fmt::Display for Info {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: version: {}, width: {}, height: {}, palette_size: {}, state: {}",
+            self.path, self.version, self.width, self.height, self.palette_size, self.state,
+        )?;
+        for (key, value) in &self.metadata {
+            write!(f, "\n  {}: {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+// "-" stands for stdin
+pub fn open_input(path: &str) -> std::io::Result<Box<dyn BufRead>> {
+    if path == "-" {
+        return Ok(Box::new(std::io::stdin().lock()));
+    }
+    Ok(Box::new(BufReader::new(std::fs::File::open(path)?)))
+}
+
+// "-" stands for stdout
+pub fn create_output(path: &str) -> std::io::Result<Box<dyn Write>> {
+    if path == "-" {
+        return Ok(Box::new(BufWriter::new(stdout().lock())));
+    }
+    Ok(Box::new(BufWriter::new(std::fs::File::create(path)?)))
+}
+
+// "-" stands for stdin
+pub fn read_input(path: &str) -> std::io::Result<Vec<u8>> {
+    if path == "-" {
+        let mut bytes = Vec::new();
+        std::io::stdin().read_to_end(&mut bytes)?;
+        return Ok(bytes);
+    }
+    std::fs::read(path)
+}
+
+pub fn get_info(path: &str) -> Result<Info, CodecError> {
+    let bytes = read_input(path).map_err(|source| CodecError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    bytes_info(path, bytes)
+}
+
+// Entries the codec keeps for itself (authentication tag, tweak marker, wrapped keys, block
+// checksums), left out of info as their bytes tell a reader nothing the state doesn't
+const INTERNAL_KEYS: [&str; 4] = [
+    keys::AUTH_KEY,
+    keys::HEADER_TWEAK_KEY,
+    keys::KEY_SLOTS_KEY,
+    chunks::KEY,
+];
+
+// Info of a file's bytes, path only labels it
+pub fn bytes_info(path: &str, mut bytes: Vec<u8>) -> Result<Info, CodecError> {
+    if fec::is_wrapped(&bytes) {
+        bytes = fec::unwrap(&bytes)?.into_owned();
+    }
+    let detection = detect(&bytes);
+    if detection.compressed {
+        bytes = compression::decompress(&bytes)?;
+    }
+    format::prefix_len(&bytes)?;
+    let version = format::version(&bytes);
+    let (width, height, _) = bare::dimensions(&bytes)?;
+    let (palette_size, _) = bare::palette_size(&bytes)?;
+    // A broken section is left out, the state tells the file is invalid
+    let metadata = bare::parse_header(&bytes)
+        .ok()
+        .and_then(|header| metadata::parse(&bytes[header.metadata]).ok())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(key, _)| !INTERNAL_KEYS.contains(&key.as_str()))
+        .map(|(key, value)| {
+            let value = metadata::display_value(&value);
+            (key, value)
+        })
+        .collect();
+    Ok(Info {
+        path: path.to_string(),
+        version,
+        width,
+        height,
+        palette_size: match format::layout_flags(&bytes) & format::LAYOUT_TRUECOLOR {
+            0 => palette_size,
+            _ => 0,
+        },
+        state: detection.describe(),
+        metadata,
+    })
+}
+
+// photo.png -> photo.ric on encode, photo.ric -> photo.png on decode
+pub fn default_output(input: &str, encode: bool) -> Option<String> {
+    if input == "-" {
+        return None;
+    }
+    let path = std::path::Path::new(input);
+    path.file_stem()?;
+    Some(
+        path.with_extension(if encode { "ric" } else { "png" })
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+// Refuses to overwrite as check_output does, key file is readable by owner only
+pub fn write_key_file(key: &str, path: &str) -> Result<(), CodecError> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", key));
+    match written {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            Err(CodecError::InvalidInput(format!(
+                "{} already exists, keys are never overwritten",
+                path
+            )))
+        }
+        written => written.map_err(|source| CodecError::Io {
+            path: path.to_string(),
+            source,
+        }),
+    }
+}
+
+// Keys of --key-name live in the platform secret store (Keychain, Secret Service, Windows
+// Credential Manager) as entries of this service named after them
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "rust_image-codec";
+
+#[cfg(feature = "keyring")]
+fn keyring_entry(name: &str) -> std::io::Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, name).map_err(std::io::Error::other)
+}
+
+// Refuses to overwrite, like write_key_file
+#[cfg(feature = "keyring")]
+pub fn store_keyring_key(key: &str, name: &str) -> std::io::Result<()> {
+    let entry = keyring_entry(name)?;
+    match entry.get_password() {
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "a key of this name is in the keyring already",
+        )),
+        Err(keyring::Error::NoEntry) => entry.set_password(key).map_err(std::io::Error::other),
+        Err(err) => Err(std::io::Error::other(err)),
+    }
+}
+
+// Base64url key stored with keygen --key-name
+#[cfg(feature = "keyring")]
+pub fn read_keyring_key(name: &str) -> std::io::Result<String> {
+    let key = keyring_entry(name)?
+        .get_password()
+        .map_err(|err| match err {
+            keyring::Error::NoEntry => std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no key of this name in the keyring, store one with keygen --key-name",
+            ),
+            err => std::io::Error::other(err),
+        })?;
+    if !base64url_to_bytes(key.trim()).is_some_and(|key| [16, 24, 32].contains(&key.len())) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "the keyring entry isn't a base64url key of 16, 24 or 32 bytes",
+        ));
+    }
+    Ok(key.trim().to_string())
+}
+
+// Base64url text (as written by keygen --out) or the raw 16, 24 or 32 key bytes,
+// warns when the file is world-readable
+pub fn read_key_file(path: &str) -> std::io::Result<String> {
+    let bytes = std::fs::read(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if std::fs::metadata(path)?.permissions().mode() & 0o004 != 0 {
+            log::warning(format_args!(
+                "{} is world-readable, restrict it with chmod 600",
+                path
+            ));
+        }
+    }
+    let key_len = |len: usize| [16, 24, 32].contains(&len);
+    if let Ok(text) = std::str::from_utf8(&bytes)
+        && base64url_to_bytes(text.trim()).is_some_and(|key| key_len(key.len()))
+    {
+        return Ok(text.trim().to_string());
+    }
+    if key_len(bytes.len()) {
+        return Ok(bytes_to_base64url(&bytes));
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "expected a base64url key or 16, 24 or 32 raw bytes",
+    ))
+}
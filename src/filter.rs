@@ -0,0 +1,110 @@
+// PNG-style row filters of the pixels data (layout flag LAYOUT_FILTERED). Every row of packed
+// indices starts with its filter type byte and stores each byte minus a prediction from the
+// byte to its left (of the previous index for 16 bit indices) and the one above it, which
+// leaves runs of small values on gradients for zstd to squeeze
+use alloc::vec::Vec;
+
+use crate::bare::Error;
+
+pub const NONE: u8 = 0;
+pub const SUB: u8 = 1;
+pub const UP: u8 = 2;
+pub const AVERAGE: u8 = 3;
+pub const PAETH: u8 = 4;
+
+// Bytes from a byte to the same byte of the index to its left
+pub fn bpp(index_bits: u32) -> usize {
+    index_bits.div_ceil(8) as usize
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (to_left, to_up, to_up_left) = (
+        (estimate - left as i16).abs(),
+        (estimate - up as i16).abs(),
+        (estimate - up_left as i16).abs(),
+    );
+    if to_left <= to_up && to_left <= to_up_left {
+        left
+    } else if to_up <= to_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+// Prediction of byte i of a row from the unfiltered bytes before it and the previous row, which
+// is all zeros above the first row
+fn predict(filter: u8, row: &[u8], prev: &[u8], i: usize, bpp: usize) -> u8 {
+    let left = if i >= bpp { row[i - bpp] } else { 0 };
+    let up_left = if i >= bpp { prev[i - bpp] } else { 0 };
+    match filter {
+        SUB => left,
+        UP => prev[i],
+        AVERAGE => ((left as u16 + prev[i] as u16) / 2) as u8,
+        PAETH => paeth(left, prev[i], up_left),
+        _ => 0,
+    }
+}
+
+// Appends the filter type byte and the filtered bytes of a row, of the filter leaving the
+// smallest sum of bytes read as signed, as libpng picks them
+pub fn filter_row(row: &[u8], prev: &[u8], bpp: usize, out: &mut Vec<u8>) {
+    let filtered = |filter| {
+        (0..row.len()).map(move |i| row[i].wrapping_sub(predict(filter, row, prev, i, bpp)))
+    };
+    let cost = |filter| {
+        filtered(filter)
+            .map(|byte| (byte as i8).unsigned_abs() as u64)
+            .sum::<u64>()
+    };
+    let best = [NONE, SUB, UP, AVERAGE, PAETH]
+        .into_iter()
+        .min_by_key(|&filter| cost(filter))
+        .unwrap_or(NONE);
+    out.push(best);
+    out.extend(filtered(best));
+}
+
+// Undoes filter_row in place, bytes before i are already unfiltered when byte i is
+pub fn unfilter_row(filter: u8, row: &mut [u8], prev: &[u8], bpp: usize) -> Result<(), Error> {
+    if filter > PAETH {
+        return Err(Error::UnknownFilter(filter));
+    }
+    for i in 0..row.len() {
+        row[i] = row[i].wrapping_add(predict(filter, row, prev, i, bpp));
+    }
+    Ok(())
+}
+
+// Filtered pixels data of whole rows of row_len bytes, prev is the row above the first one
+// (zeros above the first row of the image) and is left holding the last one
+pub fn filter(data: &[u8], row_len: usize, bpp: usize, prev: &mut [u8]) -> Vec<u8> {
+    if row_len == 0 {
+        return Vec::new();
+    }
+    let mut out = Vec::with_capacity(data.len() + data.len() / row_len);
+    for row in data.chunks_exact(row_len) {
+        filter_row(row, prev, bpp, &mut out);
+        prev.copy_from_slice(row);
+    }
+    out
+}
+
+// Packed pixels data of filtered whole rows, of row_len bytes after their filter type byte;
+// prev as in filter
+pub fn unfilter(
+    data: &[u8],
+    row_len: usize,
+    bpp: usize,
+    prev: &mut [u8],
+) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(data.len());
+    for row in data.chunks_exact(row_len + 1) {
+        let start = out.len();
+        out.extend_from_slice(&row[1..]);
+        unfilter_row(row[0], &mut out[start..], prev, bpp)?;
+        prev.copy_from_slice(&out[start..]);
+    }
+    Ok(out)
+}
@@ -0,0 +1,645 @@
+#[cfg(feature = "std")]
+use crate::{
+    ZSTD_MAGIC, bare, compression,
+    detect::{self, State},
+    error::CodecError,
+};
+#[cfg(feature = "std")]
+use alloc::borrow::Cow;
+
+// Files start with the magic and the version byte since v2 and a flags byte since v3,
+// v1 files start right with the packed dimensions
+pub const MAGIC: [u8; 4] = *b"RIC1";
+// v4 only stores the dimensions as u32 instead of 12 bits each and v5 adds wider and packed
+// palette indices, so files are written in the oldest version which holds them, which older
+// builds read
+pub const VERSION: u8 = 5;
+pub const V4: u8 = 4;
+pub const V3: u8 = 3;
+// Widest and tallest image v3 holds, its dimensions are stored minus 2
+pub const V3_MAX_DIMENSION: u32 = 4097;
+pub const PREFIX_LEN: usize = MAGIC.len() + 2;
+// Prefix of v2 files, without the flags byte
+pub const V2_PREFIX_LEN: usize = MAGIC.len() + 1;
+// Prefix of v5 files, the flags byte is followed by the layout flags byte and the bits per
+// index, with the RLE, tiled or seek layout flag by the stored length of the pixels data and
+// with the tiled one then by the tile size
+pub const V5_PREFIX_LEN: usize = PREFIX_LEN + 2;
+pub const STORED_LEN_LEN: usize = 8;
+pub const TILE_SIZE_LEN: usize = 2;
+// Largest palette, of 16 bit indices; indices are a byte before v5, so larger palettes than 256
+// colors are written as v5
+pub const MAX_PALETTE_SIZE: usize = 1 << 16;
+
+// The file was written inside a zstd frame. Decoding goes by the frame's own magic, as the
+// flag is only readable once decompressed, it's kept for info and inspect
+pub const FLAG_COMPRESSED: u8 = 1;
+// A key check follows the palette (and the key block) and pixels data is encrypted
+pub const FLAG_ENCRYPTED: u8 = 1 << 1;
+// A recipients or key slots block follows the palette
+pub const FLAG_KEY_BLOCK: u8 = 1 << 2;
+// A CRC32 of everything before it ends the file
+pub const FLAG_CHECKSUM: u8 = 1 << 3;
+// A metadata section follows the palette, see metadata.rs
+pub const FLAG_METADATA: u8 = 1 << 4;
+// Palette entries are RGBA, 4 bytes instead of 3
+pub const FLAG_ALPHA: u8 = 1 << 5;
+// One palette index is fully transparent (GIF-style), its byte follows the palette
+pub const FLAG_TRANSPARENT: u8 = 1 << 6;
+// Palette entries are one luma byte. Every bit of the flags byte is taken now, further
+// flags need a new version
+pub const FLAG_GRAY: u8 = 1 << 7;
+// Layout flags of v5 files, how the pixels data is laid out. Every row starts with a PNG
+// filter type byte, see filter.rs
+pub const LAYOUT_FILTERED: u8 = 1;
+// The (filtered) pixels data is run-length encoded, see rle.rs
+pub const LAYOUT_RLE: u8 = 1 << 1;
+// Two bits, the order the pixels' indices are serialized in, see scan.rs
+pub const LAYOUT_SCAN: u8 = 0b11 << 2;
+// Square tiles with a palette each, see tiles.rs
+pub const LAYOUT_TILED: u8 = 1 << 4;
+// Adam7 passes instead of rows, see interlace.rs
+pub const LAYOUT_INTERLACED: u8 = 1 << 5;
+// Row offsets before the rows, each row filtered and run-length encoded on its own, see seek.rs
+pub const LAYOUT_SEEK: u8 = 1 << 6;
+// Pixels are stored as their channel bytes instead of palette indices, the index bits byte is 8
+// per channel and the header holds no palette entries, see Encoder::lossless. Every bit of the
+// layout flags byte is taken now, further layouts need a new version
+pub const LAYOUT_TRUECOLOR: u8 = 1 << 7;
+// Layout flags of files whose header holds the stored length of the pixels data
+pub const LAYOUT_STORED_LEN: u8 = LAYOUT_RLE | LAYOUT_TILED | LAYOUT_SEEK;
+// Little endian CRC32 (IEEE) of the uncompressed file, taken after encryption so files can be
+// checked without the key
+pub const CHECKSUM_LEN: usize = 4;
+
+pub fn prefix(version: u8, flags: u8) -> [u8; PREFIX_LEN] {
+    let mut prefix = [0u8; PREFIX_LEN];
+    prefix[..MAGIC.len()].copy_from_slice(&MAGIC);
+    prefix[MAGIC.len()] = version;
+    prefix[MAGIC.len() + 1] = flags;
+    prefix
+}
+
+// Version the encoder writes an image in: v3 unless it's too large or (below 2 pixels) too
+// small for it, then v4, and v5 for indices of other than 8 bits or a layout flag
+pub fn version_for(width: u32, height: u32, index_bits: u32, layout: u8) -> u8 {
+    let fits = |dimension| (2..=V3_MAX_DIMENSION).contains(&dimension);
+    if index_bits != 8 || layout != 0 {
+        VERSION
+    } else if fits(width) && fits(height) {
+        V3
+    } else {
+        V4
+    }
+}
+
+// Fewest bits per palette index a palette size takes
+pub fn index_bits_for(palette_size: usize) -> u32 {
+    match palette_size {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        17..=256 => 8,
+        _ => 16,
+    }
+}
+
+// Bits per palette index in the pixels data, 1, 2, 4, 8 or 16 (little endian) since v5, 8
+// before
+pub fn index_bits(bytes: &[u8]) -> u32 {
+    if version(bytes) >= VERSION {
+        bytes.get(PREFIX_LEN + 1).map_or(8, |&bits| bits as u32)
+    } else {
+        8
+    }
+}
+
+// Layout flags byte of a v5 file, 0 (rows of indices only) before
+pub fn layout_flags(bytes: &[u8]) -> u8 {
+    if version(bytes) >= VERSION {
+        bytes.get(PREFIX_LEN).copied().unwrap_or(0)
+    } else {
+        0
+    }
+}
+
+// Prefix length of a v5 file of these layout flags
+pub fn v5_prefix_len(layout: u8) -> usize {
+    let stored_len = layout & LAYOUT_STORED_LEN != 0;
+    let tiled = layout & LAYOUT_TILED != 0;
+    V5_PREFIX_LEN + stored_len as usize * STORED_LEN_LEN + tiled as usize * TILE_SIZE_LEN
+}
+
+// Stored length of the pixels data of a run-length encoded, tiled or seekable file, a little
+// endian u64 after the index bits byte; None for other files, whose pixels data length follows
+// from the header
+pub fn stored_len(bytes: &[u8]) -> Option<usize> {
+    if layout_flags(bytes) & LAYOUT_STORED_LEN == 0 {
+        return None;
+    }
+    let len = bytes.get(V5_PREFIX_LEN..V5_PREFIX_LEN + STORED_LEN_LEN)?;
+    Some(u64::from_le_bytes(len.try_into().unwrap()) as usize)
+}
+
+// Side of the tiles of a tiled file, a little endian u16 after the stored length
+pub fn tile_size(bytes: &[u8]) -> Option<u32> {
+    if layout_flags(bytes) & LAYOUT_TILED == 0 {
+        return None;
+    }
+    let at = V5_PREFIX_LEN + STORED_LEN_LEN;
+    let size = bytes.get(at..at + TILE_SIZE_LEN)?;
+    Some(u16::from_le_bytes([size[0], size[1]]) as u32)
+}
+
+// Dimensions as the version stores them, see bare::dimensions
+pub fn dimensions_bytes(version: u8, width: u32, height: u32) -> alloc::vec::Vec<u8> {
+    if version < V4 {
+        let packed = (width - 2) << 12 | (height - 2);
+        return packed.to_be_bytes()[1..].to_vec();
+    }
+    let mut bytes = width.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&height.to_le_bytes());
+    bytes
+}
+
+// Palette size as the version stores it, minus 2 in a byte or, since v5, a little endian u16
+pub fn palette_size_bytes(version: u8, palette_size: usize) -> alloc::vec::Vec<u8> {
+    if version < VERSION {
+        return alloc::vec![(palette_size - 2) as u8];
+    }
+    ((palette_size - 2) as u16).to_le_bytes().to_vec()
+}
+
+// Bytes of a row of pixels data, indices of less than 8 bits are packed and every row starts
+// on a byte as in PNG
+pub fn row_len(width: u32, index_bits: u32) -> usize {
+    (width as usize)
+        .saturating_mul(index_bits as usize)
+        .div_ceil(8)
+}
+
+// Bytes a row takes in the pixels data, with its filter type byte when filtered
+pub fn stride(width: u32, index_bits: u32, layout: u8) -> usize {
+    row_len(width, index_bits) + (layout & LAYOUT_FILTERED != 0) as usize
+}
+
+// Bytes of the pixels data of an image, before the key block and key check, and before
+// run-length encoding; the row offsets of seekable files included
+pub fn data_len(width: u32, height: u32, index_bits: u32, layout: u8) -> usize {
+    if layout & LAYOUT_INTERLACED != 0 {
+        return crate::interlace::data_len(width, height, index_bits, layout);
+    }
+    let table_len = if layout & LAYOUT_SEEK != 0 {
+        crate::seek::table_len(height)
+    } else {
+        0
+    };
+    stride(width, index_bits, layout)
+        .saturating_mul(height as usize)
+        .saturating_add(table_len)
+}
+
+// Offset of a pixel's index from the start of the pixels data, of its filtered byte when
+// filtered
+pub fn index_offset(pixel: usize, width: u32, index_bits: u32, layout: u8) -> usize {
+    let (y, x) = (pixel / width as usize, pixel % width as usize);
+    y * stride(width, index_bits, layout)
+        + (layout & LAYOUT_FILTERED != 0) as usize
+        + x * index_bits as usize / 8
+}
+
+// Pixels data of the palette indices of whole rows; packed indices fill bytes from the high
+// bits down
+pub fn pack_indices(indices: &[u16], width: u32, index_bits: u32) -> alloc::vec::Vec<u8> {
+    match index_bits {
+        16 => indices
+            .iter()
+            .flat_map(|index| index.to_le_bytes())
+            .collect(),
+        8 => indices.iter().map(|&index| index as u8).collect(),
+        _ => {
+            let per_byte = (8 / index_bits) as usize;
+            let mut data = alloc::vec::Vec::with_capacity(
+                row_len(width, index_bits) * indices.len() / (width as usize).max(1),
+            );
+            for row in indices.chunks(width as usize) {
+                data.extend(row.chunks(per_byte).map(|byte| {
+                    byte.iter().enumerate().fold(0u8, |packed, (i, &index)| {
+                        packed | (index as u8) << (8 - index_bits as usize * (i + 1))
+                    })
+                }));
+            }
+            data
+        }
+    }
+}
+
+// Palette indices of the whole rows in pixels data, row padding is dropped
+pub fn unpack_indices(data: &[u8], width: u32, index_bits: u32) -> alloc::vec::Vec<u16> {
+    match index_bits {
+        16 => data
+            .chunks_exact(2)
+            .map(|index| u16::from_le_bytes([index[0], index[1]]))
+            .collect(),
+        8 => data.iter().map(|&index| index as u16).collect(),
+        _ => {
+            let row_len = row_len(width, index_bits);
+            if row_len == 0 {
+                return alloc::vec::Vec::new();
+            }
+            let mask = (1u8 << index_bits) - 1;
+            let mut indices = alloc::vec::Vec::with_capacity(data.len() / row_len * width as usize);
+            for row in data.chunks_exact(row_len) {
+                indices.extend((0..width as usize).map(|x| {
+                    let bit = x * index_bits as usize;
+                    (row[bit / 8] >> (8 - index_bits as usize - bit % 8) & mask) as u16
+                }));
+            }
+            indices
+        }
+    }
+}
+
+// Palette index of a pixel in the packed indices of whole rows, as unpack_indices reads it
+pub fn packed_index(data: &[u8], pixel: usize, width: u32, index_bits: u32) -> u16 {
+    let (y, x) = (pixel / width as usize, pixel % width as usize);
+    let row = &data[y * row_len(width, index_bits)..];
+    match index_bits {
+        16 => u16::from_le_bytes([row[x * 2], row[x * 2 + 1]]),
+        8 => row[x] as u16,
+        _ => {
+            let bit = x * index_bits as usize;
+            let mask = (1u8 << index_bits) - 1;
+            (row[bit / 8] >> (8 - index_bits as usize - bit % 8) & mask) as u16
+        }
+    }
+}
+
+// Flags byte of a v3 or later file, None for older versions, which have to be guessed from
+// their bytes
+pub fn flags(bytes: &[u8]) -> Option<u8> {
+    if version(bytes) >= V3 {
+        bytes.get(MAGIC.len() + 1).copied()
+    } else {
+        None
+    }
+}
+
+// Bytes per palette entry
+pub fn channels(bytes: &[u8]) -> usize {
+    match flags(bytes) {
+        Some(flags) if flags & FLAG_ALPHA != 0 => 4,
+        Some(flags) if flags & FLAG_GRAY != 0 => 1,
+        _ => 3,
+    }
+}
+
+// Bytes of the transparent index, which is as wide as the indices of 16 bits
+pub fn transparent_len(bytes: &[u8]) -> usize {
+    match flags(bytes) {
+        Some(flags) if flags & FLAG_TRANSPARENT != 0 => index_bits(bytes).div_ceil(8) as usize,
+        _ => 0,
+    }
+}
+
+// Entries the header holds of a palette of palette_size entries, none in tiled files, whose
+// palette size is that of the largest tile palette (2 at least) and whose tiles hold their own,
+// and in truecolor ones, which have no palette
+pub fn palette_entries(bytes: &[u8], palette_size: usize) -> usize {
+    if layout_flags(bytes) & (LAYOUT_TILED | LAYOUT_TRUECOLOR) != 0 {
+        0
+    } else {
+        palette_size
+    }
+}
+
+// Bytes of a palette of palette_size entries, with the transparent index after it
+pub fn palette_len(bytes: &[u8], palette_size: usize) -> usize {
+    palette_entries(bytes, palette_size) * channels(bytes) + transparent_len(bytes)
+}
+
+// Ends the file with the checksum of its bytes
+pub fn append_checksum(bytes: &mut alloc::vec::Vec<u8>) {
+    let checksum = crc32fast::hash(bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+}
+
+// Length of the bytes the checksum footer (if any) covers
+pub fn content_len(bytes: &[u8]) -> usize {
+    match flags(bytes) {
+        Some(flags) if flags & FLAG_CHECKSUM != 0 => bytes.len().saturating_sub(CHECKSUM_LEN),
+        _ => bytes.len(),
+    }
+}
+
+// Names of the set flags, for info and inspect
+#[cfg(feature = "std")]
+pub fn describe_flags(flags: u8) -> String {
+    let names = [
+        (FLAG_COMPRESSED, "compressed"),
+        (FLAG_ENCRYPTED, "encrypted"),
+        (FLAG_KEY_BLOCK, "key block"),
+        (FLAG_CHECKSUM, "checksum"),
+        (FLAG_METADATA, "metadata"),
+        (FLAG_ALPHA, "alpha"),
+        (FLAG_TRANSPARENT, "transparent index"),
+        (FLAG_GRAY, "gray"),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags & flag != 0)
+    .map(|(_, name)| *name)
+    .collect::<Vec<_>>();
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+// Names of the set layout flags, for inspect
+#[cfg(feature = "std")]
+pub fn describe_layout(layout: u8) -> String {
+    let names = [
+        (LAYOUT_FILTERED, "filtered rows"),
+        (LAYOUT_RLE, "run-length encoded"),
+        (LAYOUT_TILED, "tiled"),
+        (LAYOUT_INTERLACED, "adam7 interlaced"),
+        (LAYOUT_SEEK, "row seek table"),
+        (LAYOUT_TRUECOLOR, "truecolor"),
+    ]
+    .iter()
+    .filter(|(flag, _)| layout & flag != 0)
+    .map(|(_, name)| name.to_string())
+    .chain(
+        (layout & LAYOUT_SCAN != 0)
+            .then(|| format!("{} scan", crate::scan::Scan::from_layout(layout).name())),
+    )
+    .collect::<Vec<_>>();
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+// Version of the file, v1 when there's no magic
+pub fn version(bytes: &[u8]) -> u8 {
+    if bytes.starts_with(&MAGIC) {
+        bytes.get(MAGIC.len()).copied().unwrap_or(0)
+    } else {
+        1
+    }
+}
+
+// Length of the bytes before the v1 layout, see bare::prefix_len
+#[cfg(feature = "std")]
+pub fn prefix_len(bytes: &[u8]) -> Result<usize, CodecError> {
+    // A container would be read as a v1 file
+    if crate::container::is_container(bytes) {
+        return Err(CodecError::BadHeader(
+            "a container of images, list them with list and take one out with extract".to_string(),
+        ));
+    }
+    Ok(bare::prefix_len(bytes)?)
+}
+
+// Same as prefix_len, but a file without the magic is only read as v1 when legacy is set,
+// otherwise any non-.ric input would be decoded as noise. Containers get their own error there
+#[cfg(feature = "std")]
+pub fn checked_prefix_len(bytes: &[u8], legacy: bool) -> Result<usize, CodecError> {
+    if !legacy && !bytes.starts_with(&MAGIC) && !crate::container::is_container(bytes) {
+        return Err(CodecError::BadHeader(
+            "missing the RIC1 magic, not a .ric file or a format version 1 one (pass --legacy)"
+                .to_string(),
+        ));
+    }
+    prefix_len(bytes)
+}
+
+// Rewraps a file of a version before v3 into the latest version without touching the palette or
+// pixels data; returns the version it had. Its flags are taken from what the file looks like and
+// its metadata section records the version it was upgraded from. Encrypted files need their key
+// for their cipher entry and authentication tag, see upgrade_with_key. v3 and later files have
+// the magic, flags, checksum and metadata already and are returned as they are
+#[cfg(feature = "std")]
+pub fn upgrade(bytes: &[u8]) -> Result<(Vec<u8>, u8), CodecError> {
+    upgrade_with(bytes, None)
+}
+
+// Same as upgrade() for encrypted files, whose key is checked against their key check and makes
+// their authentication tag. Encrypted v1 files of the first builds have no key check (see
+// codec::unchecked_v1): their pixels data is decrypted with key as threads threads did and
+// encrypted again in segments after a key check
+#[cfg(feature = "std")]
+pub fn upgrade_with_key(
+    bytes: &[u8],
+    key: &str,
+    threads: usize,
+) -> Result<(Vec<u8>, u8), CodecError> {
+    upgrade_with(bytes, Some((key, threads)))
+}
+
+#[cfg(feature = "std")]
+fn upgrade_with(bytes: &[u8], key: Option<(&str, usize)>) -> Result<(Vec<u8>, u8), CodecError> {
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        let decompressed = compression::decompress(bytes)?;
+        if version(&decompressed) >= V3 {
+            return Ok((bytes.to_vec(), version(&decompressed)));
+        }
+        let (upgraded, version) = rewrap(&decompressed, FLAG_COMPRESSED, key)?;
+        return Ok((
+            compression::compress(&upgraded, compression::DEFAULT_LEVEL)?,
+            version,
+        ));
+    }
+    if version(bytes) >= V3 {
+        return Ok((bytes.to_vec(), version(bytes)));
+    }
+    rewrap(bytes, 0, key)
+}
+
+#[cfg(feature = "std")]
+fn rewrap(
+    original: &[u8],
+    flags: u8,
+    key: Option<(&str, usize)>,
+) -> Result<(Vec<u8>, u8), CodecError> {
+    use crate::{keys, metadata};
+    use hmac::Mac;
+
+    let bytes = match key {
+        Some((key, threads)) => crate::codec::checked_v1(original, key, threads)?,
+        None => Cow::Borrowed(original),
+    };
+    let state = detect::detect_state(&bytes);
+    let encrypted = match state {
+        State::Plain => false,
+        State::Encrypted { key_block_len: 0 } => true,
+        State::Encrypted { .. } => {
+            return Err(CodecError::InvalidInput(
+                "its data key is in a key block, decode it and encode it again to upgrade it"
+                    .to_string(),
+            ));
+        }
+        State::EncryptedLegacy => {
+            return Err(CodecError::BadKey(
+                "pixels data looks encrypted without a key check, upgrading it needs its key"
+                    .to_string(),
+            ));
+        }
+        State::Invalid(reason) => return Err(CodecError::BadHeader(reason)),
+    };
+    let (width, height, _) = bare::dimensions(&bytes)?;
+    let (palette_size, palette_start) = bare::palette_size(&bytes)?;
+    // Key check (if encrypted) and pixels data, as they are
+    let rest = &bytes[palette_start + palette_size * 3..];
+    let mut entries = vec![(
+        metadata::UPGRADED_FROM_KEY.to_string(),
+        version(original).to_string().into_bytes(),
+    )];
+    let byte_key = match (encrypted, key) {
+        (false, _) => None,
+        (true, None) => {
+            return Err(CodecError::BadKey(
+                "file is encrypted, upgrading it needs its key for its authentication tag"
+                    .to_string(),
+            ));
+        }
+        (true, Some((key, _))) => {
+            let byte_key = keys::check_key(key)?;
+            if rest[..keys::KEY_CHECK_LEN] != keys::key_check(&byte_key) {
+                return Err(CodecError::BadKey("wrong key".to_string()));
+            }
+            let cipher = keys::cipher_name(keys::Fpe::Ff1, byte_key.len()).unwrap();
+            entries.push((keys::CIPHER_KEY.to_string(), cipher.into_bytes()));
+            Some(byte_key)
+        }
+    };
+    let flags = flags | FLAG_CHECKSUM | FLAG_METADATA | if encrypted { FLAG_ENCRYPTED } else { 0 };
+    let mut upgraded = Vec::with_capacity(V5_PREFIX_LEN + 20 + bytes.len() + CHECKSUM_LEN);
+    upgraded.extend_from_slice(&prefix(VERSION, flags));
+    upgraded.extend_from_slice(&[0, 8]);
+    upgraded.extend_from_slice(&dimensions_bytes(VERSION, width, height));
+    upgraded.extend_from_slice(&palette_size_bytes(VERSION, palette_size));
+    upgraded.extend_from_slice(&bytes[palette_start..palette_start + palette_size * 3]);
+    if let Some(byte_key) = byte_key {
+        let mut mac = keys::authenticator(&byte_key, &upgraded, &entries);
+        mac.update(rest);
+        let tag = keys::auth_tag(mac).to_vec();
+        entries.push((keys::AUTH_KEY.to_string(), tag));
+    }
+    upgraded.extend_from_slice(&metadata::write(&entries));
+    upgraded.extend_from_slice(rest);
+    append_checksum(&mut upgraded);
+    Ok((upgraded, version(original)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const INDEX_BITS: [u32; 5] = [1, 2, 4, 8, 16];
+
+    fn indices(width: u32, height: u32, index_bits: u32) -> Vec<u16> {
+        let max = (1u32 << index_bits) - 1;
+        (0..width * height)
+            .map(|i| (i.wrapping_mul(2_654_435_761) % (max + 1)) as u16)
+            .collect()
+    }
+
+    // Widths ending rows on, just past and just short of a byte boundary
+    #[test]
+    fn pack_round_trip() {
+        for index_bits in INDEX_BITS {
+            for width in 1..=17 {
+                for height in [1, 3] {
+                    let indices = indices(width, height, index_bits);
+                    let data = pack_indices(&indices, width, index_bits);
+                    assert_eq!(data.len(), row_len(width, index_bits) * height as usize);
+                    assert_eq!(
+                        unpack_indices(&data, width, index_bits),
+                        indices,
+                        "{} bits, width {}",
+                        index_bits,
+                        width
+                    );
+                    for (pixel, &index) in indices.iter().enumerate() {
+                        assert_eq!(packed_index(&data, pixel, width, index_bits), index);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty() {
+        for index_bits in INDEX_BITS {
+            assert!(pack_indices(&[], 5, index_bits).is_empty());
+            assert!(unpack_indices(&[], 5, index_bits).is_empty());
+        }
+    }
+
+    // High bits first, every row starts on a byte and ends padded with zeros
+    #[test]
+    fn packed_bits_at_row_ends() {
+        assert_eq!(pack_indices(&[1, 0, 1], 3, 1), [0b1010_0000]);
+        assert_eq!(
+            pack_indices(&[1, 1, 1, 1, 1, 1, 1, 1, 1, 0], 5, 1),
+            [0b1111_1000, 0b1111_0000]
+        );
+        assert_eq!(pack_indices(&[3, 2, 1], 3, 2), [0b1110_0100]);
+        assert_eq!(
+            pack_indices(&[3, 2, 1, 0, 3, 0, 1, 2, 3, 1], 5, 2),
+            [0b1110_0100, 0b1100_0000, 0b0001_1011, 0b0100_0000]
+        );
+        assert_eq!(pack_indices(&[15, 1, 7], 1, 4), [0xf0, 0x10, 0x70]);
+        assert_eq!(pack_indices(&[0xabc], 1, 16), [0xbc, 0x0a]);
+    }
+
+    // Padding bits are dropped, not read as pixels
+    #[test]
+    fn unpack_ignores_padding() {
+        assert_eq!(unpack_indices(&[0b1011_1111], 3, 1), [1, 0, 1]);
+        assert_eq!(unpack_indices(&[0xff, 0x0f], 3, 4), [15, 15, 0]);
+    }
+
+    #[test]
+    fn row_lengths() {
+        assert_eq!(row_len(0, 1), 0);
+        assert_eq!(row_len(1, 1), 1);
+        assert_eq!(row_len(8, 1), 1);
+        assert_eq!(row_len(9, 1), 2);
+        assert_eq!(row_len(5, 2), 2);
+        assert_eq!(row_len(3, 4), 2);
+        assert_eq!(row_len(3, 16), 6);
+        assert_eq!(stride(3, 4, LAYOUT_FILTERED), 3);
+        assert_eq!(data_len(9, 2, 1, LAYOUT_FILTERED), 6);
+    }
+
+    #[test]
+    fn index_offsets() {
+        assert_eq!(index_offset(7, 9, 1, 0), 0);
+        assert_eq!(index_offset(8, 9, 1, 0), 1);
+        assert_eq!(index_offset(9, 9, 1, 0), 2);
+        assert_eq!(index_offset(9, 9, 1, LAYOUT_FILTERED), 4);
+        assert_eq!(index_offset(3, 4, 16, 0), 6);
+    }
+
+    #[test]
+    fn index_bits_of_palette_sizes() {
+        for (palette_size, bits) in [
+            (2, 1),
+            (3, 2),
+            (4, 2),
+            (5, 4),
+            (16, 4),
+            (17, 8),
+            (256, 8),
+            (257, 16),
+            (MAX_PALETTE_SIZE, 16),
+        ] {
+            assert_eq!(index_bits_for(palette_size), bits, "{}", palette_size);
+        }
+    }
+}
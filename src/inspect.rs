@@ -0,0 +1,441 @@
+use std::fmt::Write;
+
+use crate::{ZSTD_MAGIC, bare, compression, fec, format, interlace, metadata, seek, tiles};
+
+const BYTES_PER_LINE: usize = 16;
+// Pixels data lines shown without --full
+const PREVIEW_LINES: usize = 8;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn swatch(rgb: &[u8], color: bool) -> String {
+    if color {
+        format!(" \x1b[48;2;{};{};{}m    \x1b[0m", rgb[0], rgb[1], rgb[2])
+    } else {
+        String::new()
+    }
+}
+
+// The first lines of bytes, all of them without a limit
+fn dump(out: &mut String, bytes: &[u8], offset: usize, lines: Option<usize>) {
+    let shown = lines.map_or(bytes.len(), |lines| {
+        lines.saturating_mul(BYTES_PER_LINE).min(bytes.len())
+    });
+    for (i, line) in bytes[..shown].chunks(BYTES_PER_LINE).enumerate() {
+        let ascii = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        _ = writeln!(
+            out,
+            "{:08x}  {:<width$}  |{}|",
+            offset + i * BYTES_PER_LINE,
+            hex(line),
+            ascii,
+            width = BYTES_PER_LINE * 3 - 1
+        );
+    }
+    if shown < bytes.len() {
+        _ = writeln!(out, "          ... {} more bytes", bytes.len() - shown);
+    }
+}
+
+// Annotated hex view of the header, palette and pixels data boundaries
+pub fn inspect(bytes: &[u8], color: bool, full: bool) -> String {
+    let mut out = String::new();
+    if fec::is_wrapped(bytes) {
+        _ = writeln!(out, "{}", fec::describe(bytes));
+        return match fec::unwrap(bytes) {
+            Ok(file) => {
+                _ = writeln!(out, "protected file, {} bytes:", file.len());
+                out + &inspect(&file, color, full)
+            }
+            Err(err) => {
+                _ = writeln!(out, "{}", err);
+                out
+            }
+        };
+    }
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        _ = writeln!(out, "zstd frame, {} bytes compressed", bytes.len());
+        return match compression::decompress(bytes) {
+            Ok(decompressed) => {
+                _ = writeln!(out, "decompressed content, {} bytes:", decompressed.len());
+                out + &inspect(&decompressed, color, full)
+            }
+            Err(err) => {
+                _ = writeln!(out, "{}", err);
+                out
+            }
+        };
+    }
+    let base = match format::prefix_len(bytes) {
+        Ok(prefix_len) => prefix_len,
+        Err(err) => {
+            _ = writeln!(out, "{}", err);
+            dump(&mut out, bytes, 0, Some(PREVIEW_LINES));
+            return out;
+        }
+    };
+    if base > 0 {
+        _ = writeln!(
+            out,
+            "{:08x}  {:<23}  magic \"{}\"",
+            0,
+            hex(&format::MAGIC),
+            String::from_utf8_lossy(&format::MAGIC)
+        );
+        _ = writeln!(
+            out,
+            "{:08x}  {:<23}  format version: {}",
+            format::MAGIC.len(),
+            hex(&bytes[format::MAGIC.len()..format::V2_PREFIX_LEN]),
+            format::version(bytes)
+        );
+        if let Some(flags) = format::flags(bytes) {
+            _ = writeln!(
+                out,
+                "{:08x}  {:<23}  flags: {}",
+                format::V2_PREFIX_LEN,
+                hex(&[flags]),
+                format::describe_flags(flags)
+            );
+        }
+        if base >= format::V5_PREFIX_LEN {
+            let layout = format::layout_flags(bytes);
+            _ = writeln!(
+                out,
+                "{:08x}  {:<23}  layout flags: {}",
+                format::PREFIX_LEN,
+                hex(&[layout]),
+                format::describe_layout(layout)
+            );
+            _ = writeln!(
+                out,
+                "{:08x}  {:<23}  index bits: {}",
+                format::PREFIX_LEN + 1,
+                hex(&bytes[format::PREFIX_LEN + 1..format::V5_PREFIX_LEN]),
+                format::index_bits(bytes)
+            );
+        }
+        if let Some(stored_len) = format::stored_len(bytes) {
+            let end = format::V5_PREFIX_LEN + format::STORED_LEN_LEN;
+            _ = writeln!(
+                out,
+                "{:08x}  {:<23}  stored pixels data: {} bytes (little endian u64)",
+                format::V5_PREFIX_LEN,
+                hex(&bytes[format::V5_PREFIX_LEN..end]),
+                stored_len
+            );
+        }
+        if let Some(tile_size) = format::tile_size(bytes) {
+            let at = format::V5_PREFIX_LEN + format::STORED_LEN_LEN;
+            _ = writeln!(
+                out,
+                "{:08x}  {:<23}  tile size: {} (little endian u16)",
+                at,
+                hex(&bytes[at..base]),
+                tile_size
+            );
+        }
+    } else {
+        _ = writeln!(out, "no magic: format version 1");
+    }
+    let file_len = bytes.len();
+    let content_len = format::content_len(bytes).max(base);
+    let (content, footer) = bytes.split_at(content_len);
+    let bytes = &content[base..];
+    let Ok((width, height, palette_size_at)) = bare::dimensions(content) else {
+        _ = writeln!(out, "truncated header, {} bytes:", bytes.len());
+        dump(&mut out, bytes, base, None);
+        return out;
+    };
+    let (width, height) = (width as usize, height as usize);
+    let dimensions_len = palette_size_at - base;
+    let Ok((palette_size, palette_start)) = bare::palette_size(content) else {
+        _ = writeln!(out, "truncated header, {} bytes:", bytes.len());
+        dump(&mut out, bytes, base, None);
+        return out;
+    };
+    let palette_start = palette_start - base;
+    _ = writeln!(
+        out,
+        "{:08x}  {:<23}  dimensions: {}x{} ({})",
+        base,
+        hex(&bytes[..dimensions_len]),
+        width,
+        height,
+        if dimensions_len == 3 {
+            "12 bits each, stored minus 2"
+        } else {
+            "little endian u32 each"
+        }
+    );
+    let truecolor = format::layout_flags(content) & format::LAYOUT_TRUECOLOR != 0;
+    _ = writeln!(
+        out,
+        "{:08x}  {:<23}  palette size: {}{} (stored minus 2{})",
+        palette_size_at,
+        hex(&bytes[dimensions_len..palette_start]),
+        palette_size,
+        if format::tile_size(content).is_some() {
+            " of the largest tile palette"
+        } else if truecolor {
+            ", unused by truecolor pixels"
+        } else {
+            ""
+        },
+        if palette_start - dimensions_len > 1 {
+            ", little endian u16"
+        } else {
+            ""
+        }
+    );
+    // RGBA entries with the alpha flag, printed as #rrggbbaa, gray ones as #rrggbb
+    let channels = format::channels(content);
+    let entries = format::palette_entries(content, palette_size);
+    let mut palette_end = palette_start + entries * channels;
+    let encrypted_palette = bare::metadata_range(content)
+        .ok()
+        .and_then(|range| metadata::parse(content.get(range)?).ok())
+        .is_some_and(|entries| {
+            entries
+                .iter()
+                .any(|(key, _)| key == metadata::ENCRYPTED_PALETTE_KEY)
+        });
+    if format::tile_size(content).is_some() {
+        _ = writeln!(
+            out,
+            "          no palette entries, every tile holds its own"
+        );
+    } else if truecolor {
+        _ = writeln!(
+            out,
+            "          no palette entries, pixels are stored as their colors"
+        );
+    } else {
+        _ = writeln!(
+            out,
+            "{:08x}  palette, {} entries{}:",
+            base + palette_start,
+            entries,
+            if encrypted_palette {
+                ", encrypted (encode --encrypt-palette)"
+            } else {
+                ""
+            }
+        );
+    }
+    for (idx, entry) in bytes[palette_start..palette_end.min(bytes.len())]
+        .chunks(channels)
+        .enumerate()
+    {
+        if entry.len() < channels {
+            _ = writeln!(
+                out,
+                "{:08x}  {:<23}  truncated entry",
+                base + palette_start + idx * channels,
+                hex(entry)
+            );
+            break;
+        }
+        if encrypted_palette {
+            _ = writeln!(
+                out,
+                "{:08x}  {:<23}  {:>3}: encrypted",
+                base + palette_start + idx * channels,
+                hex(entry),
+                idx
+            );
+            continue;
+        }
+        let rgb = if channels == 1 {
+            [entry[0]; 3].to_vec()
+        } else {
+            entry.to_vec()
+        };
+        _ = writeln!(
+            out,
+            "{:08x}  {:<23}  {:>3}: #{}{}",
+            base + palette_start + idx * channels,
+            hex(entry),
+            idx,
+            rgb.iter().map(|c| format!("{:02x}", c)).collect::<String>(),
+            swatch(&rgb, color)
+        );
+    }
+    if palette_end > bytes.len() {
+        _ = writeln!(out, "file ends inside the palette");
+        return out;
+    }
+    let transparent_len = format::transparent_len(content);
+    if transparent_len > 0 {
+        let Some(index) = bytes.get(palette_end..palette_end + transparent_len) else {
+            _ = writeln!(out, "file ends before the transparent index");
+            return out;
+        };
+        _ = writeln!(
+            out,
+            "{:08x}  {:<23}  transparent index: {}",
+            base + palette_end,
+            hex(index),
+            match *index {
+                [index] => index as u16,
+                _ => u16::from_le_bytes([index[0], index[1]]),
+            }
+        );
+        palette_end += transparent_len;
+    }
+    let data_start = match bare::metadata_range(content) {
+        // No section, the range starts after its length otherwise
+        Ok(range) if range.start == base + palette_end => palette_end,
+        Ok(range) if range.end <= content.len() => {
+            _ = writeln!(
+                out,
+                "{:08x}  {:<23}  metadata, {} bytes:",
+                base + palette_end,
+                hex(&content[range.start - 4..range.start]),
+                range.len()
+            );
+            match metadata::parse(&content[range.clone()]) {
+                Ok(entries) => {
+                    for (key, value) in entries {
+                        _ = writeln!(
+                            out,
+                            "          {}: {}",
+                            key,
+                            metadata::display_value(&value)
+                        );
+                    }
+                }
+                Err(err) => _ = writeln!(out, "          {}", err),
+            }
+            range.end - base
+        }
+        _ => {
+            _ = writeln!(out, "file ends inside the metadata");
+            return out;
+        }
+    };
+    let data = &bytes[data_start..];
+    let pixels = width * height;
+    let unpacked_len = format::data_len(
+        width as u32,
+        height as u32,
+        format::index_bits(content),
+        format::layout_flags(content),
+    );
+    let data_len = format::stored_len(content).unwrap_or(unpacked_len);
+    _ = writeln!(
+        out,
+        "{:08x}  pixels data, {} bytes for {} pixels",
+        base + data_start,
+        data.len(),
+        pixels
+    );
+    if format::layout_flags(content) & format::LAYOUT_RLE != 0 {
+        _ = writeln!(
+            out,
+            "          run-length encoded, {} bytes decoded",
+            unpacked_len
+        );
+    }
+    if truecolor {
+        _ = writeln!(
+            out,
+            "          {} bytes per pixel, its channels, instead of a palette index",
+            channels
+        );
+    }
+    let layout = format::layout_flags(content);
+    if layout & format::LAYOUT_INTERLACED != 0 {
+        let (width, height, index_bits) =
+            (width as u32, height as u32, format::index_bits(content));
+        let passes = (0..interlace::PASSES)
+            .map(|pass| {
+                let (pass_width, pass_height) = interlace::pass_dimensions(pass, width, height);
+                let len = interlace::pass_len(pass, width, height, index_bits, layout);
+                format!("{}x{} {} bytes", pass_width, pass_height, len)
+            })
+            .collect::<Vec<_>>();
+        _ = writeln!(
+            out,
+            "          adam7 passes, one after another: {}",
+            passes.join(", ")
+        );
+    }
+    if layout & format::LAYOUT_SEEK != 0 {
+        _ = writeln!(
+            out,
+            "          {} row offsets (little endian u64) first, {} bytes, each row stored on its own",
+            height,
+            seek::table_len(height as u32)
+        );
+    }
+    match format::tile_size(content) {
+        Some(size) if !(tiles::MIN_SIZE..=tiles::MAX_SIZE).contains(&size) => {
+            _ = writeln!(out, "          invalid tile size {}", size);
+        }
+        Some(size) => {
+            let (across, down) = tiles::grid(width as u32, height as u32, size);
+            _ = writeln!(
+                out,
+                "          {} tiles of {}x{} ({} across, {} down) with a palette each, after {} bytes of tile offsets (little endian u64)",
+                across * down,
+                size,
+                size,
+                across,
+                down,
+                across * down * tiles::OFFSET_LEN
+            );
+        }
+        None => {}
+    }
+    if data.len() > data_len {
+        _ = writeln!(
+            out,
+            "          {} extra leading bytes: key block and key check of an encrypted file",
+            data.len() - data_len
+        );
+    } else if data.len() < data_len {
+        _ = writeln!(
+            out,
+            "          {} bytes missing: truncated",
+            data_len - data.len()
+        );
+    }
+    dump(
+        &mut out,
+        data,
+        base + data_start,
+        (!full).then_some(PREVIEW_LINES),
+    );
+    if !footer.is_empty() {
+        let actual = crc32fast::hash(content);
+        _ = writeln!(
+            out,
+            "{:08x}  {:<23}  checksum: {}",
+            content_len,
+            hex(footer),
+            match footer.try_into().map(u32::from_le_bytes) {
+                Ok(stored) if stored == actual => format!("{:08x} (ok)", stored),
+                Ok(stored) => format!("{:08x} (mismatch, computed {:08x})", stored, actual),
+                Err(_) => "truncated".to_string(),
+            }
+        );
+    }
+    _ = writeln!(out, "{:08x}  end of file", file_len);
+    out
+}
@@ -0,0 +1,293 @@
+// Adam7 interlaced pixels data (layout flag LAYOUT_INTERLACED): the indices are stored in 7
+// passes over every 8th pixel of every 8th row, then the ones between them, down to the odd
+// rows, as in PNG. Each pass is a small image of its own, packed (and filtered, from zeros above
+// its first row) in rows of its width, so the start of a file already holds a coarse image that
+// later passes refine
+use alloc::vec::Vec;
+
+use crate::bare::Error;
+use crate::filter;
+use crate::format::{
+    LAYOUT_FILTERED, index_offset as raster_offset, pack_indices, row_len, stride, unpack_indices,
+};
+
+pub const PASSES: usize = 7;
+// x and y of the first pixel of each pass and the steps to the next ones
+const PASS: [(u32, u32, u32, u32); PASSES] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+// Pixels a pixel of each pass stands for until the passes after it arrive
+const BLOCK: [(u32, u32); PASSES] = [(8, 8), (4, 8), (4, 4), (2, 4), (2, 2), (1, 2), (1, 1)];
+
+// Width and height of a pass, 0 when the image is too small to have pixels in it
+pub fn pass_dimensions(pass: usize, width: u32, height: u32) -> (u32, u32) {
+    let (x, y, dx, dy) = PASS[pass];
+    (
+        width.saturating_sub(x).div_ceil(dx),
+        height.saturating_sub(y).div_ceil(dy),
+    )
+}
+
+// Bytes of a pass in the pixels data, nothing for empty passes, not even filter type bytes
+pub fn pass_len(pass: usize, width: u32, height: u32, index_bits: u32, layout: u8) -> usize {
+    match pass_dimensions(pass, width, height) {
+        (0, _) | (_, 0) => 0,
+        (pass_width, pass_height) => {
+            stride(pass_width, index_bits, layout).saturating_mul(pass_height as usize)
+        }
+    }
+}
+
+// Bytes of the pixels data of every pass, before run-length encoding
+pub fn data_len(width: u32, height: u32, index_bits: u32, layout: u8) -> usize {
+    (0..PASSES).fold(0usize, |len, pass| {
+        len.saturating_add(pass_len(pass, width, height, index_bits, layout))
+    })
+}
+
+// Raster offset (y * width + x) of every pixel, pass by pass
+pub fn order(width: u32, height: u32) -> Vec<usize> {
+    let mut order = Vec::with_capacity(width as usize * height as usize);
+    for (x0, y0, dx, dy) in PASS {
+        for y in (y0..height).step_by(dy as usize) {
+            for x in (x0..width).step_by(dx as usize) {
+                order.push(y as usize * width as usize + x as usize);
+            }
+        }
+    }
+    order
+}
+
+// Pass of a pixel and its offset in the pass, row by row
+fn locate(pixel: usize, width: u32) -> (usize, usize) {
+    let (x, y) = (
+        (pixel % width as usize) as u32,
+        (pixel / width as usize) as u32,
+    );
+    let pass = PASS
+        .iter()
+        .position(|&(x0, y0, dx, dy)| x % dx == x0 && y % dy == y0)
+        .unwrap_or(PASSES - 1);
+    let (x0, y0, dx, dy) = PASS[pass];
+    let pass_width = width.saturating_sub(x0).div_ceil(dx) as usize;
+    (
+        pass,
+        ((y - y0) / dy) as usize * pass_width + ((x - x0) / dx) as usize,
+    )
+}
+
+// Offset of a pixel's index from the start of the pixels data, of its filtered byte when
+// filtered
+pub fn index_offset(pixel: usize, width: u32, height: u32, index_bits: u32, layout: u8) -> usize {
+    let (pass, position) = locate(pixel, width);
+    let before = (0..pass)
+        .map(|pass| pass_len(pass, width, height, index_bits, layout))
+        .sum::<usize>();
+    let (pass_width, _) = pass_dimensions(pass, width, height);
+    before + raster_offset(position, pass_width, index_bits, layout)
+}
+
+// Pixels data of indices serialized in the order of order, packed pass by pass in rows of the
+// pass's width and filtered when filter is set
+pub fn pack(serialized: &[u16], width: u32, height: u32, index_bits: u32, filter: bool) -> Vec<u8> {
+    let layout = if filter { LAYOUT_FILTERED } else { 0 };
+    let mut data = Vec::with_capacity(data_len(width, height, index_bits, layout));
+    let mut rest = serialized;
+    for pass in 0..PASSES {
+        let (pass_width, pass_height) = pass_dimensions(pass, width, height);
+        let (indices, after) = rest.split_at(pass_width as usize * pass_height as usize);
+        rest = after;
+        if indices.is_empty() {
+            continue;
+        }
+        let packed = pack_indices(indices, pass_width, index_bits);
+        if filter {
+            let row_len = row_len(pass_width, index_bits);
+            let mut prev = alloc::vec![0u8; row_len];
+            data.extend(filter::filter(
+                &packed,
+                row_len,
+                filter::bpp(index_bits),
+                &mut prev,
+            ));
+        } else {
+            data.extend(packed);
+        }
+    }
+    data
+}
+
+// Packed pixels data of (the start of) filtered interlaced pixels data, each pass unfiltered on
+// its own; a row the data ends inside is left out
+pub fn unfilter(data: &[u8], width: u32, height: u32, index_bits: u32) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut rest = data;
+    for pass in 0..PASSES {
+        let len = pass_len(pass, width, height, index_bits, LAYOUT_FILTERED).min(rest.len());
+        let (stored, after) = rest.split_at(len);
+        rest = after;
+        if stored.is_empty() {
+            continue;
+        }
+        let row_len = row_len(pass_dimensions(pass, width, height).0, index_bits);
+        let mut prev = alloc::vec![0u8; row_len];
+        out.extend(filter::unfilter(
+            stored,
+            row_len,
+            filter::bpp(index_bits),
+            &mut prev,
+        )?);
+    }
+    Ok(out)
+}
+
+// Indices in raster order of (the start of) packed interlaced pixels data and the passes it
+// holds in full. When it ends early, every decoded pixel also stands for the pixels of the
+// passes still to come to its right and below, as a coarse preview
+pub fn deinterlace(data: &[u8], width: u32, height: u32, index_bits: u32) -> (Vec<u16>, usize) {
+    let mut indices = alloc::vec![0u16; width as usize * height as usize];
+    let whole = data.len() >= data_len(width, height, index_bits, 0);
+    let mut rest = data;
+    for pass in 0..PASSES {
+        let (pass_width, pass_height) = pass_dimensions(pass, width, height);
+        let row_len = row_len(pass_width, index_bits);
+        if pass_width == 0 || pass_height == 0 {
+            continue;
+        }
+        let rows = (rest.len() / row_len).min(pass_height as usize);
+        let (stored, after) = rest.split_at(rows * row_len);
+        rest = after;
+        let (x0, y0, dx, dy) = PASS[pass];
+        let (block_width, block_height) = if whole { (1, 1) } else { BLOCK[pass] };
+        let pass_indices = unpack_indices(stored, pass_width, index_bits);
+        for (row, row_indices) in pass_indices.chunks_exact(pass_width as usize).enumerate() {
+            let y = y0 + row as u32 * dy;
+            for (column, &index) in row_indices.iter().enumerate() {
+                let x = x0 + column as u32 * dx;
+                for block_y in y..(y + block_height).min(height) {
+                    let start = block_y as usize * width as usize;
+                    indices[start + x as usize..start + (x + block_width).min(width) as usize]
+                        .fill(index);
+                }
+            }
+        }
+        if rows < pass_height as usize {
+            return (indices, pass);
+        }
+    }
+    (indices, PASSES)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan;
+    use alloc::vec;
+
+    const SIZES: [(u32, u32); 7] = [(1, 1), (2, 1), (1, 9), (3, 5), (8, 8), (9, 10), (17, 3)];
+
+    fn indices(width: u32, height: u32, index_bits: u32) -> Vec<u16> {
+        let max = (1u32 << index_bits) - 1;
+        (0..width * height)
+            .map(|i| (i.wrapping_mul(2_654_435_761) % (max + 1)) as u16)
+            .collect()
+    }
+
+    fn interlaced(
+        indices: &[u16],
+        width: u32,
+        height: u32,
+        index_bits: u32,
+        filter: bool,
+    ) -> Vec<u8> {
+        let serialized = scan::serialize(indices, &order(width, height));
+        pack(&serialized, width, height, index_bits, filter)
+    }
+
+    #[test]
+    fn order_is_a_permutation() {
+        for (width, height) in SIZES.into_iter().chain([(0, 0), (0, 4)]) {
+            let mut order = order(width, height);
+            order.sort_unstable();
+            assert_eq!(order, (0..(width * height) as usize).collect::<Vec<_>>());
+        }
+    }
+
+    // A single pixel is all in the first pass, the others are empty and take no bytes
+    #[test]
+    fn one_pixel() {
+        assert_eq!(pass_dimensions(0, 1, 1), (1, 1));
+        for pass in 1..PASSES {
+            assert_eq!(pass_len(pass, 1, 1, 8, LAYOUT_FILTERED), 0);
+        }
+        assert_eq!(data_len(1, 1, 4, LAYOUT_FILTERED), 2);
+        assert_eq!(interlaced(&[5], 1, 1, 4, false), [0x50]);
+        assert_eq!(deinterlace(&[0x50], 1, 1, 4), (vec![5], PASSES));
+    }
+
+    #[test]
+    fn round_trip() {
+        for index_bits in [1, 2, 4, 8, 16] {
+            for (width, height) in SIZES {
+                let indices = indices(width, height, index_bits);
+                let data = interlaced(&indices, width, height, index_bits, false);
+                assert_eq!(data.len(), data_len(width, height, index_bits, 0));
+                assert_eq!(
+                    deinterlace(&data, width, height, index_bits),
+                    (indices.clone(), PASSES),
+                    "{} bits, {}x{}",
+                    index_bits,
+                    width,
+                    height
+                );
+                let filtered = interlaced(&indices, width, height, index_bits, true);
+                assert_eq!(
+                    filtered.len(),
+                    data_len(width, height, index_bits, LAYOUT_FILTERED)
+                );
+                let unfiltered = unfilter(&filtered, width, height, index_bits).unwrap();
+                assert_eq!(unfiltered, data);
+            }
+        }
+    }
+
+    #[test]
+    fn index_offsets() {
+        let (width, height) = (9, 10);
+        let indices = indices(width, height, 8);
+        let data = interlaced(&indices, width, height, 8, false);
+        for (pixel, &index) in indices.iter().enumerate() {
+            assert_eq!(data[index_offset(pixel, width, height, 8, 0)] as u16, index);
+        }
+    }
+
+    // The first pass alone fills 8x8 blocks with its pixels
+    #[test]
+    fn preview_of_the_first_pass() {
+        let (width, height) = (9, 10);
+        let indices = indices(width, height, 8);
+        let data = interlaced(&indices, width, height, 8, false);
+        let first = pass_len(0, width, height, 8, 0);
+        let (preview, passes) = deinterlace(&data[..first], width, height, 8);
+        assert_eq!(passes, 1);
+        assert_eq!(preview[7 * width as usize + 7], indices[0]);
+        assert_eq!(preview[8], indices[8]);
+        assert_eq!(
+            preview[9 * width as usize + 8],
+            indices[8 * width as usize + 8]
+        );
+    }
+
+    #[test]
+    fn empty_data() {
+        let (preview, passes) = deinterlace(&[], 3, 3, 8);
+        assert_eq!((preview, passes), (vec![0; 9], 0));
+        assert!(unfilter(&[], 3, 3, 8).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+#[cfg(feature = "crypto")]
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+pub const SALT_LEN: usize = 16;
+// Master key from a passphrase is an AES-256 key
+#[cfg(feature = "crypto")]
+pub const PASSPHRASE_KEY_LEN: usize = 32;
+// Key block of encode --passphrase: the salt, then the Argon2id memory cost in KiB, time cost and
+// parallelism as little endian u32s
+pub const PASSPHRASE_BLOCK_LEN: usize = SALT_LEN + 3 * 4;
+// Costs a file may ask for, so a crafted one can't make decode allocate or spin without end
+#[cfg(feature = "crypto")]
+const MAX_M_COST: u32 = 4 * 1024 * 1024;
+#[cfg(feature = "crypto")]
+const MAX_T_COST: u32 = 64;
+#[cfg(feature = "crypto")]
+const MAX_P_COST: u32 = 64;
+
+// Derived key has the master key length, so AES key size is preserved
+pub fn derive_file_key(master_key: &[u8], salt: &[u8]) -> Vec<u8> {
+    let hkdf = Hkdf::<Sha256>::new(Some(salt), master_key);
+    let mut file_key = vec![0u8; master_key.len()];
+    hkdf.expand(b"ric-file-key", &mut file_key).unwrap();
+    file_key
+}
+
+// Argon2id with its default cost and a fixed salt: the master key of --passphrase --derive (and
+// --ask-key); files of --passphrase alone get a salt of their own in a passphrase block instead
+#[cfg(feature = "crypto")]
+pub fn passphrase_key(passphrase: &str) -> Vec<u8> {
+    let mut master_key = vec![0u8; PASSPHRASE_KEY_LEN];
+    Argon2::default()
+        .hash_password_into(
+            passphrase.as_bytes(),
+            b"ric-passphrase-key",
+            &mut master_key,
+        )
+        .unwrap();
+    master_key
+}
+
+// Key block of a new --passphrase file with the salt and Argon2id's default costs
+#[cfg(feature = "crypto")]
+pub fn passphrase_block(salt: &[u8; SALT_LEN]) -> Vec<u8> {
+    let mut block = salt.to_vec();
+    block.extend_from_slice(&Params::DEFAULT_M_COST.to_le_bytes());
+    block.extend_from_slice(&Params::DEFAULT_T_COST.to_le_bytes());
+    block.extend_from_slice(&Params::DEFAULT_P_COST.to_le_bytes());
+    block
+}
+
+// AES-256 key of a passphrase with the salt and costs of a --passphrase key block, None for a
+// block cut short or asking for more than the MAX_ costs
+#[cfg(feature = "crypto")]
+pub fn passphrase_file_key(passphrase: &str, block: &[u8]) -> Option<Vec<u8>> {
+    let block = block.get(..PASSPHRASE_BLOCK_LEN)?;
+    let cost = |at: usize| u32::from_le_bytes(block[at..at + 4].try_into().unwrap());
+    let (m_cost, t_cost, p_cost) = (cost(SALT_LEN), cost(SALT_LEN + 4), cost(SALT_LEN + 8));
+    if m_cost > MAX_M_COST || t_cost > MAX_T_COST || p_cost > MAX_P_COST {
+        return None;
+    }
+    let params = Params::new(m_cost, t_cost, p_cost, Some(PASSPHRASE_KEY_LEN)).ok()?;
+    let mut file_key = vec![0u8; PASSPHRASE_KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &block[..SALT_LEN], &mut file_key)
+        .ok()?;
+    Some(file_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER_KEY: &[u8] = b"0123456789abcdef";
+
+    fn salt() -> [u8; SALT_LEN] {
+        core::array::from_fn(|i| i as u8)
+    }
+
+    // HKDF-SHA256 of RFC 5869, so files derived before stay readable
+    #[test]
+    fn file_key_known_answer() {
+        let file_key = derive_file_key(MASTER_KEY, &salt());
+        let expected = [
+            0x61, 0x14, 0x2b, 0xb6, 0xb9, 0xd8, 0x32, 0x1e, 0x82, 0xcc, 0x7c, 0x09, 0x93, 0x23,
+            0x31, 0xa1,
+        ];
+        assert_eq!(file_key, expected);
+    }
+
+    // Every AES key size keeps its size, and the salt gives every file a key of its own
+    #[test]
+    fn file_key_sizes_and_salts() {
+        for len in [16, 24, 32] {
+            let master_key = vec![7u8; len];
+            let file_key = derive_file_key(&master_key, &salt());
+            assert_eq!(file_key.len(), len);
+            assert_ne!(file_key, master_key);
+            assert_eq!(derive_file_key(&master_key, &salt()), file_key);
+            assert_ne!(derive_file_key(&master_key, &[0; SALT_LEN]), file_key);
+        }
+    }
+
+    // Block of the salt and small costs, quick to hash
+    #[cfg(feature = "crypto")]
+    fn cheap_block(m_cost: u32, t_cost: u32, p_cost: u32) -> Vec<u8> {
+        let mut block = salt().to_vec();
+        for cost in [m_cost, t_cost, p_cost] {
+            block.extend_from_slice(&cost.to_le_bytes());
+        }
+        block
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn passphrase_block_layout() {
+        let block = passphrase_block(&salt());
+        assert_eq!(block.len(), PASSPHRASE_BLOCK_LEN);
+        assert_eq!(block[..SALT_LEN], salt());
+        assert_eq!(
+            block[SALT_LEN..],
+            cheap_block(
+                Params::DEFAULT_M_COST,
+                Params::DEFAULT_T_COST,
+                Params::DEFAULT_P_COST
+            )[SALT_LEN..]
+        );
+    }
+
+    // The key follows from the passphrase, salt and costs of the block alone
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn passphrase_file_keys() {
+        let block = cheap_block(8, 1, 1);
+        let file_key = passphrase_file_key("correct horse", &block).unwrap();
+        assert_eq!(file_key.len(), PASSPHRASE_KEY_LEN);
+        assert_eq!(
+            passphrase_file_key("correct horse", &block),
+            Some(file_key.clone())
+        );
+        assert_ne!(
+            passphrase_file_key("correct horsf", &block),
+            Some(file_key.clone())
+        );
+        let mut other_salt = block.clone();
+        other_salt[0] ^= 1;
+        assert_ne!(
+            passphrase_file_key("correct horse", &other_salt),
+            Some(file_key.clone())
+        );
+        assert_ne!(
+            passphrase_file_key("correct horse", &cheap_block(8, 2, 1)),
+            Some(file_key)
+        );
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn bad_passphrase_blocks() {
+        let block = cheap_block(8, 1, 1);
+        assert!(passphrase_file_key("p", &block[..PASSPHRASE_BLOCK_LEN - 1]).is_none());
+        assert!(passphrase_file_key("p", &cheap_block(MAX_M_COST + 1, 1, 1)).is_none());
+        assert!(passphrase_file_key("p", &cheap_block(8, MAX_T_COST + 1, 1)).is_none());
+        assert!(passphrase_file_key("p", &cheap_block(8, 1, MAX_P_COST + 1)).is_none());
+        // Below Argon2's own minimums
+        assert!(passphrase_file_key("p", &cheap_block(8, 0, 1)).is_none());
+        assert!(passphrase_file_key("p", &cheap_block(8, 1, 0)).is_none());
+    }
+}
@@ -0,0 +1,313 @@
+// Key options of both syntaxes resolved into the key and key block to encode or decode with;
+// errors exit as the other argument checks of main do
+#[cfg(feature = "crypto")]
+use rand::Rng;
+#[cfg(feature = "crypto")]
+use rust_image_codec::{
+    age_keys, kdf, recipients, shamir, slot_table,
+    utils::{base64url_to_bytes, bytes_to_base64url},
+};
+use rust_image_codec::{
+    error::{EXIT_CRYPTO, EXIT_IO, EXIT_USAGE},
+    keys::KeyBlock,
+    log,
+};
+use std::process::exit;
+
+use crate::files::read_key_file;
+#[cfg(feature = "keyring")]
+use crate::files::read_keyring_key;
+
+// Named key options of the subcommands
+#[derive(Clone, Default)]
+pub struct KeyArgs {
+    pub recipients: Vec<String>,
+    pub age_recipients: Vec<String>,
+    pub shares: Option<String>,
+    pub identity: Option<String>,
+    pub age_identity: Option<String>,
+    pub derive: bool,
+    // Environment variable holding the base64url key, keeps it out of argv and shell history
+    pub key_env: Option<String>,
+    pub key_file: Option<String>,
+    // Name of the key in the platform secret store, see utils::read_keyring_key
+    pub key_name: Option<String>,
+    // Typed at the --passphrase prompt, not taken from args
+    pub passphrase: Option<String>,
+    // Encode: keys given a slot of the key-slot table besides the key, each opens the file
+    pub slot_keys: Vec<String>,
+}
+
+pub struct Keys {
+    // Data key the pixels are encrypted with (master key before unwrapping on decode)
+    pub key: Option<String>,
+    pub key_block: Option<KeyBlock>,
+    // Encode only: key block to write after the palette
+    pub key_slots: Option<Vec<u8>>,
+    // Encode only: key-slot table of the key-slots entry
+    pub slot_table: Option<Vec<u8>>,
+}
+
+impl KeyArgs {
+    // Key given directly ("c" flag, --key), through --key-env, --key-file or --key-name
+    fn direct_key(&self, key: Option<String>) -> Option<String> {
+        let sources = key.is_some() as u8
+            + self.key_env.is_some() as u8
+            + self.key_file.is_some() as u8
+            + self.key_name.is_some() as u8;
+        if sources > 1 {
+            log::error(format_args!(
+                "only one of \"c\" flag, --key-env, --key-file and --key-name can be used"
+            ));
+            exit(EXIT_USAGE);
+        }
+        if let Some(name) = &self.key_env {
+            return match std::env::var(name) {
+                Ok(value) => Some(value.trim().to_string()),
+                Err(_) => {
+                    log::error(format_args!("environment variable {} is not set", name));
+                    exit(EXIT_USAGE);
+                }
+            };
+        }
+        if let Some(path) = &self.key_file {
+            return Some(read_key_file(path).unwrap_or_else(|err| {
+                log::error(format_args!("{}: {}", path, err));
+                // A file that isn't a key is a bad key, not an IO failure
+                exit(if err.kind() == std::io::ErrorKind::InvalidData {
+                    EXIT_CRYPTO
+                } else {
+                    EXIT_IO
+                });
+            }));
+        }
+        #[cfg(not(feature = "keyring"))]
+        if self.key_name.is_some() {
+            log::error(format_args!(
+                "{}",
+                rust_image_codec::CodecError::FeatureDisabled("keyring")
+            ));
+            exit(EXIT_USAGE);
+        }
+        #[cfg(feature = "keyring")]
+        if let Some(name) = &self.key_name {
+            return Some(read_keyring_key(name).unwrap_or_else(|err| {
+                log::error(format_args!("keyring key {}: {}", name, err));
+                exit(if err.kind() == std::io::ErrorKind::InvalidData {
+                    EXIT_CRYPTO
+                } else {
+                    EXIT_IO
+                });
+            }));
+        }
+        key
+    }
+
+    // Without encryption in the build any key option is an error
+    #[cfg(not(feature = "crypto"))]
+    pub fn resolve(self, key: Option<String>) -> Keys {
+        let keyed = self.direct_key(key).is_some()
+            || !self.recipients.is_empty()
+            || !self.age_recipients.is_empty()
+            || self.shares.is_some()
+            || self.identity.is_some()
+            || self.age_identity.is_some()
+            || self.derive
+            || self.passphrase.is_some()
+            || !self.slot_keys.is_empty();
+        if keyed {
+            log::error(format_args!(
+                "{}",
+                rust_image_codec::CodecError::FeatureDisabled("crypto")
+            ));
+            exit(EXIT_USAGE);
+        }
+        Keys {
+            key: None,
+            key_block: None,
+            key_slots: None,
+            slot_table: None,
+        }
+    }
+
+    #[cfg(feature = "crypto")]
+    pub fn resolve(self, key: Option<String>) -> Keys {
+        let mut key = self.direct_key(key);
+        let mut key_block = match (self.identity, self.age_identity) {
+            (Some(_), Some(_)) => {
+                log::error(format_args!(
+                    "--identity and --age-identity can't be used together"
+                ));
+                exit(EXIT_USAGE);
+            }
+            (Some(code), None) => Some(KeyBlock::X25519(
+                recipients::parse_key(code.as_str()).unwrap_or_else(|| {
+                    log::error(format_args!("invalid identity"));
+                    exit(EXIT_CRYPTO);
+                }),
+            )),
+            (None, Some(path)) => Some(KeyBlock::Age(path)),
+            // The --passphrase prompt rejects other key options
+            (None, None) => self.passphrase.map(KeyBlock::Passphrase),
+        };
+        if let Some(shares) = self.shares {
+            if key.is_some() {
+                log::error(format_args!(
+                    "a key (\"c\" flag, --key-env, --key-file, --key-name) and --shares can't be used together"
+                ));
+                exit(EXIT_USAGE);
+            }
+            let shares = shares.split(',').map(str::to_string).collect::<Vec<_>>();
+            let combined = shamir::combine(&shares).unwrap_or_else(|err| {
+                log::error(format_args!("{}", err));
+                exit(err.exit_code());
+            });
+            key = Some(bytes_to_base64url(&combined));
+        }
+        if (!self.recipients.is_empty() || !self.age_recipients.is_empty()) && key.is_some() {
+            log::error(format_args!(
+                "recipients can't be used together with \"c\" flag or --shares"
+            ));
+            exit(EXIT_USAGE);
+        }
+        let data_key: [u8; recipients::DATA_KEY_LEN] = rand::rng().random();
+        let slot_table = if self.slot_keys.is_empty() {
+            None
+        } else {
+            if !self.recipients.is_empty()
+                || !self.age_recipients.is_empty()
+                || self.derive
+                || key_block.is_some()
+            {
+                log::error(format_args!(
+                    "--key-slot can't be used together with recipients, identities, --derive or --passphrase"
+                ));
+                exit(EXIT_USAGE);
+            }
+            // The key, when given, takes the first slot
+            let keys = key
+                .iter()
+                .chain(&self.slot_keys)
+                .map(|code| {
+                    base64url_to_bytes(code)
+                        .filter(|key| matches!(key.len(), 16 | 24 | 32))
+                        .unwrap_or_else(|| {
+                            log::error(format_args!("invalid --key-slot key"));
+                            exit(EXIT_CRYPTO);
+                        })
+                })
+                .collect::<Vec<_>>();
+            if keys.len() > slot_table::MAX_SLOTS {
+                log::error(format_args!(
+                    "at most {} key slots are supported",
+                    slot_table::MAX_SLOTS
+                ));
+                exit(EXIT_USAGE);
+            }
+            Some(slot_table::add(&[], &data_key, &keys).unwrap_or_else(|| {
+                log::error(format_args!("a key is given a slot twice"));
+                exit(EXIT_USAGE);
+            }))
+        };
+        let key_slots = if !self.recipients.is_empty() && !self.age_recipients.is_empty() {
+            log::error(format_args!(
+                "--recipient and --age-recipient can't be used together"
+            ));
+            exit(EXIT_USAGE);
+        } else if !self.recipients.is_empty() {
+            if self.recipients.len() > recipients::MAX_RECIPIENTS {
+                log::error(format_args!(
+                    "at most {} recipients are supported",
+                    recipients::MAX_RECIPIENTS
+                ));
+                exit(EXIT_USAGE);
+            }
+            let recipients = self
+                .recipients
+                .iter()
+                .map(|code| {
+                    recipients::parse_key(code.as_str()).unwrap_or_else(|| {
+                        log::error(format_args!("invalid recipient {}", code));
+                        exit(EXIT_CRYPTO);
+                    })
+                })
+                .collect::<Vec<_>>();
+            Some(recipients::wrap_key(&data_key, &recipients))
+        } else if !self.age_recipients.is_empty() {
+            let age_recipients = self
+                .age_recipients
+                .iter()
+                .map(|code| {
+                    age_keys::parse_recipient(code.as_str()).unwrap_or_else(|| {
+                        log::error(format_args!("invalid age recipient {}", code));
+                        exit(EXIT_CRYPTO);
+                    })
+                })
+                .collect::<Vec<_>>();
+            Some(
+                age_keys::wrap_key(&data_key, &age_recipients).unwrap_or_else(|| {
+                    log::error(format_args!("age encryption failed"));
+                    exit(EXIT_CRYPTO);
+                }),
+            )
+        } else {
+            None
+        };
+        if key_slots.is_some() || slot_table.is_some() {
+            key = Some(bytes_to_base64url(&data_key));
+        }
+        let key_slots = if self.derive {
+            let master_key = key
+                .as_deref()
+                .and_then(base64url_to_bytes)
+                .unwrap_or_else(|| {
+                    log::error(format_args!(
+                        "--derive requires a master key (\"c\" flag or --shares)"
+                    ));
+                    exit(EXIT_USAGE);
+                });
+            if key_slots.is_some() || key_block.is_some() {
+                log::error(format_args!(
+                    "--derive can't be used together with recipients"
+                ));
+                exit(EXIT_USAGE);
+            }
+            let salt: [u8; kdf::SALT_LEN] = rand::rng().random();
+            key = Some(bytes_to_base64url(&kdf::derive_file_key(
+                &master_key,
+                &salt,
+            )));
+            key_block = Some(KeyBlock::Derived(master_key));
+            Some(salt.to_vec())
+        } else {
+            key_slots
+        };
+        Keys {
+            key,
+            key_block,
+            key_slots,
+            slot_table,
+        }
+    }
+}
+
+impl Keys {
+    // Encode: a passphrase's key is stretched with a new salt, which its key block stores; decode
+    // stretches it once it has read the block
+    #[cfg(feature = "crypto")]
+    pub fn sealed(mut self) -> Self {
+        if let Some(KeyBlock::Passphrase(passphrase)) = &self.key_block {
+            let salt: [u8; kdf::SALT_LEN] = rand::rng().random();
+            let block = kdf::passphrase_block(&salt);
+            let file_key = kdf::passphrase_file_key(passphrase, &block).unwrap();
+            self.key = Some(bytes_to_base64url(&file_key));
+            self.key_slots = Some(block);
+        }
+        self
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    pub fn sealed(self) -> Self {
+        self
+    }
+}
@@ -0,0 +1,204 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "crypto")]
+use crate::{age_keys, recipients, slot_table};
+use crate::{error::CodecError, format, kdf, metadata, utils::base64url_to_bytes};
+
+pub const KEY_CHECK_LEN: usize = 8;
+// Authentication tag of encrypted files, kept as the "auth" metadata entry, see authenticator
+pub const AUTH_KEY: &str = "auth";
+pub const AUTH_TAG_LEN: usize = 16;
+pub type Authenticator = Hmac<Sha256>;
+// Cipher of encrypted files, kept as the "cipher" metadata entry, e.g. "FF1-AES-256"
+pub const CIPHER_KEY: &str = "cipher";
+// Empty entry of encrypted files whose pixels data tweaks are bound to the header, see
+// header_tweak; files of builds before it don't have it
+pub const HEADER_TWEAK_KEY: &str = "header-tweak";
+// Key-slot table of files several keys open, the data key wrapped with each (see slot_table.rs)
+pub const KEY_SLOTS_KEY: &str = "key-slots";
+// Signature of files encoded with --sign, kept as the "signature" metadata entry, see signer
+pub const SIGNATURE_KEY: &str = "signature";
+pub const SIGNATURE_LEN: usize = 32;
+
+// Format-preserving encryption of the pixels data (encode --fpe), FF1 or the crate's block mode
+// over the FF3-1 rounds (see ff3.rs); decode reads it from the cipher entry
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fpe {
+    #[default]
+    Ff1,
+    Ff31,
+}
+
+impl Fpe {
+    pub const ALL: [Fpe; 2] = [Fpe::Ff1, Fpe::Ff31];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|fpe| fpe.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Fpe::Ff1 => "ff1",
+            Fpe::Ff31 => "ff3-1",
+        }
+    }
+}
+
+// Cipher of the mode with a key of key_len bytes, whose length selects AES-128/192/256
+pub fn cipher_name(fpe: Fpe, key_len: usize) -> Option<String> {
+    let bits = match key_len {
+        16 | 24 | 32 => key_len * 8,
+        _ => return None,
+    };
+    Some(format!("{}-AES-{}", fpe.name().to_uppercase(), bits))
+}
+
+// Mode and key length of a cipher entry's value, None for unknown ones
+pub fn parse_cipher(value: &[u8]) -> Option<(Fpe, usize)> {
+    let value = std::str::from_utf8(value).ok()?;
+    Fpe::ALL.into_iter().find_map(|fpe| {
+        let bits = value
+            .strip_prefix(&fpe.name().to_uppercase())?
+            .strip_prefix("-AES-")?;
+        let key_len = match bits {
+            "128" => 16,
+            "192" => 24,
+            "256" => 32,
+            _ => return None,
+        };
+        Some((fpe, key_len))
+    })
+}
+
+// Bytes of a base64url key, or why it isn't one of 16, 24 or 32 bytes, without showing it
+pub fn check_key(key: &str) -> Result<Vec<u8>, CodecError> {
+    let bytes = base64url_to_bytes(key).ok_or(CodecError::BadKey(
+        "invalid key, it isn't base64url (A-Z, a-z, 0-9, - and _)".to_string(),
+    ))?;
+    if cipher_name(Fpe::Ff1, bytes.len()).is_none() {
+        return Err(CodecError::BadKey(format!(
+            "invalid key of {} bytes, it should be 16, 24 or 32 (AES-128/192/256)",
+            bytes.len()
+        )));
+    }
+    Ok(bytes)
+}
+
+// Ways to get the data key from the key block following the palette
+#[derive(Clone)]
+pub enum KeyBlock {
+    X25519([u8; 32]),
+    // Path to an age identity file
+    Age(String),
+    // Master key, the block is a per-file salt
+    Derived(Vec<u8>),
+    // Passphrase, the block is its Argon2id salt and costs, see kdf::passphrase_block
+    Passphrase(String),
+}
+
+// Data key of a key-slot table the key has a slot in
+#[cfg(feature = "crypto")]
+pub fn open_slot_table(table: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    slot_table::unwrap_key(table, key).map(|data_key| data_key.to_vec())
+}
+
+#[cfg(not(feature = "crypto"))]
+pub fn open_slot_table(_table: &[u8], _key: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+// Returns data key and the key block length
+pub fn open_key_block(block: &[u8], key_block: &KeyBlock) -> Option<(Vec<u8>, usize)> {
+    match key_block {
+        #[cfg(feature = "crypto")]
+        KeyBlock::X25519(secret) => recipients::unwrap_key(block, secret),
+        #[cfg(feature = "crypto")]
+        KeyBlock::Age(path) => age_keys::unwrap_key(block, path.as_str()),
+        #[cfg(feature = "crypto")]
+        KeyBlock::Passphrase(passphrase) => kdf::passphrase_file_key(passphrase, block)
+            .map(|file_key| (file_key, kdf::PASSPHRASE_BLOCK_LEN)),
+        #[cfg(not(feature = "crypto"))]
+        KeyBlock::X25519(_) | KeyBlock::Age(_) | KeyBlock::Passphrase(_) => None,
+        KeyBlock::Derived(master_key) => block
+            .get(..kdf::SALT_LEN)
+            .map(|salt| (kdf::derive_file_key(master_key, salt), kdf::SALT_LEN)),
+    }
+}
+
+// HMAC-SHA256, keyed with one derived from the data key, of everything an encrypted file holds
+// but its tag: the header before the metadata section (the compressed flag left out), the other
+// metadata entries as a section of their own, then what follows the section (key blocks, key
+// check and pixels data as stored, after encryption and before compression); the caller updates
+// it with the latter. Its first AUTH_TAG_LEN bytes are the tag, which a wrong key or any changed
+// byte fails
+pub fn authenticator(key: &[u8], header: &[u8], entries: &metadata::Entries) -> Authenticator {
+    keyed_mac(key, b"ric-auth-key", header, entries, &[AUTH_KEY])
+}
+
+// HMAC-SHA256 of the same as authenticator, keyed with one derived from a signing key that needs
+// no encryption (encode --sign), and all 32 bytes of it are the signature. It leaves out the
+// auth entry too, which is made after it and covers it
+pub fn signer(key: &[u8], header: &[u8], entries: &metadata::Entries) -> Authenticator {
+    keyed_mac(
+        key,
+        b"ric-signature-key",
+        header,
+        entries,
+        &[SIGNATURE_KEY, AUTH_KEY],
+    )
+}
+
+fn keyed_mac(
+    key: &[u8],
+    label: &[u8],
+    header: &[u8],
+    entries: &metadata::Entries,
+    left_out: &[&str],
+) -> Authenticator {
+    let mut auth_key = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    auth_key.update(label);
+    let mut mac = Hmac::<Sha256>::new_from_slice(&auth_key.finalize().into_bytes()).unwrap();
+    // The compressed flag is informational, cleared when compression doesn't pay off
+    let (prefix, rest) = header.split_at(format::PREFIX_LEN);
+    mac.update(&prefix[..format::PREFIX_LEN - 1]);
+    mac.update(&[prefix[format::PREFIX_LEN - 1] & !format::FLAG_COMPRESSED]);
+    mac.update(rest);
+    let others = entries
+        .iter()
+        .filter(|(key, _)| !left_out.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    mac.update(&metadata::write(&others));
+    mac
+}
+
+// 8 bytes of SHA-256 of the dimensions and palette a header holds: the little endian u32 width,
+// height and palette size, then the palette entries in the clear (none for tiled and truecolor
+// files). Appended to the tweak of every segment of pixels data, so a file whose dimensions or
+// palette were swapped decrypts to noise
+pub fn header_tweak(width: u32, height: u32, palette_size: usize, entries: &[u8]) -> [u8; 8] {
+    let mut bytes = Vec::with_capacity(12 + entries.len());
+    bytes.extend_from_slice(&width.to_le_bytes());
+    bytes.extend_from_slice(&height.to_le_bytes());
+    bytes.extend_from_slice(&(palette_size as u32).to_le_bytes());
+    bytes.extend_from_slice(entries);
+    let mut tweak = [0u8; 8];
+    tweak.copy_from_slice(&Sha256::digest(&bytes)[..8]);
+    tweak
+}
+
+pub fn auth_tag(mac: Authenticator) -> [u8; AUTH_TAG_LEN] {
+    let mut tag = [0u8; AUTH_TAG_LEN];
+    tag.copy_from_slice(&mac.finalize().into_bytes()[..AUTH_TAG_LEN]);
+    tag
+}
+
+// Stored right before pixels data of encrypted files, so a wrong key fails before decryption
+pub fn key_check(key: &[u8]) -> [u8; KEY_CHECK_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(b"ric-key-check");
+    let mut check = [0u8; KEY_CHECK_LEN];
+    check.copy_from_slice(&mac.finalize().into_bytes()[..KEY_CHECK_LEN]);
+    check
+}
@@ -0,0 +1,146 @@
+// Without the default "std" feature only the bare decoder core is built
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+use image::RgbImage;
+
+#[cfg(feature = "crypto")]
+pub mod age_keys;
+#[cfg(feature = "std")]
+pub mod animation;
+#[cfg(feature = "async")]
+pub mod async_io;
+pub mod bare;
+#[cfg(feature = "std")]
+pub mod chunks;
+#[cfg(feature = "std")]
+mod codec;
+#[cfg(feature = "std")]
+pub mod compare;
+#[cfg(feature = "std")]
+pub mod compression;
+#[cfg(feature = "std")]
+pub mod container;
+#[cfg(feature = "std")]
+pub mod detect;
+#[cfg(feature = "std")]
+pub mod dither;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod exif;
+#[cfg(feature = "std")]
+pub mod fec;
+#[cfg(feature = "crypto")]
+pub mod ff3;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+pub mod format;
+#[cfg(feature = "std")]
+pub mod inspect;
+pub mod interlace;
+#[cfg(feature = "std")]
+pub mod kdf;
+#[cfg(feature = "std")]
+pub mod keys;
+#[cfg(feature = "std")]
+pub mod log;
+#[cfg(feature = "std")]
+pub mod metadata;
+#[cfg(feature = "std")]
+pub mod montage;
+#[cfg(feature = "std")]
+pub mod palette_file;
+#[cfg(feature = "std")]
+pub mod progress;
+#[cfg(feature = "crypto")]
+pub mod recipients;
+#[cfg(feature = "std")]
+mod region;
+#[cfg(feature = "std")]
+pub mod rekey;
+pub mod rle;
+#[cfg(feature = "std")]
+pub mod row_encoder;
+pub mod scan;
+pub mod seek;
+#[cfg(feature = "std")]
+pub mod selftest;
+#[cfg(feature = "std")]
+pub mod shamir;
+#[cfg(feature = "crypto")]
+pub mod slot_table;
+#[cfg(feature = "std")]
+pub mod stream;
+pub mod tiles;
+#[cfg(feature = "std")]
+pub mod timings;
+#[cfg(feature = "std")]
+pub mod utils;
+#[cfg(feature = "std")]
+pub mod verify;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(feature = "std")]
+pub use codec::{Decoder, EncodeStats, Encoder, check_auth, read_header, unchecked_v1};
+#[cfg(feature = "std")]
+pub use error::CodecError;
+#[cfg(feature = "std")]
+pub use row_encoder::RowEncoder;
+
+pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+// Encodes an image with the default dithering, the key is 16, 24 or 32 raw bytes
+#[cfg(feature = "std")]
+pub fn encode(
+    img: &RgbImage,
+    palette_size: usize,
+    key: Option<&[u8]>,
+    compress: bool,
+) -> Result<Vec<u8>, CodecError> {
+    let mut encoder = Encoder::new()
+        .palette_size(palette_size)
+        .compression(compress);
+    if let Some(key) = key {
+        encoder = encoder.key(key);
+    }
+    encoder.encode(img)
+}
+
+// Decodes a file made by encode(), compression is detected
+#[cfg(feature = "std")]
+pub fn decode(bytes: &[u8], key: Option<&[u8]>) -> Result<RgbImage, CodecError> {
+    let mut decoder = Decoder::new();
+    if let Some(key) = key {
+        decoder = decoder.key(key);
+    }
+    decoder.decode(bytes)
+}
+
+// Same as decode() but returns raw RGB bytes with width and height
+#[cfg(feature = "std")]
+pub fn decode_to_vec(bytes: &[u8], key: Option<&[u8]>) -> Result<(Vec<u8>, u32, u32), CodecError> {
+    let mut decoder = Decoder::new();
+    if let Some(key) = key {
+        decoder = decoder.key(key);
+    }
+    decoder.decode_to_vec(bytes)
+}
+
+// Same as decode() but writes raw RGB bytes into a preallocated buffer, returns width and height
+#[cfg(feature = "std")]
+pub fn decode_into(
+    bytes: &[u8],
+    key: Option<&[u8]>,
+    out: &mut [u8],
+) -> Result<(u32, u32), CodecError> {
+    let mut decoder = Decoder::new();
+    if let Some(key) = key {
+        decoder = decoder.key(key);
+    }
+    decoder.decode_into(bytes, out)
+}
@@ -0,0 +1,72 @@
+// Message levels of the CLI: status lines and progress unless quiet, per-stage timings when
+// verbose. Everything goes to stderr, stdout is left to the output
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use crate::utils::json_escape;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+// --json: errors and warnings become {"error": "..."} lines, status lines and progress are off
+pub fn set_json(json: bool) {
+    JSON.store(json, Ordering::Relaxed);
+}
+
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+pub fn level() -> Level {
+    if json() {
+        return Level::Quiet;
+    }
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Quiet,
+        1 => Level::Normal,
+        _ => Level::Verbose,
+    }
+}
+
+// Status line, e.g. the inferred output or the job being processed
+pub fn info(args: fmt::Arguments) {
+    if level() >= Level::Normal {
+        eprintln!("{}", args);
+    }
+}
+
+pub fn error(args: fmt::Arguments) {
+    if json() {
+        eprintln!("{{\"error\":\"{}\"}}", json_escape(&args.to_string()));
+    } else {
+        eprintln!("Error: {}", args);
+    }
+}
+
+pub fn warning(args: fmt::Arguments) {
+    if json() {
+        eprintln!("{{\"warning\":\"{}\"}}", json_escape(&args.to_string()));
+    } else {
+        eprintln!("Warning: {}", args);
+    }
+}
+
+// Detail line, e.g. the time a stage took
+pub fn verbose(args: fmt::Arguments) {
+    if level() >= Level::Verbose {
+        eprintln!("{}", args);
+    }
+}
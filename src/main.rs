@@ -1,282 +1,2555 @@
-use aes::Aes128;
-use cosmian_fpe::ff1::{BinaryNumeralString, FF1};
-use image::{ImageBuffer, Rgb, imageops::dither};
+use clap::{CommandFactory, Parser};
+use cli::{Cli, Command, EncodeArgs, KeyCommand, StatsFormat};
+use image::{DynamicImage, Pixel, Rgb};
+use rust_image_codec::{
+    CodecError, Decoder, EncodeStats, Encoder, ZSTD_MAGIC, animation, check_auth, compare,
+    compression, container,
+    dither::Dither,
+    error::{EXIT_CRYPTO, EXIT_IO, EXIT_SELFTEST, EXIT_USAGE},
+    exif, fec, format, inspect, interlace,
+    keys::*,
+    log, metadata, palette_file, read_header, rekey,
+    scan::Scan,
+    selftest, shamir, stream,
+    timings::Timings,
+    unchecked_v1,
+    utils::*,
+    verify,
+};
+#[cfg(feature = "crypto")]
+use rust_image_codec::{kdf, recipients, slot_table};
 use std::{
-    fs,
+    fs::File,
+    io::{BufReader, Cursor, IsTerminal, Read, Seek},
     process::exit,
-    sync::{Arc, Mutex},
-    thread,
+    time::Instant,
 };
 
-mod utils;
-use utils::*;
-
-fn encrypt(bytes: &mut [u8], key: &str) -> Option<()> {
-    let byte_key = base64url_to_bytes(key)?;
-    let ff1 = FF1::<Aes128>::new(&byte_key, 2).ok()?;
-    let bn = BinaryNumeralString::from_bytes_le(bytes);
-    let encrypted = ff1.encrypt(&[], &bn).ok()?;
-    let encrypted_bytes = encrypted.to_bytes_le();
-    bytes.copy_from_slice(&encrypted_bytes);
-    Some(())
-}
-
-fn decrypt(cipher: &mut [u8], key: &str) -> Option<()> {
-    let byte_key = base64url_to_bytes(key)?;
-    let ff1 = FF1::<Aes128>::new(&byte_key, 2).ok()?;
-    let bn = BinaryNumeralString::from_bytes_le(cipher);
-    let decrypted = ff1.decrypt(&[], &bn).ok()?;
-    let decrypted_bytes = decrypted.to_bytes_le();
-    cipher.copy_from_slice(decrypted_bytes.as_slice());
-    Some(())
-}
-
-fn process_encode(
-    chunk: Vec<Rgb<u8>>,
-    palette: &[Rgb<u8>],
-    key_opt: Option<String>,
-    progress_bar: Arc<Mutex<ProgressBar>>,
-) -> Vec<u8> {
-    let mut encode: Vec<u8> = Vec::with_capacity(chunk.len() / 3);
-    for pixel in chunk {
-        let r = pixel[0];
-        let g = pixel[1];
-        let b = pixel[2];
-
-        let closest_index = palette
-            .iter()
-            .position(|&c| c[0] == r && c[1] == g && c[2] == b)
-            .unwrap_or(0);
-        encode.push(closest_index as u8);
-        progress_bar.lock().unwrap().step();
-    }
+mod args;
+mod batch;
+mod cli;
+mod config;
+mod files;
+mod key_args;
+mod memory;
+mod watch;
+
+use files::*;
+use key_args::{KeyArgs, Keys};
 
-    if let Some(key) = key_opt {
-        encrypt(&mut encode, key.as_str()).expect("Error: invalid code or key");
-        progress_bar.lock().unwrap().step();
+// Whether clap parses the arguments: the first one that isn't a global option (--json, --config)
+// names a subcommand or asks for help or the version, anything else is the short syntax
+fn is_subcommand(args: &[String]) -> bool {
+    let mut rest = args.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--json" => {}
+            "--config" => {
+                rest.next();
+            }
+            arg if arg.starts_with("--config=") => {}
+            "help" | "--help" | "-h" | "--version" | "-V" => return true,
+            arg => {
+                return Cli::command()
+                    .get_subcommands()
+                    .any(|command| command.get_name() == arg);
+            }
+        }
     }
+    // Global options alone, clap asks for a subcommand
+    true
+}
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static GLOBAL: memory::CountingAllocator = memory::CountingAllocator;
 
-    encode
+fn key_bytes(key: &str) -> Vec<u8> {
+    base64url_to_bytes(key).unwrap_or_else(|| {
+        log::error(format_args!("invalid key"));
+        exit(EXIT_CRYPTO);
+    })
 }
 
-fn process_decode(
-    mut chunk: Vec<u8>,
-    palette: &[Rgb<u8>],
-    key_opt: Option<String>,
-    progress_bar: Arc<Mutex<ProgressBar>>,
-    cpus_amount: usize,
-) -> Vec<u8> {
-    if let Some(key) = key_opt {
-        decrypt(&mut chunk, key.as_str()).expect("Error: invalid code or key");
-        progress_bar
-            .lock()
-            .unwrap()
-            .step_percent(1.0 / cpus_amount as f32);
+fn decoder_with_keys(
+    mut decoder: Decoder,
+    key: Option<String>,
+    key_block: Option<KeyBlock>,
+) -> Decoder {
+    if let Some(key) = key {
+        decoder = decoder.key(&key_bytes(&key));
     }
-    let mut decode = Vec::with_capacity(chunk.len() * 3);
-    for &byte in chunk.as_slice() {
-        let rgb = palette.get(byte as usize).unwrap_or(&palette[0]);
-        decode.push(rgb[0]);
-        decode.push(rgb[1]);
-        decode.push(rgb[2]);
-        progress_bar.lock().unwrap().step();
+    if let Some(key_block) = key_block {
+        decoder = decoder.key_block(key_block);
     }
-    decode
+    decoder
 }
 
-// Using result as enum for two "Ok()" dtypes
-fn do_input(input: &str, encode: bool) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, Vec<u8>> {
+// Exit code by the error class, see CodecError::exit_code
+fn or_exit<T>(result: Result<T, CodecError>) -> T {
+    result.unwrap_or_else(|err| {
+        log::error(format_args!("{}", err));
+        exit(err.exit_code());
+    })
+}
+
+// Using inner result as enum for two "Ok()" dtypes
+fn do_input(
+    input: &str,
+    encode: bool,
+    input_format: InputFormat,
+    timings: &mut Timings,
+) -> Result<Result<(DynamicImage, Embedded), Vec<u8>>, CodecError> {
+    let start = Instant::now();
     if encode {
-        return match open_img(input) {
-            Ok(img) => Ok(img),
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                exit(1);
+        let img = read_img(input, input_format)?;
+        timings.record("decode input", start);
+        return Ok(Ok(img));
+    }
+    let bytes = read_input(input).map_err(|source| CodecError::Io {
+        path: input.to_string(),
+        source,
+    })?;
+    timings.record("read input", start);
+    Ok(Err(bytes))
+}
+
+// Using result as enum for two "Ok()" dtypes
+fn do_output(
+    data: Result<Vec<u8>, (DynamicImage, Embedded)>,
+    output_file_path: &str,
+    force: bool,
+    timings: &mut Timings,
+) -> Result<(), CodecError> {
+    let start = Instant::now();
+    match data {
+        Ok(bytes) => write_file(bytes.as_slice(), output_file_path, force)?,
+        Err((img, embedded)) => write_png(img, output_file_path, &embedded, force)?,
+    }
+    timings.record("write", start);
+    Ok(())
+}
+
+#[cfg(feature = "crypto")]
+fn keygen_asymmetric(out: Option<String>) {
+    let (secret, public) = recipients::gen_keypair();
+    match out {
+        Some(path) => {
+            or_exit(write_key_file(&secret, path.as_str()));
+        }
+        None if log::json() => {
+            println!("{{\"secret\":\"{}\",\"public\":\"{}\"}}", secret, public);
+            return;
+        }
+        None => println!("secret: {}", secret),
+    }
+    if log::json() {
+        println!("{{\"public\":\"{}\"}}", public);
+    } else {
+        println!("public: {}", public);
+    }
+}
+
+// --bits 128|192|256 is --bytes 16|24|32
+fn key_size(bytes: Option<String>, bits: Option<String>) -> Option<String> {
+    let Some(bits) = bits else {
+        return bytes;
+    };
+    if bytes.is_some() {
+        log::error(format_args!("--bytes and --bits can't be used together"));
+        exit(EXIT_USAGE);
+    }
+    let bytes = match bits.as_str() {
+        "128" => "16",
+        "192" => "24",
+        "256" => "32",
+        _ => {
+            log::error(format_args!("key size should be 128, 192 or 256 bits"));
+            exit(EXIT_USAGE);
+        }
+    };
+    Some(bytes.to_string())
+}
+
+// Stores the key in the platform secret store as --key-name, out of files and argv
+fn keygen_named(key: &str, name: &str) {
+    #[cfg(not(feature = "keyring"))]
+    {
+        let _ = (key, name);
+        or_exit(Err::<(), _>(CodecError::FeatureDisabled("keyring")));
+    }
+    #[cfg(feature = "keyring")]
+    if let Err(err) = store_keyring_key(key, name) {
+        log::error(format_args!("keyring key {}: {}", name, err));
+        // An existing entry is refused as an existing --out file is
+        exit(match err.kind() {
+            std::io::ErrorKind::AlreadyExists => EXIT_USAGE,
+            _ => EXIT_IO,
+        });
+    }
+}
+
+fn keygen(
+    bytes: Option<String>,
+    split: Option<String>,
+    out: Option<String>,
+    key_name: Option<String>,
+    asymmetric: bool,
+) {
+    if key_name.is_some() && (asymmetric || split.is_some() || out.is_some()) {
+        log::error(format_args!(
+            "--key-name can't be used together with --asymmetric, --split or --out"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if asymmetric {
+        #[cfg(not(feature = "crypto"))]
+        or_exit(Err::<(), _>(CodecError::FeatureDisabled("crypto")));
+        #[cfg(feature = "crypto")]
+        keygen_asymmetric(out);
+        return;
+    }
+    let size = match bytes {
+        Some(value) => match value.parse::<usize>() {
+            Ok(size @ (16 | 24 | 32)) => size,
+            _ => {
+                log::error(format_args!("key size should be 16, 24 or 32 bytes"));
+                exit(EXIT_USAGE);
             }
-        };
+        },
+        None => 16,
+    };
+    let key = gen_key(size);
+    if let Some(split) = split {
+        let (threshold, amount) = shamir::parse_split(split.as_str()).unwrap_or_else(|| {
+            log::error(format_args!(
+                "split should be \"k/n\" with 2 <= k <= n <= 255"
+            ));
+            exit(EXIT_USAGE);
+        });
+        let shares = shamir::split(&base64url_to_bytes(&key).unwrap(), threshold, amount);
+        if out.is_none() && log::json() {
+            let shares = shares
+                .iter()
+                .map(|share| format!("\"{}\"", share))
+                .collect::<Vec<_>>();
+            println!("{{\"shares\":[{}]}}", shares.join(","));
+            return;
+        }
+        for (i, share) in shares.iter().enumerate() {
+            match &out {
+                Some(path) => {
+                    let path = format!("{}.{}", path, i + 1);
+                    or_exit(write_key_file(share, path.as_str()));
+                }
+                None => println!("{}", share),
+            }
+        }
+        return;
     }
-    match fs::read(input) {
-        Ok(bytes) => Err(bytes),
-        Err(err) => {
-            eprintln!("Error: {}", err);
-            exit(1);
+    if let Some(name) = key_name {
+        keygen_named(&key, &name);
+        return;
+    }
+    match out {
+        Some(path) => {
+            or_exit(write_key_file(&key, path.as_str()));
         }
+        None if log::json() => println!("{{\"key\":\"{}\"}}", key),
+        None => println!("{}", key),
     }
 }
 
-fn do_encode(
-    mut img: ImageBuffer<Rgb<u8>, Vec<u8>>,
-    palette_size: usize,
-    key_opt: Option<String>,
-    compress: bool,
-) -> Vec<u8> {
-    let pixels: Vec<Rgb<u8>> = img.pixels().cloned().collect();
-    let (width, height) = img.dimensions();
-    if !(2..=4097).contains(&width) {
-        eprintln!("Error: width should be between 2 and 4097");
-        exit(1);
-    }
-    if !(2..=4097).contains(&height) {
-        eprintln!("Error: height should be between 2 and 4097");
-        exit(1);
-    }
-    let palette = gen_palette(pixels.as_slice(), palette_size);
-    dither(
-        &mut img,
-        &Palette {
-            colors: palette.clone(),
+// Passphrase typed on the terminal without echo for --passphrase, encode asks twice to catch typos
+fn prompt_passphrase(key: &Option<String>, key_args: &KeyArgs, confirm: bool) -> String {
+    if key.is_some()
+        || key_args.key_env.is_some()
+        || key_args.key_file.is_some()
+        || key_args.key_name.is_some()
+    {
+        log::error(format_args!(
+            "--passphrase can't be used together with \"c\" flag, --key-env, --key-file or --key-name"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if key_args.shares.is_some()
+        || !key_args.recipients.is_empty()
+        || !key_args.age_recipients.is_empty()
+        || key_args.identity.is_some()
+        || key_args.age_identity.is_some()
+    {
+        log::error(format_args!(
+            "--passphrase can't be used together with shares, recipients or identities"
+        ));
+        exit(EXIT_USAGE);
+    }
+    let prompt = |text: &str| {
+        rpassword::prompt_password(text).unwrap_or_else(|err| {
+            log::error(format_args!("reading the passphrase: {}", err));
+            exit(EXIT_IO);
+        })
+    };
+    let passphrase = prompt("Passphrase: ");
+    if passphrase.is_empty() {
+        log::error(format_args!("empty passphrase"));
+        exit(EXIT_USAGE);
+    }
+    if confirm && prompt("Repeat passphrase: ") != passphrase {
+        log::error(format_args!("passphrases don't match"));
+        exit(EXIT_USAGE);
+    }
+    passphrase
+}
+
+// --passphrase: the key is stretched from a passphrase with Argon2id and a salt of each file's
+// own, which its key block holds along with the costs (see kdf::passphrase_block). Decode with
+// --derive opens the files of --ask-key, a --derive master key from a fixed salt
+fn ask_passphrase(key: &mut Option<String>, key_args: &mut KeyArgs, encode: bool) {
+    if encode && key_args.derive {
+        log::error(format_args!(
+            "--passphrase can't be used together with --derive on encode, each file gets a salt of its own already"
+        ));
+        exit(EXIT_USAGE);
+    }
+    prompt_key(key, key_args, encode);
+}
+
+// --ask-key, hidden and kept for the scripts written before --passphrase: --passphrase --derive,
+// which encode takes from it as well
+fn ask_key(key: &mut Option<String>, key_args: &mut KeyArgs, encode: bool) {
+    key_args.derive = true;
+    prompt_key(key, key_args, encode);
+}
+
+// The key of the passphrase typed: a --derive master key with --derive, else stretched per file
+fn prompt_key(key: &mut Option<String>, key_args: &mut KeyArgs, encode: bool) {
+    let passphrase = prompt_passphrase(key, key_args, encode);
+    #[cfg(feature = "crypto")]
+    if key_args.derive {
+        *key = Some(bytes_to_base64url(&kdf::passphrase_key(&passphrase)));
+        return;
+    }
+    key_args.passphrase = Some(passphrase);
+}
+
+fn source_key_args(source: cli::KeySource) -> KeyArgs {
+    KeyArgs {
+        shares: source.shares,
+        key_env: source.key_env,
+        key_file: source.key_file,
+        key_name: source.key_name,
+        ..KeyArgs::default()
+    }
+}
+
+// Keys to decrypt with from the key options of a subcommand, prompting for --passphrase once
+fn decryption_keys(
+    keys: cli::KeyOptions,
+    identity: Option<String>,
+    age_identity: Option<String>,
+) -> Keys {
+    let mut key = keys.key;
+    let mut key_args = KeyArgs {
+        shares: keys.shares,
+        identity,
+        age_identity,
+        derive: keys.derive,
+        key_env: keys.key_env,
+        key_file: keys.key_file,
+        key_name: keys.key_name,
+        ..KeyArgs::default()
+    };
+    if keys.passphrase {
+        ask_passphrase(&mut key, &mut key_args, false);
+    }
+    if keys.ask_key {
+        ask_key(&mut key, &mut key_args, false);
+    }
+    key_args.resolve(key)
+}
+
+fn verify_key(args: cli::VerifyKeyArgs) {
+    let Keys {
+        mut key, key_block, ..
+    } = decryption_keys(args.keys, args.identity, args.age_identity);
+    if key.is_none() && key_block.is_none() {
+        log::error(format_args!("no key to verify"));
+        exit(EXIT_USAGE);
+    }
+    let path = &args.input;
+    let mut bytes = read_input(path.as_str()).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", path, err));
+        exit(EXIT_IO);
+    });
+    if fec::is_wrapped(&bytes) {
+        bytes = or_exit(fec::unwrap(&bytes)).into_owned();
+    }
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        bytes = or_exit(compression::decompress(&bytes));
+    }
+    or_exit(format::checked_prefix_len(&bytes, args.legacy));
+    let (_, data_offset) = or_exit(read_header(&bytes, &mut key, key_block));
+    if key.is_some() && unchecked_v1(&bytes, data_offset) {
+        log::error(format_args!(
+            "{}: format version 1 file without a key check, only decoding it tells whether the key is right",
+            path
+        ));
+        exit(EXIT_CRYPTO);
+    }
+    // A key that passes the key check can still meet tampered data
+    if let Some(key) = &key {
+        or_exit(check_auth(&bytes[..format::content_len(&bytes)], key));
+    }
+    if log::json() {
+        println!(
+            "{{\"path\":\"{}\",\"key_correct\":true}}",
+            json_escape(path)
+        );
+    } else {
+        println!("{}: key is correct", path);
+    }
+}
+
+// key check: a key decodes and is of an AES size, before a long encode needs it
+fn key_check(args: cli::KeyCheckArgs) {
+    let Keys { key, .. } = source_key_args(args.source).resolve(args.key.or(args.key_option));
+    let Some(key) = key else {
+        log::error(format_args!(
+            "key check requires a key (or --key-env, --key-file, --key-name, --shares)"
+        ));
+        exit(EXIT_USAGE);
+    };
+    let bits = check_key(&key).map_or_else(
+        |err| {
+            log::error(format_args!("{}", err));
+            exit(err.exit_code());
         },
+        |bytes| bytes.len() * 8,
     );
+    if log::json() {
+        println!("{{\"valid\":true,\"bits\":{}}}", bits);
+    } else {
+        println!("key is valid: {} bits, AES-{}", bits, bits);
+    }
+}
 
-    let cpus_amount = num_cpus::get();
-    let data = Arc::new(img.pixels().cloned().collect::<Vec<Rgb<u8>>>());
-    let bytes_per_thread = data.len().div_ceil(cpus_amount);
-    let palette = Arc::new(palette);
-    let progress_bar = Arc::new(Mutex::new(ProgressBar::new(data.len())));
-    let mut handles = Vec::with_capacity(cpus_amount);
-    for i in 0..cpus_amount {
-        let data = Arc::clone(&data);
-        let progress_bar = Arc::clone(&progress_bar);
-        let palette = Arc::clone(&palette);
-        let key_bind = key_opt.clone();
-        let start = i * bytes_per_thread;
-        let end = ((i + 1) * bytes_per_thread).min(data.len());
-
-        let chunk = data[start..end].to_vec();
-        let handle = thread::Builder::new()
-            .name(format!("processing-{i}/{cpus_amount}"))
-            .spawn(move || process_encode(chunk, &palette, key_bind, progress_bar))
-            .unwrap();
-        handles.push(handle);
-    }
-    let mut result = Vec::new();
-    for handle in handles {
-        let processed_chunk = handle.join().unwrap();
-        result.extend(processed_chunk);
-    }
-    let palette_bytes = palette.iter().flat_map(|rgb| rgb.0).collect::<Vec<u8>>();
-    let mut output_bytes = Vec::with_capacity(3 + palette_size * 3 + result.len());
-    output_bytes.extend_from_slice(&pack_dimensions(width as u16 - 2, height as u16 - 2));
-    output_bytes.push((palette_size - 2) as u8);
-    output_bytes.extend_from_slice(&palette_bytes);
-    output_bytes.extend_from_slice(&result);
-    if compress {
-        let compressed = zstd::encode_all(output_bytes.as_slice(), 0).expect("Compression failed");
-        return if compressed.len() < output_bytes.len() {
-            compressed
+// Re-encrypts the pixels data of files with a new key in place, see rekey::rekey
+fn rekey(args: cli::RekeyArgs) {
+    for path in &args.paths {
+        let bytes = read_or_exit(path);
+        let rekeyed = rekey::rekey(&bytes, &args.old_key, &args.new_key).unwrap_or_else(|err| {
+            log::error(format_args!("{}: {}", path, err));
+            exit(err.exit_code());
+        });
+        // Re-encrypted in place
+        or_exit(write_file(&rekeyed, path, true));
+        if log::json() {
+            println!("{{\"path\":\"{}\",\"rekeyed\":true}}", json_escape(path));
         } else {
-            output_bytes
+            println!("{}: rekeyed", path);
+        }
+    }
+}
+
+// Lists the key slots of a file, the one of the key marked, or edits them in place, see
+// rekey::edit_slots
+#[cfg(feature = "crypto")]
+fn slots(args: cli::SlotsArgs) {
+    let Keys { key, .. } = source_key_args(args.source).resolve(args.key);
+    let (path, add, remove) = (&args.input, args.add, args.remove);
+    let bytes = read_or_exit(path);
+    if add.is_empty() && remove.is_empty() {
+        let ids = metadata::read(&bytes)
+            .and_then(|entries| {
+                let (_, table) = entries
+                    .into_iter()
+                    .find(|(entry, _)| entry == KEY_SLOTS_KEY)
+                    .ok_or(CodecError::InvalidInput(
+                        "has no key-slot table, encode it with --key-slot".to_string(),
+                    ))?;
+                slot_table::slot_ids(&table).ok_or(CodecError::BadHeader(
+                    "corrupt in its key-slot table".to_string(),
+                ))
+            })
+            .unwrap_or_else(|err| {
+                log::error(format_args!("{}: {}", path, err));
+                exit(err.exit_code());
+            });
+        let own = key.map(|key| slot_table::slot_id(&key_bytes(&key)));
+        for (position, id) in ids.iter().enumerate() {
+            println!(
+                "{}: slot {}: {}{}",
+                path,
+                position,
+                id.iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>(),
+                if own == Some(*id) { " (this key)" } else { "" }
+            );
+        }
+    } else {
+        let Some(key) = key else {
+            log::error(format_args!(
+                "adding or removing key slots requires the key of a slot"
+            ));
+            exit(EXIT_USAGE);
         };
+        let add = add.iter().map(|key| key_bytes(key)).collect::<Vec<_>>();
+        let edited = rekey::edit_slots(&bytes, &key, &add, &remove).unwrap_or_else(|err| {
+            log::error(format_args!("{}: {}", path, err));
+            exit(err.exit_code());
+        });
+        // Edited in place
+        or_exit(write_file(&edited, path, true));
+        println!(
+            "{}: {} slots added, {} removed",
+            path,
+            add.len(),
+            remove.len()
+        );
     }
-    output_bytes
-}
-
-fn do_decode(
-    mut bytes: Vec<u8>,
-    key_opt: Option<String>,
-    compress: bool,
-) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-    if compress {
-        let decompressed = zstd::decode_all(&mut bytes.as_slice()).expect("Decompression failed");
-        bytes = decompressed;
-    }
-    let palette_size = bytes[3] as usize + 2;
-    let palette = decode_palette(&bytes[4..(palette_size * 3) + 4]);
-    let data = Arc::new(&bytes[(4 + palette.len() * 3)..]);
-    let cpus_amount = num_cpus::get();
-    let bytes_per_thread = data.len().div_ceil(cpus_amount);
-    let mut handles = Vec::with_capacity(cpus_amount);
-    let progress_bar = Arc::new(Mutex::new(ProgressBar::new(data.len())));
-    for i in 0..cpus_amount {
-        let data = Arc::clone(&data);
-        let progress_bar = Arc::clone(&progress_bar);
-        let palette_bind = palette.clone();
-        let key_bind = key_opt.clone();
-
-        let start = i * bytes_per_thread;
-        let end = ((i + 1) * bytes_per_thread).min(data.len());
-        let chunk: Vec<u8> = data[start..end].to_vec();
-        let handle = thread::Builder::new()
-            .name(format!("processing-{i}/{cpus_amount}"))
-            .spawn(move || {
-                process_decode(chunk, &palette_bind, key_bind, progress_bar, cpus_amount)
+}
+
+#[cfg(not(feature = "crypto"))]
+fn slots(_args: cli::SlotsArgs) {
+    or_exit(Err::<(), _>(CodecError::FeatureDisabled("crypto")));
+}
+
+fn validate(args: cli::ValidateArgs) {
+    let Keys { key, key_block, .. } = decryption_keys(args.keys, args.identity, args.age_identity);
+    let path = &args.input;
+    let bytes = read_input(path.as_str()).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", path, err));
+        exit(EXIT_IO);
+    });
+    log::set_level(log::Level::Quiet);
+    let decoder = decoder_with_keys(
+        Decoder::new().strict(!args.permissive).legacy(args.legacy),
+        key,
+        key_block,
+    );
+    let img = or_exit(decoder.decode(&bytes));
+    if log::json() {
+        println!(
+            "{{\"path\":\"{}\",\"valid\":true,\"width\":{},\"height\":{}}}",
+            json_escape(path),
+            img.width(),
+            img.height()
+        );
+    } else {
+        println!("{}: valid, {}x{}", path, img.width(), img.height());
+    }
+}
+
+fn verify(args: cli::VerifyArgs) {
+    let signing_key = parse_signing_key(args.sign.as_deref());
+    let path = &args.input;
+    let bytes = read_input(path.as_str()).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", path, err));
+        exit(EXIT_IO);
+    });
+    let verification = verify::verify(&bytes)
+        .and_then(|verification| {
+            if let Some(key) = &signing_key {
+                verify::check_signature(&bytes, key)?;
+            }
+            Ok(verification)
+        })
+        .unwrap_or_else(|err| {
+            log::error(format_args!("{}: {}", path, err));
+            exit(err.exit_code());
+        });
+    let signed = if signing_key.is_some() {
+        ", signature correct"
+    } else {
+        ""
+    };
+    match (log::json(), verification.checksum) {
+        (true, checksum) => println!(
+            "{{\"path\":\"{}\",\"ok\":true,\"version\":{},\"checksum\":{}{}}}",
+            json_escape(path),
+            verification.version,
+            checksum.map_or("null".to_string(), |checksum| format!(
+                "\"{:08x}\"",
+                checksum
+            )),
+            if signing_key.is_some() {
+                ",\"signature\":true"
+            } else {
+                ""
+            }
+        ),
+        (false, Some(checksum)) => println!("{}: ok, checksum {:08x}{}", path, checksum, signed),
+        (false, None) => println!(
+            "{}: ok, format version {} has no checksum, only the layout was checked{}",
+            path, verification.version, signed
+        ),
+    }
+}
+
+fn inspect(args: cli::InspectArgs) {
+    let path = args.input.as_str();
+    let bytes = read_input(path).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", path, err));
+        exit(EXIT_IO);
+    });
+    let color = std::io::stdout().is_terminal();
+    print!("{}", inspect::inspect(&bytes, color, args.full));
+}
+
+fn compare_sizes(args: cli::CompareSizesArgs) {
+    let sizes = args
+        .sizes
+        .split(',')
+        .map(|size| match size.trim().parse::<usize>() {
+            Ok(size) if (2..=format::MAX_PALETTE_SIZE).contains(&size) => size,
+            _ => {
+                log::error(format_args!(
+                    "palette size should be between 2 and {}, got {}",
+                    format::MAX_PALETTE_SIZE,
+                    size
+                ));
+                exit(EXIT_USAGE);
+            }
+        })
+        .collect::<Vec<_>>();
+    let img = open_img(&args.input).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", args.input, err));
+        exit(EXIT_IO);
+    });
+    let (grid, rows) = or_exit(compare::compare_sizes(&img, &sizes, args.compress));
+    print_rows(&rows);
+    or_exit(write_png(
+        DynamicImage::ImageRgb8(grid),
+        &args.out,
+        &Embedded::default(),
+        args.force,
+    ));
+}
+
+// Labels of the variants of a comparison, or with --json their array
+fn print_rows(rows: &[compare::Row]) {
+    if !log::json() {
+        rows.iter().for_each(|row| println!("{}", row.label));
+        return;
+    }
+    // JSON has no infinity, a variant identical to the image has a null PSNR
+    let number = |value: f64| {
+        if value.is_finite() {
+            format!("{:.4}", value)
+        } else {
+            "null".to_string()
+        }
+    };
+    let rows = rows
+        .iter()
+        .map(|row| {
+            format!(
+                "{{\"palette_size\":{},\"dither\":\"{}\",\"bytes\":{},\"psnr\":{},\"ssim\":{}}}",
+                row.palette_size,
+                row.dither.name(),
+                row.encoded_len,
+                number(row.psnr),
+                number(row.ssim)
+            )
+        })
+        .collect::<Vec<_>>();
+    println!("[{}]", rows.join(","));
+}
+
+fn compare_dither(args: cli::CompareDitherArgs) {
+    let img = open_img(&args.input).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", args.input, err));
+        exit(EXIT_IO);
+    });
+    let (grid, rows) = or_exit(compare::compare_dither(
+        &img,
+        args.palette_size as usize,
+        args.compress,
+    ));
+    print_rows(&rows);
+    or_exit(write_png(
+        DynamicImage::ImageRgb8(grid),
+        &args.out,
+        &Embedded::default(),
+        args.force,
+    ));
+}
+
+fn upgrade(args: cli::UpgradeArgs) {
+    let (input, output) = (&args.input, &args.output);
+    let bytes = read_input(input).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", input, err));
+        exit(EXIT_IO);
+    });
+    let upgraded = match &args.key {
+        Some(key) => format::upgrade_with_key(&bytes, key, threads(args.jobs)),
+        None => format::upgrade(&bytes),
+    };
+    let (upgraded, version) = upgraded.unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", input, err));
+        exit(err.exit_code());
+    });
+    or_exit(write_file(&upgraded, output, args.force));
+    if version >= format::V3 {
+        println!("{}: already format version {}", input, version);
+    } else {
+        println!(
+            "{}: upgraded from format version {} to {}",
+            input,
+            version,
+            format::VERSION
+        );
+    }
+}
+
+// Rebuilds the damaged shards of a file protected with --fec, parity included, so it's whole and
+// protected again
+fn repair(args: cli::RepairArgs) {
+    let (input, output) = (&args.input, &args.output);
+    let bytes = read_or_exit(input);
+    let (repaired, repair) = fec::repair(&bytes).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", input, err));
+        exit(err.exit_code());
+    });
+    or_exit(write_file(&repaired, output, args.force));
+    if repair.damaged == 0 {
+        println!(
+            "{}: nothing to repair, its {} shards check out",
+            input, repair.shards
+        );
+    } else {
+        println!(
+            "{}: repaired {} of {} shards",
+            input, repair.damaged, repair.shards
+        );
+    }
+}
+
+// Entry name of a file to add to a container, the file name it's read from
+fn entry_name(input: &str, bytes: &[u8]) -> String {
+    let name = std::path::Path::new(input)
+        .file_name()
+        .map_or("-".to_string(), |name| name.to_string_lossy().into_owned());
+    if let Err(err) = container::check_entry(&name, bytes) {
+        log::error(format_args!("{}: {}", input, err));
+        exit(err.exit_code());
+    }
+    name
+}
+
+fn read_or_exit(path: &str) -> Vec<u8> {
+    read_input(path).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", path, err));
+        exit(EXIT_IO);
+    })
+}
+
+fn read_container<'a>(path: &str, bytes: &'a [u8]) -> container::Container<'a> {
+    container::read(bytes).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", path, err));
+        exit(err.exit_code());
+    })
+}
+
+// Appends files to a container, created when it doesn't exist yet
+fn add(args: cli::AddArgs) {
+    let path = &args.container;
+    let existing = if std::path::Path::new(path).exists() {
+        read_or_exit(path)
+    } else {
+        Vec::new()
+    };
+    let mut container = if existing.is_empty() {
+        container::Container::default()
+    } else {
+        read_container(path, &existing)
+    };
+    let inputs = args
+        .inputs
+        .iter()
+        .map(|input| {
+            let bytes = read_or_exit(input);
+            (entry_name(input, &bytes), bytes)
+        })
+        .collect::<Vec<_>>();
+    let first = container.entries.len();
+    container
+        .entries
+        .extend(inputs.iter().map(|(name, bytes)| container::Entry {
+            delay: args.delay,
+            ..container::Entry::new(name.clone(), bytes)
+        }));
+    container.loops = args.loops.or(container.loops);
+    // Appended to in place
+    or_exit(write_file(&container::write(&container).0, path, true));
+    for (i, (name, _)) in inputs.iter().enumerate() {
+        println!("{}: added {} as entry {}", path, name, first + i);
+    }
+}
+
+// Entries of containers and single files one after another in a new container
+fn cat(args: cli::CatArgs) {
+    let output = args.out;
+    // Before any input is read
+    or_exit(check_output(&output, args.force));
+    let inputs = args
+        .inputs
+        .iter()
+        .map(|input| {
+            let bytes = read_or_exit(input);
+            let name = (!container::is_container(&bytes)).then(|| entry_name(input, &bytes));
+            (input, name, bytes)
+        })
+        .collect::<Vec<_>>();
+    // The loop count of the first animation among the inputs
+    let mut container = container::Container::default();
+    for (input, name, bytes) in &inputs {
+        match name {
+            Some(name) => container
+                .entries
+                .push(container::Entry::new(name.clone(), bytes)),
+            None => {
+                let read = read_container(input, bytes);
+                container.entries.extend(read.entries);
+                container.loops = container.loops.or(read.loops);
+            }
+        }
+    }
+    let (bytes, shared) = container::write(&container);
+    or_exit(write_file(&bytes, &output, args.force));
+    println!(
+        "{}: {} entries from {} files, {} of them share an earlier entry's palette",
+        output,
+        container.entries.len(),
+        inputs.len(),
+        shared
+    );
+}
+
+fn list(args: cli::ListArgs) {
+    let path = &args.container;
+    let bytes = read_or_exit(path);
+    let container::Container { entries, loops } = read_container(path, &bytes);
+    // Exit code of the last entry that failed, as info does
+    let mut failed = None;
+    let mut records = Vec::with_capacity(entries.len());
+    for (i, entry) in entries.iter().enumerate() {
+        match bytes_info(&entry.name, entry.bytes.to_vec()) {
+            Ok(info) => records.push((i, info, entry)),
+            Err(err) => {
+                log::error(format_args!(
+                    "{}: entry {} ({}): {}",
+                    path, i, entry.name, err
+                ));
+                failed = Some(err.exit_code());
+            }
+        }
+    }
+    if log::json() {
+        let items = records
+            .iter()
+            .map(|(i, info, entry)| {
+                format!(
+                    "{{\"entry\":{},\"bytes\":{},\"shared_palette\":{},\"delay\":{},\"info\":{}}}",
+                    i,
+                    entry.bytes.len(),
+                    entry
+                        .shared_palette
+                        .map_or("null".to_string(), |source| source.to_string()),
+                    entry
+                        .delay
+                        .map_or("null".to_string(), |delay| delay.to_string()),
+                    info.to_json()
+                )
             })
-            .unwrap();
-        handles.push(handle);
+            .collect::<Vec<_>>();
+        println!(
+            "{{\"path\":\"{}\",\"loops\":{},\"entries\":[{}]}}",
+            json_escape(path),
+            loops.map_or("null".to_string(), |loops| loops.to_string()),
+            items.join(",")
+        );
+    } else {
+        match loops {
+            Some(0) => println!("{}: {} entries, looping forever", path, entries.len()),
+            Some(loops) => println!("{}: {} entries, {} loops", path, entries.len(), loops),
+            None => println!("{}: {} entries", path, entries.len()),
+        }
+        for (i, info, entry) in records {
+            let delay = entry
+                .delay
+                .map_or(String::new(), |delay| format!(", {}ms", delay));
+            match entry.shared_palette {
+                Some(source) => println!(
+                    "{}: {} bytes{} (palette stored once with entry {}), {}",
+                    i,
+                    entry.bytes.len(),
+                    delay,
+                    source,
+                    info
+                ),
+                None => println!("{}: {} bytes{}, {}", i, entry.bytes.len(), delay, info),
+            }
+        }
     }
-    let (width, height) = unpack_dimensions(&bytes[..=2]);
-    let mut result = Vec::new();
-    for handle in handles {
-        let processed_chunk = handle.join().unwrap();
-        result.extend(processed_chunk);
+    if let Some(code) = failed {
+        exit(code);
     }
-    ImageBuffer::from_raw(width + 2, height + 2, result).expect(
-        "Error: Not enough data. Image is compressed (add \"z\" flag to decode mode) or corrupted",
-    )
 }
 
-// Using result as enum for two "Ok()" dtypes
-fn do_output(data: Result<Vec<u8>, ImageBuffer<Rgb<u8>, Vec<u8>>>, output_file_path: &str) {
-    match data {
-        Ok(bytes) => {
-            write_file(bytes.as_slice(), output_file_path);
+// Writes an entry of a container as the file it was added as, by default under its name
+fn extract(args: cli::ExtractArgs) {
+    let (path, index) = (&args.container, args.entry);
+    let bytes = read_or_exit(path);
+    let entries = read_container(path, &bytes).entries;
+    let Some(entry) = entries.get(index) else {
+        log::error(format_args!(
+            "entry should be a number from 0 to {}, got {}",
+            entries.len() as isize - 1,
+            index
+        ));
+        exit(EXIT_USAGE);
+    };
+    // Only the file name of a stored name, which may come from anywhere
+    let output = args.output.clone().or_else(|| {
+        std::path::Path::new(&entry.name)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+    });
+    let Some(output) = output.filter(|output| output != "-" || args.output.is_some()) else {
+        log::error(format_args!(
+            "entry {} has no usable name, pass [output_file_path]",
+            index
+        ));
+        exit(EXIT_USAGE);
+    };
+    or_exit(write_file(&entry.bytes, &output, args.force));
+}
+
+fn quantize(args: cli::QuantizeArgs) {
+    let img = open_img(&args.input).unwrap_or_else(|err| {
+        log::error(format_args!("{}: {}", args.input, err));
+        exit(EXIT_IO);
+    });
+    let pixels = img.pixels().cloned().collect::<Vec<_>>();
+    let palette = gen_palette(&pixels, args.palette_size as usize, num_cpus::get());
+    let output = args.out;
+    // Hex lines to stdout without --out
+    let path = output.as_deref().unwrap_or("-.hex");
+    let bytes = palette_file::export(&palette, path).unwrap_or_else(|err| {
+        log::error(format_args!("{}", err));
+        exit(EXIT_USAGE);
+    });
+    match output {
+        Some(path) => or_exit(write_file(&bytes, path.as_str(), args.force)),
+        None if log::json() => {
+            let colors = palette
+                .iter()
+                .map(|rgb| format!("\"{:02x}{:02x}{:02x}\"", rgb[0], rgb[1], rgb[2]))
+                .collect::<Vec<_>>();
+            println!("{{\"palette\":[{}]}}", colors.join(","));
         }
-        Err(img) => {
-            _ = save_img(img.clone(), output_file_path);
+        None => print!("{}", String::from_utf8_lossy(&bytes)),
+    }
+}
+
+// Stdin without paths
+fn info(mut paths: Vec<String>) {
+    if paths.is_empty() {
+        paths.push("-".to_string());
+    }
+    // Exit code of the last failed path
+    let mut failed = None;
+    let mut records = Vec::with_capacity(paths.len());
+    for path in paths {
+        match get_info(path.as_str()) {
+            Ok(info) => records.push(info),
+            // Io errors already name the path
+            Err(err @ CodecError::Io { .. }) => {
+                log::error(format_args!("{}", err));
+                failed = Some(err.exit_code());
+            }
+            Err(err) => {
+                log::error(format_args!("{}: {}", path, err));
+                failed = Some(err.exit_code());
+            }
         }
     }
+    if log::json() {
+        let items = records
+            .iter()
+            .map(|info| info.to_json())
+            .collect::<Vec<_>>();
+        println!("[{}]", items.join(","));
+    } else {
+        for info in records {
+            println!("{}", info);
+        }
+    }
+    if let Some(code) = failed {
+        exit(code);
+    }
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() == 1 {
-        println!("Usage: exe [options] [input_file_path] [output_file_path] [palette_size(encode)] [base64url_key(optional)]
+fn parse_meta(args: &[String]) -> metadata::Entries {
+    let entries = or_exit(
+        args.iter()
+            .map(|arg| metadata::parse_arg(arg))
+            .collect::<Result<Vec<_>, _>>(),
+    );
+    or_exit(metadata::check(&entries));
+    entries
+}
 
-    options:
-        - e - encode mode: input - existing [input_file_path], output - saved [output_file_path] or stderr
-        - d - decode mode: input - existing [input_file_path], output - saved [output_file_path] or stderr
-        - c - encryption-decryption flag
-        - z - compression-decompression flag: requires additional [base64url_key] arg at last position
-        - g - 16bytes base64url stdout key gen (doesn not need any input)");
-        return;
-    } else if args[1] == "g" {
-        println!("{}", gen_key());
-        return;
-    } else if args[1] == "i" {
-        println!("{}", get_info(args[2].as_str()));
-        return;
+// Settings shared by every job of a run
+#[derive(Clone)]
+struct JobSettings {
+    options: String,
+    input_format: InputFormat,
+    palette_size: usize,
+    // None picks the best one per image (--dither auto)
+    dither: Option<Dither>,
+    error_map: Option<String>,
+    strict: bool,
+    // Decode only: read files without the magic as format version 1
+    legacy: bool,
+    // Raw or PNM input is encoded, PPM output decoded, without holding the whole image
+    stream: bool,
+    key: Option<String>,
+    key_args: KeyArgs,
+    threads: usize,
+    compression_level: i32,
+    // Encode only: report the would-be output instead of writing it
+    dry_run: bool,
+    // Replace existing outputs (the error map and trace too)
+    force: bool,
+    // Encode only: --meta fields
+    metadata: metadata::Entries,
+    // Encode only: keep the ICC profile of the input
+    icc: bool,
+    // Encode only: keep the EXIF orientation, timestamps and camera info of the input
+    exif: bool,
+    // Encode only: RGBA palette entries instead of dropping the alpha channel
+    alpha: bool,
+    // Encode only: pixels of this color get the transparent index, without it fully
+    // transparent pixels of the input do
+    transparent: Option<Rgb<u8>>,
+    // Encode only: one byte luma palette entries even for color input, grayscale input gets
+    // them without it
+    gray: bool,
+    // Encode only: PNG-style row filters
+    filter: bool,
+    // Encode only: run-length encoded pixels data
+    rle: bool,
+    // Encode only: order the indices are serialized in
+    scan: Scan,
+    // Encode only: side of the tiles with a palette each
+    tiles: Option<u32>,
+    // Encode only: Adam7 passes instead of rows
+    interlace: bool,
+    // Decode only: coarse image of the passes an interlaced file holds so far
+    preview: bool,
+    // Encode only: row offsets before the rows, for decode --crop
+    seek: bool,
+    // Encode only: the image's own colors, or truecolor pixels when they don't fit the palette
+    lossless: bool,
+    // Encode only: palette entries encrypted along with the pixels data
+    encrypt_palette: bool,
+    // Encode only: format-preserving encryption mode of the key
+    fpe: Fpe,
+    // Decode only: x, y, width and height of the region to decode
+    crop: Option<(u32, u32, u32, u32)>,
+    // Encode only: KiB blocks with a checksum each
+    chunk_checksums: Option<u32>,
+    // Decode only: color of the damaged rows of a file with chunk checksums
+    placeholder: Option<Rgb<u8>>,
+    // Encode only: Reed-Solomon parity percent of the file
+    fec: Option<u8>,
+    // Encode only: key of the signature, see Encoder::sign
+    sign: Option<Vec<u8>>,
+    // Encode only: every frame of an animated input, as the entries of a container
+    frames: bool,
+}
+
+fn encoder(settings: &JobSettings, dither: Dither, keys: Keys) -> Encoder {
+    let mut encoder = Encoder::new()
+        .progress(ProgressBar::new())
+        .palette_size(settings.palette_size)
+        .dither(dither)
+        .compression(settings.options.contains("z"))
+        .compression_level(settings.compression_level)
+        .filter(settings.filter)
+        .rle(settings.rle)
+        .scan(settings.scan)
+        .interlace(settings.interlace)
+        .seek(settings.seek)
+        .lossless(settings.lossless)
+        .encrypt_palette(settings.encrypt_palette)
+        .fpe(settings.fpe)
+        .threads(settings.threads);
+    if let Some(size) = settings.tiles {
+        encoder = encoder.tiles(size);
     }
-    let options = args[1].clone();
-    let input_bytes = do_input(args[2].as_str(), options.contains("e"));
-    let key = if options.contains("c") {
-        if options.contains("e") {
-            Some(args[5].clone())
-        } else {
-            Some(args[4].clone())
+    if let Some(kib) = settings.chunk_checksums {
+        encoder = encoder.chunk_checksums(kib as usize * 1024);
+    }
+    if let Some(percent) = settings.fec {
+        encoder = encoder.fec(percent);
+    }
+    if let Some(key) = &settings.sign {
+        encoder = encoder.sign(key);
+    }
+    if let Some(key) = keys.key {
+        encoder = encoder.key(&key_bytes(&key));
+    }
+    if let Some(key_slots) = keys.key_slots {
+        encoder = encoder.key_slots(key_slots);
+    }
+    if let Some(slot_table) = keys.slot_table {
+        encoder = encoder.slot_table(slot_table);
+    }
+    if let Some(path) = &settings.error_map {
+        let (path, force) = (path.clone(), settings.force);
+        encoder = encoder.error_map(move |map| {
+            write_png(
+                DynamicImage::ImageRgb8(map),
+                &path,
+                &Embedded::default(),
+                force,
+            )
+        });
+    }
+    for (key, value) in &settings.metadata {
+        encoder = encoder.metadata(key, value);
+    }
+    encoder
+}
+
+// Compression is left to the decoder's detection, so "z" is optional on decode
+fn decoder(settings: &JobSettings, key: Option<String>, key_block: Option<KeyBlock>) -> Decoder {
+    let mut decoder = Decoder::new()
+        .progress(ProgressBar::new())
+        .strict(settings.strict)
+        .legacy(settings.legacy)
+        .threads(settings.threads);
+    if let Some(color) = settings.placeholder {
+        decoder = decoder.placeholder(color.to_rgba());
+    }
+    decoder_with_keys(decoder, key, key_block)
+}
+
+// Region of a file and the color profile and EXIF of its head, see Decoder::decode_region
+fn crop(
+    decoder: &Decoder,
+    mut reader: impl Read + Seek,
+    input: &str,
+    (x, y, width, height): (u32, u32, u32, u32),
+) -> Result<(DynamicImage, Embedded), CodecError> {
+    let io_error = |source| CodecError::Io {
+        path: input.to_string(),
+        source,
+    };
+    let mut head = Vec::new();
+    (&mut reader)
+        .take(stream::HEAD_LEN)
+        .read_to_end(&mut head)
+        .map_err(io_error)?;
+    // A compressed file's metadata is in the blocks read so far
+    if head.starts_with(&ZSTD_MAGIC) {
+        head = compression::decompress_partial(&head).unwrap_or_default();
+    }
+    // A file failing here fails decoding too, with the better message
+    let embedded = metadata::read(&head)
+        .map(|entries| Embedded::from_metadata(&entries))
+        .unwrap_or_default();
+    reader.rewind().map_err(io_error)?;
+    let img = decoder.decode_region(reader, x, y, width, height)?;
+    Ok((img, embedded))
+}
+
+// "-" is stdin or stdout
+fn run_stream_job(
+    settings: &JobSettings,
+    keys: Keys,
+    input: &str,
+    output: &str,
+) -> Result<(), CodecError> {
+    let mut reader = open_input(input).map_err(|source| CodecError::Io {
+        path: input.to_string(),
+        source,
+    })?;
+    write_atomically(output, settings.force, |path| {
+        let writer = create_output(path).map_err(|source| CodecError::Io {
+            path: output.to_string(),
+            source,
+        })?;
+        if !settings.options.contains("e") {
+            decoder(settings, keys.key, keys.key_block).decode_stream(reader, writer)?;
+            return Ok(());
+        }
+        let (width, height) = match settings.input_format {
+            InputFormat::Raw(width, height) => (width, height),
+            _ => stream::read_pnm_header(&mut reader)?,
+        };
+        // --dither auto is rejected with --stream
+        let dither = settings.dither.unwrap_or(Dither::FloydSteinberg);
+        encoder(settings, dither, keys).encode_stream(reader, writer, width, height)
+    })
+}
+
+// Dithering algorithm of the settings, --dither auto scores them on img
+fn pick_dither(
+    settings: &JobSettings,
+    img: &DynamicImage,
+    timings: &mut Timings,
+) -> Result<Dither, CodecError> {
+    Ok(match settings.dither {
+        Some(dither) => dither,
+        // Lossless encodes aren't dithered
+        None if settings.lossless => Dither::None,
+        None => {
+            let start = Instant::now();
+            let dither = compare::auto_dither(&img.to_rgb8(), settings.palette_size)?;
+            timings.record("dither-auto", start);
+            log::info(format_args!("Dither: {}", dither.name()));
+            dither
         }
+    })
+}
+
+// Encodes img with RGBA, keyed transparent, luma or RGB palette entries as the settings and its
+// color type ask for
+fn encode_image(
+    settings: &JobSettings,
+    encoder: Encoder,
+    img: DynamicImage,
+    timings: &mut Timings,
+) -> Result<(Vec<u8>, EncodeStats), CodecError> {
+    // Tiles have no transparent index, their palettes keep alpha instead, as lossless files
+    // do, whose transparent pixels keep their colors
+    let kept_alpha = (settings.tiles.is_some() || settings.lossless)
+        && !settings.gray
+        && img.color().has_alpha();
+    if settings.alpha || kept_alpha {
+        return encoder.encode_stats(img.into_rgba8(), timings);
+    }
+    let keyed = match settings.transparent {
+        Some(color) => Some((img.to_rgb8(), color)),
+        None if !settings.gray && img.color().has_alpha() => key_transparent(&img.to_rgba8()),
+        None => None,
+    };
+    match keyed {
+        Some((img, color)) => encoder.transparent(color).encode_stats(img, timings),
+        None if settings.gray || is_gray(&img) => encoder.encode_stats(img.into_luma8(), timings),
+        None => encoder.encode_stats(img.into_rgb8(), timings),
+    }
+}
+
+// encode --frames: a container of the frames of an animated GIF or APNG, each encoded as a file
+// of its own (with a key block or salt of its own too), with their delays and the loop count;
+// --dither auto picks the algorithm on the first frame
+fn encode_frames(
+    settings: &JobSettings,
+    input: &str,
+    output: &str,
+    timings: &mut Timings,
+) -> Result<(), CodecError> {
+    let start = Instant::now();
+    let bytes = read_input(input).map_err(|source| CodecError::Io {
+        path: input.to_string(),
+        source,
+    })?;
+    let animation::Animation { frames, loops } = animation::read(&bytes)
+        .map_err(|source| CodecError::Image {
+            path: input.to_string(),
+            source,
+        })?
+        .ok_or_else(|| {
+            CodecError::InvalidInput(format!(
+                "{}: not an animated GIF or APNG, which --frames takes",
+                input
+            ))
+        })?;
+    timings.record("decode input", start);
+    let stem = std::path::Path::new(input)
+        .file_stem()
+        .map_or("frame".to_string(), |stem| {
+            stem.to_string_lossy().into_owned()
+        });
+    let mut dither = settings.dither;
+    let mut files = Vec::with_capacity(frames.len());
+    for (img, delay) in frames {
+        let img = DynamicImage::ImageRgba8(img);
+        let picked = match dither {
+            Some(dither) => dither,
+            None => pick_dither(settings, &img, timings)?,
+        };
+        dither = Some(picked);
+        let keys = settings
+            .key_args
+            .clone()
+            .resolve(settings.key.clone())
+            .sealed();
+        let (bytes, _) = encode_image(settings, encoder(settings, picked, keys), img, timings)?;
+        files.push((bytes, delay));
+    }
+    let entries = files
+        .iter()
+        .enumerate()
+        .map(|(i, (bytes, delay))| container::Entry {
+            delay: Some(*delay),
+            ..container::Entry::new(format!("{}-{}.ric", stem, i), bytes)
+        })
+        .collect();
+    let container = container::Container {
+        entries,
+        loops: Some(loops),
+    };
+    let start = Instant::now();
+    write_file(&container::write(&container).0, output, settings.force)?;
+    timings.record("write", start);
+    Ok(())
+}
+
+// Decode of a container: its entries as the frames of an APNG, or of an animated GIF for an
+// output ending in .gif, with their delays and loop count
+fn decode_frames(
+    decoder: &Decoder,
+    bytes: &[u8],
+    input: &str,
+    output: &str,
+    force: bool,
+    timings: &mut Timings,
+) -> Result<(), CodecError> {
+    let container = container::read(bytes)?;
+    let frames = container
+        .entries
+        .iter()
+        .map(|entry| {
+            let img = decoder.decode_dynamic_timed(entry.bytes.to_vec(), timings)?;
+            Ok((
+                img.into_rgba8(),
+                entry.delay.unwrap_or(animation::DEFAULT_DELAY),
+            ))
+        })
+        .collect::<Result<Vec<_>, CodecError>>()?;
+    let animation = animation::Animation {
+        frames,
+        loops: container.loops.unwrap_or(0),
+    };
+    let start = Instant::now();
+    let mut encoded = Vec::new();
+    let written = if output.to_ascii_lowercase().ends_with(".gif") {
+        animation::write_gif(&animation, &mut encoded)
     } else {
-        None
+        animation::write_apng(&animation, &mut encoded)
     };
+    written.map_err(|source| CodecError::Image {
+        path: input.to_string(),
+        source,
+    })?;
+    write_file(&encoded, output, force)?;
+    timings.record("write", start);
+    Ok(())
+}
 
+fn run_job(
+    settings: &JobSettings,
+    input: &str,
+    output: &str,
+    timings: &mut Timings,
+) -> Result<Option<EncodeStats>, CodecError> {
+    if settings.frames {
+        return encode_frames(settings, input, output, timings).map(|_| None);
+    }
+    let options = settings.options.as_str();
+    let mut keys = settings.key_args.clone().resolve(settings.key.clone());
+    if options.contains("e") {
+        keys = keys.sealed();
+    }
+    if settings.stream {
+        return run_stream_job(settings, keys, input, output).map(|_| None);
+    }
+    // A file is read as far as the region needs, stdin whole
+    if let Some(region) = settings.crop {
+        let decoder = decoder(settings, keys.key, keys.key_block);
+        let io_error = |source| CodecError::Io {
+            path: input.to_string(),
+            source,
+        };
+        let start = Instant::now();
+        let cropped = if input == "-" {
+            let bytes = read_input(input).map_err(io_error)?;
+            crop(&decoder, Cursor::new(bytes), input, region)?
+        } else {
+            let file = File::open(input).map_err(io_error)?;
+            crop(&decoder, BufReader::new(file), input, region)?
+        };
+        timings.record("crop", start);
+        do_output(Err(cropped), output, settings.force, timings)?;
+        return Ok(None);
+    }
+    let input_bytes = do_input(input, options.contains("e"), settings.input_format, timings)?;
+    let mut encode_stats = None;
     // Using result as enum for two "Ok()" dtypes
     let processed_data = if options.contains("e") {
-        let palette_size = args[4].parse::<usize>().unwrap();
-        if !(2..=257).contains(&palette_size) {
-            eprintln!("Error: palette size should be between 2 and 257");
-            exit(1);
-        }
-        Ok(do_encode(
-            input_bytes.unwrap(),
-            palette_size,
-            key,
-            options.contains("z"),
-        ))
+        let (img, embedded) = input_bytes.unwrap();
+        let dither = pick_dither(settings, &img, timings)?;
+        let mut encoder = encoder(settings, dither, keys);
+        if settings.icc {
+            match &embedded.icc {
+                Some(icc) => encoder = encoder.metadata(metadata::ICC_KEY, icc),
+                None => log::info(format_args!("{} has no ICC profile", input)),
+            }
+        }
+        if settings.exif {
+            match embedded.exif.as_deref().and_then(exif::select) {
+                Some(exif) => encoder = encoder.metadata(metadata::EXIF_KEY, &exif),
+                None => log::info(format_args!(
+                    "{} has no EXIF orientation, time or camera tags",
+                    input
+                )),
+            }
+        }
+        let (bytes, stats) = encode_image(settings, encoder, img, timings)?;
+        if settings.dry_run {
+            // Truecolor files are compressed whatever the options say
+            let compress = options.contains("z") || (stats.truecolor && cfg!(feature = "zstd"));
+            print_dry_run(input, &stats, compress);
+            return Ok(None);
+        }
+        encode_stats = Some(stats);
+        Ok(bytes)
+    } else {
+        let bytes = input_bytes.unwrap_err();
+        if container::is_container(&bytes) {
+            let decoder = decoder(settings, keys.key, keys.key_block);
+            decode_frames(&decoder, &bytes, input, output, settings.force, timings)?;
+            return Ok(None);
+        }
+        // A file failing here fails decoding too, with the better message
+        let embedded = metadata::read(&bytes)
+            .map(|entries| Embedded::from_metadata(&entries))
+            .unwrap_or_default();
+        let decoder = decoder(settings, keys.key, keys.key_block);
+        let img = if settings.preview {
+            let (img, passes) = decoder.preview(&bytes)?;
+            log::info(format_args!(
+                "Preview of {}: {} of {} passes",
+                input,
+                passes,
+                interlace::PASSES
+            ));
+            img
+        } else {
+            decoder.decode_dynamic_timed(bytes, timings)?
+        };
+        Err((img, embedded))
+    };
+    do_output(processed_data, output, settings.force, timings)?;
+    Ok(encode_stats)
+}
+
+// Input file size and the output size as a fraction of it, None for stdin
+fn input_ratio(input: &str, len: usize) -> Option<(u64, f64)> {
+    let input_len = std::fs::metadata(input)
+        .ok()
+        .filter(|_| input != "-")?
+        .len();
+    (input_len > 0).then(|| (input_len, len as f64 / input_len as f64))
+}
+
+// --dry-run report, the ratio is against the input file unless it's stdin
+fn print_dry_run(input: &str, stats: &EncodeStats, compress: bool) {
+    if log::json() {
+        let ratio = input_ratio(input, stats.len);
+        println!(
+            "{{\"input\":\"{}\",\"width\":{},\"height\":{},\"bytes\":{},\"uncompressed_bytes\":{},\"input_bytes\":{},\"ratio\":{},\"palette_colors\":{},\"used_colors\":{},\"truecolor\":{}}}",
+            json_escape(input),
+            stats.width,
+            stats.height,
+            stats.len,
+            stats.uncompressed_len,
+            ratio.map_or("null".to_string(), |(input_len, _)| input_len.to_string()),
+            ratio.map_or("null".to_string(), |(_, ratio)| format!("{:.4}", ratio)),
+            stats.palette_colors,
+            stats.used_colors,
+            stats.truecolor
+        );
+        return;
+    }
+    let ratio = match input_ratio(input, stats.len) {
+        Some((input_len, ratio)) => {
+            format!(", {:.1}% of the {} bytes input", ratio * 100.0, input_len)
+        }
+        None => String::new(),
+    };
+    println!(
+        "{}: {}x{}, would write {} bytes{}",
+        input, stats.width, stats.height, stats.len, ratio
+    );
+    if compress && stats.len < stats.uncompressed_len {
+        println!(
+            "  zstd: {} bytes before compression",
+            stats.uncompressed_len
+        );
+    } else if compress {
+        println!("  zstd: no gain, would be stored uncompressed");
+    }
+    if stats.truecolor {
+        println!("  truecolor: more colors than the palette size, no palette");
     } else {
-        Err(do_decode(
-            input_bytes.unwrap_err(),
-            key,
-            options.contains("z"),
-        ))
+        println!(
+            "  palette: {} colors, {} used by pixels",
+            stats.palette_colors, stats.used_colors
+        );
+    }
+}
+
+// --stats report of an encode job, stderr keeps it apart from "-" output
+fn print_encode_stats(
+    format: StatsFormat,
+    input: &str,
+    output: &str,
+    stats: &EncodeStats,
+    timings: &Timings,
+) {
+    let ratio = input_ratio(input, stats.len);
+    let stages = timings.phase_totals();
+    match format {
+        StatsFormat::Json => {
+            let stages = stages
+                .iter()
+                .map(|(phase, time)| {
+                    format!(
+                        "\"{}\":{:.3}",
+                        json_escape(phase),
+                        time.as_secs_f64() * 1000.0
+                    )
+                })
+                .collect::<Vec<_>>();
+            eprintln!(
+                "{{\"input\":\"{}\",\"output\":\"{}\",\"bytes\":{},\"uncompressed_bytes\":{},\"input_bytes\":{},\"ratio\":{},\"palette_colors\":{},\"used_colors\":{},\"truecolor\":{},\"mean_error\":{},\"stages_ms\":{{{}}}}}",
+                json_escape(input),
+                json_escape(output),
+                stats.len,
+                stats.uncompressed_len,
+                ratio.map_or("null".to_string(), |(input_len, _)| input_len.to_string()),
+                ratio.map_or("null".to_string(), |(_, ratio)| format!("{:.4}", ratio)),
+                stats.palette_colors,
+                stats.used_colors,
+                stats.truecolor,
+                stats
+                    .mean_error
+                    .map_or("null".to_string(), |mean| format!("{:.3}", mean)),
+                stages.join(",")
+            );
+        }
+        StatsFormat::Text => {
+            eprintln!("\nStats: {}", output);
+            match ratio {
+                Some((input_len, ratio)) => eprintln!(
+                    "  written: {} bytes, {:.1}% of the {} bytes input",
+                    stats.len,
+                    ratio * 100.0,
+                    input_len
+                ),
+                None => eprintln!("  written: {} bytes", stats.len),
+            }
+            if stats.len < stats.uncompressed_len {
+                eprintln!(
+                    "  zstd: {} bytes before compression",
+                    stats.uncompressed_len
+                );
+            }
+            if stats.truecolor {
+                eprintln!("  truecolor: more colors than the palette size, no palette");
+            } else {
+                eprintln!(
+                    "  palette: {} of {} colors used ({:.1}%)",
+                    stats.used_colors,
+                    stats.palette_colors,
+                    stats.used_colors as f64 * 100.0 / stats.palette_colors as f64
+                );
+            }
+            if let Some(mean) = stats.mean_error {
+                eprintln!("  mean quantization error: {:.2}", mean);
+            }
+            let stages = stages
+                .iter()
+                .map(|(phase, time)| format!("{} {:.2} ms", phase, time.as_secs_f64() * 1000.0))
+                .collect::<Vec<_>>();
+            eprintln!("  stages: {}", stages.join(", "));
+        }
+    }
+}
+
+// #rrggbb of --transparent and --placeholder
+fn parse_color(option: &str, color: Option<&str>) -> Option<Rgb<u8>> {
+    color.map(|color| {
+        parse_hex_color(color).unwrap_or_else(|| {
+            log::error(format_args!(
+                "{} should be a #rrggbb color, got \"{}\"",
+                option, color
+            ));
+            exit(EXIT_USAGE);
+        })
+    })
+}
+
+// N% (or N) of --fec, 1 to 100
+fn parse_fec(percent: Option<&str>) -> Option<u8> {
+    percent.map(|percent| {
+        percent
+            .trim_end_matches('%')
+            .parse::<u8>()
+            .ok()
+            .filter(|percent| (1..=100).contains(percent))
+            .unwrap_or_else(|| {
+                log::error(format_args!(
+                    "--fec should be a percentage from 1% to 100%, got \"{}\"",
+                    percent
+                ));
+                exit(EXIT_USAGE);
+            })
+    })
+}
+
+// Base64url key of --sign, 16 bytes at least so it can't be guessed
+fn parse_signing_key(key: Option<&str>) -> Option<Vec<u8>> {
+    key.map(|key| {
+        base64url_to_bytes(key)
+            .filter(|key| key.len() >= 16)
+            .unwrap_or_else(|| {
+                log::error(format_args!(
+                    "--sign takes a base64url key of 16 bytes at least, e.g. from keygen"
+                ));
+                exit(EXIT_CRYPTO);
+            })
+    })
+}
+
+// x,y,width,height of --crop
+fn parse_crop(region: Option<&str>) -> Option<(u32, u32, u32, u32)> {
+    region.map(|region| {
+        let numbers = region
+            .split(',')
+            .map(|number| number.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>();
+        match numbers.as_deref() {
+            Ok(&[x, y, width, height]) if width > 0 && height > 0 => (x, y, width, height),
+            _ => {
+                log::error(format_args!(
+                    "--crop should be x,y,width,height in pixels, width and height at least 1, got \"{}\"",
+                    region
+                ));
+                exit(EXIT_USAGE);
+            }
+        }
+    })
+}
+
+fn parse_dither(name: Option<String>) -> Option<Dither> {
+    match name {
+        Some(name) if name == "auto" => None,
+        Some(name) => Some(Dither::parse(name.as_str()).unwrap_or_else(|| {
+            log::error(format_args!(
+                "unknown dither {}, expected auto or one of: {}",
+                name,
+                Dither::ALL.map(Dither::name).join(", ")
+            ));
+            exit(EXIT_USAGE);
+        })),
+        None => Some(Dither::FloydSteinberg),
+    }
+}
+
+fn parse_scan(name: Option<String>) -> Scan {
+    match name {
+        Some(name) => Scan::parse(name.as_str()).unwrap_or_else(|| {
+            log::error(format_args!(
+                "unknown scan {}, expected one of: {}",
+                name,
+                Scan::ALL.map(Scan::name).join(", ")
+            ));
+            exit(EXIT_USAGE);
+        }),
+        None => Scan::Raster,
+    }
+}
+
+fn parse_fpe(name: Option<&str>) -> Fpe {
+    match name {
+        Some(name) => Fpe::parse(name).unwrap_or_else(|| {
+            log::error(format_args!(
+                "unknown --fpe {}, expected one of: {}",
+                name,
+                Fpe::ALL.map(Fpe::name).join(", ")
+            ));
+            exit(EXIT_USAGE);
+        }),
+        None => Fpe::Ff1,
+    }
+}
+
+// -j/--jobs, one thread per CPU without it
+fn threads(jobs: Option<u16>) -> usize {
+    jobs.map_or_else(num_cpus::get, |jobs| jobs as usize)
+}
+
+fn set_log_level(flags: &cli::RunOptions) {
+    if flags.quiet {
+        log::set_level(log::Level::Quiet);
+    } else if flags.verbose {
+        log::set_level(log::Level::Verbose);
+    }
+}
+
+fn check_settings(settings: &JobSettings, batch: bool) {
+    if !settings.metadata.is_empty() && !settings.options.contains("e") {
+        log::error(format_args!("--meta is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.alpha && !settings.options.contains("e") {
+        log::error(format_args!("--alpha is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.gray && !settings.options.contains("e") {
+        log::error(format_args!("--gray is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if !settings.key_args.slot_keys.is_empty() && !settings.options.contains("e") {
+        log::error(format_args!("--key-slot is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.filter && !settings.options.contains("e") {
+        log::error(format_args!("--filter is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.rle && !settings.options.contains("e") {
+        log::error(format_args!("--rle is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.rle && settings.stream {
+        log::error(format_args!(
+            "--rle can't be used together with --stream, the header holds the encoded length"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.scan != Scan::Raster && !settings.options.contains("e") {
+        log::error(format_args!("--scan is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.scan != Scan::Raster && settings.stream {
+        log::error(format_args!(
+            "--scan {} can't be used together with --stream, it visits rows out of order",
+            settings.scan.name()
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.tiles.is_some() && !settings.options.contains("e") {
+        log::error(format_args!("--tiles is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.tiles.is_some() && settings.stream {
+        log::error(format_args!(
+            "--tiles can't be used together with --stream, the header holds the length of the tiles"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.interlace && !settings.options.contains("e") {
+        log::error(format_args!("--interlace is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.interlace && settings.stream {
+        log::error(format_args!(
+            "--interlace can't be used together with --stream, every pass spans the whole image"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.interlace && (settings.scan != Scan::Raster || settings.tiles.is_some()) {
+        log::error(format_args!(
+            "--interlace can't be used together with --scan or --tiles, its passes are an order of their own"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.preview && settings.options.contains("e") {
+        log::error(format_args!("--preview is decode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.preview && (settings.stream || settings.strict) {
+        log::error(format_args!(
+            "--preview can't be used together with --stream or --strict"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.seek && !settings.options.contains("e") {
+        log::error(format_args!("--seek is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.seek && settings.stream {
+        log::error(format_args!(
+            "--seek can't be used together with --stream, the row offsets come before the rows"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.seek
+        && (settings.scan != Scan::Raster || settings.tiles.is_some() || settings.interlace)
+    {
+        log::error(format_args!(
+            "--seek can't be used together with --scan, --tiles or --interlace, its offsets point at rows (tiles have offsets of their own)"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.seek && settings.options.contains("z") {
+        log::error(format_args!(
+            "--seek can't be used together with compression (\"z\"), zstd compresses the whole file as one frame; --rle keeps it seekable"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.lossless && !settings.options.contains("e") {
+        log::error(format_args!("--lossless is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.lossless
+        && (settings.stream
+            || settings.rle
+            || settings.scan != Scan::Raster
+            || settings.tiles.is_some()
+            || settings.interlace
+            || settings.seek
+            || settings.transparent.is_some())
+    {
+        log::error(format_args!(
+            "--lossless can't be used together with --stream, --rle, --scan, --tiles, --interlace, --seek or --transparent, a truecolor file is filtered rows only"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.fpe != Fpe::Ff1 && !settings.options.contains("e") {
+        log::error(format_args!(
+            "--fpe is encode only, decode reads it from the file"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.encrypt_palette && !settings.options.contains("e") {
+        log::error(format_args!("--encrypt-palette is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.chunk_checksums.is_some() && !settings.options.contains("e") {
+        log::error(format_args!("--chunk-checksums is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.chunk_checksums == Some(0) {
+        log::error(format_args!("--chunk-checksums should be at least 1 KiB"));
+        exit(EXIT_USAGE);
+    }
+    if settings.chunk_checksums.is_some() && settings.stream {
+        log::error(format_args!(
+            "--chunk-checksums can't be used together with --stream, the checksums come before the pixels data"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.fec.is_some() && !settings.options.contains("e") {
+        log::error(format_args!(
+            "--fec is encode only, decode repairs a protected file by itself"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.fec.is_some() && settings.stream {
+        log::error(format_args!(
+            "--fec can't be used together with --stream, the parity comes from the whole file"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.sign.is_some() && !settings.options.contains("e") {
+        log::error(format_args!(
+            "--sign is encode only, verify --sign checks the signature"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.sign.is_some() && settings.stream {
+        log::error(format_args!(
+            "--sign can't be used together with --stream, the signature comes before the pixels data"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.placeholder.is_some() && settings.options.contains("e") {
+        log::error(format_args!("--placeholder is decode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.placeholder.is_some() && settings.stream {
+        log::error(format_args!(
+            "--placeholder can't be used together with --stream, it checks the checksum after writing its output"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.crop.is_some() && settings.options.contains("e") {
+        log::error(format_args!("--crop is decode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.crop.is_some() && (settings.stream || settings.strict || settings.preview) {
+        log::error(format_args!(
+            "--crop can't be used together with --stream, --strict or --preview"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.gray && (settings.alpha || settings.transparent.is_some()) {
+        log::error(format_args!(
+            "--gray can't be used together with --alpha or --transparent, gray palettes have no alpha"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.gray && settings.stream {
+        log::error(format_args!(
+            "--gray can't be used together with --stream, which encodes RGB"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.transparent.is_some() && !settings.options.contains("e") {
+        log::error(format_args!("--transparent is encode only"));
+        exit(EXIT_USAGE);
+    }
+    if settings.transparent.is_some() && settings.alpha {
+        log::error(format_args!(
+            "--transparent can't be used together with --alpha, which keeps every alpha value"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.transparent.is_some() && settings.stream {
+        log::error(format_args!(
+            "--transparent can't be used together with --stream, the palette is built before the color is seen"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.alpha && settings.stream {
+        log::error(format_args!(
+            "--alpha can't be used together with --stream, raw and PNM input have no alpha"
+        ));
+        exit(EXIT_USAGE);
+    }
+    let embedded = [
+        (settings.icc, "--icc", "ICC profile"),
+        (settings.exif, "--exif", "EXIF"),
+    ];
+    for (set, flag, carried) in embedded {
+        if set && !settings.options.contains("e") {
+            log::error(format_args!("{} is encode only", flag));
+            exit(EXIT_USAGE);
+        }
+        if set && settings.stream {
+            log::error(format_args!(
+                "{} can't be used together with --stream, raw and PNM input carry no {}",
+                flag, carried
+            ));
+            exit(EXIT_USAGE);
+        }
+    }
+    if settings.dry_run {
+        if !settings.options.contains("e") {
+            log::error(format_args!("--dry-run is encode only"));
+            exit(EXIT_USAGE);
+        }
+        let conflict = if settings.stream {
+            Some("--stream")
+        } else if settings.error_map.is_some() {
+            Some("--debug-error-map")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            log::error(format_args!(
+                "--dry-run can't be used together with {}",
+                conflict
+            ));
+            exit(EXIT_USAGE);
+        }
+    }
+    if settings.error_map.is_some() && batch {
+        log::error(format_args!(
+            "--debug-error-map can't be used together with --batch"
+        ));
+        exit(EXIT_USAGE);
+    }
+    if settings.stream {
+        let conflict = if batch {
+            Some("--batch")
+        } else if settings.error_map.is_some() {
+            Some("--debug-error-map")
+        } else if settings.dither.is_none() {
+            Some("--dither auto")
+        } else if settings.options.contains("e")
+            && matches!(settings.input_format, InputFormat::Image)
+        {
+            Some("image input (use --input-format raw or pnm)")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            log::error(format_args!(
+                "--stream can't be used together with {}",
+                conflict
+            ));
+            exit(EXIT_USAGE);
+        }
+    }
+}
+
+fn read_batch(path: &str) -> Vec<(String, String)> {
+    batch::read_jobs(path).unwrap_or_else(|err| {
+        log::error(format_args!("{}", err));
+        exit(EXIT_IO);
+    })
+}
+
+// [output_file_path] is optional, inferred from [input_file_path]
+fn single_job(input: &str, output: Option<String>, encode: bool) -> Vec<(String, String)> {
+    let output = output.unwrap_or_else(|| {
+        let output = default_output(input, encode).unwrap_or_else(|| {
+            log::error(format_args!(
+                "can't infer [output_file_path] from {}",
+                input
+            ));
+            exit(EXIT_USAGE);
+        });
+        log::info(format_args!("Output: {}", output));
+        output
+    });
+    vec![(input.to_string(), output)]
+}
+
+// Every file matching the pattern, outputs inferred into out_dir
+fn glob_jobs(
+    pattern: &str,
+    out_dir: Option<&str>,
+    encode: bool,
+    dry_run: bool,
+) -> Vec<(String, String)> {
+    let jobs = batch::glob_jobs(pattern, out_dir, encode).unwrap_or_else(|err| {
+        log::error(format_args!("{}", err));
+        exit(EXIT_USAGE);
+    });
+    if let Some(dir) = out_dir
+        && !dry_run
+        && let Err(err) = std::fs::create_dir_all(dir)
+    {
+        log::error(format_args!("{}: {}", dir, err));
+        exit(EXIT_IO);
+    }
+    jobs
+}
+
+// --json: one line per job on stdout, unless the output itself goes there
+fn print_job_json(input: &str, output: &str, status: &str) {
+    if log::json() && output != "-" {
+        println!(
+            "{{\"input\":\"{}\",\"output\":\"{}\",\"status\":\"{}\"}}",
+            json_escape(input),
+            json_escape(output),
+            status
+        );
+    }
+}
+
+// --json turns --stats into --stats=json
+fn stats_format(stats: Option<StatsFormat>) -> Option<StatsFormat> {
+    stats.map(|format| {
+        if log::json() {
+            StatsFormat::Json
+        } else {
+            format
+        }
+    })
+}
+
+fn run(settings: &JobSettings, flags: cli::RunOptions, jobs: &[(String, String)]) {
+    let stats_format = stats_format(flags.stats);
+    let mut timings = Timings::new();
+    // Batch and glob runs list their jobs
+    let listed = flags.batch.is_some() || jobs.len() > 1;
+    // A dry run writes nothing, not even the journal
+    let mut journal = flags.batch.filter(|_| !settings.dry_run).map(|path| {
+        let journal_path = format!("{}.journal", path);
+        batch::Journal::open(journal_path.as_str(), flags.resume).unwrap_or_else(|err| {
+            log::error(format_args!("{}: {}", journal_path, err));
+            exit(EXIT_IO);
+        })
+    });
+    // Every output is checked before any is written, but those of jobs the journal has done or
+    // started, which are skipped or redone; the error map and trace too
+    let mut outputs = jobs
+        .iter()
+        .map(|(_, output)| output)
+        .filter(|output| {
+            !journal
+                .as_ref()
+                .is_some_and(|journal| journal.is_done(output) || journal.is_unfinished(output))
+        })
+        .chain(&settings.error_map)
+        .chain(&flags.trace_out);
+    if !settings.dry_run
+        && let Some(output) = outputs.find(|output| check_output(output, settings.force).is_err())
+    {
+        log::error(format_args!(
+            "{} already exists, pass --force to overwrite, nothing was written",
+            output
+        ));
+        exit(EXIT_USAGE);
+    }
+    for (i, (input, output)) in jobs.iter().enumerate() {
+        if let Some(journal) = &journal
+            && journal.is_done(output)
+        {
+            print_job_json(input, output, "skipped");
+            log::info(format_args!(
+                "[{}/{}] {} is done, skipping",
+                i + 1,
+                jobs.len(),
+                output
+            ));
+            continue;
+        }
+        if listed {
+            log::info(format_args!(
+                "[{}/{}] {} -> {}",
+                i + 1,
+                jobs.len(),
+                input,
+                output
+            ));
+        }
+        if let Some(journal) = &mut journal {
+            journal.start(output).unwrap_or_else(|err| {
+                log::error(format_args!("journal: {}", err));
+                exit(EXIT_IO);
+            });
+        }
+        // What an interrupted run left of the output is replaced
+        let redo;
+        let settings = match &journal {
+            Some(journal) if journal.is_unfinished(output) && !settings.force => {
+                redo = JobSettings {
+                    force: true,
+                    ..settings.clone()
+                };
+                &redo
+            }
+            _ => settings,
+        };
+        let mut job_timings = Timings::new();
+        let stats = or_exit(run_job(settings, input, output, &mut job_timings));
+        if let (Some(format), Some(stats)) = (stats_format, &stats) {
+            print_encode_stats(format, input, output, stats, &job_timings);
+        }
+        if !settings.dry_run {
+            print_job_json(input, output, "done");
+        }
+        timings.append(job_timings);
+        if let Some(journal) = &mut journal {
+            journal.finish(output).unwrap_or_else(|err| {
+                log::error(format_args!("journal: {}", err));
+                exit(EXIT_IO);
+            });
+        }
+        if listed {
+            log::info(format_args!(""));
+        }
+    }
+    if flags.timings {
+        eprintln!("\n{}", timings.report());
+    }
+    if let Some(path) = flags.trace_out {
+        or_exit(write_file(
+            timings.to_chrome_trace().as_bytes(),
+            path.as_str(),
+            settings.force,
+        ));
+    }
+    match stats_format {
+        Some(StatsFormat::Text) => {
+            eprintln!("\nStats:");
+            match memory::peak_rss() {
+                Some(bytes) => eprintln!("  peak memory (RSS): {}", memory::format_bytes(bytes)),
+                None => eprintln!("  peak memory (RSS): unavailable on this platform"),
+            }
+            if let Some(bytes) = memory::allocator_peak() {
+                eprintln!("  allocator peak: {}", memory::format_bytes(bytes));
+            }
+        }
+        Some(StatsFormat::Json) => {
+            let bytes = |bytes: Option<u64>| bytes.map_or("null".to_string(), |b| b.to_string());
+            eprintln!(
+                "{{\"peak_rss_bytes\":{},\"allocator_peak_bytes\":{}}}",
+                bytes(memory::peak_rss()),
+                bytes(memory::allocator_peak())
+            );
+        }
+        None => {}
+    }
+}
+
+// Every supported file under dir whose output is missing or older, directories of the
+// outputs are created; the older outputs are replaced without --force
+fn tree_jobs(
+    dir: &str,
+    out_dir: Option<&str>,
+    encode: bool,
+    dry_run: bool,
+) -> Vec<(String, String)> {
+    let (jobs, up_to_date) = batch::tree_jobs(dir, out_dir, encode).unwrap_or_else(|err| {
+        log::error(format_args!("{}", err));
+        exit(EXIT_IO);
+    });
+    if up_to_date > 0 {
+        log::info(format_args!("Up to date, skipped: {}", up_to_date));
+    }
+    for (_, output) in jobs.iter().filter(|_| !dry_run) {
+        if let Some(parent) = std::path::Path::new(output).parent()
+            && let Err(err) = std::fs::create_dir_all(parent)
+        {
+            log::error(format_args!("{}: {}", parent.display(), err));
+            exit(EXIT_IO);
+        }
+    }
+    jobs
+}
+
+fn jobs(
+    flags: &cli::RunOptions,
+    input: Option<String>,
+    output: Option<String>,
+    out_dir: Option<String>,
+    recursive: bool,
+    encode: bool,
+    dry_run: bool,
+) -> Vec<(String, String)> {
+    match (&flags.batch, input) {
+        (Some(path), _) => read_batch(path),
+        (None, Some(input)) if recursive => tree_jobs(&input, out_dir.as_deref(), encode, dry_run),
+        (None, Some(input)) if out_dir.is_some() || batch::is_pattern(&input) => {
+            if output.is_some() {
+                log::error(format_args!(
+                    "a glob pattern takes --out-dir instead of [output_file_path]"
+                ));
+                exit(EXIT_USAGE);
+            }
+            glob_jobs(&input, out_dir.as_deref(), encode, dry_run)
+        }
+        (None, Some(input)) => single_job(&input, output, encode),
+        // clap requires one of them
+        (None, None) => unreachable!(),
+    }
+}
+
+// Flags win over the preset, the preset over the config file
+fn encode_settings(args: &EncodeArgs, config: &config::Config) -> JobSettings {
+    let preset = match &args.preset {
+        Some(name) => config.preset(name).unwrap_or_else(|err| {
+            log::error(format_args!("{}", err));
+            exit(EXIT_USAGE);
+        }),
+        None => config::Preset::default(),
     };
-    do_output(processed_data, args[3].as_str());
+    let defaults = preset.or(config.defaults());
+    let compress = args.compress || !args.no_compress && defaults.compress == Some(true);
+    JobSettings {
+        options: if compress { "ez" } else { "e" }.to_string(),
+        input_format: or_exit(
+            InputFormat::parse(&args.input_format, args.size.as_deref())
+                .map_err(CodecError::InvalidInput),
+        ),
+        palette_size: args.palette_size.or(defaults.palette_size).unwrap_or(256) as usize,
+        dither: parse_dither(args.dither.clone().or(defaults.dither)),
+        error_map: args.debug_error_map.clone(),
+        strict: false,
+        legacy: false,
+        stream: args.stream,
+        key: args.keys.key.clone(),
+        key_args: KeyArgs {
+            recipients: args.recipient.clone(),
+            age_recipients: args.age_recipient.clone(),
+            slot_keys: args.key_slot.clone(),
+            shares: args.keys.shares.clone(),
+            derive: args.keys.derive,
+            key_env: args.keys.key_env.clone(),
+            key_file: args.keys.key_file.clone(),
+            key_name: args.keys.key_name.clone(),
+            ..KeyArgs::default()
+        },
+        threads: threads(args.run.jobs.or(config.jobs)),
+        compression_level: args
+            .compression_level
+            .or(defaults.compression_level)
+            .unwrap_or(compression::DEFAULT_LEVEL),
+        dry_run: args.dry_run,
+        // --recursive only lists outputs that are missing or out of date
+        force: args.run.force || args.recursive,
+        metadata: parse_meta(&args.meta),
+        icc: args.icc,
+        exif: args.exif,
+        alpha: args.alpha,
+        transparent: parse_color("--transparent", args.transparent.as_deref()),
+        gray: args.gray,
+        filter: args.filter,
+        rle: args.rle,
+        // A configured scan gives way to the flags --scan can't be used with
+        scan: parse_scan(args.scan.clone().or(defaults.scan.filter(|_| {
+            !(args.stream || args.tiles.is_some() || args.interlace || args.seek || args.lossless)
+        }))),
+        tiles: args.tiles,
+        interlace: args.interlace,
+        preview: false,
+        seek: args.seek,
+        lossless: args.lossless,
+        encrypt_palette: args.encrypt_palette,
+        fpe: parse_fpe(args.fpe.as_deref()),
+        crop: None,
+        chunk_checksums: args.chunk_checksums,
+        placeholder: None,
+        fec: parse_fec(args.fec.as_deref()),
+        sign: parse_signing_key(args.sign.as_deref()),
+        frames: args.frames,
+    }
+}
+
+// Encodes now and again on every save of the input until interrupted; a failed encode (e.g. of
+// a half-written image) is reported and the previous output kept
+fn watch_job(settings: &JobSettings, stats: Option<StatsFormat>, input: &str, output: &str) {
+    or_exit(check_output(output, settings.force));
+    // The output is the watch's own from here on
+    let settings = &JobSettings {
+        force: true,
+        ..settings.clone()
+    };
+    let encode = || {
+        let mut timings = Timings::new();
+        match run_job(settings, input, output, &mut timings) {
+            Ok(encode_stats) => {
+                log::info(format_args!("Encoded {} -> {}", input, output));
+                print_job_json(input, output, "done");
+                if let (Some(format), Some(encode_stats)) = (stats, encode_stats) {
+                    print_encode_stats(format, input, output, &encode_stats, &timings);
+                }
+            }
+            Err(err) => log::error(format_args!("{}", err)),
+        }
+    };
+    encode();
+    log::info(format_args!("Watching {}, Ctrl+C to stop", input));
+    if let Err(err) = watch::watch(input, encode) {
+        log::error(format_args!("watching {}: {}", input, err));
+        exit(EXIT_IO);
+    }
+}
+
+fn run_cli(cli: Cli) {
+    let config = config::load(cli.config.as_deref()).unwrap_or_else(|err| {
+        log::error(format_args!("{}", err));
+        exit(EXIT_USAGE);
+    });
+    match cli.command {
+        Command::Encode(args) => {
+            set_log_level(&args.run);
+            let mut settings = encode_settings(&args, &config);
+            check_settings(&settings, args.run.batch.is_some());
+            let jobs = jobs(
+                &args.run,
+                args.input,
+                args.output,
+                args.out_dir,
+                args.recursive,
+                true,
+                args.dry_run,
+            );
+            if args.keys.passphrase {
+                ask_passphrase(&mut settings.key, &mut settings.key_args, true);
+            }
+            if args.keys.ask_key {
+                ask_key(&mut settings.key, &mut settings.key_args, true);
+            }
+            run(&settings, args.run, &jobs);
+        }
+        Command::Decode(args) => {
+            set_log_level(&args.run);
+            let mut settings = JobSettings {
+                options: if args.compress { "dz" } else { "d" }.to_string(),
+                input_format: InputFormat::Image,
+                palette_size: 0,
+                dither: Some(Dither::FloydSteinberg),
+                error_map: None,
+                strict: args.strict,
+                legacy: args.legacy,
+                stream: args.stream,
+                key: args.keys.key,
+                key_args: KeyArgs {
+                    shares: args.keys.shares,
+                    identity: args.identity,
+                    age_identity: args.age_identity,
+                    derive: args.keys.derive,
+                    key_env: args.keys.key_env,
+                    key_file: args.keys.key_file,
+                    key_name: args.keys.key_name,
+                    ..KeyArgs::default()
+                },
+                threads: threads(args.run.jobs.or(config.jobs)),
+                compression_level: compression::DEFAULT_LEVEL,
+                dry_run: false,
+                force: args.run.force || args.recursive,
+                metadata: Vec::new(),
+                icc: false,
+                exif: false,
+                alpha: false,
+                transparent: None,
+                gray: false,
+                filter: false,
+                rle: false,
+                scan: Scan::Raster,
+                tiles: None,
+                interlace: false,
+                preview: args.preview,
+                seek: false,
+                lossless: false,
+                encrypt_palette: false,
+                fpe: Fpe::Ff1,
+                crop: parse_crop(args.crop.as_deref()),
+                chunk_checksums: None,
+                placeholder: parse_color("--placeholder", args.placeholder.as_deref()),
+                fec: None,
+                sign: None,
+                frames: false,
+            };
+            check_settings(&settings, args.run.batch.is_some());
+            let jobs = jobs(
+                &args.run,
+                args.input,
+                args.output,
+                args.out_dir,
+                args.recursive,
+                false,
+                false,
+            );
+            if args.keys.passphrase {
+                ask_passphrase(&mut settings.key, &mut settings.key_args, false);
+            }
+            if args.keys.ask_key {
+                ask_key(&mut settings.key, &mut settings.key_args, false);
+            }
+            run(&settings, args.run, &jobs);
+        }
+        Command::Watch(args) => {
+            set_log_level(&args.run);
+            let mut settings = encode_settings(&args, &config);
+            check_settings(&settings, false);
+            let (Some(input), Some(output)) = (&args.input, &args.output) else {
+                log::error(format_args!("watch requires INPUT and OUTPUT files"));
+                exit(EXIT_USAGE);
+            };
+            let run = &args.run;
+            if input == "-"
+                || batch::is_pattern(input)
+                || args.out_dir.is_some()
+                || args.recursive
+                || args.dry_run
+                || run.batch.is_some()
+                || run.timings
+                || run.trace_out.is_some()
+            {
+                log::error(format_args!(
+                    "watch takes a single INPUT file, without --out-dir, --recursive, --dry-run, --batch, --timings or --trace-out"
+                ));
+                exit(EXIT_USAGE);
+            }
+            if args.keys.passphrase {
+                ask_passphrase(&mut settings.key, &mut settings.key_args, true);
+            }
+            if args.keys.ask_key {
+                ask_key(&mut settings.key, &mut settings.key_args, true);
+            }
+            watch_job(&settings, stats_format(run.stats), input, output);
+        }
+        Command::Info(args) => info(args.paths),
+        Command::Keygen(args) => keygen(
+            key_size(args.bytes, args.bits),
+            args.split,
+            args.out,
+            args.key_name,
+            args.asymmetric,
+        ),
+        Command::VerifyKey(args) => verify_key(args),
+        Command::Verify(args) => verify(args),
+        Command::Validate(args) => validate(args),
+        Command::Inspect(args) => inspect(args),
+        Command::CompareSizes(args) => compare_sizes(args),
+        Command::CompareDither(args) => compare_dither(args),
+        Command::Quantize(args) => quantize(args),
+        Command::Upgrade(args) => upgrade(args),
+        Command::Key {
+            command: KeyCommand::Check(args),
+        } => key_check(args),
+        Command::Rekey(args) => rekey(args),
+        Command::Slots(args) => slots(args),
+        Command::Repair(args) => repair(args),
+        Command::Add(args) => add(args),
+        Command::List(args) => list(args),
+        Command::Extract(args) => extract(args),
+        Command::Cat(args) => cat(args),
+        Command::Selftest => {
+            let cases = selftest::run();
+            for case in &cases {
+                match &case.error {
+                    None => println!("PASS {}", case.name),
+                    Some(error) => println!("FAIL {}: {}", case.name, error),
+                }
+            }
+            let failed = cases.iter().filter(|case| case.error.is_some()).count();
+            println!("{} passed, {} failed", cases.len() - failed, failed);
+            if failed > 0 {
+                exit(EXIT_SELFTEST);
+            }
+        }
+        Command::Completions { shell } => clap_complete::generate(
+            shell,
+            &mut Cli::command(),
+            "rust_image-codec",
+            &mut std::io::stdout(),
+        ),
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    // Before parsing, so argument errors are JSON as well
+    log::set_json(args.iter().any(|arg| arg == "--json"));
+    if args.len() == 1 {
+        println!("Usage: exe <subcommand> [flags] - see exe help and exe help [subcommand]
+       exe [options] [input_file_path] [output_file_path(optional)] [palette_size(encode)] [base64url_key(optional)]
+
+    options:
+        - e - encode mode (encode --palette-size [palette_size]): input - existing [input_file_path], output - saved [output_file_path]; \"-\" for either is stdin/stdout
+        - d - decode mode (decode): output - saved [output_file_path] (PNG)
+        - c - encryption-decryption flag (--key [base64url_key])
+        - z - compression flag (--compress), decode detects compressed files without it
+        - g - base64url key gen (keygen), 16 bytes to stdout by default
+        - i - info mode (info): [input_file_path]... described on stdout
+
+    Flags of the subcommand go along, e.g. exe ecz image.png encoded.ric 256 [base64url_key] --tiles 64");
+        return;
+    }
+    if !is_subcommand(&args) {
+        args = args::short_syntax(&args);
+    }
+    let cli = Cli::try_parse_from(&args).unwrap_or_else(|err| {
+        // --help and --version are "errors" printed to stdout
+        if !log::json() || !err.use_stderr() {
+            err.exit();
+        }
+        let message = err.to_string();
+        let message = message.lines().next().unwrap_or_default();
+        log::error(format_args!(
+            "{}",
+            message.strip_prefix("error: ").unwrap_or(message)
+        ));
+        exit(EXIT_USAGE);
+    });
+    run_cli(cli);
 }
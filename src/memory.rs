@@ -0,0 +1,50 @@
+#[cfg(feature = "alloc-stats")]
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// Peak resident set size in bytes, Linux only
+pub fn peak_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(feature = "alloc-stats")]
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+#[cfg(feature = "alloc-stats")]
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+// System allocator keeping track of the heap high-water mark
+#[cfg(feature = "alloc-stats")]
+pub struct CountingAllocator;
+
+#[cfg(feature = "alloc-stats")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+pub fn allocator_peak() -> Option<u64> {
+    #[cfg(feature = "alloc-stats")]
+    return Some(PEAK_ALLOCATED.load(Ordering::Relaxed) as u64);
+    #[cfg(not(feature = "alloc-stats"))]
+    None
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    format!("{:.2} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
@@ -0,0 +1,193 @@
+// Key/value metadata stored in the clear after the palette: a little endian u32 section length,
+// then per entry a key length byte, the UTF-8 key, a little endian u32 value length and the value
+use crate::{ZSTD_MAGIC, bare, compression, error::CodecError, fec};
+
+pub type Entries = Vec<(String, Vec<u8>)>;
+
+// ICC color profile of the source image (encode --icc), attached to the decoded PNG
+pub const ICC_KEY: &str = "icc";
+// EXIF tags of the source image kept by encode --exif (see exif::select), attached the same way
+pub const EXIF_KEY: &str = "exif";
+// Empty entry of files whose palette entries are encrypted (encode --encrypt-palette)
+pub const ENCRYPTED_PALETTE_KEY: &str = "encrypted-palette";
+// Format version a file was rewrapped from by format::upgrade, e.g. "1"
+pub const UPGRADED_FROM_KEY: &str = "upgraded-from";
+
+pub fn check(entries: &Entries) -> Result<(), CodecError> {
+    for (idx, (key, value)) in entries.iter().enumerate() {
+        if key.is_empty() || key.len() > u8::MAX as usize {
+            return Err(CodecError::InvalidInput(format!(
+                "metadata key \"{}\" should be 1 to 255 bytes long",
+                key
+            )));
+        }
+        if u32::try_from(value.len()).is_err() {
+            return Err(CodecError::InvalidInput(format!(
+                "metadata value of \"{}\" is larger than 4GiB",
+                key
+            )));
+        }
+        if entries[..idx].iter().any(|(other, _)| other == key) {
+            return Err(CodecError::InvalidInput(format!(
+                "metadata key \"{}\" is given twice",
+                key
+            )));
+        }
+    }
+    Ok(())
+}
+
+// The whole section, length included
+pub fn write(entries: &Entries) -> Vec<u8> {
+    let mut section = vec![0u8; 4];
+    for (key, value) in entries {
+        section.push(key.len() as u8);
+        section.extend_from_slice(key.as_bytes());
+        section.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        section.extend_from_slice(value);
+    }
+    let len = (section.len() - 4) as u32;
+    section[..4].copy_from_slice(&len.to_le_bytes());
+    section
+}
+
+// Entries of the section, without its length; a key given twice is corrupt as well, encode never
+// writes one and it could stand for either entry (e.g. of an auth tag)
+pub fn parse(mut entries: &[u8]) -> Result<Entries, CodecError> {
+    let corrupt = || CodecError::BadHeader("corrupt in its metadata section".to_string());
+    let mut parsed = Vec::new();
+    while let Some((&key_len, rest)) = entries.split_first() {
+        let key = rest.get(..key_len as usize).ok_or_else(corrupt)?;
+        let key = String::from_utf8(key.to_vec()).map_err(|_| corrupt())?;
+        let rest = &rest[key_len as usize..];
+        let value_len = rest.get(..4).ok_or_else(corrupt)?;
+        let value_len = u32::from_le_bytes(value_len.try_into().unwrap()) as usize;
+        let value = rest.get(4..4 + value_len).ok_or_else(corrupt)?;
+        if parsed.iter().any(|(other, _)| *other == key) {
+            return Err(corrupt());
+        }
+        parsed.push((key, value.to_vec()));
+        entries = &rest[4 + value_len..];
+    }
+    Ok(parsed)
+}
+
+// Metadata of a file, compressed or not; encrypted files need no key as it's stored in the clear
+pub fn read(bytes: &[u8]) -> Result<Entries, CodecError> {
+    if fec::is_wrapped(bytes) {
+        return read(&fec::unwrap(bytes)?);
+    }
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        return read(&compression::decompress(bytes)?);
+    }
+    let header = bare::parse_header(bytes)?;
+    parse(&bytes[header.metadata])
+}
+
+// Text values as is, binary ones (e.g. a color profile) by their size
+pub fn display_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) if !text.contains('\0') => text.to_string(),
+        _ => format!("<{} bytes>", value.len()),
+    }
+}
+
+// "key=value" of --meta
+pub fn parse_arg(arg: &str) -> Result<(String, Vec<u8>), CodecError> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.as_bytes().to_vec())),
+        _ => Err(CodecError::InvalidInput(format!(
+            "--meta should be key=value, got \"{}\"",
+            arg
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Entries {
+        vec![
+            ("title".to_string(), b"Sunset".to_vec()),
+            ("empty".to_string(), Vec::new()),
+            ("k".repeat(255), vec![0, 1, 2, 255]),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let section = write(&entries());
+        let len = u32::from_le_bytes(section[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, section.len() - 4);
+        assert_eq!(parse(&section[4..]).unwrap(), entries());
+    }
+
+    #[test]
+    fn empty_section() {
+        assert_eq!(write(&Vec::new()), [0, 0, 0, 0]);
+        assert!(parse(&[]).unwrap().is_empty());
+    }
+
+    // Cut anywhere but between entries, the section is corrupt
+    #[test]
+    fn truncated() {
+        let section = write(&entries());
+        let ends: Vec<usize> = (1..=entries().len())
+            .map(|count| write(&entries()[..count].to_vec()).len() - 4)
+            .collect();
+        for len in 1..section.len() - 4 {
+            let parsed = parse(&section[4..4 + len]);
+            if ends.contains(&len) {
+                assert!(parsed.is_ok(), "{}", len);
+            } else {
+                assert!(matches!(parsed, Err(CodecError::BadHeader(_))), "{}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn value_longer_than_the_section() {
+        let mut section = write(&vec![("a".to_string(), b"xyz".to_vec())]);
+        section[6] = 4;
+        assert!(parse(&section[4..]).is_err());
+        section[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse(&section[4..]).is_err());
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let twice = vec![("auth".to_string(), vec![1]), ("auth".to_string(), vec![2])];
+        assert!(matches!(check(&twice), Err(CodecError::InvalidInput(_))));
+        assert!(matches!(
+            parse(&write(&twice)[4..]),
+            Err(CodecError::BadHeader(_))
+        ));
+    }
+
+    #[test]
+    fn key_lengths() {
+        assert!(check(&vec![(String::new(), Vec::new())]).is_err());
+        assert!(check(&vec![("k".repeat(256), Vec::new())]).is_err());
+        assert!(check(&entries()).is_ok());
+    }
+
+    #[test]
+    fn key_not_utf8() {
+        assert!(parse(&[1, 0xff, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn args_and_values() {
+        assert_eq!(
+            parse_arg("a=b=c").unwrap(),
+            ("a".to_string(), b"b=c".to_vec())
+        );
+        assert_eq!(parse_arg("a=").unwrap(), ("a".to_string(), Vec::new()));
+        assert!(parse_arg("=b").is_err());
+        assert!(parse_arg("ab").is_err());
+        assert_eq!(display_value(b"text"), "text");
+        assert_eq!(display_value(&[0xff, 0]), "<2 bytes>");
+        assert_eq!(display_value(b"a\0b"), "<3 bytes>");
+    }
+}
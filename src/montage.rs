@@ -0,0 +1,112 @@
+use image::{ImageBuffer, Rgb};
+
+type Image = ImageBuffer<Rgb<u8>, Vec<u8>>;
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const LABEL_PADDING: u32 = 2;
+const LABEL_HEIGHT: u32 = GLYPH_HEIGHT + LABEL_PADDING * 2;
+const GAP: u32 = 4;
+
+// 5x7 bitmap font rows, lowercase letters are drawn as uppercase
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        _ => [0x00; 7],
+    }
+}
+
+fn text_width(text: &str) -> u32 {
+    text.chars().count() as u32 * (GLYPH_WIDTH + 1)
+}
+
+pub fn draw_text(img: &mut Image, x: u32, y: u32, text: &str, color: Rgb<u8>) {
+    for (i, c) in text.chars().enumerate() {
+        let left = x + i as u32 * (GLYPH_WIDTH + 1);
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                let (px, py) = (left + col, y + row as u32);
+                if px < img.width() && py < img.height() {
+                    img.put_pixel(px, py, color);
+                }
+            }
+        }
+    }
+}
+
+// Lays tiles out in a near-square grid with each label under its tile
+pub fn montage(tiles: &[(Image, String)]) -> Image {
+    let columns = (tiles.len() as f64).sqrt().ceil().max(1.0) as u32;
+    let rows = (tiles.len() as u32).div_ceil(columns);
+    let cell_width = tiles
+        .iter()
+        .map(|(img, label)| img.width().max(text_width(label) + LABEL_PADDING * 2))
+        .max()
+        .unwrap_or(0);
+    let cell_height = tiles.iter().map(|(img, _)| img.height()).max().unwrap_or(0) + LABEL_HEIGHT;
+    let mut grid = ImageBuffer::from_pixel(
+        columns * (cell_width + GAP) + GAP,
+        rows * (cell_height + GAP) + GAP,
+        Rgb([255, 255, 255]),
+    );
+    for (i, (img, label)) in tiles.iter().enumerate() {
+        let x = GAP + (i as u32 % columns) * (cell_width + GAP);
+        let y = GAP + (i as u32 / columns) * (cell_height + GAP);
+        image::imageops::replace(&mut grid, img, x as i64, y as i64);
+        draw_text(
+            &mut grid,
+            x + LABEL_PADDING,
+            y + img.height() + LABEL_PADDING,
+            label,
+            Rgb([0, 0, 0]),
+        );
+    }
+    grid
+}
@@ -0,0 +1,54 @@
+use image::Rgb;
+
+pub const FORMATS: [&str; 4] = ["gpl", "pal", "hex", "act"];
+
+// Palette file in the format given by the path extension
+pub fn export(palette: &[Rgb<u8>], path: &str) -> Result<Vec<u8>, String> {
+    let name = std::path::Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase());
+    match extension.as_deref() {
+        // GIMP, Inkscape, Krita
+        Some("gpl") => {
+            let mut out = format!("GIMP Palette\nName: {}\nColumns: 8\n#\n", name);
+            for (idx, rgb) in palette.iter().enumerate() {
+                out += &format!("{:>3} {:>3} {:>3}\tIndex {}\n", rgb[0], rgb[1], rgb[2], idx);
+            }
+            Ok(out.into_bytes())
+        }
+        // JASC-PAL (Paint Shop Pro, Aseprite)
+        Some("pal") => {
+            let mut out = format!("JASC-PAL\r\n0100\r\n{}\r\n", palette.len());
+            for rgb in palette {
+                out += &format!("{} {} {}\r\n", rgb[0], rgb[1], rgb[2]);
+            }
+            Ok(out.into_bytes())
+        }
+        // One RRGGBB per line (Lospec, Aseprite)
+        Some("hex") => Ok(palette
+            .iter()
+            .map(|rgb| format!("{:02x}{:02x}{:02x}\n", rgb[0], rgb[1], rgb[2]))
+            .collect::<String>()
+            .into_bytes()),
+        // Adobe Color Table: 256 RGB entries, then colors amount and transparent index
+        Some("act") => {
+            if palette.len() > 256 {
+                return Err("act palettes hold at most 256 colors".to_string());
+            }
+            let mut out = vec![0u8; 256 * 3];
+            for (idx, rgb) in palette.iter().enumerate() {
+                out[idx * 3..idx * 3 + 3].copy_from_slice(&rgb.0);
+            }
+            out.extend_from_slice(&(palette.len() as u16).to_be_bytes());
+            out.extend_from_slice(&0xffffu16.to_be_bytes());
+            Ok(out)
+        }
+        _ => Err(format!(
+            "unknown palette format of {}, expected one of: {}",
+            path,
+            FORMATS.join(", ")
+        )),
+    }
+}
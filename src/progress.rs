@@ -0,0 +1,39 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+
+// Receives the progress of each processing pass (mapping, encryption), called once per step
+// from worker threads, so it should be cheap
+pub trait Progress: Send + Sync {
+    fn report(&self, done: u64, total: u64);
+}
+
+// Ignores progress, the default of Encoder and Decoder
+pub struct Silent;
+
+impl Progress for Silent {
+    fn report(&self, _done: u64, _total: u64) {}
+}
+
+// Counts the steps of one pass and passes them on
+pub(crate) struct Tracker {
+    progress: Arc<dyn Progress>,
+    done: AtomicU64,
+    total: u64,
+}
+
+impl Tracker {
+    pub(crate) fn new(progress: &Arc<dyn Progress>, total: usize) -> Self {
+        Self {
+            progress: Arc::clone(progress),
+            done: AtomicU64::new(0),
+            total: total as u64,
+        }
+    }
+
+    pub(crate) fn step(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.progress.report(done.min(self.total), self.total);
+    }
+}
@@ -0,0 +1,84 @@
+use aes::{
+    Aes128,
+    cipher::{BlockDecrypt, BlockEncrypt, KeyInit, generic_array::GenericArray},
+};
+use hkdf::Hkdf;
+use rand::{Rng, rng};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::utils::{base64url_to_bytes, bytes_to_base64url};
+
+const KEY_LEN: usize = 32;
+pub const DATA_KEY_LEN: usize = 16;
+const SLOT_LEN: usize = KEY_LEN + DATA_KEY_LEN;
+pub const MAX_RECIPIENTS: usize = u8::MAX as usize;
+
+fn random_secret() -> StaticSecret {
+    let bytes: [u8; KEY_LEN] = rng().random();
+    StaticSecret::from(bytes)
+}
+
+// (secret, public) base64url pair
+pub fn gen_keypair() -> (String, String) {
+    let secret = random_secret();
+    let public = PublicKey::from(&secret);
+    (
+        bytes_to_base64url(secret.as_bytes()),
+        bytes_to_base64url(public.as_bytes()),
+    )
+}
+
+pub fn parse_key(code: &str) -> Option<[u8; KEY_LEN]> {
+    base64url_to_bytes(code)?.try_into().ok()
+}
+
+fn derive_kek(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> Aes128 {
+    let mut salt = [0u8; KEY_LEN * 2];
+    salt[..KEY_LEN].copy_from_slice(ephemeral.as_bytes());
+    salt[KEY_LEN..].copy_from_slice(recipient.as_bytes());
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared);
+    let mut kek = [0u8; DATA_KEY_LEN];
+    hkdf.expand(b"ric-recipient-wrap", &mut kek).unwrap();
+    Aes128::new(&kek.into())
+}
+
+// Layout: recipients amount (1 byte), ephemeral public key (32 bytes),
+// then per recipient: public key (32 bytes) + wrapped data key (16 bytes)
+pub fn wrap_key(data_key: &[u8; DATA_KEY_LEN], recipients: &[[u8; KEY_LEN]]) -> Vec<u8> {
+    let ephemeral_secret = random_secret();
+    let ephemeral = PublicKey::from(&ephemeral_secret);
+    let mut block = Vec::with_capacity(1 + KEY_LEN + recipients.len() * SLOT_LEN);
+    block.push(recipients.len() as u8);
+    block.extend_from_slice(ephemeral.as_bytes());
+    for &recipient_bytes in recipients {
+        let recipient = PublicKey::from(recipient_bytes);
+        let shared = ephemeral_secret.diffie_hellman(&recipient);
+        let kek = derive_kek(shared.as_bytes(), &ephemeral, &recipient);
+        let mut wrapped = GenericArray::clone_from_slice(data_key);
+        kek.encrypt_block(&mut wrapped);
+        block.extend_from_slice(recipient.as_bytes());
+        block.extend_from_slice(&wrapped);
+    }
+    block
+}
+
+// Returns data key and the whole block length
+pub fn unwrap_key(bytes: &[u8], identity: &[u8; KEY_LEN]) -> Option<(Vec<u8>, usize)> {
+    let amount = *bytes.first()? as usize;
+    let block_len = 1 + KEY_LEN + amount * SLOT_LEN;
+    if bytes.len() < block_len {
+        return None;
+    }
+    let ephemeral = PublicKey::from(<[u8; KEY_LEN]>::try_from(&bytes[1..=KEY_LEN]).ok()?);
+    let secret = StaticSecret::from(*identity);
+    let public = PublicKey::from(&secret);
+    let slot = bytes[1 + KEY_LEN..block_len]
+        .chunks_exact(SLOT_LEN)
+        .find(|slot| slot[..KEY_LEN] == public.as_bytes()[..])?;
+    let shared = secret.diffie_hellman(&ephemeral);
+    let kek = derive_kek(shared.as_bytes(), &ephemeral, &public);
+    let mut data_key = GenericArray::clone_from_slice(&slot[KEY_LEN..]);
+    kek.decrypt_block(&mut data_key);
+    Some((data_key.to_vec(), block_len))
+}
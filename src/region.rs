@@ -0,0 +1,292 @@
+// Region of interest decode: a crop of a file read through Read + Seek, touching only its header
+// and the rows or tiles the region is in. Tiled and seekable files (see seek.rs) are read by
+// their offsets, plain rows by their stride, filtered ones down from the first row as each is
+// predicted from the one above it. Compressed files, those protected with --fec and those
+// serialized in another order than rows, run-length encoded without a seek table or truecolor
+// are read and decoded whole
+use image::DynamicImage;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::{
+    ZSTD_MAGIC, bare,
+    codec::{
+        Decoder, Indexed, SegmentKey, covering_segments, decoded_channels, read_header, segment_key,
+    },
+    detect,
+    error::CodecError,
+    fec, format,
+    scan::Scan,
+    seek,
+    stream::{HEAD_LEN, input_error},
+    tiles,
+    timings::Timings,
+};
+
+// Rows or tiles of a file of these layout flags can be read on their own
+fn seekable(layout: u8) -> bool {
+    Scan::from_layout(layout) == Scan::Raster
+        && layout & format::LAYOUT_INTERLACED == 0
+        && layout & format::LAYOUT_TRUECOLOR == 0
+        && (layout & format::LAYOUT_RLE == 0 || layout & format::LAYOUT_SEEK != 0)
+}
+
+fn check_region(
+    (image_width, image_height): (u32, u32),
+    (x, y, width, height): (u32, u32, u32, u32),
+) -> Result<(), CodecError> {
+    if width == 0
+        || height == 0
+        || x as u64 + width as u64 > image_width as u64
+        || y as u64 + height as u64 > image_height as u64
+    {
+        return Err(CodecError::InvalidInput(format!(
+            "region {}x{} at {},{} isn't inside the {}x{} image",
+            width, height, x, y, image_width, image_height
+        )));
+    }
+    Ok(())
+}
+
+// Stored pixels data read on demand, encrypted data by the whole segments a range is in
+struct Stored<'a, R> {
+    reader: R,
+    // Position of the pixels data in the reader
+    start: u64,
+    len: usize,
+    key: Option<SegmentKey<'a>>,
+}
+
+impl<R: Read + Seek> Stored<'_, R> {
+    fn read(&mut self, range: Range<usize>) -> Result<Vec<u8>, CodecError> {
+        let covering = match self.key {
+            Some(_) => covering_segments(&range, self.len),
+            None => Vec::new(),
+        };
+        let segments = match (covering.first(), covering.last()) {
+            (Some((_, first)), Some((_, last))) => first.start..last.end,
+            _ => range.clone(),
+        };
+        self.reader
+            .seek(SeekFrom::Start(self.start + segments.start as u64))
+            .map_err(input_error)?;
+        let mut data = vec![0u8; segments.len()];
+        self.reader.read_exact(&mut data).map_err(|err| {
+            if err.kind() == ErrorKind::UnexpectedEof {
+                bare::Error::NotEnoughData.into()
+            } else {
+                input_error(err)
+            }
+        })?;
+        if let Some(key) = self.key {
+            for (number, segment) in covering {
+                let segment = segment.start - segments.start..segment.end - segments.start;
+                key.decrypt(&mut data[segment], number)?;
+            }
+        }
+        data.drain(..range.start - segments.start);
+        data.truncate(range.len());
+        Ok(data)
+    }
+}
+
+impl Decoder {
+    // width x height pixels at x, y of the file the reader is at, reading only what the file's
+    // layout lets the region be decoded from (see above). The checksum isn't checked, it takes
+    // the whole file, and strict doesn't apply
+    pub fn decode_region(
+        &self,
+        mut reader: impl Read + Seek,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Result<DynamicImage, CodecError> {
+        let region = (x, y, width, height);
+        let start = reader.stream_position().map_err(input_error)?;
+        let mut head = Vec::new();
+        (&mut reader)
+            .take(HEAD_LEN)
+            .read_to_end(&mut head)
+            .map_err(input_error)?;
+        if head.starts_with(&ZSTD_MAGIC) || fec::is_wrapped(&head) {
+            return self.decode_whole(reader, start, region);
+        }
+        format::checked_prefix_len(&head, self.legacy)?;
+        let layout = format::layout_flags(&head);
+        let keyed = self.key.is_some() || self.key_block.is_some();
+        // Encrypted v1 files may be of the first builds, whose blocks span many rows
+        if !seekable(layout) || (keyed && format::version(&head) == 1) {
+            return self.decode_whole(reader, start, region);
+        }
+        // Metadata may hold more than the bytes read ahead, as in decode_stream
+        if let Ok(metadata) = bare::metadata_range(&head)
+            && metadata.end > head.len()
+        {
+            (&mut reader)
+                .take((metadata.end - head.len()) as u64 + HEAD_LEN)
+                .read_to_end(&mut head)
+                .map_err(input_error)?;
+        }
+        if let Some(flags) = format::flags(&head)
+            && (flags & format::FLAG_ENCRYPTED != 0) != keyed
+        {
+            return Err(CodecError::WrongOptions {
+                looks: if keyed { "plain" } else { "encrypted" }.to_string(),
+                hint: detect::hint(!keyed, false),
+            });
+        }
+        let mut key_opt = self.key.clone();
+        let (palette, data_offset) = read_header(&head, &mut key_opt, self.key_block.clone())?;
+        let (image_width, image_height, _) = bare::dimensions(&head)?;
+        check_region((image_width, image_height), region)?;
+        let index_bits = format::index_bits(&head);
+        let len = format::stored_len(&head)
+            .unwrap_or_else(|| format::data_len(image_width, image_height, index_bits, layout));
+        // Reads go by the header's length and offsets, which have to be within the file
+        let end = reader.seek(SeekFrom::End(0)).map_err(input_error)?;
+        if (len as u64).saturating_add(start + data_offset as u64) > end {
+            return Err(bare::Error::NotEnoughData.into());
+        }
+        bare::check_data_len(image_width, image_height, index_bits, layout, len)?;
+        let mut stored = Stored {
+            reader,
+            start: start + data_offset as u64,
+            len,
+            key: key_opt
+                .as_deref()
+                .map(|key| segment_key(&head, key))
+                .transpose()?,
+        };
+        let channels = decoded_channels(&head);
+        if let Some(size) = format::tile_size(&head) {
+            let (indexed, left, top) = tiled_region(
+                &mut stored,
+                &head,
+                (image_width, image_height),
+                region,
+                size,
+            )?;
+            let img = self.dynamic(&indexed, &mut Timings::new())?;
+            return Ok(img.crop_imm(x - left, y - top, width, height));
+        }
+        let rows = y as usize..(y + height) as usize;
+        let data = if layout & format::LAYOUT_SEEK != 0 {
+            let offsets = stored.read(
+                rows.start * seek::OFFSET_LEN
+                    ..(rows.end + 1).min(image_height as usize) * seek::OFFSET_LEN,
+            )?;
+            let ranges = seek::ranges(&offsets, rows.clone(), image_height, stored.len)?;
+            let span = ranges[0].start..ranges[ranges.len() - 1].end;
+            let bytes = stored.read(span.clone())?;
+            seek::rows(
+                &bytes,
+                span.start,
+                &ranges,
+                rows.start,
+                image_width,
+                index_bits,
+                layout,
+            )?
+        } else {
+            let stride = format::stride(image_width, index_bits, layout);
+            // Filtered rows are predicted from the ones above, down from the first
+            let first = if layout & format::LAYOUT_FILTERED != 0 {
+                0
+            } else {
+                rows.start
+            };
+            let bytes = stored.read(first * stride..rows.end * stride)?;
+            let packed = bare::packed_data(
+                &bytes,
+                image_width,
+                (rows.end - first) as u32,
+                index_bits,
+                layout,
+            )?;
+            packed[(rows.start - first) * format::row_len(image_width, index_bits)..].to_vec()
+        };
+        let indexed = Indexed {
+            width: image_width,
+            height,
+            palette,
+            channels,
+            indices: format::unpack_indices(&data, image_width, index_bits),
+            packed: None,
+            tiles: None,
+            truecolor: None,
+            damaged: None,
+        };
+        let img = self.dynamic(&indexed, &mut Timings::new())?;
+        Ok(img.crop_imm(x, 0, width, height))
+    }
+
+    // Crop of the whole decoded file
+    fn decode_whole(
+        &self,
+        mut reader: impl Read + Seek,
+        start: u64,
+        (x, y, width, height): (u32, u32, u32, u32),
+    ) -> Result<DynamicImage, CodecError> {
+        reader.seek(SeekFrom::Start(start)).map_err(input_error)?;
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(input_error)?;
+        let img = self.decode_dynamic_timed(bytes, &mut Timings::new())?;
+        check_region((img.width(), img.height()), (x, y, width, height))?;
+        Ok(img.crop_imm(x, y, width, height))
+    }
+}
+
+// Indexed of the tiles a region is in, which are a tiled image of their own, and where its top
+// left pixel is in the image
+fn tiled_region<R: Read + Seek>(
+    stored: &mut Stored<R>,
+    head: &[u8],
+    (image_width, image_height): (u32, u32),
+    (x, y, width, height): (u32, u32, u32, u32),
+    size: u32,
+) -> Result<(Indexed, u32, u32), CodecError> {
+    let (across, down) = tiles::grid(image_width, image_height, size);
+    let count = across * down;
+    let columns = (x / size) as usize..((x + width - 1) / size + 1) as usize;
+    let rows = (y / size) as usize..((y + height - 1) / size + 1) as usize;
+    let mut read = Vec::with_capacity(columns.len() * rows.len());
+    for row in rows.clone() {
+        // The tiles of a row of tiles are one after another
+        let first = row * across + columns.start;
+        let end = row * across + columns.end;
+        let offsets =
+            stored.read(first * tiles::OFFSET_LEN..(end + 1).min(count) * tiles::OFFSET_LEN)?;
+        let ranges =
+            tiles::ranges(&offsets, first..end, count, stored.len).map_err(bare::Error::BadTile)?;
+        let span = ranges[0].start..ranges[ranges.len() - 1].end;
+        let bytes = stored.read(span.clone())?;
+        for (i, range) in (first..).zip(ranges) {
+            let (_, _, tile_width, tile_height) = tiles::rect(i, image_width, image_height, size);
+            let tile = &bytes[range.start - span.start..range.end - span.start];
+            read.push(tiles::read(
+                tile,
+                i,
+                tile_width,
+                tile_height,
+                format::channels(head),
+            )?);
+        }
+    }
+    let (left, top) = (columns.start as u32 * size, rows.start as u32 * size);
+    let band_width = (columns.end as u32).saturating_mul(size).min(image_width) - left;
+    let band_height = (rows.end as u32).saturating_mul(size).min(image_height) - top;
+    let tiled = tiles::assemble(read, band_width, band_height, size);
+    let indexed = Indexed {
+        width: band_width,
+        height: band_height,
+        palette: tiled.palette.into_iter().map(image::Rgba).collect(),
+        channels: decoded_channels(head),
+        indices: tiled.indices,
+        packed: None,
+        tiles: Some(tiled.palettes),
+        truecolor: None,
+        damaged: None,
+    };
+    Ok((indexed, left, top))
+}
@@ -0,0 +1,233 @@
+// Key rotation (rekey): the pixels data of an encrypted file decrypted with the old key and
+// encrypted again with the new one segment by segment, without decoding, re-quantizing or
+// touching the palette. The palette entries of --encrypt-palette files are encrypted again too,
+// the key check, cipher entry, chunk checksums and authentication tag are written for the new
+// key and the rest stays byte for byte, so the file keeps its length. Compressed files and those
+// protected with --fec are unwrapped and wrapped again. Files whose data key is in a key block
+// (recipients, --derive, --passphrase) are encoded again instead, the block would have to be
+// written anew, and those with a key-slot table get their slots edited (edit_slots)
+use hmac::Mac;
+
+#[cfg(feature = "crypto")]
+use crate::slot_table;
+use crate::{
+    ZSTD_MAGIC, bare, chunks,
+    codec::{
+        PALETTE_TWEAK, SegmentKey, check_auth, encrypt, plain_header, read_header, require_crypto,
+        segment_key, split_segments, unchecked_v1,
+    },
+    compression, container,
+    error::CodecError,
+    fec, format,
+    keys::*,
+    metadata,
+    utils::*,
+};
+
+// The file with its content edited, compressed and --fec files unwrapped and wrapped again and
+// the checksum footer checked and written anew
+fn rewritten<F>(bytes: &[u8], edit: &F) -> Result<Vec<u8>, CodecError>
+where
+    F: Fn(&[u8]) -> Result<Vec<u8>, CodecError>,
+{
+    if container::is_container(bytes) {
+        return Err(CodecError::InvalidInput(
+            "is a container, extract its entries and change their keys one by one".to_string(),
+        ));
+    }
+    if fec::is_wrapped(bytes) {
+        let file = fec::unwrap(bytes)?;
+        return fec::rewrap(bytes, &rewritten(&file, edit)?);
+    }
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        let file = compression::decompress(bytes)?;
+        return compression::compress(&rewritten(&file, edit)?, compression::DEFAULT_LEVEL);
+    }
+    format::checked_prefix_len(bytes, false)?;
+    let flags = format::flags(bytes).unwrap_or_default();
+    if flags & format::FLAG_ENCRYPTED == 0 {
+        return Err(CodecError::InvalidInput(
+            "isn't encrypted, encode it with a key instead".to_string(),
+        ));
+    }
+    let content = bare::without_checksum(bytes)?;
+    // The edits take the metadata section for whole from here on
+    let range = bare::metadata_range(content)?;
+    let entries = metadata::parse(content.get(range).ok_or(bare::Error::TooShort)?)?;
+    if entries.iter().any(|(entry, _)| entry == SIGNATURE_KEY) {
+        return Err(CodecError::InvalidInput(
+            "is signed with --sign, the signature wouldn't match any more; encode it again instead"
+                .to_string(),
+        ));
+    }
+    let mut edited = edit(content)?;
+    if flags & format::FLAG_CHECKSUM != 0 {
+        format::append_checksum(&mut edited);
+    }
+    Ok(edited)
+}
+
+// Makes the authentication tag anew over the header before the metadata section, the other
+// entries and what follows the section, for files that have one
+fn retag(entries: &mut metadata::Entries, key: &[u8], header: &[u8], rest: &[&[u8]]) {
+    if let Some(index) = entries.iter().position(|(entry, _)| entry == AUTH_KEY) {
+        let mut mac = authenticator(key, header, entries);
+        for part in rest {
+            mac.update(part);
+        }
+        entries[index].1 = auth_tag(mac).to_vec();
+    }
+}
+
+// The file with its key changed from old_key to new_key, either of 16, 24 or 32 bytes
+pub fn rekey(bytes: &[u8], old_key: &str, new_key: &str) -> Result<Vec<u8>, CodecError> {
+    require_crypto()?;
+    rewritten(bytes, &|content| rekey_content(content, old_key, new_key))
+}
+
+fn rekey_content(content: &[u8], old_key: &str, new_key: &str) -> Result<Vec<u8>, CodecError> {
+    let flags = format::flags(content).unwrap_or_default();
+    if flags & format::FLAG_KEY_BLOCK != 0 {
+        return Err(CodecError::InvalidInput(
+            "its data key is in a key block (recipients, --derive, --passphrase), encode it again to change it"
+                .to_string(),
+        ));
+    }
+    let range = bare::metadata_range(content)?;
+    let has_metadata = flags & format::FLAG_METADATA != 0;
+    let mut entries = if has_metadata {
+        metadata::parse(&content[range.clone()])?
+    } else {
+        metadata::Entries::new()
+    };
+    if entries.iter().any(|(entry, _)| entry == KEY_SLOTS_KEY) {
+        return Err(CodecError::InvalidInput(
+            "has a key-slot table, add the new key and remove the old one with slots instead"
+                .to_string(),
+        ));
+    }
+    let new_bytes = base64url_to_bytes(new_key)
+        .filter(|key| cipher_name(Fpe::Ff1, key.len()).is_some())
+        .ok_or(CodecError::BadKey("invalid new key".to_string()))?;
+    // Checks the old key and that nothing was tampered with before the tag is made anew
+    let (_, data_offset) = read_header(content, &mut Some(old_key.to_string()), None)?;
+    if unchecked_v1(content, data_offset) {
+        return Err(CodecError::InvalidInput(
+            "format version 1 file without a key check, upgrade it with its key first".to_string(),
+        ));
+    }
+    check_auth(content, old_key)?;
+    let old = segment_key(content, old_key)?;
+    // The header tweak is of the plain palette, which stays the same
+    let new = SegmentKey {
+        key: new_key,
+        ..old
+    };
+    let mut data = content[data_offset..].to_vec();
+    for (number, segment) in split_segments(&mut data) {
+        old.decrypt(segment, number)?;
+        new.encrypt(segment, number)?;
+    }
+    // The header before the metadata section, its length left out
+    let mut header = content[..range.start - if has_metadata { 4 } else { 0 }].to_vec();
+    if entries
+        .iter()
+        .any(|(entry, _)| entry == metadata::ENCRYPTED_PALETTE_KEY)
+    {
+        header = plain_header(&header, old_key, old.fpe)?;
+        let (palette_size, start) = bare::palette_size(&header)?;
+        let len = format::palette_entries(&header, palette_size) * format::channels(&header);
+        encrypt(
+            &mut header[start..start + len],
+            new_key,
+            &PALETTE_TWEAK,
+            old.fpe,
+        )
+        .ok_or(CodecError::BadKey("invalid new key".to_string()))?;
+    }
+    let key_check = key_check(&new_bytes);
+    for (entry, value) in entries.iter_mut() {
+        if entry == CIPHER_KEY {
+            *value = cipher_name(old.fpe, new_bytes.len()).unwrap().into_bytes();
+        } else if entry == chunks::KEY && value.len() >= 4 {
+            let block_len = u32::from_le_bytes(value[..4].try_into().unwrap()) as usize;
+            *value = chunks::entry(block_len, &header, &[&key_check, &data]);
+        }
+    }
+    // The tag covers the other entries, it's made last
+    retag(&mut entries, &new_bytes, &header, &[&key_check, &data]);
+    let mut rekeyed = header;
+    if has_metadata {
+        rekeyed.extend_from_slice(&metadata::write(&entries));
+    }
+    rekeyed.extend_from_slice(&key_check);
+    rekeyed.extend_from_slice(&data);
+    Ok(rekeyed)
+}
+
+// The file with a slot of each key in add appended to its key-slot table and the slots at the
+// positions in remove taken out, opened with key, which has a slot. The data key stays, so only
+// the key-slots entry and the authentication tag change; a removed key that opened the file
+// before may have kept its data key, encode the image again to shut it out for good
+#[cfg(feature = "crypto")]
+pub fn edit_slots(
+    bytes: &[u8],
+    key: &str,
+    add: &[Vec<u8>],
+    remove: &[usize],
+) -> Result<Vec<u8>, CodecError> {
+    rewritten(bytes, &|content| {
+        let range = bare::metadata_range(content)?;
+        let mut entries = metadata::parse(&content[range.clone()])?;
+        let Some(index) = entries.iter().position(|(entry, _)| entry == KEY_SLOTS_KEY) else {
+            return Err(CodecError::InvalidInput(
+                "has no key-slot table, encode it with --key-slot".to_string(),
+            ));
+        };
+        // Opens the slot of the key, the data key comes back
+        let mut key_opt = Some(key.to_string());
+        read_header(content, &mut key_opt, None)?;
+        let data_key = key_opt.unwrap();
+        check_auth(content, &data_key)?;
+        let data_key = base64url_to_bytes(&data_key).unwrap();
+        let table = &entries[index].1;
+        let slots = slot_table::slot_ids(table)
+            .ok_or(CodecError::BadHeader(
+                "corrupt in its key-slot table".to_string(),
+            ))?
+            .len();
+        if let Some(position) = remove.iter().find(|&&position| position >= slots) {
+            return Err(CodecError::InvalidInput(format!(
+                "has no key slot {}, it has {}",
+                position, slots
+            )));
+        }
+        let table = slot_table::add(
+            &slot_table::remove(table, remove),
+            &data_key.as_slice().try_into().unwrap(),
+            add,
+        )
+        .ok_or(CodecError::InvalidInput(
+            "a key to add has a slot already".to_string(),
+        ))?;
+        let slots = slot_table::slot_ids(&table).unwrap().len();
+        if slots == 0 {
+            return Err(CodecError::InvalidInput(
+                "would have no key slot left, nothing could open it".to_string(),
+            ));
+        }
+        if slots > slot_table::MAX_SLOTS {
+            return Err(CodecError::InvalidInput(format!(
+                "would have more than {} key slots",
+                slot_table::MAX_SLOTS
+            )));
+        }
+        entries[index].1 = table;
+        let header = &content[..range.start - 4];
+        retag(&mut entries, &data_key, header, &[&content[range.end..]]);
+        let mut edited = header.to_vec();
+        edited.extend_from_slice(&metadata::write(&entries));
+        edited.extend_from_slice(&content[range.end..]);
+        Ok(edited)
+    })
+}
@@ -0,0 +1,223 @@
+// Run-length encoding of the pixels data (layout flag LAYOUT_RLE), PackBits-style over elements
+// of a palette index's bytes: 2 for 16 bit indices, 1 otherwise, so runs of packed indices are
+// runs of whole bytes. A control byte below 128 is followed by that many plus one literal
+// elements, one of 128 or more by an element repeated control - 126 times. Filtered rows of 16
+// bit indices may leave the last element a byte short
+use alloc::vec::Vec;
+
+use crate::bare::Error;
+
+const MAX_LITERAL: usize = 128;
+const MAX_RUN: usize = 129;
+// Shorter runs take no fewer bytes than literals
+const MIN_RUN: usize = 3;
+// Zero bytes the encoder may end the data with, so no encryption segment is shorter than FF1
+// takes
+pub const MAX_PADDING: usize = 2;
+
+fn element(data: &[u8], bpp: usize, i: usize) -> &[u8] {
+    &data[i * bpp..((i + 1) * bpp).min(data.len())]
+}
+
+fn push_literals(out: &mut Vec<u8>, data: &[u8], bpp: usize, elements: core::ops::Range<usize>) {
+    let mut start = elements.start;
+    while start < elements.end {
+        let end = elements.end.min(start + MAX_LITERAL);
+        out.push((end - start - 1) as u8);
+        out.extend_from_slice(&data[start * bpp..(end * bpp).min(data.len())]);
+        start = end;
+    }
+}
+
+pub fn encode(data: &[u8], bpp: usize) -> Vec<u8> {
+    let elements = data.len().div_ceil(bpp);
+    let mut out = Vec::new();
+    let (mut literal_start, mut i) = (0, 0);
+    while i < elements {
+        let first = element(data, bpp, i);
+        let run = (i..elements.min(i + MAX_RUN))
+            .take_while(|&j| element(data, bpp, j) == first)
+            .count();
+        if run < MIN_RUN {
+            i += 1;
+            continue;
+        }
+        push_literals(&mut out, data, bpp, literal_start..i);
+        out.push((run + 126) as u8);
+        out.extend_from_slice(first);
+        i += run;
+        literal_start = i;
+    }
+    push_literals(&mut out, data, bpp, literal_start..elements);
+    out
+}
+
+// Decodes packets as they arrive, so the data may end inside one, e.g. at a segment
+pub struct Decoder {
+    bpp: usize,
+    // Decoded bytes still to come
+    left: usize,
+    // Bytes of a packet the previous data ended inside
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    // len is the length of the decoded data
+    pub fn new(len: usize, bpp: usize) -> Self {
+        Self {
+            bpp,
+            left: len,
+            pending: Vec::new(),
+        }
+    }
+
+    // Appends the bytes of the packets complete with data to out
+    pub fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.pending.extend_from_slice(data);
+        let mut at = 0;
+        while self.left > 0 && at < self.pending.len() {
+            let control = self.pending[at] as usize;
+            let packet = &self.pending[at + 1..];
+            if control < 128 {
+                let len = ((control + 1) * self.bpp).min(self.left);
+                if packet.len() < len {
+                    break;
+                }
+                out.extend_from_slice(&packet[..len]);
+                self.left -= len;
+                at += 1 + len;
+            } else {
+                let element_len = self.bpp.min(self.left);
+                if packet.len() < element_len {
+                    break;
+                }
+                let len = ((control - 126) * element_len).min(self.left);
+                out.extend(packet[..element_len].iter().cycle().take(len));
+                self.left -= len;
+                at += 1 + element_len;
+            }
+        }
+        self.pending.drain(..at);
+    }
+
+    // Fails when the data ended before the decoded length or goes on beyond the padding
+    pub fn finish(self) -> Result<(), Error> {
+        if self.left > 0 {
+            return Err(Error::NotEnoughData);
+        }
+        if self.pending.len() > MAX_PADDING {
+            return Err(Error::TrailingBytes(self.pending.len()));
+        }
+        Ok(())
+    }
+}
+
+// Most bytes len bytes of run-length encoded data decode to, runs of MAX_RUN elements only
+pub fn max_decoded_len(len: usize, bpp: usize) -> usize {
+    (len / (1 + bpp) + 1).saturating_mul(MAX_RUN * bpp)
+}
+
+// Decoded data of len bytes
+pub fn decode(data: &[u8], len: usize, bpp: usize) -> Result<Vec<u8>, Error> {
+    let mut decoder = Decoder::new(len, bpp);
+    let mut out = Vec::with_capacity(len.min(max_decoded_len(data.len(), bpp)));
+    decoder.feed(data, &mut out);
+    decoder.finish()?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn round_trip(data: &[u8], bpp: usize) -> Vec<u8> {
+        let encoded = encode(data, bpp);
+        assert_eq!(decode(&encoded, data.len(), bpp).unwrap(), data);
+        encoded
+    }
+
+    #[test]
+    fn empty() {
+        assert!(round_trip(&[], 1).is_empty());
+        assert!(round_trip(&[], 2).is_empty());
+    }
+
+    #[test]
+    fn one_pixel() {
+        assert_eq!(round_trip(&[7], 1), [0, 7]);
+        assert_eq!(round_trip(&[1, 2], 2), [0, 1, 2]);
+    }
+
+    // The longest run fits a packet, one element more starts the next
+    #[test]
+    fn longest_run() {
+        assert_eq!(round_trip(&[5; MAX_RUN], 1), [255, 5]);
+        assert_eq!(round_trip(&[5; MAX_RUN + 1], 1), [255, 5, 0, 5]);
+        assert_eq!(round_trip(&[5; MAX_RUN + MIN_RUN], 1), [255, 5, 129, 5]);
+        assert_eq!(round_trip(&[1, 2].repeat(MAX_RUN), 2), [255, 1, 2]);
+    }
+
+    // Runs shorter than MIN_RUN stay literals, literals longer than MAX_LITERAL are split
+    #[test]
+    fn literals() {
+        assert_eq!(round_trip(&[1, 1, 2], 1), [2, 1, 1, 2]);
+        let data: Vec<u8> = (0..=255).chain(0..=255).collect();
+        let encoded = round_trip(&data, 1);
+        assert_eq!(encoded.len(), data.len() + data.len() / MAX_LITERAL);
+        assert_eq!(encoded[0] as usize, MAX_LITERAL - 1);
+    }
+
+    // Filtered rows of 16 bit indices may end a byte short of an element
+    #[test]
+    fn short_last_element() {
+        round_trip(&[1, 2, 1, 2, 1, 2, 1], 2);
+        round_trip(&[1, 2, 3], 2);
+        round_trip(&[9], 2);
+    }
+
+    #[test]
+    fn fed_byte_by_byte() {
+        let data: Vec<u8> = [[3u8; 200].as_slice(), &[1, 2, 3, 4, 5], &[0; 10]].concat();
+        let encoded = encode(&data, 1);
+        let mut decoder = Decoder::new(data.len(), 1);
+        let mut out = Vec::new();
+        for byte in &encoded {
+            decoder.feed(core::slice::from_ref(byte), &mut out);
+        }
+        decoder.finish().unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn truncated() {
+        let encoded = encode(&[4; 50], 1);
+        assert!(matches!(
+            decode(&encoded[..1], 50, 1),
+            Err(Error::NotEnoughData)
+        ));
+        assert!(matches!(decode(&encoded, 51, 1), Err(Error::NotEnoughData)));
+    }
+
+    // Padding up to MAX_PADDING bytes is taken, more is trailing data
+    #[test]
+    fn padding() {
+        let mut encoded = encode(&[4; 50], 1);
+        encoded.extend_from_slice(&[0; MAX_PADDING]);
+        assert_eq!(decode(&encoded, 50, 1).unwrap(), [4; 50]);
+        encoded.push(0);
+        assert!(matches!(
+            decode(&encoded, 50, 1),
+            Err(Error::TrailingBytes(3))
+        ));
+    }
+
+    #[test]
+    fn max_decoded_len_bounds_runs() {
+        for bpp in [1, 2] {
+            let data = vec![6; MAX_RUN * bpp * 4];
+            let encoded = encode(&data, bpp);
+            assert!(max_decoded_len(encoded.len(), bpp) >= data.len());
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use image::{Rgb, RgbImage};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{
+    codec::Encoder, error::CodecError, stream::SAMPLE_PIXELS, timings::Timings, utils::gen_palette,
+};
+
+// Encoder fed one row at a time, e.g. by a capture pipeline. Rows are kept as RGB bytes and
+// the palette is built at finish() from a reservoir sample of every pushed pixel, which holds
+// all of them up to SAMPLE_PIXELS, so smaller images encode the same as with Encoder::encode
+pub struct RowEncoder {
+    encoder: Encoder,
+    width: u32,
+    height: u32,
+    rgb: Vec<u8>,
+    sample: Vec<Rgb<u8>>,
+    seen: usize,
+    // Seeded, so the same rows give the same file
+    rng: StdRng,
+}
+
+impl RowEncoder {
+    pub fn new(encoder: Encoder, width: u32) -> Self {
+        Self {
+            encoder,
+            width,
+            height: 0,
+            rgb: Vec::new(),
+            sample: Vec::new(),
+            seen: 0,
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+
+    pub fn push_row(&mut self, row: &[Rgb<u8>]) -> Result<(), CodecError> {
+        if row.len() != self.width as usize {
+            return Err(CodecError::InvalidInput(format!(
+                "row {} has {} pixels, the width is {}",
+                self.height,
+                row.len(),
+                self.width
+            )));
+        }
+        for &pixel in row {
+            self.rgb.extend_from_slice(&pixel.0);
+            // Algorithm R: the n-th pixel replaces a random sample one with probability k/n
+            if self.sample.len() < SAMPLE_PIXELS {
+                self.sample.push(pixel);
+            } else {
+                let slot = self.rng.random_range(0..=self.seen);
+                if slot < SAMPLE_PIXELS {
+                    self.sample[slot] = pixel;
+                }
+            }
+            self.seen += 1;
+        }
+        self.height += 1;
+        Ok(())
+    }
+
+    // Rows pushed so far
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // Header, palette and pixels data of every pushed row
+    pub fn finish(self) -> Result<Vec<u8>, CodecError> {
+        self.encoder.check(self.width, self.height)?;
+        // The transparent color gets its own entry in encode_with
+        let sample = self
+            .sample
+            .into_iter()
+            .filter(|&pixel| Some(pixel) != self.encoder.transparent)
+            .collect::<Vec<_>>();
+        let palette = if sample.is_empty() {
+            Vec::new()
+        } else {
+            gen_palette(
+                &sample,
+                self.encoder.opaque_palette_size(),
+                self.encoder.threads,
+            )
+        };
+        drop(sample);
+        let img = RgbImage::from_raw(self.width, self.height, self.rgb).ok_or(
+            CodecError::InvalidInput("rows don't match the width".to_string()),
+        )?;
+        Ok(self
+            .encoder
+            .encode_with(img, Some(palette), &mut Timings::new())?
+            .0)
+    }
+}
@@ -0,0 +1,233 @@
+// Orders palette indices are serialized in, bits 2 and 3 of the layout flags. Raster is rows
+// top to bottom, zigzag the same with every other row right to left, column is columns left to
+// right and hilbert a generalized Hilbert curve (gilbert2d) over any width and height, which
+// keeps pixels close in the image close in the data. Serialized indices are packed, filtered
+// and run-length encoded in rows of the image's width, as raster ones are
+use alloc::vec::Vec;
+
+use crate::format::LAYOUT_SCAN;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scan {
+    Raster = 0,
+    Hilbert = 1,
+    Zigzag = 2,
+    Column = 3,
+}
+
+impl Scan {
+    pub const ALL: [Scan; 4] = [Scan::Raster, Scan::Hilbert, Scan::Zigzag, Scan::Column];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|scan| scan.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scan::Raster => "raster",
+            Scan::Hilbert => "hilbert",
+            Scan::Zigzag => "zigzag",
+            Scan::Column => "column",
+        }
+    }
+
+    // Scan of a layout flags byte
+    pub fn from_layout(layout: u8) -> Self {
+        Self::ALL[((layout & LAYOUT_SCAN) >> LAYOUT_SCAN.trailing_zeros()) as usize]
+    }
+
+    // Layout flags bits of the scan
+    pub fn layout(self) -> u8 {
+        (self as u8) << LAYOUT_SCAN.trailing_zeros()
+    }
+
+    // Raster offset (y * width + x) of every pixel, in scan order
+    pub fn order(self, width: u32, height: u32) -> Vec<usize> {
+        let (width, height) = (width as usize, height as usize);
+        match self {
+            Scan::Raster => (0..width * height).collect(),
+            Scan::Zigzag => (0..height)
+                .flat_map(|y| {
+                    (0..width).map(move |x| y * width + if y % 2 == 0 { x } else { width - 1 - x })
+                })
+                .collect(),
+            Scan::Column => (0..width)
+                .flat_map(|x| (0..height).map(move |y| y * width + x))
+                .collect(),
+            // gilbert never gets down to a line of an empty rectangle
+            Scan::Hilbert if width == 0 || height == 0 => Vec::new(),
+            Scan::Hilbert => {
+                let mut order = Vec::with_capacity(width * height);
+                let (w, h) = (width as i64, height as i64);
+                if width >= height {
+                    gilbert(&mut order, width, (0, 0), (w, 0), (0, h));
+                } else {
+                    gilbert(&mut order, width, (0, 0), (0, h), (w, 0));
+                }
+                order
+            }
+        }
+    }
+}
+
+// Fills the rectangle at (x, y) spanned by the major direction a and the minor one b
+fn gilbert(order: &mut Vec<usize>, width: usize, (x, y): (i64, i64), a: (i64, i64), b: (i64, i64)) {
+    let (w, h) = ((a.0 + a.1).abs(), (b.0 + b.1).abs());
+    let da = (a.0.signum(), a.1.signum());
+    let db = (b.0.signum(), b.1.signum());
+    let line = |order: &mut Vec<usize>, len: i64, d: (i64, i64)| {
+        for i in 0..len {
+            order.push(((y + i * d.1) as usize) * width + (x + i * d.0) as usize);
+        }
+    };
+    if h == 1 {
+        return line(order, w, da);
+    }
+    if w == 1 {
+        return line(order, h, db);
+    }
+    let mut a2 = (a.0.div_euclid(2), a.1.div_euclid(2));
+    let mut b2 = (b.0.div_euclid(2), b.1.div_euclid(2));
+    if 2 * w > 3 * h {
+        // Long and narrow, split in two along the major direction
+        if (a2.0 + a2.1).abs() % 2 == 1 && w > 2 {
+            a2 = (a2.0 + da.0, a2.1 + da.1);
+        }
+        gilbert(order, width, (x, y), a2, b);
+        gilbert(
+            order,
+            width,
+            (x + a2.0, y + a2.1),
+            (a.0 - a2.0, a.1 - a2.1),
+            b,
+        );
+    } else {
+        // Up the minor direction, along the major one and back down
+        if (b2.0 + b2.1).abs() % 2 == 1 && h > 2 {
+            b2 = (b2.0 + db.0, b2.1 + db.1);
+        }
+        gilbert(order, width, (x, y), b2, a2);
+        gilbert(
+            order,
+            width,
+            (x + b2.0, y + b2.1),
+            a,
+            (b.0 - b2.0, b.1 - b2.1),
+        );
+        gilbert(
+            order,
+            width,
+            (
+                x + (a.0 - da.0) + (b2.0 - db.0),
+                y + (a.1 - da.1) + (b2.1 - db.1),
+            ),
+            (-b2.0, -b2.1),
+            (a2.0 - a.0, a2.1 - a.1),
+        );
+    }
+}
+
+// Indices of an image in the order of order
+pub fn serialize(indices: &[u16], order: &[usize]) -> Vec<u16> {
+    order.iter().map(|&pixel| indices[pixel]).collect()
+}
+
+// Indices in raster order of indices serialized in the order of order
+pub fn deserialize(serialized: &[u16], order: &[usize]) -> Vec<u16> {
+    let mut indices = alloc::vec![0u16; serialized.len()];
+    for (&index, &pixel) in serialized.iter().zip(order) {
+        indices[pixel] = index;
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZES: [(u32, u32); 10] = [
+        (0, 0),
+        (0, 3),
+        (1, 1),
+        (1, 7),
+        (7, 1),
+        (2, 2),
+        (3, 5),
+        (16, 16),
+        (17, 10),
+        (10, 33),
+    ];
+
+    // Every pixel once, whatever the size
+    #[test]
+    fn orders_are_permutations() {
+        for scan in Scan::ALL {
+            for (width, height) in SIZES {
+                let mut order = scan.order(width, height);
+                order.sort_unstable();
+                let all: Vec<usize> = (0..(width * height) as usize).collect();
+                assert_eq!(order, all, "{} {}x{}", scan.name(), width, height);
+            }
+        }
+    }
+
+    // Hilbert steps to a neighbour at every pixel, also on sizes that aren't powers of two
+    #[test]
+    fn hilbert_is_continuous() {
+        for (width, height) in SIZES {
+            let order = Scan::Hilbert.order(width, height);
+            let width = width as usize;
+            for pair in order.windows(2) {
+                let (x0, y0) = ((pair[0] % width) as i64, (pair[0] / width) as i64);
+                let (x1, y1) = ((pair[1] % width) as i64, (pair[1] / width) as i64);
+                assert!(
+                    (x0 - x1).abs().max((y0 - y1).abs()) == 1,
+                    "{}x{} at {:?}",
+                    width,
+                    height,
+                    pair
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn hilbert_starts_at_the_origin() {
+        for (width, height) in SIZES.into_iter().filter(|&(w, h)| w * h > 0) {
+            assert_eq!(Scan::Hilbert.order(width, height)[0], 0);
+        }
+        assert_eq!(Scan::Hilbert.order(2, 2), [0, 2, 3, 1]);
+    }
+
+    #[test]
+    fn small_orders() {
+        assert_eq!(Scan::Raster.order(3, 2), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(Scan::Zigzag.order(3, 2), [0, 1, 2, 5, 4, 3]);
+        assert_eq!(Scan::Column.order(3, 2), [0, 3, 1, 4, 2, 5]);
+        for scan in Scan::ALL {
+            assert_eq!(scan.order(1, 1), [0]);
+        }
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        for scan in Scan::ALL {
+            let (width, height) = (13, 9);
+            let indices: Vec<u16> = (0..width * height).map(|i| (i * 7 % 31) as u16).collect();
+            let order = scan.order(width, height);
+            let serialized = serialize(&indices, &order);
+            assert_eq!(deserialize(&serialized, &order), indices, "{}", scan.name());
+        }
+    }
+
+    #[test]
+    fn names_and_layout_bits() {
+        for scan in Scan::ALL {
+            assert_eq!(Scan::parse(scan.name()), Some(scan));
+            assert_eq!(Scan::from_layout(scan.layout()), scan);
+            assert_eq!(scan.layout() & !LAYOUT_SCAN, 0);
+            assert_eq!(Scan::from_layout(scan.layout() | !LAYOUT_SCAN), scan);
+        }
+        assert_eq!(Scan::parse("spiral"), None);
+    }
+}
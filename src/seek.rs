@@ -0,0 +1,226 @@
+// Seekable pixels data (layout flag LAYOUT_SEEK): the pixels data starts with the little endian
+// u64 offsets of the image's rows from its start, as tiled pixels data does with its tiles, and
+// every row is filtered (from zeros above it) and run-length encoded on its own, so a region of
+// the image is decoded from its rows alone
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::bare::Error;
+use crate::format::{LAYOUT_FILTERED, LAYOUT_RLE, index_offset as raster_offset, row_len};
+use crate::{filter, rle, tiles};
+
+pub const OFFSET_LEN: usize = tiles::OFFSET_LEN;
+
+// Bytes of the row offsets of an image of height rows
+pub fn table_len(height: u32) -> usize {
+    (height as usize).saturating_mul(OFFSET_LEN)
+}
+
+// Offset of a pixel's index from the start of the pixels data, of its filtered byte when
+// filtered and of its run-length decoded byte when run-length encoded
+pub fn index_offset(pixel: usize, width: u32, height: u32, index_bits: u32, layout: u8) -> usize {
+    table_len(height) + raster_offset(pixel, width, index_bits, layout)
+}
+
+// Pixels data of packed whole rows of width pixels, the offset table first
+pub fn write(packed: &[u8], width: u32, index_bits: u32, filter: bool, rle: bool) -> Vec<u8> {
+    let bpp = filter::bpp(index_bits);
+    let row_len = row_len(width, index_bits);
+    let zeros = alloc::vec![0u8; row_len];
+    let rows = packed
+        .chunks_exact(row_len)
+        .map(|row| {
+            let mut stored = Vec::with_capacity(row_len + filter as usize);
+            if filter {
+                filter::filter_row(row, &zeros, bpp, &mut stored);
+            } else {
+                stored.extend_from_slice(row);
+            }
+            if rle {
+                rle::encode(&stored, bpp)
+            } else {
+                stored
+            }
+        })
+        .collect::<Vec<_>>();
+    tiles::join(&rows)
+}
+
+// Ranges of rows in the pixels data of an image of height rows, from the bytes of their offsets
+// and the one after the last, see tiles::ranges
+pub fn ranges(
+    offsets: &[u8],
+    rows: Range<usize>,
+    height: u32,
+    data_len: usize,
+) -> Result<Vec<Range<usize>>, Error> {
+    tiles::ranges(offsets, rows, height as usize, data_len).map_err(Error::BadRow)
+}
+
+// Packed indices of the rows first.. at ranges, of which data holds the bytes from start on
+pub fn rows(
+    data: &[u8],
+    start: usize,
+    ranges: &[Range<usize>],
+    first: usize,
+    width: u32,
+    index_bits: u32,
+    layout: u8,
+) -> Result<Vec<u8>, Error> {
+    let bpp = filter::bpp(index_bits);
+    let row_len = row_len(width, index_bits);
+    let filtered = layout & LAYOUT_FILTERED != 0;
+    let len = row_len + filtered as usize;
+    // Rows are checked one by one below, a crafted width can't reserve more than they hold
+    let stored = ranges.iter().map(|range| range.len()).sum::<usize>();
+    let most = if layout & LAYOUT_RLE != 0 {
+        rle::max_decoded_len(stored, bpp)
+    } else {
+        stored
+    };
+    let mut out = Vec::with_capacity(ranges.len().saturating_mul(row_len).min(most));
+    for (i, range) in ranges.iter().enumerate() {
+        let stored = data
+            .get(range.start - start..range.end - start)
+            .ok_or(Error::NotEnoughData)?;
+        let row = if layout & LAYOUT_RLE != 0 {
+            rle::decode(stored, len, bpp).map_err(|_| Error::BadRow(first + i))?
+        } else if stored.len() == len {
+            stored.to_vec()
+        } else {
+            return Err(Error::BadRow(first + i));
+        };
+        if filtered {
+            let mut zeros = alloc::vec![0u8; row_len];
+            out.extend(filter::unfilter(&row, row_len, bpp, &mut zeros)?);
+        } else {
+            out.extend(row);
+        }
+    }
+    Ok(out)
+}
+
+// Packed indices of the whole stored pixels data
+pub fn packed_data(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    index_bits: u32,
+    layout: u8,
+) -> Result<Vec<u8>, Error> {
+    let table = data.get(..table_len(height)).ok_or(Error::NotEnoughData)?;
+    let ranges = ranges(table, 0..height as usize, height, data.len())?;
+    rows(data, 0, &ranges, 0, width, index_bits, layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::pack_indices;
+
+    const LAYOUTS: [u8; 4] = [0, LAYOUT_FILTERED, LAYOUT_RLE, LAYOUT_FILTERED | LAYOUT_RLE];
+
+    fn packed(width: u32, height: u32, index_bits: u32) -> Vec<u8> {
+        let max = (1u32 << index_bits) - 1;
+        let indices: Vec<u16> = (0..width * height)
+            .map(|i| ((i / 3).wrapping_mul(2_654_435_761) % (max + 1)) as u16)
+            .collect();
+        pack_indices(&indices, width, index_bits)
+    }
+
+    fn seekable(packed: &[u8], width: u32, index_bits: u32, layout: u8) -> Vec<u8> {
+        let (filter, rle) = (layout & LAYOUT_FILTERED != 0, layout & LAYOUT_RLE != 0);
+        write(packed, width, index_bits, filter, rle)
+    }
+
+    #[test]
+    fn round_trip() {
+        for layout in LAYOUTS {
+            for index_bits in [1, 4, 8, 16] {
+                for (width, height) in [(1, 1), (7, 3), (40, 5)] {
+                    let packed = packed(width, height, index_bits);
+                    let data = seekable(&packed, width, index_bits, layout);
+                    assert_eq!(
+                        packed_data(&data, width, height, index_bits, layout).unwrap(),
+                        packed,
+                        "layout {:#x}, {} bits, {}x{}",
+                        layout,
+                        index_bits,
+                        width,
+                        height
+                    );
+                }
+            }
+        }
+    }
+
+    // Rows of a region come from their own bytes, the data may start where the first one does;
+    // the last row ends with the data
+    #[test]
+    fn region_rows() {
+        let (width, height, index_bits) = (40, 6, 8);
+        let packed = packed(width, height, index_bits);
+        for layout in LAYOUTS {
+            let data = seekable(&packed, width, index_bits, layout);
+            for region in [2..4, 4..6] {
+                let offsets = &data[region.start * OFFSET_LEN..table_len(height)];
+                let ranges = ranges(offsets, region.clone(), height, data.len()).unwrap();
+                let start = ranges[0].start;
+                let rows = rows(
+                    &data[start..],
+                    start,
+                    &ranges,
+                    region.start,
+                    width,
+                    index_bits,
+                    layout,
+                );
+                assert_eq!(rows.unwrap(), packed[region.start * 40..region.end * 40]);
+            }
+        }
+    }
+
+    #[test]
+    fn unfiltered_offsets() {
+        let (width, height) = (5, 3);
+        let packed = packed(width, height, 8);
+        let data = seekable(&packed, width, 8, 0);
+        for (pixel, &index) in packed.iter().enumerate() {
+            assert_eq!(data[index_offset(pixel, width, height, 8, 0)], index);
+        }
+    }
+
+    #[test]
+    fn truncated_table() {
+        let data = seekable(&packed(4, 3, 8), 4, 8, 0);
+        assert!(matches!(
+            packed_data(&data[..table_len(3) - 1], 4, 3, 8, 0),
+            Err(Error::NotEnoughData)
+        ));
+    }
+
+    // A row whose bytes don't decode to a whole row is reported by its number
+    #[test]
+    fn bad_rows() {
+        let (width, height) = (4, 3);
+        let mut data = seekable(&packed(width, height, 8), width, 8, 0);
+        data.pop();
+        assert!(matches!(
+            packed_data(&data, width, height, 8, 0),
+            Err(Error::BadRow(2))
+        ));
+        let mut data = seekable(&packed(width, height, 8), width, 8, 0);
+        data[OFFSET_LEN..2 * OFFSET_LEN].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            packed_data(&data, width, height, 8, 0),
+            Err(Error::BadRow(0))
+        ));
+        let mut data = seekable(&packed(width, height, 8), width, 8, LAYOUT_RLE);
+        let last = data.len() - 2;
+        data[last] = 0x7f;
+        assert!(matches!(
+            packed_data(&data, width, height, 8, LAYOUT_RLE),
+            Err(Error::BadRow(2))
+        ));
+    }
+}
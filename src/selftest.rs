@@ -0,0 +1,222 @@
+use image::{ImageBuffer, Rgb};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::panic;
+
+use crate::{
+    Decoder, Encoder, RowEncoder, bare, error::CodecError, stream::read_pnm_header, utils::*,
+};
+
+type Image = ImageBuffer<Rgb<u8>, Vec<u8>>;
+
+// Way the encoded bytes go through the codec
+#[derive(Clone, Copy, PartialEq)]
+enum Path {
+    Whole,
+    Stream,
+    // Decoded into a larger caller buffer
+    Into,
+    // Encoded row by row with RowEncoder
+    Rows,
+    // Decoded lazily through Decoder::pixels
+    Pixels,
+    // no_std decoder core, plain uncompressed files only
+    Bare,
+}
+
+fn synthetic_images() -> Vec<(&'static str, Image)> {
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+    vec![
+        (
+            "gradient",
+            ImageBuffer::from_fn(64, 48, |x, y| {
+                Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+            }),
+        ),
+        (
+            "checkerboard",
+            ImageBuffer::from_fn(17, 9, |x, y| {
+                if (x + y) % 2 == 0 {
+                    Rgb([0, 0, 0])
+                } else {
+                    Rgb([255, 255, 255])
+                }
+            }),
+        ),
+        (
+            "noise",
+            ImageBuffer::from_fn(33, 33, |_, _| Rgb(rng.random())),
+        ),
+        (
+            "tiny",
+            ImageBuffer::from_fn(2, 2, |x, y| Rgb([x as u8 * 200, y as u8 * 200, 90])),
+        ),
+    ]
+}
+
+fn round_trip(
+    img: &Image,
+    palette_size: usize,
+    key: Option<String>,
+    compress: bool,
+    path: Path,
+) -> Result<Image, CodecError> {
+    let mut encoder = Encoder::new()
+        .palette_size(palette_size)
+        .compression(compress);
+    let mut decoder = Decoder::new().compression(compress).strict(true);
+    if let Some(key) = key.as_deref().and_then(base64url_to_bytes) {
+        encoder = encoder.key(&key);
+        decoder = decoder.key(&key);
+    }
+    match path {
+        Path::Whole => return decoder.decode(&encoder.encode(img)?),
+        Path::Bare => {
+            let (rgb, width, height) = bare::decode(&encoder.encode(img)?)?;
+            return ImageBuffer::from_raw(width, height, rgb).ok_or(CodecError::BadData(
+                "bare decode is shorter than its dimensions".to_string(),
+            ));
+        }
+        Path::Into => {
+            let mut out = vec![0u8; img.as_raw().len() + 7];
+            let (width, height) = decoder.decode_into(&encoder.encode(img)?, &mut out)?;
+            out.truncate(width as usize * height as usize * 3);
+            return ImageBuffer::from_raw(width, height, out).ok_or(CodecError::BadData(
+                "decode_into returned wrong dimensions".to_string(),
+            ));
+        }
+        Path::Rows => {
+            let mut rows = RowEncoder::new(encoder, img.width());
+            for row in img.rows() {
+                rows.push_row(&row.copied().collect::<Vec<_>>())?;
+            }
+            return decoder.decode(&rows.finish()?);
+        }
+        Path::Pixels => {
+            let pixels = decoder.pixels(&encoder.encode(img)?)?;
+            let rgb = pixels.flat_map(|pixel| pixel.0).collect();
+            return ImageBuffer::from_raw(img.width(), img.height(), rgb).ok_or(
+                CodecError::BadData("pixels iterator is shorter than the image".to_string()),
+            );
+        }
+        Path::Stream => {}
+    }
+    // Encrypted files are decoded streamed only, their tag comes before the pixels data
+    let mut encoded = Vec::new();
+    if key.is_some() {
+        encoded = encoder.encode(img)?;
+    } else {
+        encoder.encode_stream(
+            img.as_raw().as_slice(),
+            &mut encoded,
+            img.width(),
+            img.height(),
+        )?;
+    }
+    let mut ppm = Vec::new();
+    let (width, height) = decoder.decode_stream(encoded.as_slice(), &mut ppm)?;
+    let mut pixels = ppm.as_slice();
+    read_pnm_header(&mut pixels)?;
+    ImageBuffer::from_raw(width, height, pixels.to_vec()).ok_or(CodecError::BadData(
+        "decoded PPM is shorter than its header says".to_string(),
+    ))
+}
+
+// Worst-case palette can't be farther than this from any source pixel on average
+const MAX_MEAN_ERROR: f64 = 128.0;
+
+fn mean_error(a: &Image, b: &Image) -> f64 {
+    let total: u64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&x, &y)| x.abs_diff(y) as u64)
+        .sum();
+    total as f64 / a.as_raw().len() as f64
+}
+
+// Outcome of one options combination
+pub struct Case {
+    pub name: String,
+    // Why the case failed, None when it passed
+    pub error: Option<String>,
+}
+
+// Encodes and decodes synthetic images with every options combination, the plain round trip
+// of each image and palette size is the reference for the compressed and encrypted ones
+pub fn run() -> Vec<Case> {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let keys = [None, Some(gen_key(16)), Some(gen_key(32))];
+    let mut cases = Vec::new();
+    for (name, img) in synthetic_images() {
+        for palette_size in [2, 16, 257] {
+            let reference =
+                panic::catch_unwind(|| round_trip(&img, palette_size, None, false, Path::Whole));
+            for (compress, path) in [
+                (false, Path::Whole),
+                (true, Path::Whole),
+                (false, Path::Stream),
+                (true, Path::Stream),
+                (true, Path::Into),
+                (false, Path::Rows),
+                (false, Path::Pixels),
+                (false, Path::Bare),
+            ] {
+                for key in &keys {
+                    // Bare decodes plain files only; builds without a feature skip its cases
+                    if (path == Path::Bare || !cfg!(feature = "crypto")) && key.is_some()
+                        || compress && !cfg!(feature = "zstd")
+                    {
+                        continue;
+                    }
+                    let case = format!(
+                        "{} {}x{} palette={}{}{}{}",
+                        name,
+                        img.width(),
+                        img.height(),
+                        palette_size,
+                        match path {
+                            Path::Whole => "",
+                            Path::Stream => " stream",
+                            Path::Into => " into",
+                            Path::Rows => " rows",
+                            Path::Pixels => " pixels",
+                            Path::Bare => " bare",
+                        },
+                        if compress { " compress" } else { "" },
+                        match key {
+                            Some(key) => format!(
+                                " encrypt(AES-{})",
+                                base64url_to_bytes(key).unwrap().len() * 8
+                            ),
+                            None => String::new(),
+                        }
+                    );
+                    let result = panic::catch_unwind(|| {
+                        round_trip(&img, palette_size, key.clone(), compress, path)
+                    });
+                    let error = match (&reference, result) {
+                        (Ok(Ok(reference)), Ok(Ok(decoded))) => {
+                            if decoded.dimensions() != img.dimensions() {
+                                Some("dimensions mismatch".to_string())
+                            } else if decoded != *reference {
+                                Some("differs from plain round trip".to_string())
+                            } else if mean_error(&img, &decoded) > MAX_MEAN_ERROR {
+                                Some("quantization error is too high".to_string())
+                            } else {
+                                None
+                            }
+                        }
+                        (Ok(Err(err)), _) => Some(format!("plain round trip failed: {}", err)),
+                        (Err(_), _) => Some("plain round trip panicked".to_string()),
+                        (_, Ok(Err(err))) => Some(err.to_string()),
+                        (_, Err(_)) => Some("panicked".to_string()),
+                    };
+                    cases.push(Case { name: case, error });
+                }
+            }
+        }
+    }
+    panic::set_hook(default_hook);
+    cases
+}
@@ -0,0 +1,161 @@
+use hmac::{Hmac, Mac};
+use rand::{Rng, rng};
+use sha2::Sha256;
+
+use crate::{
+    error::CodecError,
+    utils::{base64url_to_bytes, bytes_to_base64url},
+};
+
+// Shares are x, the threshold, then the y bytes of the secret followed by CHECK_LEN bytes of an
+// HMAC keyed with it. The check is split with the secret, so fewer shares than the threshold tell
+// nothing of either, and only the combined secret verifies it: a short or mixed set is told
+// apart from the right one
+const CHECK_LEN: usize = 4;
+
+fn check(secret: &[u8]) -> [u8; CHECK_LEN] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(b"ric-share-check");
+    mac.finalize().into_bytes()[..CHECK_LEN].try_into().unwrap()
+}
+
+// GF(256) with the AES reduction polynomial
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80;
+        a <<= 1;
+        if carry != 0 {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+fn gf_inv(a: u8) -> u8 {
+    // a^254 == a^-1
+    let mut result = 1;
+    let mut base = a;
+    let mut exp = 254u8;
+    while exp != 0 {
+        if exp & 1 != 0 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+// Parses "k/n" threshold notation
+pub fn parse_split(value: &str) -> Option<(u8, u8)> {
+    let (threshold, shares) = value.split_once('/')?;
+    let threshold = threshold.parse::<u8>().ok()?;
+    let shares = shares.parse::<u8>().ok()?;
+    if threshold < 2 || threshold > shares {
+        return None;
+    }
+    Some((threshold, shares))
+}
+
+// Each share is base64url of the layout above with one y byte per secret and check byte
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Vec<String> {
+    let mut rng = rng();
+    let coefficients = secret
+        .iter()
+        .chain(&check(secret))
+        .map(|&byte| {
+            let mut poly = vec![byte];
+            poly.extend((1..threshold).map(|_| rng.random::<u8>()));
+            poly
+        })
+        .collect::<Vec<_>>();
+    (1..=shares)
+        .map(|x| {
+            let mut share = vec![x, threshold];
+            for poly in &coefficients {
+                // Horner's scheme
+                share.push(poly.iter().rev().fold(0, |acc, &c| gf_mul(acc, x) ^ c));
+            }
+            bytes_to_base64url(&share)
+        })
+        .collect()
+}
+
+// Lagrange interpolation at x = 0 of shares of x followed by the y bytes
+fn interpolate(shares: &[&[u8]]) -> Vec<u8> {
+    let xs = shares.iter().map(|share| share[0]).collect::<Vec<_>>();
+    let weights = xs
+        .iter()
+        .enumerate()
+        .map(|(j, &xj)| {
+            xs.iter()
+                .enumerate()
+                .filter(|&(m, _)| m != j)
+                .fold(1, |acc, (_, &xm)| gf_mul(acc, gf_mul(xm, gf_inv(xm ^ xj))))
+        })
+        .collect::<Vec<_>>();
+    (1..shares[0].len())
+        .map(|i| {
+            shares
+                .iter()
+                .zip(&weights)
+                .fold(0, |acc, (share, &weight)| acc ^ gf_mul(share[i], weight))
+        })
+        .collect()
+}
+
+// Fewer shares than the threshold, or shares of different splits, are rejected rather than
+// giving a wrong key
+pub fn combine(shares: &[String]) -> Result<Vec<u8>, CodecError> {
+    let invalid = || CodecError::BadKey("invalid or duplicated key shares".to_string());
+    let different = || CodecError::BadKey("the key shares are of different keys".to_string());
+    let shares = shares
+        .iter()
+        .map(|code| base64url_to_bytes(code))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(invalid)?;
+    let first = shares.first().ok_or_else(invalid)?;
+    let len = first.len();
+    if len < 3 + CHECK_LEN
+        || shares
+            .iter()
+            .any(|share| share.len() != len || share[0] == 0)
+    {
+        return Err(invalid());
+    }
+    let xs = shares.iter().map(|share| share[0]).collect::<Vec<_>>();
+    if (1..xs.len()).any(|i| xs[..i].contains(&xs[i])) {
+        return Err(invalid());
+    }
+    if shares.iter().any(|share| share[1] != first[1]) {
+        return Err(different());
+    }
+    let threshold = first[1] as usize;
+    if shares.len() < threshold {
+        return Err(CodecError::BadKey(format!(
+            "{} key shares given, the key takes {}",
+            shares.len(),
+            threshold
+        )));
+    }
+    let points = shares
+        .iter()
+        .map(|share| {
+            let mut point = vec![share[0]];
+            point.extend(&share[2..]);
+            point
+        })
+        .collect::<Vec<_>>();
+    let points = points.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    let mut secret = interpolate(&points);
+    let stored = secret.split_off(secret.len() - CHECK_LEN);
+    if check(&secret)[..] != stored {
+        return Err(different());
+    }
+    Ok(secret)
+}
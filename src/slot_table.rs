@@ -0,0 +1,90 @@
+use aes::{
+    Aes128,
+    cipher::{BlockDecrypt, BlockEncrypt, KeyInit, generic_array::GenericArray},
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::recipients::DATA_KEY_LEN;
+
+const ID_LEN: usize = 8;
+const SLOT_LEN: usize = ID_LEN + DATA_KEY_LEN;
+pub const MAX_SLOTS: usize = u8::MAX as usize;
+
+fn hkdf_expand(key: &[u8], info: &[u8], out: &mut [u8]) {
+    Hkdf::<Sha256>::new(None, key).expand(info, out).unwrap();
+}
+
+// Names the slot of a key without giving the key away, so the slot a key opens is found without
+// trying every one
+pub fn slot_id(key: &[u8]) -> [u8; ID_LEN] {
+    let mut id = [0u8; ID_LEN];
+    hkdf_expand(key, b"ric-key-slot-id", &mut id);
+    id
+}
+
+fn kek(key: &[u8]) -> Aes128 {
+    let mut kek = [0u8; DATA_KEY_LEN];
+    hkdf_expand(key, b"ric-key-slot-wrap", &mut kek);
+    Aes128::new(&kek.into())
+}
+
+fn slot(data_key: &[u8; DATA_KEY_LEN], key: &[u8]) -> Vec<u8> {
+    let mut wrapped = GenericArray::clone_from_slice(data_key);
+    kek(key).encrypt_block(&mut wrapped);
+    let mut slot = slot_id(key).to_vec();
+    slot.extend_from_slice(&wrapped);
+    slot
+}
+
+// Layout: per key, its slot id (8 bytes) + wrapped data key (16 bytes)
+pub fn wrap_key(data_key: &[u8; DATA_KEY_LEN], keys: &[Vec<u8>]) -> Vec<u8> {
+    keys.iter().flat_map(|key| slot(data_key, key)).collect()
+}
+
+// Slot ids in order, None for a table cut short
+pub fn slot_ids(table: &[u8]) -> Option<Vec<[u8; ID_LEN]>> {
+    if !table.len().is_multiple_of(SLOT_LEN) {
+        return None;
+    }
+    Some(
+        table
+            .chunks_exact(SLOT_LEN)
+            .map(|slot| slot[..ID_LEN].try_into().unwrap())
+            .collect(),
+    )
+}
+
+pub fn unwrap_key(table: &[u8], key: &[u8]) -> Option<[u8; DATA_KEY_LEN]> {
+    let id = slot_id(key);
+    let position = slot_ids(table)?.iter().position(|slot| *slot == id)?;
+    let start = position * SLOT_LEN + ID_LEN;
+    let mut data_key = GenericArray::clone_from_slice(&table[start..start + DATA_KEY_LEN]);
+    kek(key).decrypt_block(&mut data_key);
+    Some(data_key.into())
+}
+
+// The table with a slot of each key appended, None when a key has one already
+pub fn add(table: &[u8], data_key: &[u8; DATA_KEY_LEN], keys: &[Vec<u8>]) -> Option<Vec<u8>> {
+    let mut ids = slot_ids(table)?;
+    let mut table = table.to_vec();
+    for key in keys {
+        let id = slot_id(key);
+        if ids.contains(&id) {
+            return None;
+        }
+        ids.push(id);
+        table.extend_from_slice(&slot(data_key, key));
+    }
+    Some(table)
+}
+
+// The table without the slots at the positions given
+pub fn remove(table: &[u8], positions: &[usize]) -> Vec<u8> {
+    table
+        .chunks_exact(SLOT_LEN)
+        .enumerate()
+        .filter(|(position, _)| !positions.contains(position))
+        .flat_map(|(_, slot)| slot.to_vec())
+        .collect()
+}
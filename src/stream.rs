@@ -0,0 +1,551 @@
+use hmac::Mac;
+use image::{ImageBuffer, Pixel, Rgb, imageops::ColorMap};
+use std::io::{self, BufRead, Cursor, ErrorKind, Read, Write};
+
+use crate::{
+    ZSTD_MAGIC, bare,
+    codec::{
+        Decoder, Encoder, SEGMENT_LEN, auth_mac, color, index_location, read_header, segment_key,
+        segments,
+    },
+    detect,
+    error::CodecError,
+    fec, filter, format,
+    progress::Tracker,
+    rle,
+    scan::Scan,
+    utils::{Palette, gen_palette, order_by_frequency, sort_by_luma},
+};
+
+// Rows are dithered in bands, so error diffusion restarts every band; a multiple of the
+// Bayer matrix size keeps ordered dithering seamless
+const BAND_ROWS: usize = 64;
+// Leading pixels the palette of a streamed image is built from
+pub(crate) const SAMPLE_PIXELS: usize = 1 << 20;
+// Bytes read ahead of the pixels data, enough for the palette and any key block
+pub const HEAD_LEN: u64 = 1 << 18;
+
+pub(crate) fn input_error(source: io::Error) -> CodecError {
+    CodecError::Io {
+        path: "input".to_string(),
+        source,
+    }
+}
+
+// Reads until buf is full or the input ends, returns the bytes read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(len) => read += len,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+fn output_error(source: io::Error) -> CodecError {
+    CodecError::Io {
+        path: "output".to_string(),
+        source,
+    }
+}
+
+// Reads the header of a binary PPM (P6) with 8-bit channels, returns the dimensions
+pub fn read_pnm_header(reader: &mut impl BufRead) -> Result<(u32, u32), CodecError> {
+    let mut fields = Vec::with_capacity(4);
+    while fields.len() < 4 {
+        let mut token = Vec::new();
+        loop {
+            let mut byte = [0u8];
+            reader.read_exact(&mut byte).map_err(input_error)?;
+            match byte[0] {
+                b'#' if token.is_empty() => {
+                    reader
+                        .read_until(b'\n', &mut Vec::new())
+                        .map_err(input_error)?;
+                }
+                byte if byte.is_ascii_whitespace() => {
+                    if !token.is_empty() {
+                        break;
+                    }
+                }
+                byte => token.push(byte),
+            }
+        }
+        fields.push(String::from_utf8_lossy(&token).into_owned());
+    }
+    let invalid = || {
+        CodecError::InvalidInput("input is not a binary PPM (P6) with 8-bit channels".to_string())
+    };
+    if fields[0] != "P6" || fields[3] != "255" {
+        return Err(invalid());
+    }
+    let width = fields[1].parse().map_err(|_| invalid())?;
+    let height = fields[2].parse().map_err(|_| invalid())?;
+    Ok((width, height))
+}
+
+impl Encoder {
+    // Encodes headerless RGB rows as they're read, the palette is built from the leading rows;
+    // compressed output is kept even when it's larger
+    pub fn encode_stream(
+        &self,
+        mut reader: impl Read,
+        writer: impl Write,
+        width: u32,
+        height: u32,
+    ) -> Result<(), CodecError> {
+        self.check(width, height)?;
+        if self.error_map.is_some() {
+            return Err(CodecError::InvalidInput(
+                "the error map needs the whole image, it can't be streamed".to_string(),
+            ));
+        }
+        if self.transparent.is_some() {
+            return Err(CodecError::InvalidInput(
+                "a transparent color can't be streamed, the palette is built before it's seen"
+                    .to_string(),
+            ));
+        }
+        if self.rle {
+            return Err(CodecError::InvalidInput(
+                "run-length encoding can't be streamed, the header holds the length it ends up with"
+                    .to_string(),
+            ));
+        }
+        if self.tile_size.is_some() {
+            return Err(CodecError::InvalidInput(
+                "tiles can't be streamed, the header holds the length they end up with".to_string(),
+            ));
+        }
+        if self.scan != Scan::Raster {
+            return Err(CodecError::InvalidInput(format!(
+                "the {} scan can't be streamed, it visits rows out of order",
+                self.scan.name()
+            )));
+        }
+        if self.interlace {
+            return Err(CodecError::InvalidInput(
+                "interlacing can't be streamed, every pass spans the whole image".to_string(),
+            ));
+        }
+        if self.seek {
+            return Err(CodecError::InvalidInput(
+                "a seek table can't be streamed, the row offsets come before the rows".to_string(),
+            ));
+        }
+        if self.fec.is_some() {
+            return Err(CodecError::InvalidInput(
+                "forward error correction can't be streamed, its parity comes from the whole file"
+                    .to_string(),
+            ));
+        }
+        if self.chunk_len.is_some() {
+            return Err(CodecError::InvalidInput(
+                "chunk checksums can't be streamed, they come before the pixels data".to_string(),
+            ));
+        }
+        if self.signing_key.is_some() {
+            return Err(CodecError::InvalidInput(
+                "a signature can't be streamed, it comes before the pixels data".to_string(),
+            ));
+        }
+        if self.key.is_some() {
+            return Err(CodecError::InvalidInput(
+                "encryption can't be streamed, the authentication tag comes before the pixels data"
+                    .to_string(),
+            ));
+        }
+        let row_len = width as usize * 3;
+        let sample_rows = (SAMPLE_PIXELS / width as usize).clamp(1, height as usize);
+        let mut sample = vec![0u8; sample_rows * row_len];
+        reader.read_exact(&mut sample).map_err(input_error)?;
+        let pixels = sample
+            .chunks_exact(3)
+            .map(|rgb| Rgb([rgb[0], rgb[1], rgb[2]]))
+            .collect::<Vec<_>>();
+        let mut colors = gen_palette(&pixels, self.palette_size, self.threads);
+        // The order the whole image would get, as far as the leading rows tell
+        if self.filter {
+            sort_by_luma(&mut colors);
+        } else {
+            let palette = Palette::new(colors.clone());
+            let mut indices = pixels
+                .iter()
+                .map(|pixel| palette.index_of(pixel) as u16)
+                .collect::<Vec<_>>();
+            order_by_frequency(&mut colors, &mut indices, 0);
+        }
+        drop(pixels);
+        let palette = Palette::new(colors);
+        let reader = Cursor::new(sample).chain(reader);
+        if self.compress {
+            #[cfg(feature = "zstd")]
+            {
+                let writer = zstd::stream::Encoder::new(writer, self.compression_level)
+                    .map_err(CodecError::Compression)?;
+                self.write_stream(reader, writer, width, height, &palette)?
+                    .finish()
+                    .map_err(CodecError::Compression)?;
+            }
+        } else {
+            self.write_stream(reader, writer, width, height, &palette)?
+                .flush()
+                .map_err(output_error)?;
+        }
+        Ok(())
+    }
+
+    fn write_stream<W: Write>(
+        &self,
+        mut reader: impl Read,
+        mut writer: W,
+        width: u32,
+        height: u32,
+        palette: &Palette,
+    ) -> Result<W, CodecError> {
+        let index_bits = self.index_bits(width, height, palette.colors.len());
+        let data_len = format::data_len(width, height, index_bits, self.layout_flags());
+        let header = self.header(width, height, &palette.colors, None, data_len, None)?;
+        let mut checksum = crc32fast::Hasher::new();
+        checksum.update(&header);
+        writer.write_all(&header).map_err(output_error)?;
+        let row_len = width as usize * 3;
+        let tracker = Tracker::new(&self.progress, height as usize);
+        let mut band = vec![0u8; BAND_ROWS * row_len];
+        // Last packed row of the previous band, which the first row of a band is filtered against
+        let mut prev = vec![0u8; format::row_len(width, index_bits)];
+        // Pixels data of the rows which don't fill the next segment yet
+        let mut data = Vec::with_capacity(SEGMENT_LEN * 2);
+        let mut segments = segments(data_len).peekable();
+        let mut rows_left = height as usize;
+        while rows_left > 0 {
+            let rows = rows_left.min(BAND_ROWS);
+            let bytes = &mut band[..rows * row_len];
+            reader.read_exact(bytes).map_err(input_error)?;
+            let mut img: ImageBuffer<Rgb<u8>, Vec<u8>> =
+                ImageBuffer::from_raw(width, rows as u32, bytes.to_vec()).unwrap();
+            self.dither.apply(&mut img, palette);
+            let indices = img
+                .pixels()
+                .map(|pixel| palette.index_of(pixel) as u16)
+                .collect::<Vec<_>>();
+            let packed = format::pack_indices(&indices, width, index_bits);
+            if self.filter {
+                let bpp = filter::bpp(index_bits);
+                data.extend(filter::filter(&packed, prev.len(), bpp, &mut prev));
+            } else {
+                data.extend(packed);
+            }
+            while let Some(segment) = segments.next_if(|segment| segment.len() <= data.len()) {
+                let len = segment.len();
+                write_segment(&mut writer, &mut checksum, &data[..len])?;
+                data.drain(..len);
+            }
+            rows_left -= rows;
+            for _ in 0..rows {
+                tracker.step();
+            }
+        }
+        writer
+            .write_all(&checksum.finalize().to_le_bytes())
+            .map_err(output_error)?;
+        Ok(writer)
+    }
+}
+
+// Segments are numbered from the start of the pixels data, as in whole-image encoding
+fn write_segment(
+    writer: &mut impl Write,
+    checksum: &mut crc32fast::Hasher,
+    segment: &[u8],
+) -> Result<(), CodecError> {
+    checksum.update(segment);
+    writer.write_all(segment).map_err(output_error)?;
+    Ok(())
+}
+
+impl Decoder {
+    // Writes a binary PPM (P6) segment by segment as pixels data is read, returns the dimensions
+    pub fn decode_stream(
+        &self,
+        mut reader: impl Read,
+        writer: impl Write,
+    ) -> Result<(u32, u32), CodecError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(input_error)?;
+        // A file protected with --fec is read as stored, without repairing it, so a damaged one
+        // fails its checksum
+        let mut len = u64::MAX;
+        if magic == fec::MAGIC {
+            let mut head = magic.to_vec();
+            (&mut reader)
+                .take((fec::PARAMS_END - magic.len()) as u64)
+                .read_to_end(&mut head)
+                .map_err(input_error)?;
+            let range = fec::file_range(&head)?;
+            io::copy(
+                &mut (&mut reader).take((range.start - head.len()) as u64),
+                &mut io::sink(),
+            )
+            .map_err(input_error)?;
+            reader.read_exact(&mut magic).map_err(input_error)?;
+            len = range.len().saturating_sub(magic.len()) as u64;
+        }
+        let reader = reader.take(len);
+        let compressed = magic == ZSTD_MAGIC;
+        if compressed && !self.compress.unwrap_or(compressed) {
+            let keyed = self.key.is_some() || self.key_block.is_some();
+            return Err(CodecError::WrongOptions {
+                looks: "compressed".to_string(),
+                hint: detect::hint(keyed, true),
+            });
+        }
+        let reader = Cursor::new(magic).chain(reader);
+        if compressed {
+            #[cfg(not(feature = "zstd"))]
+            return Err(CodecError::FeatureDisabled("zstd"));
+            #[cfg(feature = "zstd")]
+            {
+                let reader =
+                    zstd::stream::Decoder::new(reader).map_err(CodecError::Decompression)?;
+                self.read_stream(reader, writer, compressed)
+            }
+        } else {
+            self.read_stream(reader, writer, compressed)
+        }
+    }
+
+    fn read_stream(
+        &self,
+        mut reader: impl Read,
+        mut writer: impl Write,
+        compressed: bool,
+    ) -> Result<(u32, u32), CodecError> {
+        let mut head = Vec::new();
+        (&mut reader)
+            .take(HEAD_LEN)
+            .read_to_end(&mut head)
+            .map_err(input_error)?;
+        format::checked_prefix_len(&head, self.legacy)?;
+        let keyed = self.key.is_some() || self.key_block.is_some();
+        if keyed && format::version(&head) == 1 {
+            return Err(CodecError::InvalidInput(
+                "encrypted format version 1 files may be of the first builds, whose blocks span the whole data; decode it without --stream"
+                    .to_string(),
+            ));
+        }
+        if let Some(flags) = format::flags(&head)
+            && (flags & format::FLAG_ENCRYPTED != 0) != keyed
+        {
+            return Err(CodecError::WrongOptions {
+                looks: if keyed { "plain" } else { "encrypted" }.to_string(),
+                hint: detect::hint(!keyed, compressed),
+            });
+        }
+        // Metadata may hold more than the bytes read ahead, e.g. a large color profile
+        if let Ok(metadata) = bare::metadata_range(&head)
+            && metadata.end > head.len()
+        {
+            (&mut reader)
+                .take((metadata.end - head.len()) as u64 + HEAD_LEN)
+                .read_to_end(&mut head)
+                .map_err(input_error)?;
+        }
+        if format::channels(&head) == 4 {
+            return Err(CodecError::InvalidInput(
+                "file has an alpha channel, which PPM can't hold; decode it without --stream"
+                    .to_string(),
+            ));
+        }
+        if format::tile_size(&head).is_some() {
+            return Err(CodecError::InvalidInput(
+                "file is tiled, its rows are spread over the tiles; decode it without --stream"
+                    .to_string(),
+            ));
+        }
+        let scan = Scan::from_layout(format::layout_flags(&head));
+        if scan != Scan::Raster {
+            return Err(CodecError::InvalidInput(format!(
+                "file is serialized in {} scan order, whose rows can't be written as they come; decode it without --stream",
+                scan.name()
+            )));
+        }
+        if format::layout_flags(&head) & format::LAYOUT_INTERLACED != 0 {
+            return Err(CodecError::InvalidInput(
+                "file is interlaced, its rows are spread over the passes; decode it without --stream"
+                    .to_string(),
+            ));
+        }
+        if format::layout_flags(&head) & format::LAYOUT_TRUECOLOR != 0 {
+            return Err(CodecError::InvalidInput(
+                "file is truecolor, --stream maps the palette indices of rows as they come; decode it without --stream"
+                    .to_string(),
+            ));
+        }
+        if format::layout_flags(&head) & format::LAYOUT_SEEK != 0 {
+            return Err(CodecError::InvalidInput(
+                "file has a seek table, its rows are stored one by one; decode it without --stream"
+                    .to_string(),
+            ));
+        }
+        let mut key_opt = self.key.clone();
+        let (palette, data_offset) = read_header(&head, &mut key_opt, self.key_block.clone())?;
+        let segment_key = key_opt
+            .as_deref()
+            .map(|key| segment_key(&head, key))
+            .transpose()?;
+        let (width, height, _) = bare::dimensions(&head)?;
+        let index_bits = format::index_bits(&head);
+        let layout = format::layout_flags(&head);
+        let unpacked_len = format::data_len(width, height, index_bits, layout);
+        let data_len = format::stored_len(&head).unwrap_or(unpacked_len);
+        let stride = format::stride(width, index_bits, layout);
+        let mut rle = (layout & format::LAYOUT_RLE != 0)
+            .then(|| rle::Decoder::new(unpacked_len, filter::bpp(index_bits)));
+        if format::stored_len(&head).is_some() {
+            bare::check_data_len(width, height, index_bits, layout, data_len)?;
+        }
+        // Last unfiltered row, of filtered files; sized with the first whole row, so a crafted
+        // width takes no more memory than the data holds
+        let mut prev = Vec::new();
+        // Ciphertext is hashed as read, the footer can only be checked once the output is written
+        let mut checksum = format::flags(&head)
+            .is_some_and(|flags| flags & format::FLAG_CHECKSUM != 0)
+            .then(crc32fast::Hasher::new);
+        if let Some(checksum) = &mut checksum {
+            checksum.update(&head[..data_offset]);
+        }
+        // So is the authentication tag of an encrypted file, see keys::authenticator
+        let mut auth = match &key_opt {
+            Some(key) => auth_mac(&head, key)?,
+            None => None,
+        };
+        if let Some((mac, _)) = &mut auth {
+            mac.update(&head[bare::metadata_range(&head)?.end..data_offset]);
+        }
+        let ahead = head.get(data_offset..).unwrap_or_default().to_vec();
+        let mut reader = Cursor::new(ahead).chain(reader);
+        writer
+            .write_all(format!("P6\n{} {}\n255\n", width, height).as_bytes())
+            .map_err(output_error)?;
+        let tracker = Tracker::new(&self.progress, segments(data_len).len());
+        let mut buffer = vec![
+            0u8;
+            segments(data_len)
+                .map(|range| range.len())
+                .max()
+                .unwrap_or(0)
+        ];
+        let mut rgb = Vec::with_capacity(SEGMENT_LEN * 3);
+        // Bytes of pixels data read so far and run-length decoded, and of the row a segment ended
+        // inside
+        let mut done = 0;
+        let mut decoded = 0;
+        let mut rows = Vec::with_capacity(SEGMENT_LEN + stride.min(SEGMENT_LEN));
+        for (segment_number, range) in segments(data_len).enumerate() {
+            let segment = &mut buffer[..range.len()];
+            let read = read_full(&mut reader, segment).map_err(input_error)?;
+            if read < segment.len() {
+                return Err(CodecError::BadData(format!(
+                    "truncated at byte {} of the {}file, {} bytes of pixels data are missing",
+                    data_offset + done + read,
+                    if compressed { "decompressed " } else { "" },
+                    data_len - done - read
+                )));
+            }
+            if let Some(checksum) = &mut checksum {
+                checksum.update(segment);
+            }
+            if let Some((mac, _)) = &mut auth {
+                mac.update(segment);
+            }
+            if let Some(key) = segment_key {
+                key.decrypt(segment, segment_number as u64)?;
+            }
+            // A row spanning two segments is unpacked with the second one
+            let first_pixel = (decoded - rows.len()) / stride * width as usize;
+            let before = rows.len();
+            match &mut rle {
+                Some(rle) => rle.feed(segment, &mut rows),
+                None => rows.extend_from_slice(segment),
+            }
+            decoded += rows.len() - before;
+            let whole = rows.len() / stride * stride;
+            let indices = if layout & format::LAYOUT_FILTERED != 0 {
+                let bpp = filter::bpp(index_bits);
+                if whole > 0 {
+                    prev.resize(format::row_len(width, index_bits), 0);
+                }
+                let packed = filter::unfilter(&rows[..whole], prev.len(), bpp, &mut prev)?;
+                format::unpack_indices(&packed, width, index_bits)
+            } else {
+                format::unpack_indices(&rows[..whole], width, index_bits)
+            };
+            rows.drain(..whole);
+            if self.strict
+                && let Some(pos) = indices
+                    .iter()
+                    .position(|&index| index as usize >= palette.len())
+            {
+                let pixel = first_pixel + pos;
+                return Err(CodecError::BadData(format!(
+                    "index {} of pixel {} (x {}, y {}) is beyond the palette of {} colors, at {}",
+                    indices[pos],
+                    pixel,
+                    pixel % width as usize,
+                    pixel / width as usize,
+                    palette.len(),
+                    index_location(
+                        data_offset,
+                        format::index_offset(pixel, width, index_bits, layout),
+                        layout,
+                        compressed
+                    )
+                )));
+            }
+            rgb.clear();
+            rgb.extend(
+                indices
+                    .iter()
+                    .flat_map(|&index| color(&palette, index).to_rgb().0),
+            );
+            writer.write_all(&rgb).map_err(output_error)?;
+            done += segment.len();
+            tracker.step();
+        }
+        if let Some(rle) = rle {
+            rle.finish()?;
+        }
+        writer.flush().map_err(output_error)?;
+        if let Some(checksum) = checksum {
+            let mut footer = [0u8; format::CHECKSUM_LEN];
+            if read_full(&mut reader, &mut footer).map_err(input_error)? < footer.len() {
+                return Err(CodecError::BadData(format!(
+                    "truncated at byte {} of the {}file, inside the checksum",
+                    data_offset + data_len,
+                    if compressed { "decompressed " } else { "" }
+                )));
+            }
+            let stored = u32::from_le_bytes(footer);
+            let actual = checksum.finalize();
+            if stored != actual {
+                return Err(bare::Error::ChecksumMismatch { stored, actual }.into());
+            }
+        }
+        if let Some((mac, tag)) = auth {
+            mac.verify_truncated_left(&tag)
+                .map_err(|_| CodecError::BadKey("wrong key or tampered data".to_string()))?;
+        }
+        if reader.read(&mut [0u8]).map_err(input_error)? > 0 {
+            return Err(CodecError::BadData(
+                "unexpected bytes after the pixels data, decode encrypted files with a key"
+                    .to_string(),
+            ));
+        }
+        Ok((width, height))
+    }
+}
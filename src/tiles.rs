@@ -0,0 +1,323 @@
+// Tiled pixels data (layout flag LAYOUT_TILED): the image is cut into square tiles of the
+// header's tile size, narrower or shorter at the right and bottom edges, and every tile has its
+// own palette, so a varied image keeps the colors each part needs. The pixels data starts with
+// the little endian u64 offsets of the tiles from its start, row of tiles by row, and then holds
+// each tile: its palette size minus 1 as a little endian u16, its palette entries and its
+// indices packed into the fewest bits its palette takes, in rows of the tile's width
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::bare::{Error, entry_rgba};
+use crate::format::{index_bits_for, pack_indices, row_len, unpack_indices};
+
+pub const OFFSET_LEN: usize = 8;
+pub const PALETTE_SIZE_LEN: usize = 2;
+// Smaller tiles spend more on their palettes than they save
+pub const MIN_SIZE: u32 = 8;
+pub const MAX_SIZE: u32 = u16::MAX as u32;
+
+// Tiles across and down an image
+pub fn grid(width: u32, height: u32, size: u32) -> (usize, usize) {
+    (
+        width.div_ceil(size) as usize,
+        height.div_ceil(size) as usize,
+    )
+}
+
+// x, y, width and height of tile i
+pub fn rect(i: usize, width: u32, height: u32, size: u32) -> (u32, u32, u32, u32) {
+    let across = width.div_ceil(size) as usize;
+    let (x, y) = ((i % across) as u32 * size, (i / across) as u32 * size);
+    (x, y, size.min(width - x), size.min(height - y))
+}
+
+// Bytes of a tile of palette_size entries (channels bytes each, one after another) and of the
+// indices of its rows of width pixels
+pub fn write(palette: &[u8], palette_size: usize, indices: &[u16], width: u32) -> Vec<u8> {
+    let mut tile = Vec::with_capacity(PALETTE_SIZE_LEN + palette.len() + indices.len());
+    tile.extend_from_slice(&((palette_size - 1) as u16).to_le_bytes());
+    tile.extend_from_slice(palette);
+    tile.extend(pack_indices(indices, width, index_bits_for(palette_size)));
+    tile
+}
+
+// Pixels data of the tiles in order, the offset table first
+pub fn join(tiles: &[Vec<u8>]) -> Vec<u8> {
+    let table_len = tiles.len() * OFFSET_LEN;
+    let mut data = Vec::with_capacity(table_len + tiles.iter().map(Vec::len).sum::<usize>());
+    let mut offset = table_len;
+    for tile in tiles {
+        data.extend_from_slice(&(offset as u64).to_le_bytes());
+        offset += tile.len();
+    }
+    for tile in tiles {
+        data.extend_from_slice(tile);
+    }
+    data
+}
+
+// Ranges in the pixels data of the entries of an offset table of count entries, from the bytes
+// of their offsets and the one after the last (none after the table's last entry, which ends at
+// data_len). Fails with the first entry which starts before the end of the table, after its end
+// or beyond data_len
+pub fn ranges(
+    offsets: &[u8],
+    entries: Range<usize>,
+    count: usize,
+    data_len: usize,
+) -> Result<Vec<Range<usize>>, usize> {
+    let first = entries.start;
+    let mut starts = offsets
+        .chunks_exact(OFFSET_LEN)
+        .map(|offset| u64::from_le_bytes(offset.try_into().unwrap()) as usize)
+        .take(entries.len() + 1)
+        .collect::<Vec<_>>();
+    if entries.end == count {
+        starts.push(data_len);
+    }
+    starts
+        .windows(2)
+        .enumerate()
+        .map(|(i, ends)| {
+            if ends[0] < count * OFFSET_LEN || ends[0] > ends[1] || ends[1] > data_len {
+                return Err(first + i);
+            }
+            Ok(ends[0]..ends[1])
+        })
+        .collect()
+}
+
+// Bytes of tile i of the pixels data of count tiles, which ends where the next one starts
+pub fn bytes(data: &[u8], i: usize, count: usize) -> Result<&[u8], Error> {
+    let offset = |i: usize| {
+        if i == count {
+            return Ok(data.len());
+        }
+        let offset = data
+            .get(i * OFFSET_LEN..(i + 1) * OFFSET_LEN)
+            .ok_or(Error::NotEnoughData)?;
+        Ok(u64::from_le_bytes(offset.try_into().unwrap()) as usize)
+    };
+    let (start, end) = (offset(i)?, offset(i + 1)?);
+    if start < count * OFFSET_LEN || start > end || end > data.len() {
+        return Err(Error::BadTile(i));
+    }
+    Ok(&data[start..end])
+}
+
+pub struct Tile {
+    // RGBA, as bare::Header's
+    pub palette: Vec<[u8; 4]>,
+    pub indices: Vec<u16>,
+}
+
+// Palette and indices of tile i, of width x height pixels and palette entries of channels bytes
+pub fn read(
+    tile: &[u8],
+    i: usize,
+    width: u32,
+    height: u32,
+    channels: usize,
+) -> Result<Tile, Error> {
+    let size = tile.get(..PALETTE_SIZE_LEN).ok_or(Error::BadTile(i))?;
+    let palette_size = u16::from_le_bytes([size[0], size[1]]) as usize + 1;
+    let palette_end = PALETTE_SIZE_LEN + palette_size * channels;
+    let palette = tile
+        .get(PALETTE_SIZE_LEN..palette_end)
+        .ok_or(Error::BadTile(i))?
+        .chunks_exact(channels)
+        .map(entry_rgba)
+        .collect();
+    let index_bits = index_bits_for(palette_size);
+    let data = &tile[palette_end..];
+    if data.len() != row_len(width, index_bits) * height as usize {
+        return Err(Error::BadTile(i));
+    }
+    Ok(Tile {
+        palette,
+        indices: unpack_indices(data, width, index_bits),
+    })
+}
+
+// Where each tile's palette is in the palettes of every tile one after another
+pub struct Palettes {
+    size: u32,
+    across: usize,
+    ranges: Vec<Range<usize>>,
+}
+
+impl Palettes {
+    // Range of the palette of the tile holding pixel (y * width + x)
+    pub fn range(&self, pixel: usize, width: u32) -> Range<usize> {
+        self.ranges[self.tile(pixel, width)].clone()
+    }
+
+    // Tile holding pixel
+    pub fn tile(&self, pixel: usize, width: u32) -> usize {
+        let (x, y) = (pixel % width as usize, pixel / width as usize);
+        y / self.size as usize * self.across + x / self.size as usize
+    }
+}
+
+// Decoded tiles of an image
+pub struct Tiled {
+    // Every tile's palette one after another
+    pub palette: Vec<[u8; 4]>,
+    pub palettes: Palettes,
+    // Indices of the image row by row, into the palette of their tile
+    pub indices: Vec<u16>,
+}
+
+// Tiled of the tiles of an image, in order
+pub fn assemble(tiles: Vec<Tile>, width: u32, height: u32, size: u32) -> Tiled {
+    let mut palette = Vec::new();
+    let mut ranges = Vec::with_capacity(tiles.len());
+    let mut indices = alloc::vec![0u16; width as usize * height as usize];
+    for (i, tile) in tiles.into_iter().enumerate() {
+        let (x, y, tile_width, _) = rect(i, width, height, size);
+        ranges.push(palette.len()..palette.len() + tile.palette.len());
+        palette.extend(tile.palette);
+        for (row, tile_row) in tile.indices.chunks_exact(tile_width as usize).enumerate() {
+            let start = (y as usize + row) * width as usize + x as usize;
+            indices[start..start + tile_width as usize].copy_from_slice(tile_row);
+        }
+    }
+    Tiled {
+        palette,
+        palettes: Palettes {
+            size,
+            across: grid(width, height, size).0,
+            ranges,
+        },
+        indices,
+    }
+}
+
+// Tiled of the pixels data, tiles read one by one
+pub fn decode(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    size: u32,
+    channels: usize,
+) -> Result<Tiled, Error> {
+    let (across, down) = grid(width, height, size);
+    let count = across * down;
+    let tiles = (0..count)
+        .map(|i| {
+            let (_, _, tile_width, tile_height) = rect(i, width, height, size);
+            read(bytes(data, i, count)?, i, tile_width, tile_height, channels)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(assemble(tiles, width, height, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // Pixels data of an image of tiles with 2, 3 and 20 colors in turn, RGB entries of the tile
+    // number, and the indices each tile holds
+    fn tiled(width: u32, height: u32, size: u32) -> (Vec<u8>, Vec<Vec<u16>>) {
+        let (across, down) = grid(width, height, size);
+        let (mut tiles, mut indices) = (Vec::new(), Vec::new());
+        for i in 0..across * down {
+            let (_, _, tile_width, tile_height) = rect(i, width, height, size);
+            let palette_size = [2, 3, 20][i % 3];
+            let palette: Vec<u8> = (0..palette_size * 3).map(|_| i as u8).collect();
+            let tile_indices: Vec<u16> = (0..tile_width * tile_height)
+                .map(|j| ((j as usize + i) % palette_size) as u16)
+                .collect();
+            tiles.push(write(&palette, palette_size, &tile_indices, tile_width));
+            indices.push(tile_indices);
+        }
+        (join(&tiles), indices)
+    }
+
+    // Tiles at the right and bottom edges are cut to the image
+    #[test]
+    fn edge_tiles() {
+        assert_eq!(grid(20, 10, 8), (3, 2));
+        assert_eq!(rect(0, 20, 10, 8), (0, 0, 8, 8));
+        assert_eq!(rect(2, 20, 10, 8), (16, 0, 4, 8));
+        assert_eq!(rect(5, 20, 10, 8), (16, 8, 4, 2));
+        assert_eq!(grid(1, 1, MAX_SIZE), (1, 1));
+        assert_eq!(rect(0, 1, 1, MAX_SIZE), (0, 0, 1, 1));
+    }
+
+    #[test]
+    fn round_trip() {
+        for (width, height, size) in [(1, 1, 8), (8, 8, 8), (20, 10, 8), (9, 17, 16)] {
+            let (data, indices) = tiled(width, height, size);
+            let decoded = decode(&data, width, height, size, 3).unwrap();
+            for (pixel, &index) in decoded.indices.iter().enumerate() {
+                let tile = decoded.palettes.tile(pixel, width);
+                let (x, y, tile_width, _) = rect(tile, width, height, size);
+                let (px, py) = (pixel as u32 % width, pixel as u32 / width);
+                let in_tile = ((py - y) * tile_width + px - x) as usize;
+                assert_eq!(index, indices[tile][in_tile]);
+                let range = decoded.palettes.range(pixel, width);
+                assert_eq!(range.len(), [2, 3, 20][tile % 3]);
+                assert_eq!(
+                    decoded.palette[range.start],
+                    [tile as u8, tile as u8, tile as u8, 255]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn offsets_out_of_the_data() {
+        let (data, _) = tiled(20, 10, 8);
+        let count = 6;
+        assert!(bytes(&data, 5, count).is_ok());
+        let mut before_table = data.clone();
+        before_table[OFFSET_LEN..2 * OFFSET_LEN].copy_from_slice(&8u64.to_le_bytes());
+        assert!(matches!(
+            bytes(&before_table, 1, count),
+            Err(Error::BadTile(1))
+        ));
+        let mut beyond = data.clone();
+        beyond[..OFFSET_LEN].copy_from_slice(&(data.len() as u64 + 1).to_le_bytes());
+        assert!(matches!(bytes(&beyond, 0, count), Err(Error::BadTile(0))));
+        assert!(matches!(
+            bytes(&data[..OFFSET_LEN], 0, count),
+            Err(Error::NotEnoughData)
+        ));
+        assert!(decode(&beyond, 20, 10, 8, 3).is_err());
+    }
+
+    #[test]
+    fn truncated_tile() {
+        let tile = write(&[1, 2, 3, 4, 5, 6], 2, &[0, 1, 1], 3);
+        assert!(read(&tile, 0, 3, 1, 3).is_ok());
+        assert!(matches!(
+            read(&tile[..tile.len() - 1], 0, 3, 1, 3),
+            Err(Error::BadTile(0))
+        ));
+        assert!(matches!(
+            read(&tile[..5], 0, 3, 1, 3),
+            Err(Error::BadTile(0))
+        ));
+        assert!(matches!(
+            read(&tile[..1], 0, 3, 1, 3),
+            Err(Error::BadTile(0))
+        ));
+        assert!(matches!(read(&tile, 0, 3, 2, 3), Err(Error::BadTile(0))));
+    }
+
+    #[test]
+    fn offset_ranges() {
+        let table: Vec<u8> = [16u64, 20].iter().flat_map(|o| o.to_le_bytes()).collect();
+        assert_eq!(ranges(&table, 0..2, 2, 30), Ok(vec![16..20, 20..30]));
+        assert_eq!(ranges(&table[..OFFSET_LEN], 0..1, 3, 30), Ok(vec![]));
+        let first = ranges(&table, 0..1, 2, 30).unwrap();
+        assert_eq!((first.len(), first[0].clone()), (1, 16..20));
+        assert_eq!(ranges(&table, 0..2, 3, 30), Err(0));
+        assert_eq!(ranges(&table, 0..2, 2, 18), Err(0));
+        let backwards: Vec<u8> = [20u64, 16].iter().flat_map(|o| o.to_le_bytes()).collect();
+        assert_eq!(ranges(&backwards, 0..2, 2, 30), Err(0));
+        assert_eq!(ranges(&backwards, 3..5, 5, 30), Err(3));
+    }
+}
@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+// std's Instant panics on wasm32, where the browser clock is read instead
+#[cfg(not(target_family = "wasm"))]
+pub use std::time::Instant;
+#[cfg(target_family = "wasm")]
+pub use web_time::Instant;
+
+use crate::{log, utils::json_escape};
+
+struct Span {
+    phase: &'static str,
+    // Worker thread name, None for the main thread
+    thread: Option<String>,
+    start: Instant,
+    time: Duration,
+}
+
+// Wall time per phase and busy time per worker thread
+pub struct Timings {
+    phases: Vec<Span>,
+    threads: Vec<Span>,
+    start: Instant,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Self {
+            phases: Vec::new(),
+            threads: Vec::new(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, phase: &'static str, since: Instant) {
+        let time = since.elapsed();
+        log::verbose(format_args!(
+            "{}: {:.2} ms",
+            phase,
+            time.as_secs_f64() * 1000.0
+        ));
+        self.phases.push(Span {
+            phase,
+            thread: None,
+            start: since,
+            time,
+        });
+    }
+
+    pub fn record_thread(
+        &mut self,
+        phase: &'static str,
+        name: String,
+        since: Instant,
+        busy: Duration,
+    ) {
+        self.threads.push(Span {
+            phase,
+            thread: Some(name),
+            start: since,
+            time: busy,
+        });
+    }
+
+    // Moves the spans of another run (e.g. a single job) into this one
+    pub fn append(&mut self, other: Timings) {
+        self.phases.extend(other.phases);
+        self.threads.extend(other.threads);
+    }
+
+    // Wall time per phase in first-recorded order, repeated phases summed
+    pub fn phase_totals(&self) -> Vec<(&'static str, Duration)> {
+        let mut totals: Vec<(&'static str, Duration)> = Vec::new();
+        for span in &self.phases {
+            match totals.iter_mut().find(|(phase, _)| *phase == span.phase) {
+                Some((_, time)) => *time += span.time,
+                None => totals.push((span.phase, span.time)),
+            }
+        }
+        totals
+    }
+
+    fn phase_time(&self, phase: &str) -> Duration {
+        self.phases
+            .iter()
+            .filter(|span| span.phase == phase)
+            .map(|span| span.time)
+            .sum()
+    }
+
+    pub fn report(&self) -> String {
+        let total = self.start.elapsed();
+        let mut lines = vec!["Timings:".to_string()];
+        for span in &self.phases {
+            lines.push(format!(
+                "  {:<14} {:>10.2} ms ({:>5.1}%)",
+                span.phase,
+                span.time.as_secs_f64() * 1000.0,
+                span.time.as_secs_f64() / total.as_secs_f64() * 100.0
+            ));
+        }
+        lines.push(format!(
+            "  {:<14} {:>10.2} ms",
+            "total",
+            total.as_secs_f64() * 1000.0
+        ));
+        if !self.threads.is_empty() {
+            lines.push("Threads utilization:".to_string());
+            for span in &self.threads {
+                let wall = self.phase_time(span.phase).as_secs_f64();
+                let utilization = if wall > 0.0 {
+                    span.time.as_secs_f64() / wall * 100.0
+                } else {
+                    0.0
+                };
+                lines.push(format!(
+                    "  {:<8} {:<20} {:>10.2} ms ({:>5.1}%)",
+                    span.phase,
+                    span.thread.as_deref().unwrap_or_default(),
+                    span.time.as_secs_f64() * 1000.0,
+                    utilization
+                ));
+            }
+        }
+        lines.join("\n")
+    }
+
+    // Chrome trace event format (chrome://tracing, Perfetto, speedscope)
+    pub fn to_chrome_trace(&self) -> String {
+        let mut thread_names: Vec<&str> = Vec::new();
+        let mut events = Vec::with_capacity(self.phases.len() + self.threads.len());
+        for span in self.phases.iter().chain(&self.threads) {
+            let tid = match &span.thread {
+                Some(name) => match thread_names.iter().position(|known| known == name) {
+                    Some(idx) => idx + 1,
+                    None => {
+                        thread_names.push(name);
+                        thread_names.len()
+                    }
+                },
+                None => 0,
+            };
+            events.push(format!(
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":{}}}",
+                json_escape(span.phase),
+                if span.thread.is_some() { "worker" } else { "phase" },
+                span.start.duration_since(self.start).as_micros(),
+                span.time.as_micros(),
+                tid
+            ));
+        }
+        events.push(
+            "{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":0,\"args\":{\"name\":\"main\"}}"
+                .to_string(),
+        );
+        for (idx, name) in thread_names.iter().enumerate() {
+            events.push(format!(
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+                idx + 1,
+                json_escape(name)
+            ));
+        }
+        format!("[\n{}\n]\n", events.join(",\n"))
+    }
+}
@@ -1,171 +1,338 @@
-use image::{DynamicImage, ImageBuffer, ImageFormat, Rgb, imageops::ColorMap};
-use itertools::Itertools;
+use image::{
+    DynamicImage, ImageBuffer, ImageEncoder, Luma, Pixel, Rgb, RgbImage, Rgba, RgbaImage,
+    codecs::png::PngEncoder, imageops::ColorMap,
+};
 use rand::{Rng, rng};
-use std::{io::Write, process::exit};
+use std::{
+    collections::HashSet,
+    io::{IsTerminal, Write, stderr, stdout},
+    ops::IndexMut,
+    sync::Mutex,
+    thread,
+};
+
+use crate::{error::CodecError, format, log, metadata, progress::Progress, timings::Instant};
 
 const PROGRESS_BAR_WIDTH: usize = 50;
 
+// Percent step between plain log lines when stderr is not a terminal
+const PROGRESS_LOG_STEP: usize = 10;
+
+// Terminal implementation of Progress on stderr (stdout may carry the output), redraws a bar
+// in place or logs plain lines
 pub struct ProgressBar {
-    pub last_step: usize,
-    current_step: usize,
+    // Redraw in place with carriage returns, otherwise log plain lines
+    terminal: bool,
+    // Last logged percent and the start of the current pass
+    logged: Mutex<(usize, Instant)>,
+}
+
+impl Default for ProgressBar {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ProgressBar {
-    pub fn new(last_step: usize) -> Self {
+    pub fn new() -> Self {
         Self {
-            last_step,
-            current_step: 0,
+            terminal: stderr().is_terminal(),
+            logged: Mutex::new((0, Instant::now())),
+        }
+    }
+
+    fn log(&self, done: u64, percent: usize) {
+        let mut logged = self.logged.lock().unwrap();
+        let (logged_percent, start) = &mut *logged;
+        // Each pass (mapping, encryption) counts from the start
+        if done == 1 {
+            *logged_percent = 0;
+            *start = Instant::now();
+        }
+        if percent < *logged_percent + PROGRESS_LOG_STEP && percent < 100
+            || percent == *logged_percent
+        {
+            return;
         }
+        *logged_percent = percent;
+        let elapsed = start.elapsed().as_secs_f64();
+        let eta = (elapsed / percent.max(1) as f64 * (100 - percent) as f64) as u64;
+        _ = writeln!(
+            stderr(),
+            "{}% complete, ETA {:02}:{:02}",
+            percent,
+            eta / 60,
+            eta % 60
+        );
     }
+}
 
-    pub fn step(&mut self) {
-        self.current_step = (self.current_step + 1).min(self.last_step);
-        let percent = self.current_step as f32 / self.last_step as f32 * 100.0;
+impl Progress for ProgressBar {
+    fn report(&self, done: u64, total: u64) {
+        if log::level() == log::Level::Quiet {
+            return;
+        }
+        let percent = done as f32 / total as f32 * 100.0;
+        if !self.terminal {
+            self.log(done, percent as usize);
+            return;
+        }
         let done_width = (percent / 100.0 * PROGRESS_BAR_WIDTH as f32) as usize;
 
-        print!("\r{}", " ".repeat(PROGRESS_BAR_WIDTH));
-        print!(
+        let mut stderr = stderr().lock();
+        _ = write!(stderr, "\r{}", " ".repeat(PROGRESS_BAR_WIDTH));
+        _ = write!(
+            stderr,
             "\rProcessing... [{}{}] ({}%)",
             "|".repeat(done_width),
             " ".repeat(PROGRESS_BAR_WIDTH - done_width),
             percent as usize
         );
-        use std::io::{Write, stdout};
-        stdout().flush().unwrap();
+        _ = stderr.flush();
+    }
+}
+
+// Pixel types a file holds, RGB or, with the alpha flag, RGBA or, with the gray flag, luma
+pub trait Color:
+    Pixel<Subpixel = u8> + IndexMut<usize, Output = u8> + PartialEq + Send + Sync + 'static
+{
+    // Header flags of files with this palette entry layout
+    const FLAGS: u8;
+    // Leading channels which are dithered, alpha isn't
+    const COLOR_CHANNELS: usize;
+}
+
+impl Color for Rgb<u8> {
+    const FLAGS: u8 = 0;
+    const COLOR_CHANNELS: usize = 3;
+}
+
+impl Color for Rgba<u8> {
+    const FLAGS: u8 = format::FLAG_ALPHA;
+    const COLOR_CHANNELS: usize = 3;
+}
+
+impl Color for Luma<u8> {
+    const FLAGS: u8 = format::FLAG_GRAY;
+    const COLOR_CHANNELS: usize = 1;
+}
+
+pub struct Palette<P = Rgb<u8>> {
+    pub colors: Vec<P>,
+    // Indices of colors as a k-d tree: the middle of each range is a node splitting the rest on
+    // one channel, the next one a level deeper, see index_of
+    tree: Vec<usize>,
+}
+
+impl<P: Color> Palette<P> {
+    pub fn new(colors: Vec<P>) -> Self {
+        let mut tree = (0..colors.len()).collect::<Vec<_>>();
+        build_tree(&colors, &mut tree, 0);
+        Self { colors, tree }
     }
 
-    pub fn step_percent(&mut self, percent: f32) {
-        for _ in 0..(percent * PROGRESS_BAR_WIDTH as f32) as usize {
-            self.step();
+    // Visits the node of range, then its side of the pixel and the other one unless the split
+    // channel alone is farther than the best match; ties are visited too, see index_of
+    fn nearest<'a>(
+        &'a self,
+        range: &[usize],
+        depth: usize,
+        color: &P,
+        best: &mut (i32, &'a [u8], usize),
+    ) {
+        if range.is_empty() {
+            return;
+        }
+        let mid = range.len() / 2;
+        let entry = &self.colors[range[mid]];
+        let distance = entry
+            .channels()
+            .iter()
+            .zip(color.channels())
+            .map(|(&a, &b)| (a as i32 - b as i32).pow(2))
+            .sum::<i32>();
+        *best = (*best).min((distance, entry.channels(), range[mid]));
+        let channel = depth % P::CHANNEL_COUNT as usize;
+        let offset = color[channel] as i32 - entry[channel] as i32;
+        let (near, far) = if offset < 0 {
+            (&range[..mid], &range[mid + 1..])
+        } else {
+            (&range[mid + 1..], &range[..mid])
+        };
+        self.nearest(near, depth + 1, color, best);
+        if offset * offset <= best.0 {
+            self.nearest(far, depth + 1, color, best);
         }
     }
 }
 
-pub struct Palette {
-    pub colors: Vec<Rgb<u8>>,
+// Puts the median of the range on the channel of the level in its middle, the lower ones before
+// it and the higher ones after, then does the same for both halves a level deeper
+fn build_tree<P: Color>(colors: &[P], range: &mut [usize], depth: usize) {
+    if range.len() <= 1 {
+        return;
+    }
+    let channel = depth % P::CHANNEL_COUNT as usize;
+    let mid = range.len() / 2;
+    range.select_nth_unstable_by_key(mid, |&idx| colors[idx][channel]);
+    let (lower, upper) = range.split_at_mut(mid);
+    build_tree(colors, lower, depth + 1);
+    build_tree(colors, &mut upper[1..], depth + 1);
 }
 
-impl ColorMap for Palette {
-    type Color = Rgb<u8>;
+impl<P: Color> ColorMap for Palette<P> {
+    type Color = P;
 
+    // Nearest color, the lowest one on ties, so which color a pixel gets doesn't depend on the
+    // order of the palette (see order_by_frequency). The k-d tree leaves out the branches that
+    // can't hold a closer color, so palettes of thousands of colors don't cost a scan per pixel
     fn index_of(&self, color: &Self::Color) -> usize {
-        self.colors
-            .iter()
-            .enumerate()
-            .min_by_key(|&(_, rgb)| {
-                let r = rgb[0] as i32 - color[0] as i32;
-                let g = rgb[1] as i32 - color[1] as i32;
-                let b = rgb[2] as i32 - color[2] as i32;
-                r * r + g * g + b * b
-            })
-            .map(|(idx, _)| idx)
-            .unwrap_or(0)
+        let mut best = (i32::MAX, &[][..], 0);
+        self.nearest(&self.tree, 0, color, &mut best);
+        best.2
     }
 
     fn map_color(&self, color: &mut Self::Color) {
         let idx = self.index_of(color);
-        let rgb = self.colors.to_owned()[idx];
-        *color = Rgb([rgb[0], rgb[1], rgb[2]]);
+        *color = self.colors[idx];
     }
 }
 
-struct Bucket {
-    pixels: Vec<Rgb<u8>>,
+// Buckets smaller than this are measured on the calling thread
+const PARALLEL_STATS_MIN: usize = 1 << 16;
+
+// Per-channel sums, squared sums and ranges of a bucket, RGB pixels leave the fourth unused
+#[derive(Clone, Copy)]
+struct Stats {
+    sum: [u64; 4],
+    squares: [u64; 4],
+    min: [u8; 4],
+    max: [u8; 4],
 }
 
-impl Bucket {
-    fn new(pixels: Vec<Rgb<u8>>) -> Self {
-        Self { pixels }
+impl Stats {
+    fn new<P: Color>(pixels: &[P]) -> Self {
+        let mut stats = Self {
+            sum: [0; 4],
+            squares: [0; 4],
+            min: [u8::MAX; 4],
+            max: [0; 4],
+        };
+        for p in pixels {
+            for (c, &value) in p.channels().iter().enumerate() {
+                stats.sum[c] += value as u64;
+                stats.squares[c] += value as u64 * value as u64;
+                stats.min[c] = stats.min[c].min(value);
+                stats.max[c] = stats.max[c].max(value);
+            }
+        }
+        stats
     }
 
-    fn largest_range_channel(&self) -> usize {
-        let (min_r, max_r) = self
-            .pixels
-            .iter()
-            .map(|p| p[0])
-            .minmax()
-            .into_option()
-            .unwrap();
-        let (min_g, max_g) = self
-            .pixels
-            .iter()
-            .map(|p| p[1])
-            .minmax()
-            .into_option()
-            .unwrap();
-        let (min_b, max_b) = self
-            .pixels
-            .iter()
-            .map(|p| p[2])
-            .minmax()
-            .into_option()
-            .unwrap();
-
-        let range_r = max_r - min_r;
-        let range_g = max_g - min_g;
-        let range_b = max_b - min_b;
-
-        if range_r >= range_g && range_r >= range_b {
-            0
-        } else if range_g >= range_r && range_g >= range_b {
-            1
-        } else {
-            2
+    fn merge(mut self, other: Self) -> Self {
+        for c in 0..4 {
+            self.sum[c] += other.sum[c];
+            self.squares[c] += other.squares[c];
+            self.min[c] = self.min[c].min(other.min[c]);
+            self.max[c] = self.max[c].max(other.max[c]);
         }
+        self
+    }
+
+    // Sums are exact, so the result doesn't depend on the threads amount
+    fn parallel<P: Color>(pixels: &[P], threads: usize) -> Self {
+        if pixels.len() < PARALLEL_STATS_MIN || threads <= 1 {
+            return Self::new(pixels);
+        }
+        thread::scope(|scope| {
+            pixels
+                .chunks(pixels.len().div_ceil(threads))
+                .map(|chunk| scope.spawn(move || Self::new(chunk)))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .reduce(Self::merge)
+                .unwrap()
+        })
+    }
+}
+
+struct Bucket<P> {
+    pixels: Vec<P>,
+    stats: Stats,
+    // Cached, buckets are only ever split, never changed
+    variance: u64,
+}
+
+impl<P: Color> Bucket<P> {
+    fn new(pixels: Vec<P>, threads: usize) -> Self {
+        let stats = Stats::parallel(&pixels, threads);
+        let mut bucket = Self {
+            pixels,
+            stats,
+            variance: 0,
+        };
+        bucket.variance = bucket.variance();
+        bucket
+    }
+
+    // The first one on ties
+    fn largest_range_channel(&self) -> usize {
+        (0..P::CHANNEL_COUNT as usize)
+            .rev()
+            .max_by_key(|&c| self.stats.max[c] - self.stats.min[c])
+            .unwrap()
     }
 
-    fn split(self) -> (Self, Self) {
+    // Counting sort by the largest range channel; children large enough are measured on the
+    // threads of Stats::parallel, the many small ones on the calling thread
+    fn split(self, threads: usize) -> (Self, Self) {
         let ch = self.largest_range_channel();
-        let mut pixels = self.pixels;
-        pixels.sort_unstable_by_key(|p| p[ch]);
+        let mut offsets = [0usize; 256];
+        for p in &self.pixels {
+            offsets[p[ch] as usize] += 1;
+        }
+        let mut total = 0;
+        for offset in offsets.iter_mut() {
+            (*offset, total) = (total, total + *offset);
+        }
+        let mut pixels = vec![self.pixels[0]; self.pixels.len()];
+        for p in self.pixels {
+            pixels[offsets[p[ch] as usize]] = p;
+            offsets[p[ch] as usize] += 1;
+        }
 
-        let mid = pixels.len() / 2;
-        let lower = pixels[..mid].to_vec();
-        let upper = pixels[mid..].to_vec();
+        let upper = pixels.split_off(pixels.len() / 2);
+        (Self::new(pixels, threads), Self::new(upper, threads))
+    }
 
-        (Self::new(lower), Self::new(upper))
+    fn average_color(&self) -> P {
+        let len = self.pixels.len() as u64;
+        let average = self.stats.sum.map(|sum| (sum / len) as u8);
+        *P::from_slice(&average[..P::CHANNEL_COUNT as usize])
     }
 
-    fn average_color(&self) -> Rgb<u8> {
-        let len = self.pixels.len() as u32;
-        let (r_sum, g_sum, b_sum) =
-            self.pixels
-                .iter()
-                .fold((0u32, 0u32, 0u32), |(r_acc, g_acc, b_acc), p| {
-                    (
-                        r_acc + p[0] as u32,
-                        g_acc + p[1] as u32,
-                        b_acc + p[2] as u32,
-                    )
-                });
-        Rgb([
-            (r_sum / len) as u8,
-            (g_sum / len) as u8,
-            (b_sum / len) as u8,
-        ])
-    }
-
-    fn variance(&self) -> u32 {
-        let len = self.pixels.len() as u32;
+    // Mean squared distance to the average color: sum((p - avg)^2) = sum(p^2) - 2 * avg * sum(p) + n * avg^2
+    fn variance(&self) -> u64 {
+        let len = self.pixels.len() as u64;
         if len == 0 {
             return 0;
         }
 
         let avg = self.average_color();
-        self.pixels
+        avg.channels()
             .iter()
-            .map(|p| {
-                let dr = p[0] as i32 - avg[0] as i32;
-                let dg = p[1] as i32 - avg[1] as i32;
-                let db = p[2] as i32 - avg[2] as i32;
-                (dr * dr + dg * dg + db * db) as u32
+            .enumerate()
+            .map(|(c, &avg)| {
+                let avg = avg as u64;
+                self.stats.squares[c] + len * avg * avg - 2 * avg * self.stats.sum[c]
             })
-            .sum::<u32>()
+            .sum::<u64>()
             / len
     }
 }
 
-fn bytes_to_base64url(bytes: &[u8]) -> String {
+pub fn bytes_to_base64url(bytes: &[u8]) -> String {
     base64_url::encode(bytes)
 }
 
@@ -173,55 +340,44 @@ pub fn base64url_to_bytes(code: &str) -> Option<Vec<u8>> {
     base64_url::decode(code).ok()
 }
 
-pub fn pack_dimensions(width: u16, height: u16) -> [u8; 3] {
-    let combined: u32 = ((width as u32) << 12) | (height as u32);
-
-    [
-        ((combined >> 16) & 0xFF) as u8,
-        ((combined >> 8) & 0xFF) as u8,
-        (combined & 0xFF) as u8,
-    ]
-}
-
-pub fn unpack_dimensions(bytes: &[u8]) -> (u32, u32) {
-    let combined: u32 = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | (bytes[2] as u32);
-
-    let width = (combined >> 12) as u16 & 0xFFF;
-    let height = (combined & 0xFFF) as u16;
-
-    (width as u32, height as u32)
+// Bytes as they are, stdout is not translated on any platform
+pub fn write_stdout(bytes: &[u8]) -> std::io::Result<()> {
+    let mut stdout = stdout().lock();
+    stdout.write_all(bytes)?;
+    stdout.flush()
 }
 
-pub fn write_file(bytes: &[u8], output_file_path: &str) {
-    match std::fs::File::create(output_file_path) {
-        Ok(mut file) => {
-            if let Some(err) = file.write_all(bytes).err() {
-                eprintln!("Error: {}", err);
-                exit(1);
+// The colors of the pixels in the order they're first seen when there are at most n, None when
+// there are more
+pub fn exact_palette<P: Color>(pixels: &[P], n: usize) -> Option<Vec<P>> {
+    let mut seen = HashSet::new();
+    let mut palette = Vec::new();
+    for pixel in pixels {
+        if seen.insert(pixel.channels()) {
+            if palette.len() == n {
+                return None;
             }
+            palette.push(*pixel);
         }
-        Err(err) => {
-            eprintln!("Error: {}", err);
-            exit(1);
-        }
     }
+    // The format holds 2 colors at least, as in gen_palette
+    if palette.len() == 1 {
+        palette.push(palette[0]);
+    }
+    Some(palette)
 }
 
-pub fn gen_palette(pixels: &[Rgb<u8>], n: usize) -> Vec<Rgb<u8>> {
-    let mut buckets = vec![Bucket::new(pixels.to_vec())];
+pub fn gen_palette<P: Color>(pixels: &[P], n: usize, threads: usize) -> Vec<P> {
+    let mut buckets = vec![Bucket::new(pixels.to_vec(), threads)];
     while buckets.len() < n {
-        if let Some((idx, _)) = buckets
-            .iter()
-            .enumerate()
-            .max_by_key(|&(_, b)| b.variance())
-        {
+        if let Some((idx, _)) = buckets.iter().enumerate().max_by_key(|&(_, b)| b.variance) {
             let bucket = buckets.swap_remove(idx);
             if bucket.pixels.len() <= 1 {
                 buckets.push(bucket);
                 break;
             }
 
-            let (b1, b2) = bucket.split();
+            let (b1, b2) = bucket.split(threads);
             buckets.push(b1);
             buckets.push(b2);
         } else {
@@ -229,48 +385,201 @@ pub fn gen_palette(pixels: &[Rgb<u8>], n: usize) -> Vec<Rgb<u8>> {
         }
     }
 
-    buckets.iter().map(|b| b.average_color()).collect()
+    let mut palette: Vec<P> = buckets.iter().map(|b| b.average_color()).collect();
+    // The format holds 2 colors at least, a 1 pixel image gets its color twice
+    if palette.len() == 1 {
+        palette.push(palette[0]);
+    }
+    palette
 }
 
-pub fn decode_palette(bytes: &[u8]) -> Vec<Rgb<u8>> {
-    let mut palette: Vec<Rgb<u8>> = Vec::new();
-    for i in 0..bytes.len() / 3 {
-        let rgb = [bytes[i * 3], bytes[i * 3 + 1], bytes[i * 3 + 2]];
-        palette.push(Rgb(rgb));
+// Luma (x1000) and the channels, which break its ties
+fn luma_key<P: Color>(color: &P) -> (u32, Vec<u8>) {
+    let channels = color.channels();
+    let luma = match P::COLOR_CHANNELS {
+        1 => channels[0] as u32 * 1000,
+        _ => 299 * channels[0] as u32 + 587 * channels[1] as u32 + 114 * channels[2] as u32,
+    };
+    (luma, channels.to_vec())
+}
+
+// Orders a palette by luma, so pixels of a gradient get neighbouring indices as their colors
+// get closer, which row filters predict well
+pub fn sort_by_luma<P: Color>(palette: &mut [P]) {
+    palette.sort_by_key(luma_key);
+}
+
+// Reorders a palette so the colors most pixels have get the lowest indices, colors as common
+// (the unused ones at the end above all) by luma so similar ones sit together, and remaps the
+// indices to it; the first fixed entries (the transparent index) keep theirs
+pub fn order_by_frequency<P: Color>(palette: &mut Vec<P>, indices: &mut [u16], fixed: usize) {
+    let mut counts = vec![0usize; palette.len()];
+    for &index in indices.iter() {
+        counts[index as usize] += 1;
     }
-    palette
+    let mut order = (0..palette.len()).collect::<Vec<_>>();
+    order[fixed..].sort_by_cached_key(|&i| (std::cmp::Reverse(counts[i]), luma_key(&palette[i])));
+    let mut new_index = vec![0u16; palette.len()];
+    for (new, &old) in order.iter().enumerate() {
+        new_index[old] = new as u16;
+    }
+    for index in indices.iter_mut() {
+        *index = new_index[*index as usize];
+    }
+    *palette = order.iter().map(|&i| palette[i]).collect();
 }
 
-pub fn open_img(path: &str) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, image::ImageError> {
-    let img: ImageBuffer<Rgb<u8>, Vec<u8>> = image::ImageReader::open(path)?.decode()?.into_rgb8();
-    Ok(img)
+// A grayscale color type, or RGB(A) with equal channels in every pixel
+pub fn is_gray(img: &DynamicImage) -> bool {
+    !img.color().has_color()
+        || img
+            .to_rgb8()
+            .pixels()
+            .all(|pixel| pixel[0] == pixel[1] && pixel[1] == pixel[2])
 }
 
+// "#rrggbb", the # may be left out
+pub fn parse_hex_color(text: &str) -> Option<Rgb<u8>> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |idx: usize| u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).ok();
+    Some(Rgb([channel(0)?, channel(1)?, channel(2)?]))
+}
+
+// RGB of img with its fully transparent pixels set to one color no other pixel has, which
+// Encoder::transparent then stores as the transparent index. None when no pixel is fully
+// transparent or (hardly) every color is taken
+pub fn key_transparent(img: &RgbaImage) -> Option<(RgbImage, Rgb<u8>)> {
+    let number = |rgb: &[u8]| (rgb[0] as usize) << 16 | (rgb[1] as usize) << 8 | rgb[2] as usize;
+    // One bit per RGB color
+    let mut used = vec![0u64; (1 << 24) / 64];
+    let mut first = None;
+    for pixel in img.pixels() {
+        if pixel[3] == 0 {
+            first.get_or_insert(number(&pixel.0));
+        } else {
+            let color = number(&pixel.0);
+            used[color / 64] |= 1 << (color % 64);
+        }
+    }
+    // The color of the transparent pixels themselves when it's free, decoding to RGB shows it
+    let key = std::iter::once(first?)
+        .chain(0..1 << 24)
+        .find(|&color| used[color / 64] & (1 << (color % 64)) == 0)?;
+    let key = Rgb([(key >> 16) as u8, (key >> 8) as u8, key as u8]);
+    let rgb = RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let pixel = img.get_pixel(x, y);
+        if pixel[3] == 0 { key } else { pixel.to_rgb() }
+    });
+    Some((rgb, key))
+}
+
+// What the encode input carries beside its pixels, kept in the metadata section and written
+// back into the decoded PNG
+#[derive(Debug, Default)]
+pub struct Embedded {
+    pub icc: Option<Vec<u8>>,
+    pub exif: Option<Vec<u8>>,
+}
+
+impl Embedded {
+    pub fn from_metadata(entries: &metadata::Entries) -> Self {
+        let find = |key| {
+            entries
+                .iter()
+                .find(|(other, _)| other == key)
+                .map(|(_, value)| value.clone())
+        };
+        Embedded {
+            icc: find(metadata::ICC_KEY),
+            exif: find(metadata::EXIF_KEY),
+        }
+    }
+}
+
+// Headerless RGB bytes, rows without padding
+pub fn raw_img(
+    bytes: Vec<u8>,
+    width: u32,
+    height: u32,
+) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, CodecError> {
+    let expected = width as usize * height as usize * 3;
+    if bytes.len() != expected {
+        return Err(CodecError::InvalidInput(format!(
+            "raw input is {} bytes, {}x{} RGB needs {}",
+            bytes.len(),
+            width,
+            height,
+            expected
+        )));
+    }
+    Ok(ImageBuffer::from_raw(width, height, bytes).unwrap())
+}
+
+// "-" writes the PNG to stdout
 pub fn save_img(
     img: ImageBuffer<Rgb<u8>, Vec<u8>>,
     output_file_path: &str,
 ) -> Result<(), image::ImageError> {
-    match DynamicImage::ImageRgb8(img).save_with_format(output_file_path, ImageFormat::Png) {
-        Ok(_) => Ok(()),
-        Err(err) => Err(err),
+    save_png(
+        DynamicImage::ImageRgb8(img),
+        output_file_path,
+        &Embedded::default(),
+    )
+}
+
+// save_img of RGB or RGBA images, with the ICC profile and EXIF of the source attached
+pub fn save_png(
+    img: DynamicImage,
+    output_file_path: &str,
+    embedded: &Embedded,
+) -> Result<(), image::ImageError> {
+    let mut png = Vec::new();
+    let mut encoder = PngEncoder::new(&mut png);
+    if let Some(icc) = &embedded.icc {
+        encoder
+            .set_icc_profile(icc.clone())
+            .map_err(image::ImageError::Unsupported)?;
+    }
+    if let Some(exif) = &embedded.exif {
+        encoder
+            .set_exif_metadata(exif.clone())
+            .map_err(image::ImageError::Unsupported)?;
+    }
+    encoder.write_image(
+        img.as_bytes(),
+        img.width(),
+        img.height(),
+        img.color().into(),
+    )?;
+    if output_file_path == "-" {
+        return write_stdout(&png).map_err(image::ImageError::IoError);
     }
+    std::fs::write(output_file_path, png).map_err(image::ImageError::IoError)
 }
 
-pub fn get_info(file_path: &str) -> String {
-    let bytes = std::fs::read(file_path).unwrap();
-    let (width, height) = unpack_dimensions(&bytes[0..3]);
-    format!(
-        "width: {}, height: {}, palette_size: {}",
-        width + 2,
-        height + 2,
-        bytes[3] as usize + 2,
-    )
+pub fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
 }
 
-pub fn gen_key() -> String {
+pub fn gen_key(size: usize) -> String {
     let mut rng = rng();
     bytes_to_base64url(
-        (0..16)
+        (0..size)
             .map(|_| rng.random())
             .collect::<Vec<u8>>()
             .as_slice(),
@@ -0,0 +1,199 @@
+// Integrity check of a file without decoding it, so encrypted files need no key
+use hmac::Mac;
+use std::ops::Range;
+
+use crate::{
+    ZSTD_MAGIC, bare, chunks, compression,
+    detect::{self, State},
+    error::CodecError,
+    fec, format, interlace,
+    keys::{SIGNATURE_KEY, signer},
+    metadata, seek,
+};
+
+pub struct Verification {
+    pub version: u8,
+    // CRC32 the file matched, None for files written without one (before v3)
+    pub checksum: Option<u32>,
+}
+
+// Checks the header against the data length, then the checksum footer. A damaged file tells
+// where the damage is by its chunk checksums when it has them, else a plain one is scanned for
+// the first index beyond its palette
+pub fn verify(bytes: &[u8]) -> Result<Verification, CodecError> {
+    // Damage the parity rebuilds still fails, repair fixes the file
+    if fec::is_wrapped(bytes) {
+        let repair = fec::check(bytes)?;
+        if (1..=repair.parity).contains(&repair.damaged) {
+            return Err(CodecError::BadData(format!(
+                "{} of its {} forward error correction shards are damaged, repair rebuilds them",
+                repair.damaged, repair.shards
+            )));
+        }
+        return verify(&fec::unwrap(bytes)?);
+    }
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        return check(&compression::decompress(bytes)?, "decompressed ");
+    }
+    check(bytes, "")
+}
+
+// Checks the signature of a file encoded with --sign against the key it was signed with, see
+// keys::signer; verify checks the rest
+pub fn check_signature(bytes: &[u8], key: &[u8]) -> Result<(), CodecError> {
+    if fec::is_wrapped(bytes) {
+        return check_signature(&fec::unwrap(bytes)?, key);
+    }
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        return check_signature(&compression::decompress(bytes)?, key);
+    }
+    let content = &bytes[..format::content_len(bytes)];
+    let range = bare::metadata_range(content)?;
+    let entries = metadata::parse(content.get(range.clone()).ok_or(bare::Error::TooShort)?)?;
+    let Some((_, signature)) = entries.iter().find(|(entry, _)| entry == SIGNATURE_KEY) else {
+        return Err(CodecError::InvalidInput(
+            "isn't signed, it was encoded without --sign".to_string(),
+        ));
+    };
+    let mut mac = signer(key, &content[..range.start - 4], &entries);
+    mac.update(&content[range.end..]);
+    mac.verify_slice(signature).map_err(|_| {
+        CodecError::BadKey(
+            "signature doesn't match, the file was modified or signed with another key".to_string(),
+        )
+    })
+}
+
+fn check(bytes: &[u8], file: &str) -> Result<Verification, CodecError> {
+    format::prefix_len(bytes)?;
+    let state = detect::detect_state(bytes);
+    if let State::Invalid(reason) = state {
+        return Err(CodecError::BadHeader(reason));
+    }
+    let content = &bytes[..format::content_len(bytes)];
+    let damaged_at = || match state {
+        State::Plain => first_out_of_range(content),
+        _ => None,
+    };
+    let checksum = match bare::without_checksum(bytes) {
+        Ok(checked) if checked.len() < bytes.len() => Some(u32::from_le_bytes(
+            bytes[checked.len()..].try_into().unwrap(),
+        )),
+        Ok(_) => None,
+        Err(bare::Error::ChecksumMismatch { stored, actual }) => {
+            if let Some(ranges) = chunks::damaged(content)? {
+                return Err(CodecError::BadData(damaged_chunks(
+                    content, &ranges, &state, file,
+                )));
+            }
+            return Err(CodecError::BadData(match damaged_at() {
+                Some(byte) => format!(
+                    "corrupt at byte {} of the {}file, an index beyond the palette (checksum stored {:08x}, computed {:08x})",
+                    byte, file, stored, actual
+                ),
+                None => bare::Error::ChecksumMismatch { stored, actual }.to_string(),
+            }));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    if checksum.is_none()
+        && let Some(byte) = damaged_at()
+    {
+        return Err(CodecError::BadData(format!(
+            "corrupt at byte {} of the {}file, an index beyond the palette",
+            byte, file
+        )));
+    }
+    Ok(Verification {
+        version: format::version(bytes),
+        checksum,
+    })
+}
+
+// Damaged bytes of a file with chunk checksums, and the rows they are in for plain ones
+fn damaged_chunks(content: &[u8], ranges: &[Range<usize>], state: &State, file: &str) -> String {
+    let Ok(header) = bare::parse_header(content) else {
+        return format!("corrupt in the {}file's header", file);
+    };
+    if ranges.is_empty() {
+        return format!(
+            "corrupt outside the {}file's pixels data, its metadata section or checksum",
+            file
+        );
+    }
+    let bytes = ranges
+        .iter()
+        .map(|range| {
+            format!(
+                "{}-{}",
+                header.data_offset + range.start,
+                header.data_offset + range.end - 1
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let rows = match state {
+        State::Plain => chunks::damaged_rows(
+            ranges,
+            &content[header.data_offset..],
+            header.width,
+            header.height,
+            header.index_bits,
+            header.layout,
+            false,
+        ),
+        _ => None,
+    };
+    match rows {
+        Some(rows) => format!(
+            "corrupt at bytes {} of the {}file, rows {}",
+            bytes,
+            file,
+            chunks::describe_rows(&rows)
+        ),
+        None => format!("corrupt at bytes {} of the {}file", bytes, file),
+    }
+}
+
+// Offset of the first pixels data byte with an index beyond the palette, filtered rows with
+// an unknown filter type leave it unknown, as do run-length encoded data, whose bytes aren't
+// a pixel's, tiles, which hold their own palettes, and truecolor files, which have none
+fn first_out_of_range(bytes: &[u8]) -> Option<usize> {
+    let header = bare::parse_header(bytes).ok()?;
+    if header.layout & (format::LAYOUT_RLE | format::LAYOUT_TILED | format::LAYOUT_TRUECOLOR) != 0 {
+        return None;
+    }
+    let data = bare::packed_data(
+        &bytes[header.data_offset..],
+        header.width,
+        header.height,
+        header.index_bits,
+        header.layout,
+    )
+    .ok()?;
+    let out_of_range = |&index: &u16| index as usize >= header.palette.len();
+    // Raster order, whose first pixel out of range isn't the first in the passes
+    if header.layout & format::LAYOUT_INTERLACED != 0 {
+        let (width, height, index_bits) = (header.width, header.height, header.index_bits);
+        let (indices, _) = interlace::deinterlace(&data, width, height, index_bits);
+        return (0..indices.len())
+            .filter(|&pixel| out_of_range(&indices[pixel]))
+            .map(|pixel| {
+                header.data_offset
+                    + interlace::index_offset(pixel, width, height, index_bits, header.layout)
+            })
+            .min();
+    }
+    let (width, index_bits, layout) = (header.width, header.index_bits, header.layout);
+    format::unpack_indices(&data, width, index_bits)
+        .iter()
+        .position(out_of_range)
+        .map(|pixel| {
+            header.data_offset
+                + if layout & format::LAYOUT_SEEK != 0 {
+                    seek::index_offset(pixel, width, header.height, index_bits, layout)
+                } else {
+                    format::index_offset(pixel, width, index_bits, layout)
+                }
+        })
+}
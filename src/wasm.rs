@@ -0,0 +1,165 @@
+// Browser API, see README for the build; everything runs on the calling thread without
+// a progress bar
+use js_sys::{Reflect, Uint8Array};
+use wasm_bindgen::prelude::*;
+
+use std::io::Cursor;
+
+use crate::{Decoder, Encoder, dither::Dither, interlace};
+
+#[wasm_bindgen]
+pub struct DecodedImage {
+    width: u32,
+    height: u32,
+    rgb: Vec<u8>,
+    passes: usize,
+}
+
+#[wasm_bindgen]
+impl DecodedImage {
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // RGB rows without padding
+    #[wasm_bindgen(getter)]
+    pub fn rgb(&self) -> Vec<u8> {
+        self.rgb.clone()
+    }
+
+    // Passes of an interlaced file decoded in full, 7 once the image is whole
+    #[wasm_bindgen(getter)]
+    pub fn passes(&self) -> usize {
+        self.passes
+    }
+
+    // Opaque RGBA for `new ImageData(new Uint8ClampedArray(image.rgba()), image.width)`
+    pub fn rgba(&self) -> Vec<u8> {
+        self.rgb
+            .chunks_exact(3)
+            .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+            .collect()
+    }
+}
+
+fn option(opts: &JsValue, name: &str) -> Result<Option<JsValue>, JsError> {
+    if opts.is_undefined() || opts.is_null() {
+        return Ok(None);
+    }
+    let value = Reflect::get(opts, &JsValue::from_str(name))
+        .map_err(|_| JsError::new("options must be an object"))?;
+    Ok((!value.is_undefined() && !value.is_null()).then_some(value))
+}
+
+// opts: { paletteSize: number, dither: string, compress: boolean, interlace: boolean,
+// seek: boolean, lossless: boolean, key: Uint8Array }, all optional
+#[wasm_bindgen]
+pub fn encode(rgb: &[u8], width: u32, height: u32, opts: JsValue) -> Result<Vec<u8>, JsError> {
+    let mut encoder = Encoder::new().threads(1);
+    if let Some(value) = option(&opts, "paletteSize")? {
+        let palette_size = value
+            .as_f64()
+            .ok_or_else(|| JsError::new("paletteSize must be a number"))?;
+        encoder = encoder.palette_size(palette_size as usize);
+    }
+    if let Some(value) = option(&opts, "dither")? {
+        let dither = value
+            .as_string()
+            .and_then(|name| Dither::parse(&name))
+            .ok_or_else(|| {
+                JsError::new(&format!(
+                    "dither must be one of: {}",
+                    Dither::ALL.map(Dither::name).join(", ")
+                ))
+            })?;
+        encoder = encoder.dither(dither);
+    }
+    if let Some(value) = option(&opts, "compress")? {
+        encoder = encoder.compression(value.is_truthy());
+    }
+    if let Some(value) = option(&opts, "interlace")? {
+        encoder = encoder.interlace(value.is_truthy());
+    }
+    if let Some(value) = option(&opts, "seek")? {
+        encoder = encoder.seek(value.is_truthy());
+    }
+    if let Some(value) = option(&opts, "lossless")? {
+        encoder = encoder.lossless(value.is_truthy());
+    }
+    if let Some(value) = option(&opts, "key")? {
+        let key = value
+            .dyn_into::<Uint8Array>()
+            .map_err(|_| JsError::new("key must be a Uint8Array"))?;
+        encoder = encoder.key(&key.to_vec());
+    }
+    encoder
+        .encode_raw(rgb, width, height)
+        .map_err(|err| JsError::new(&err.to_string()))
+}
+
+fn decoder(key: Option<Vec<u8>>) -> Decoder {
+    let decoder = Decoder::new().threads(1);
+    match key {
+        Some(key) => decoder.key(&key),
+        None => decoder,
+    }
+}
+
+// Compression is detected, the key is the one the file was encoded with
+#[wasm_bindgen]
+pub fn decode(bytes: &[u8], key: Option<Vec<u8>>) -> Result<DecodedImage, JsError> {
+    let (rgb, width, height) = decoder(key)
+        .decode_to_vec(bytes)
+        .map_err(|err| JsError::new(&err.to_string()))?;
+    Ok(DecodedImage {
+        width,
+        height,
+        rgb,
+        passes: interlace::PASSES,
+    })
+}
+
+// Coarse image of the bytes of an interlaced file received so far, e.g. of a fetch() stream,
+// refined with every pass; call it again as more bytes arrive
+#[wasm_bindgen]
+pub fn preview(bytes: &[u8], key: Option<Vec<u8>>) -> Result<DecodedImage, JsError> {
+    let (img, passes) = decoder(key)
+        .preview(bytes)
+        .map_err(|err| JsError::new(&err.to_string()))?;
+    let img = img.into_rgb8();
+    Ok(DecodedImage {
+        width: img.width(),
+        height: img.height(),
+        rgb: img.into_raw(),
+        passes,
+    })
+}
+
+// width x height pixels at x, y of a file, of which only the rows or tiles they're in are
+// decrypted and decoded when it has a seek table or tiles
+#[wasm_bindgen]
+pub fn crop(
+    bytes: &[u8],
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    key: Option<Vec<u8>>,
+) -> Result<DecodedImage, JsError> {
+    let img = decoder(key)
+        .decode_region(Cursor::new(bytes), x, y, width, height)
+        .map_err(|err| JsError::new(&err.to_string()))?
+        .into_rgb8();
+    Ok(DecodedImage {
+        width: img.width(),
+        height: img.height(),
+        rgb: img.into_raw(),
+        passes: interlace::PASSES,
+    })
+}
@@ -0,0 +1,60 @@
+// Calls back whenever a file is saved. Editors often write a temporary file and rename it over
+// the original, which drops a watch on the file itself, so its directory is watched instead
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::{
+    ffi::OsStr,
+    path::Path,
+    sync::mpsc,
+    time::{Duration, SystemTime},
+};
+
+// A save arrives as a burst of events, the callback runs once it's quiet for this long
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+fn touches(event: &Event, file_name: &OsStr) -> bool {
+    matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+        && event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == Some(file_name))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// Blocks until the watcher fails, on_change runs after each save that changed the file
+pub fn watch(path: &str, mut on_change: impl FnMut()) -> Result<(), String> {
+    let path = Path::new(path);
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("{} is not a file", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(|err| err.to_string())?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .map_err(|err| format!("{}: {}", dir.display(), err))?;
+    let mut last_modified = modified(path);
+    loop {
+        let event = receiver
+            .recv()
+            .map_err(|err| err.to_string())?
+            .map_err(|err| err.to_string())?;
+        if !touches(&event, file_name) {
+            continue;
+        }
+        while receiver.recv_timeout(DEBOUNCE).is_ok() {}
+        // Metadata-only events (e.g. opening the file in some editors) don't re-run
+        let current = modified(path);
+        if current.is_some() && current != last_modified {
+            last_modified = current;
+            on_change();
+        }
+    }
+}
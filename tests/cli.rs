@@ -0,0 +1,351 @@
+// The rust_image-codec binary run on scratch files
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust_image-codec"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// Fresh directory of the test holding in.png, a 32x24 gradient
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ric-cli-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    image::RgbImage::from_fn(32, 24, |x, y| {
+        image::Rgb([(x * 8) as u8, (y * 10) as u8, ((x + y) * 4) as u8])
+    })
+    .save(dir.join("in.png"))
+    .unwrap();
+    dir
+}
+
+#[test]
+fn leading_json_goes_to_the_subcommand() {
+    let dir = scratch("leading-json");
+    assert!(
+        run(&dir, &["-q", "e", "in.png", "a.ric", "16"])
+            .status
+            .success()
+    );
+    std::fs::copy(dir.join("a.ric"), dir.join("b.ric")).unwrap();
+    let output = run(&dir, &["--json", "info", "a.ric", "b.ric"]);
+    assert!(output.status.success());
+    let json = stdout(&output);
+    assert!(json.starts_with("[{\"path\":\"a.ric\""), "{}", json);
+    assert!(json.contains("{\"path\":\"b.ric\""), "{}", json);
+    // Not decoded over the second file as the short syntax would
+    assert_eq!(
+        std::fs::read(dir.join("a.ric")).unwrap(),
+        std::fs::read(dir.join("b.ric")).unwrap()
+    );
+}
+
+#[test]
+fn leading_config_goes_to_the_subcommand() {
+    let dir = scratch("leading-config");
+    std::fs::write(dir.join("c.toml"), "palette-size = 4\n").unwrap();
+    let output = run(
+        &dir,
+        &["--config", "c.toml", "encode", "-q", "in.png", "out.ric"],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(&dir, &["--json", "encode", "-q", "in.png", "json.ric"]);
+    assert!(output.status.success(), "{:?}", output);
+    let json = stdout(&run(&dir, &["info", "--json", "out.ric", "json.ric"]));
+    assert!(json.contains("\"palette_size\":4"), "{}", json);
+    assert!(json.contains("\"palette_size\":256"), "{}", json);
+}
+
+#[test]
+fn info_leaves_internal_entries_out() {
+    let dir = scratch("info-internal");
+    let key = "MDEyMzQ1Njc4OWFiY2RlZg";
+    let output = run(
+        &dir,
+        &[
+            "encode", "-q", "--key", key, "--meta", "note=hi", "in.png", "out.ric",
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let text = stdout(&run(&dir, &["info", "out.ric"]));
+    assert!(text.contains("state: encrypted\n  note: hi\n"), "{}", text);
+    assert!(text.contains("cipher: FF1-AES-128"), "{}", text);
+    for hidden in ["auth", "header-tweak", "decode with"] {
+        assert!(!text.contains(hidden), "{}", text);
+    }
+}
+
+// Outputs are checked before any job writes, --recursive replaces the out-of-date ones
+#[test]
+fn existing_outputs() {
+    let dir = scratch("existing-outputs");
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    std::fs::copy(dir.join("in.png"), dir.join("src/a.png")).unwrap();
+    let recursive = ["encode", "-q", "-r", "src", "--out-dir", "out"];
+    assert!(run(&dir, &recursive).status.success());
+    let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+    let out = std::fs::File::options()
+        .write(true)
+        .open(dir.join("out/a.ric"))
+        .unwrap();
+    out.set_modified(old).unwrap();
+    let output = run(&dir, &recursive);
+    assert!(output.status.success(), "{:?}", output);
+    let modified = std::fs::metadata(dir.join("out/a.ric")).unwrap().modified();
+    assert!(modified.unwrap() > old);
+
+    // A file named as a temporary output could be is left alone
+    std::fs::write(dir.join("c.ric.tmp"), "kept").unwrap();
+    let output = run(&dir, &["-q", "e", "in.png", "c.ric", "16"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("c.ric.tmp")).unwrap(), b"kept");
+
+    std::fs::write(dir.join("jobs.txt"), "in.png a.ric\nin.png b.ric\n").unwrap();
+    std::fs::write(dir.join("b.ric"), "kept").unwrap();
+    let output = run(&dir, &["encode", "-q", "--batch", "jobs.txt"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    assert!(!dir.join("a.ric").exists());
+    let output = run(&dir, &["upgrade", "out/a.ric", "b.ric"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("b.ric")).unwrap(), b"kept");
+    let output = run(&dir, &["upgrade", "out/a.ric", "b.ric", "--force"]);
+    assert!(output.status.success(), "{:?}", output);
+    // Key files are refused alike, with the usage exit code
+    for keygen in [&["keygen", "--out", "b.ric"][..], &["g", "--out", "b.ric"]] {
+        let output = run(&dir, keygen);
+        assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    }
+    assert!(
+        std::fs::read(dir.join("b.ric"))
+            .unwrap()
+            .starts_with(b"RIC1")
+    );
+}
+
+// Any 3 of 5 shares restore the key, 2 or a share of another key are rejected
+#[test]
+fn shamir_shares() {
+    let dir = scratch("shamir");
+    let split = |dir: &Path| -> Vec<String> {
+        let json = stdout(&run(dir, &["--json", "keygen", "--split", "3/5"]));
+        let shares = json.split('"').skip(3).step_by(2);
+        shares.map(str::to_string).collect()
+    };
+    let shares = split(&dir);
+    assert_eq!(shares.len(), 5, "{:?}", shares);
+    let encode = |shares: &[String], out: &str| {
+        let shares = shares.join(",");
+        run(&dir, &["encode", "-q", "--shares", &shares, "in.png", out])
+    };
+    assert!(encode(&shares[..3], "a.ric").status.success());
+    let output = encode(&shares[..2], "b.ric");
+    assert_eq!(output.status.code(), Some(5), "{:?}", output);
+    assert!(!dir.join("b.ric").exists());
+    let mixed = [&shares[..2], &split(&dir)[2..3]].concat();
+    assert_eq!(encode(&mixed, "b.ric").status.code(), Some(5));
+    let others = shares[2..].join(",");
+    let output = run(
+        &dir,
+        &["decode", "-q", "--shares", &others, "a.ric", "a.png"],
+    );
+    assert!(output.status.success(), "{:?}", output);
+}
+
+#[test]
+fn inspect_full_dumps_every_line() {
+    let dir = scratch("inspect-full");
+    assert!(
+        run(&dir, &["-q", "e", "in.png", "a.ric", "16"])
+            .status
+            .success()
+    );
+    let output = run(&dir, &["inspect", "a.ric", "--full"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!stdout(&output).contains("more bytes"));
+    let output = run(&dir, &["inspect", "a.ric"]);
+    assert!(stdout(&output).contains("more bytes"));
+}
+
+// Tool subcommands print their help rather than reading "--help" as a path, and are listed
+#[test]
+fn tool_subcommands_have_help() {
+    let dir = scratch("tool-help");
+    for verb in [
+        "verify",
+        "verify-key",
+        "validate",
+        "inspect",
+        "compare-sizes",
+        "compare-dither",
+        "quantize",
+        "upgrade",
+        "selftest",
+        "add",
+        "list",
+        "extract",
+        "cat",
+        "repair",
+        "rekey",
+        "slots",
+    ] {
+        let output = run(&dir, &[verb, "--help"]);
+        assert!(output.status.success(), "{:?}", output);
+        assert!(stdout(&output).contains("Usage:"), "{}", verb);
+        assert!(stdout(&run(&dir, &["--help"])).contains(verb), "{}", verb);
+    }
+    assert!(
+        run(&dir, &["-q", "e", "in.png", "a.ric", "16"])
+            .status
+            .success()
+    );
+    let output = run(&dir, &["--json", "validate", "a.ric"]);
+    assert!(stdout(&output).contains("\"valid\":true"), "{:?}", output);
+    let output = run(&dir, &["verify", "a.ric"]);
+    assert!(stdout(&output).contains("ok, checksum"), "{:?}", output);
+    assert!(run(&dir, &["add", "c.ricc", "a.ric"]).status.success());
+    let output = run(&dir, &["list", "c.ricc"]);
+    assert!(stdout(&output).contains("1 entries"), "{:?}", output);
+    let output = run(&dir, &["extract", "c.ricc", "0", "b.ric"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        std::fs::read(dir.join("a.ric")).unwrap(),
+        std::fs::read(dir.join("b.ric")).unwrap()
+    );
+    let output = run(&dir, &["cat", "c.ricc", "a.ric", "-o", "d.ricc"]);
+    assert!(stdout(&output).contains("2 entries"), "{:?}", output);
+    let output = run(&dir, &["key", "check", "--help"]);
+    assert!(stdout(&output).contains("Usage:"), "{:?}", output);
+    let output = run(&dir, &["key", "check", "AAAAAAAAAAAAAAAAAAAAAA"]);
+    assert!(stdout(&output).contains("128 bits"), "{:?}", output);
+    let output = run(&dir, &["quantize", "in.png", "--palette-size", "1"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+// The scan order of a config file preset reaches the encoder
+#[test]
+fn preset_scan() {
+    let dir = scratch("preset-scan");
+    std::fs::write(dir.join("c.toml"), "[presets.p]\nscan = \"hilbert\"\n").unwrap();
+    let encode = |extra: &[&str], out: &str| {
+        let mut args = vec!["--config", "c.toml", "encode", "-q", "--preset", "p"];
+        args.extend(extra);
+        args.extend(["in.png", out]);
+        let output = run(&dir, &args);
+        assert!(output.status.success(), "{:?}", output);
+        stdout(&run(&dir, &["inspect", out]))
+    };
+    assert!(encode(&[], "a.ric").contains("hilbert scan"));
+    // --seek can't be used with another scan, the preset's gives way
+    assert!(encode(&["--seek"], "b.ric").contains("row seek table"));
+}
+
+// keygen prints a key starting with "-" about once in 64, which every key argument takes
+#[test]
+fn keys_starting_with_a_hyphen() {
+    let dir = scratch("hyphen-key");
+    let key = "-AABAgMEBQYHCAkKCwwNDg";
+    let key_equals = format!("--key={}", key);
+    for args in [
+        &["key", "check", key][..],
+        &["key", "check", "--key", key],
+        &["key", "check", &key_equals],
+    ] {
+        let output = run(&dir, args);
+        assert!(stdout(&output).contains("128 bits"), "{:?}", output);
+    }
+    let output = run(&dir, &["encode", "-q", "--key", key, "in.png", "a.ric"]);
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(&dir, &["decode", "-q", &key_equals, "a.ric", "a.png"]);
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(&dir, &["verify-key", "a.ric", "--key", key]);
+    assert!(stdout(&output).contains("key is correct"), "{:?}", output);
+}
+
+// The short syntax is the subcommand it stands for, flags of which go anywhere
+#[test]
+fn short_syntax_takes_subcommand_flags() {
+    let dir = scratch("short-syntax");
+    let key = "-AABAgMEBQYHCAkKCwwNDg";
+    let output = run(
+        &dir,
+        &[
+            "-q", "ecz", "in.png", "a.ric", "8", key, "--scan", "hilbert",
+        ],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout(&run(&dir, &["inspect", "a.ric"])).contains("hilbert"));
+    let output = run(&dir, &["dc", "a.ric", "a.png", key, "-q"]);
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(&dir, &["--json", "i", "a.ric"]);
+    assert!(
+        stdout(&output).contains("\"palette_size\":8"),
+        "{:?}",
+        output
+    );
+    assert!(
+        run(&dir, &["g", "--bits", "256", "--out", "k.key"])
+            .status
+            .success()
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("k.key"))
+            .unwrap()
+            .trim()
+            .len(),
+        43
+    );
+    for args in [&["ed", "in.png", "b.ric", "8"][..], &["e", "in.png"]] {
+        let output = run(&dir, args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    }
+}
+
+// A resumed batch skips the outputs the journal has done and redoes the one it was interrupted
+// in, whose partial output is replaced
+#[test]
+fn resume_interrupted_batch() {
+    let dir = scratch("resume");
+    std::fs::write(dir.join("jobs.txt"), "in.png j1.ric\nin.png j2.ric\n").unwrap();
+    std::fs::write(
+        dir.join("jobs.txt.journal"),
+        "start\tj1.ric\ndone\tj1.ric\nstart\tj2.ric\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("j1.ric"), "done").unwrap();
+    std::fs::write(dir.join("j2.ric"), "partial").unwrap();
+    let output = run(&dir, &["encode", "-q", "--batch", "jobs.txt", "--resume"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(std::fs::read(dir.join("j1.ric")).unwrap(), b"done");
+    let output = run(&dir, &["verify", "j2.ric"]);
+    assert!(output.status.success(), "{:?}", output);
+    // Without --resume the journal is started anew and the outputs are refused
+    let output = run(&dir, &["encode", "-q", "--batch", "jobs.txt"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+}
+
+// The hint of a file decoded without its key names the options of the syntax at hand as well
+#[test]
+fn encrypted_file_hint() {
+    let dir = scratch("hint");
+    let key = stdout(&run(&dir, &["keygen"]));
+    let output = run(
+        &dir,
+        &["encode", "-q", "--key", key.trim(), "in.png", "a.ric"],
+    );
+    assert!(output.status.success(), "{:?}", output);
+    let output = run(&dir, &["decode", "a.ric", "a.png"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("decode --key"), "{}", stderr);
+    assert!(stderr.contains("--passphrase"), "{}", stderr);
+}
@@ -0,0 +1,10 @@
+// include/rust_image_codec.h is the header the build script generates from src/ffi.rs
+#[test]
+fn header_is_current() {
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/rust_image_codec.h"));
+    let checked_in = include_str!("../include/rust_image_codec.h");
+    assert!(
+        generated == checked_in,
+        "include/rust_image_codec.h is stale, run cbindgen --config cbindgen.toml --output include/rust_image_codec.h src/ffi.rs"
+    );
+}
@@ -0,0 +1,386 @@
+// Library encode and decode round trips
+use rust_image_codec::{
+    CodecError, Decoder, Encoder, animation, bare, check_auth, container, fec, format, inspect,
+    keys::{self, Fpe},
+    metadata,
+    scan::Scan,
+    utils, verify,
+};
+
+const KEY: [u8; 16] = *b"0123456789abcdef";
+
+// width x 1 strip cycling through 256 colors
+fn strip(width: u32) -> Vec<u8> {
+    (0..width * 3).map(|i| (i * 7 % 256) as u8).collect()
+}
+
+// Encrypted pixels data is cut into 65536 byte segments, 1 and 2 byte tails are shorter than
+// either cipher accepts; FF3-1 as FF1 takes long on whole segments in debug builds
+#[test]
+fn segment_boundary_lengths() {
+    for width in [65535, 65536, 65537, 65538, 131073] {
+        let pixels = strip(width);
+        let plain = Encoder::new()
+            .palette_size(256)
+            .encode_raw(&pixels, width, 1)
+            .unwrap();
+        let expected = Decoder::new().decode_to_vec(&plain).unwrap();
+        let encoder = Encoder::new().palette_size(256).key(&KEY).fpe(Fpe::Ff31);
+        let encrypted = encoder.encode_raw(&pixels, width, 1).unwrap();
+        let decoder = Decoder::new().key(&KEY);
+        assert_eq!(
+            decoder.decode_to_vec(&encrypted).unwrap(),
+            expected,
+            "{width}"
+        );
+
+        let mut ppm = Vec::new();
+        assert_eq!(
+            decoder
+                .decode_stream(encrypted.as_slice(), &mut ppm)
+                .unwrap(),
+            (width, 1)
+        );
+        assert!(ppm.ends_with(&expected.0), "{width}");
+        // Its tag would come before the pixels data it covers
+        let streamed = encoder.encode_stream(pixels.as_slice(), &mut Vec::new(), width, 1);
+        assert!(streamed.is_err(), "{width}");
+    }
+}
+
+// Encoder option of a test series, by name
+type Variant = (&'static str, fn(Encoder) -> Encoder);
+
+// Every layout under every cipher option: an encrypted file decodes to the pixels of the plain
+// one, and not with another key (segment_boundary_lengths covers pixels data of many segments)
+#[test]
+fn layouts_under_encryption() {
+    let image = image::RgbImage::from_fn(40, 30, |x, y| {
+        image::Rgb([(x * 7 + y * 13) as u8, (x * y) as u8, (x ^ y) as u8])
+    });
+    let layouts: [Variant; 11] = [
+        ("raster", |encoder| encoder),
+        ("filter", |encoder| encoder.filter(true)),
+        ("rle", |encoder| encoder.rle(true)),
+        ("interlace", |encoder| encoder.interlace(true)),
+        ("seek", |encoder| encoder.seek(true)),
+        ("seek rle", |encoder| encoder.seek(true).rle(true)),
+        ("hilbert", |encoder| encoder.scan(Scan::Hilbert)),
+        ("zigzag", |encoder| encoder.scan(Scan::Zigzag)),
+        ("column", |encoder| encoder.scan(Scan::Column)),
+        ("tiles", |encoder| encoder.tiles(16)),
+        ("truecolor", |encoder| encoder.lossless(true)),
+    ];
+    let ciphers: [Variant; 4] = [
+        ("ff1", |encoder| encoder),
+        ("ff3-1", |encoder| encoder.fpe(Fpe::Ff31)),
+        ("palette", |encoder| encoder.encrypt_palette(true)),
+        ("compressed", |encoder| encoder.compression(true)),
+    ];
+    for (layout, with_layout) in layouts {
+        let encoder = with_layout(Encoder::new().palette_size(16));
+        let plain = encoder.encode(&image).unwrap();
+        let expected = Decoder::new().decode_to_vec(&plain).unwrap();
+        for (cipher, with_cipher) in ciphers {
+            let name = format!("{layout} {cipher}");
+            let encoder = with_cipher(encoder.clone().key(&KEY));
+            let encrypted = match encoder.encode(&image) {
+                Ok(encrypted) => encrypted,
+                // Tiled files have no palette entries to encrypt, seek ones aren't compressed
+                Err(_)
+                    if matches!(
+                        (layout, cipher),
+                        ("tiles", "palette") | ("seek" | "seek rle", "compressed")
+                    ) =>
+                {
+                    continue;
+                }
+                Err(err) => panic!("{name}: {err}"),
+            };
+            assert_ne!(encrypted, plain, "{name}");
+            let decoded = Decoder::new().key(&KEY).decode_to_vec(&encrypted);
+            assert_eq!(decoded.unwrap(), expected, "{name}");
+            let wrong = Decoder::new().key(b"fedcba9876543210");
+            assert!(wrong.decode_to_vec(&encrypted).is_err(), "{name}");
+        }
+    }
+}
+
+// An encrypted file whose tag was stripped and pixels data changed, with its checksum made anew,
+// fails decode and the tag check instead of decoding the changed pixels
+#[test]
+fn stripped_auth_tag() {
+    let image = image::RgbImage::from_fn(20, 10, |x, y| {
+        image::Rgb([(x * 12) as u8, (y * 25) as u8, 0])
+    });
+    let encrypted = Encoder::new()
+        .palette_size(16)
+        .key(&KEY)
+        .encode(&image)
+        .unwrap();
+    let content = bare::without_checksum(&encrypted).unwrap();
+    let range = bare::metadata_range(content).unwrap();
+    let mut entries = metadata::parse(&content[range.clone()]).unwrap();
+    entries.retain(|(entry, _)| entry != keys::AUTH_KEY);
+    let mut tampered = content[..range.start - 4].to_vec();
+    tampered.extend(metadata::write(&entries));
+    tampered.extend(&content[range.end..]);
+    *tampered.last_mut().unwrap() ^= 1;
+    let key = utils::bytes_to_base64url(&KEY);
+    assert!(check_auth(&tampered, &key).is_err());
+    format::append_checksum(&mut tampered);
+    assert!(Decoder::new().key(&KEY).decode_to_vec(&tampered).is_err());
+}
+
+// Damage found by the chunk checksums of an encrypted file doesn't spare it the tag check, a
+// placeholder color only fills the damaged rows of plain files
+#[test]
+fn damaged_encrypted_fails_auth() {
+    let image =
+        image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, 0]));
+    let encoder = Encoder::new().palette_size(16).chunk_checksums(256);
+    let placeholder = image::Rgba([255, 0, 255, 255]);
+    for key in [None, Some(KEY)] {
+        let encoder = match key {
+            Some(key) => encoder.clone().key(&key),
+            None => encoder.clone(),
+        };
+        let mut damaged = encoder.encode(&image).unwrap();
+        let at = damaged.len() - 100;
+        damaged[at] ^= 0xff;
+        let decoder = match key {
+            Some(key) => Decoder::new().key(&key),
+            None => Decoder::new(),
+        };
+        let decoded = decoder.placeholder(placeholder).decode_to_vec(&damaged);
+        match key {
+            Some(_) => assert!(matches!(decoded, Err(CodecError::BadKey(_)))),
+            None => assert!(decoded.is_ok()),
+        }
+    }
+}
+
+// File of the first builds: v1 layout without a key check, the pixels data encrypted in one
+// FF1 block per thread (of 2) with an empty tweak; with its decoded pixels
+// rows and pixels read raster files' indices packed, at every index width and with rows ending
+// mid byte, and unpack the other layouts
+#[test]
+fn rows_and_pixels() {
+    let image = image::RgbImage::from_fn(13, 7, |x, y| {
+        image::Rgb([(x * 19 + y * 3) as u8, (x * y * 5) as u8, (x * 40) as u8])
+    });
+    let layouts: [Variant; 4] = [
+        ("raster", |encoder| encoder),
+        ("filter rle", |encoder| encoder.filter(true).rle(true)),
+        ("interlace", |encoder| encoder.interlace(true)),
+        ("zigzag", |encoder| encoder.scan(Scan::Zigzag)),
+    ];
+    for palette_size in [2, 4, 16, 256, 1000] {
+        for (layout, with_layout) in layouts {
+            let encoder = with_layout(Encoder::new().palette_size(palette_size));
+            let bytes = encoder.encode(&image).unwrap();
+            for decoder in [Decoder::new(), Decoder::new().strict(true)] {
+                let expected = decoder.decode_to_vec(&bytes).unwrap().0;
+                let pixels = decoder.pixels(&bytes).unwrap().flat_map(|pixel| pixel.0);
+                assert_eq!(
+                    pixels.collect::<Vec<_>>(),
+                    expected,
+                    "{palette_size} {layout}"
+                );
+                let rows = decoder
+                    .rows(&bytes)
+                    .unwrap()
+                    .flatten()
+                    .flat_map(|pixel| pixel.0);
+                assert_eq!(
+                    rows.collect::<Vec<_>>(),
+                    expected,
+                    "{palette_size} {layout}"
+                );
+            }
+        }
+    }
+}
+
+fn first_build_v1() -> (Vec<u8>, (Vec<u8>, u32, u32)) {
+    use aes::Aes128;
+    use cosmian_fpe::ff1::{BinaryNumeralString, FF1};
+
+    let (width, height) = (40u32, 30u32);
+    let palette = (0..16u8).flat_map(|i| [i * 16, 255 - i * 16, i]);
+    let indices = (0..width * height)
+        .map(|i| (i % 16) as u8)
+        .collect::<Vec<_>>();
+    let dimensions = ((width - 2) << 12) | (height - 2);
+    let mut file = dimensions.to_be_bytes()[1..].to_vec();
+    file.push(16 - 2);
+    file.extend(palette.clone());
+    let ff1 = FF1::<Aes128>::new(&KEY, 2).unwrap();
+    for chunk in indices.chunks(indices.len().div_ceil(2)) {
+        let bn = BinaryNumeralString::from_bytes_le(chunk);
+        file.extend(ff1.encrypt(&[], &bn).unwrap().to_bytes_le());
+    }
+    let palette = palette.collect::<Vec<_>>();
+    let pixels = indices
+        .iter()
+        .flat_map(|&index| palette[index as usize * 3..][..3].to_vec())
+        .collect();
+    (file, (pixels, width, height))
+}
+
+#[test]
+fn v1_without_key_check() {
+    let (file, expected) = first_build_v1();
+    let decoder = Decoder::new().key(&KEY).legacy(true).threads(2);
+    assert_eq!(decoder.decode_to_vec(&file).unwrap(), expected);
+}
+
+#[test]
+fn upgrade_v1_without_key_check() {
+    let (file, expected) = first_build_v1();
+    assert!(format::upgrade(&file).is_err());
+    let key = utils::bytes_to_base64url(&KEY);
+    let (upgraded, version) = format::upgrade_with_key(&file, &key, 2).unwrap();
+    assert_eq!(version, 1);
+    assert_eq!(format::version(&upgraded), format::VERSION);
+    let entries = metadata::read(&upgraded).unwrap();
+    assert!(entries.contains(&(metadata::UPGRADED_FROM_KEY.to_string(), b"1".to_vec())));
+    assert_eq!(
+        Decoder::new().key(&KEY).decode_to_vec(&upgraded).unwrap(),
+        expected
+    );
+    let wrong = Decoder::new().key(b"fedcba9876543210");
+    assert!(wrong.decode_to_vec(&upgraded).is_err());
+}
+
+// Files whose header claims the widest image, with a checksum made anew and with the stale one
+// verify looks for damage with
+fn oversized(encoder: Encoder) -> (Vec<u8>, Vec<u8>) {
+    let image = image::RgbImage::from_fn(32, 24, |x, y| {
+        image::Rgb([(x * 8) as u8, (y * 10) as u8, ((x + y) * 4) as u8])
+    });
+    let mut stale = encoder.palette_size(16).encode(&image).unwrap();
+    let (_, _, palette_size_at) = bare::dimensions(&stale).unwrap();
+    stale[palette_size_at - 8..palette_size_at - 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    let mut checked = stale[..stale.len() - format::CHECKSUM_LEN].to_vec();
+    format::append_checksum(&mut checked);
+    (checked, stale)
+}
+
+#[test]
+fn oversized_header_dimensions() {
+    for (name, encoder) in [
+        ("seek", Encoder::new().seek(true)),
+        ("seek rle", Encoder::new().seek(true).rle(true)),
+        ("rle", Encoder::new().rle(true)),
+        ("interlace", Encoder::new().interlace(true)),
+        ("filter", Encoder::new().filter(true)),
+    ] {
+        let (checked, stale) = oversized(encoder);
+        assert!(Decoder::new().decode(&checked).is_err(), "{name}");
+        assert!(Decoder::new().preview(&checked).is_err(), "{name}");
+        let region = Decoder::new().decode_region(std::io::Cursor::new(&checked), 0, 0, 8, 8);
+        assert!(region.is_err(), "{name}");
+        assert!(verify::verify(&stale).is_err(), "{name}");
+        let mut ppm = Vec::new();
+        assert!(
+            Decoder::new()
+                .decode_stream(checked.as_slice(), &mut ppm)
+                .is_err(),
+            "{name}"
+        );
+    }
+}
+
+// The library takes the compression levels the CLI and config file do
+#[test]
+fn compression_levels() {
+    let image = image::RgbImage::from_raw(20, 1, strip(20)).unwrap();
+    for (level, ok) in [(0, false), (1, true), (22, true), (23, false)] {
+        let encoded = Encoder::new()
+            .compression(true)
+            .compression_level(level)
+            .encode(&image);
+        assert_eq!(encoded.is_ok(), ok, "{level}");
+    }
+}
+
+// inspect describes a tiled file whose tile size is out of range rather than dividing by it
+#[test]
+fn inspect_bad_tile_size() {
+    let image = image::RgbImage::from_fn(32, 24, |x, y| {
+        image::Rgb([(x * 8) as u8, (y * 10) as u8, ((x + y) * 4) as u8])
+    });
+    let mut bytes = Encoder::new().tiles(8).encode(&image).unwrap();
+    let at = format::V5_PREFIX_LEN + format::STORED_LEN_LEN;
+    bytes[at..at + format::TILE_SIZE_LEN].fill(0);
+    assert!(inspect::inspect(&bytes, false, false).contains("invalid tile size 0"));
+    assert!(Decoder::new().decode(&bytes).is_err());
+}
+
+// --fec 10%: one run of damaged bytes of 10% of the file is repaired wherever it starts,
+// straddling shards or in the parity
+#[test]
+fn fec_burst() {
+    let file = strip(20_000);
+    let wrapped = fec::wrap(&file, 10).unwrap();
+    let start = fec::file_range(&wrapped).unwrap().start;
+    let burst = file.len() / 10;
+    for at in (start..wrapped.len() - burst).step_by(997) {
+        let mut damaged = wrapped.clone();
+        damaged[at..at + burst]
+            .iter_mut()
+            .for_each(|byte| *byte ^= 0xa5);
+        assert_eq!(fec::unwrap(&damaged).unwrap(), file.as_slice(), "{at}");
+    }
+}
+
+// Frame delays and the loop count survive a GIF or APNG, then a container of the frames
+#[test]
+fn animation_timing() {
+    let frames = (0..3u32)
+        .map(|i| {
+            let img = image::RgbaImage::from_fn(8, 6, |x, y| {
+                image::Rgba([(x * 30) as u8, (y * 40) as u8, (i * 80) as u8, 255])
+            });
+            (img, 40 + i * 30)
+        })
+        .collect();
+    let source = animation::Animation { frames, loops: 3 };
+    let (mut gif, mut apng) = (Vec::new(), Vec::new());
+    animation::write_gif(&source, &mut gif).unwrap();
+    animation::write_apng(&source, &mut apng).unwrap();
+    for bytes in [gif, apng] {
+        let read = animation::read(&bytes).unwrap().unwrap();
+        assert_eq!(read.loops, 3);
+        let files = read
+            .frames
+            .iter()
+            .map(|(img, _)| Encoder::new().palette_size(16).encode(img).unwrap())
+            .collect::<Vec<_>>();
+        let entries = files
+            .iter()
+            .zip(&read.frames)
+            .map(|(file, (_, delay))| container::Entry {
+                delay: Some(*delay),
+                ..container::Entry::new("frame".to_string(), file)
+            })
+            .collect();
+        let written = container::write(&container::Container {
+            entries,
+            loops: Some(read.loops),
+        })
+        .0;
+        let container = container::read(&written).unwrap();
+        assert_eq!(container.loops, Some(3));
+        let delays = container.entries.iter().map(|entry| entry.delay);
+        assert!(delays.eq([Some(40), Some(70), Some(100)]));
+    }
+    // Containers that aren't animations stay version 1, which older builds read
+    let file = Encoder::new().encode(&source.frames[0].0).unwrap();
+    let still = container::Container {
+        entries: vec![container::Entry::new("still".to_string(), &file)],
+        loops: None,
+    };
+    assert_eq!(container::write(&still).0[4], 1);
+}